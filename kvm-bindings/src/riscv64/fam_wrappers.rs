@@ -56,10 +56,35 @@ impl PartialEq for kvm_irq_routing {
 /// [FamStructWrapper](../vmm_sys_util/fam/struct.FamStructWrapper.html).
 pub type KvmIrqRouting = FamStructWrapper<kvm_irq_routing>;
 
+/// Size in bytes of the kernel's `sigset_t`, which is the only length accepted by
+/// `KVM_SET_SIGNAL_MASK`.
+pub const KVM_SIGSET_SIZE: usize = 8;
+
+// Implement the FamStruct trait for kvm_signal_mask.
+generate_fam_struct_impl!(kvm_signal_mask, u8, sigset, u32, len, KVM_SIGSET_SIZE);
+
+// Implement the PartialEq trait for kvm_signal_mask.
+impl PartialEq for kvm_signal_mask {
+    fn eq(&self, other: &kvm_signal_mask) -> bool {
+        // No need to call entries's eq, FamStructWrapper's PartialEq will do it for you
+        self.len == other.len
+    }
+}
+
+/// Wrapper over the `kvm_signal_mask` structure.
+///
+/// The `kvm_signal_mask` structure contains a flexible array member, holding the kernel's
+/// `sigset_t` in native byte order. For details check the [KVM
+/// API](https://docs.kernel.org/virt/kvm/api.html#kvm-set-signal-mask) documentation on
+/// `kvm_signal_mask`. To provide safe access to the array elements, this type is implemented using
+/// [FamStructWrapper](../vmm_sys_util/fam/struct.FamStructWrapper.html).
+pub type SigSet = FamStructWrapper<kvm_signal_mask>;
+
 #[cfg(test)]
 mod tests {
     use super::KvmIrqRouting;
     use super::RegList;
+    use super::{KVM_SIGSET_SIZE, SigSet};
     use vmm_sys_util::fam::FamStruct;
 
     #[test]
@@ -82,4 +107,25 @@ mod tests {
         assert_eq!(wrapper.as_fam_struct_ref().len(), 1);
         assert_eq!(wrapper.as_fam_struct_ref().nr, 1);
     }
+
+    #[test]
+    fn test_sigset() {
+        // A signal mask always has the size of the kernel's `sigset_t`, and starts empty.
+        let mut wrapper = SigSet::new(KVM_SIGSET_SIZE).unwrap();
+        assert_eq!(wrapper.as_fam_struct_ref().len, KVM_SIGSET_SIZE as u32);
+        assert_eq!(wrapper.as_slice(), [0; KVM_SIGSET_SIZE]);
+        assert!(SigSet::new(KVM_SIGSET_SIZE + 1).is_err());
+        assert!(SigSet::from_entries(&[0; KVM_SIGSET_SIZE + 1]).is_err());
+
+        // Signal `n` is bit `n - 1` of the mask, in native byte order, and the mask
+        // survives a copy through its bytes.
+        let sigusr1 = 10;
+        wrapper
+            .as_mut_slice()
+            .copy_from_slice(&(1u64 << (sigusr1 - 1)).to_ne_bytes());
+        let copy = SigSet::from_entries(wrapper.as_slice()).unwrap();
+        assert_eq!(copy.as_fam_struct_ref().len, KVM_SIGSET_SIZE as u32);
+        let mask = u64::from_ne_bytes(copy.as_slice().try_into().unwrap());
+        assert_eq!(mask, 1 << 9);
+    }
 }
@@ -0,0 +1,522 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::fmt;
+use std::mem::offset_of;
+
+use kvm_bindings::*;
+
+use crate::ioctls::vcpu::reg_size;
+
+const KVM_REG_ARM_COPROC_MASK_U64: u64 = KVM_REG_ARM_COPROC_MASK as u64;
+
+/// Known system registers and their `(op0, op1, CRn, CRm, op2)` encodings.
+///
+/// The two virtual timer registers exposed by KVM as `KVM_REG_ARM_TIMER_CNT` and
+/// `KVM_REG_ARM_TIMER_CVAL` have their encodings swapped compared to the architecture
+/// (`CNTV_CVAL_EL0` and `CNTVCT_EL0` respectively). This is part of the KVM ABI, so they
+/// are listed under their KVM names.
+const SYS_REG_NAMES: &[(&str, [u8; 5])] = &[
+    ("MIDR_EL1", [3, 0, 0, 0, 0]),
+    ("MPIDR_EL1", [3, 0, 0, 0, 5]),
+    ("REVIDR_EL1", [3, 0, 0, 0, 6]),
+    ("ID_AA64PFR0_EL1", [3, 0, 0, 4, 0]),
+    ("ID_AA64PFR1_EL1", [3, 0, 0, 4, 1]),
+    ("ID_AA64ZFR0_EL1", [3, 0, 0, 4, 4]),
+    ("ID_AA64SMFR0_EL1", [3, 0, 0, 4, 5]),
+    ("ID_AA64DFR0_EL1", [3, 0, 0, 5, 0]),
+    ("ID_AA64DFR1_EL1", [3, 0, 0, 5, 1]),
+    ("ID_AA64AFR0_EL1", [3, 0, 0, 5, 4]),
+    ("ID_AA64AFR1_EL1", [3, 0, 0, 5, 5]),
+    ("ID_AA64ISAR0_EL1", [3, 0, 0, 6, 0]),
+    ("ID_AA64ISAR1_EL1", [3, 0, 0, 6, 1]),
+    ("ID_AA64ISAR2_EL1", [3, 0, 0, 6, 2]),
+    ("ID_AA64MMFR0_EL1", [3, 0, 0, 7, 0]),
+    ("ID_AA64MMFR1_EL1", [3, 0, 0, 7, 1]),
+    ("ID_AA64MMFR2_EL1", [3, 0, 0, 7, 2]),
+    ("ID_AA64MMFR3_EL1", [3, 0, 0, 7, 3]),
+    ("SCTLR_EL1", [3, 0, 1, 0, 0]),
+    ("ACTLR_EL1", [3, 0, 1, 0, 1]),
+    ("CPACR_EL1", [3, 0, 1, 0, 2]),
+    ("ZCR_EL1", [3, 0, 1, 2, 0]),
+    ("TTBR0_EL1", [3, 0, 2, 0, 0]),
+    ("TTBR1_EL1", [3, 0, 2, 0, 1]),
+    ("TCR_EL1", [3, 0, 2, 0, 2]),
+    ("AFSR0_EL1", [3, 0, 5, 1, 0]),
+    ("AFSR1_EL1", [3, 0, 5, 1, 1]),
+    ("ESR_EL1", [3, 0, 5, 2, 0]),
+    ("FAR_EL1", [3, 0, 6, 0, 0]),
+    ("PAR_EL1", [3, 0, 7, 4, 0]),
+    ("MAIR_EL1", [3, 0, 10, 2, 0]),
+    ("AMAIR_EL1", [3, 0, 10, 3, 0]),
+    ("VBAR_EL1", [3, 0, 12, 0, 0]),
+    ("CONTEXTIDR_EL1", [3, 0, 13, 0, 1]),
+    ("TPIDR_EL1", [3, 0, 13, 0, 4]),
+    ("CNTKCTL_EL1", [3, 0, 14, 1, 0]),
+    ("CCSIDR_EL1", [3, 1, 0, 0, 0]),
+    ("CLIDR_EL1", [3, 1, 0, 0, 1]),
+    ("CSSELR_EL1", [3, 2, 0, 0, 0]),
+    ("CTR_EL0", [3, 3, 0, 0, 1]),
+    ("DCZID_EL0", [3, 3, 0, 0, 7]),
+    ("PMCR_EL0", [3, 3, 9, 12, 0]),
+    ("TPIDR_EL0", [3, 3, 13, 0, 2]),
+    ("TPIDRRO_EL0", [3, 3, 13, 0, 3]),
+    ("CNTFRQ_EL0", [3, 3, 14, 0, 0]),
+    ("CNTPCT_EL0", [3, 3, 14, 0, 1]),
+    ("KVM_REG_ARM_TIMER_CVAL", [3, 3, 14, 0, 2]),
+    ("CNTP_CTL_EL0", [3, 3, 14, 2, 1]),
+    ("CNTP_CVAL_EL0", [3, 3, 14, 2, 2]),
+    ("KVM_REG_ARM_TIMER_CTL", [3, 3, 14, 3, 1]),
+    ("KVM_REG_ARM_TIMER_CNT", [3, 3, 14, 3, 2]),
+    ("MDCCINT_EL1", [2, 0, 0, 2, 0]),
+    ("MDSCR_EL1", [2, 0, 0, 2, 2]),
+    ("OSLSR_EL1", [2, 0, 1, 1, 4]),
+];
+
+/// Class of an arm64 register, as encoded in its `KVM_REG_ARM_COPROC_MASK` bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Arm64RegClass {
+    /// Core register, addressed by its offset in `kvm_regs` (`KVM_REG_ARM_CORE`).
+    Core,
+    /// Demultiplexed register such as `CCSIDR_EL1` (`KVM_REG_ARM_DEMUX`).
+    Demux,
+    /// System register (`KVM_REG_ARM64_SYSREG`).
+    SysReg,
+    /// Firmware pseudo-register (`KVM_REG_ARM_FW`).
+    Firmware,
+    /// SVE register slice or the SVE vector length register (`KVM_REG_ARM64_SVE`).
+    Sve,
+    /// Firmware feature bitmap register (`KVM_REG_ARM_FW_FEAT_BMAP`).
+    FwFeatBmap,
+}
+
+/// Identifier of an arm64 vCPU register as used by `KVM_GET_ONE_REG`/`KVM_SET_ONE_REG`.
+///
+/// The constructors encode the architecture, size and class bits so that callers do not
+/// have to assemble register ids by hand. The raw id can be obtained with
+/// [`Arm64Reg::id`] and passed to [`VcpuFd::get_one_reg`](crate::VcpuFd::get_one_reg) and
+/// [`VcpuFd::set_one_reg`](crate::VcpuFd::set_one_reg).
+///
+/// # Example
+///
+/// ```rust
+/// # #[cfg(target_arch = "aarch64")]
+/// # {
+/// use kvm_ioctls::Arm64Reg;
+///
+/// assert_eq!(Arm64Reg::PC.id(), 0x6030_0000_0010_0040);
+/// assert_eq!(Arm64Reg::x(0).size(), 8);
+/// assert_eq!(Arm64Reg::MPIDR_EL1.to_string(), "MPIDR_EL1");
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Arm64Reg(u64);
+
+impl Arm64Reg {
+    /// Stack pointer (`SP_EL0`).
+    pub const SP: Self = Self::core(offset_of!(kvm_regs, regs.sp), KVM_REG_SIZE_U64);
+    /// Program counter.
+    pub const PC: Self = Self::core(offset_of!(kvm_regs, regs.pc), KVM_REG_SIZE_U64);
+    /// Processor state.
+    pub const PSTATE: Self = Self::core(offset_of!(kvm_regs, regs.pstate), KVM_REG_SIZE_U64);
+    /// EL1 stack pointer.
+    pub const SP_EL1: Self = Self::core(offset_of!(kvm_regs, sp_el1), KVM_REG_SIZE_U64);
+    /// EL1 exception link register.
+    pub const ELR_EL1: Self = Self::core(offset_of!(kvm_regs, elr_el1), KVM_REG_SIZE_U64);
+    /// Floating-point status register.
+    pub const FPSR: Self = Self::core(offset_of!(kvm_regs, fp_regs.fpsr), KVM_REG_SIZE_U32);
+    /// Floating-point control register.
+    pub const FPCR: Self = Self::core(offset_of!(kvm_regs, fp_regs.fpcr), KVM_REG_SIZE_U32);
+
+    /// Main ID register.
+    pub const MIDR_EL1: Self = Self::sys_reg(3, 0, 0, 0, 0);
+    /// Multiprocessor affinity register.
+    pub const MPIDR_EL1: Self = Self::sys_reg(3, 0, 0, 0, 5);
+    /// Processor feature register 0.
+    pub const ID_AA64PFR0_EL1: Self = Self::sys_reg(3, 0, 0, 4, 0);
+    /// Memory model feature register 0.
+    pub const ID_AA64MMFR0_EL1: Self = Self::sys_reg(3, 0, 0, 7, 0);
+    /// System control register.
+    pub const SCTLR_EL1: Self = Self::sys_reg(3, 0, 1, 0, 0);
+    /// Virtual timer control register (`KVM_REG_ARM_TIMER_CTL`).
+    pub const TIMER_CTL: Self = Self::sys_reg(3, 3, 14, 3, 1);
+    /// Virtual counter register (`KVM_REG_ARM_TIMER_CNT`).
+    ///
+    /// Note that KVM encodes this register as `CNTV_CVAL_EL0`.
+    pub const TIMER_CNT: Self = Self::sys_reg(3, 3, 14, 3, 2);
+    /// Virtual timer compare value register (`KVM_REG_ARM_TIMER_CVAL`).
+    ///
+    /// Note that KVM encodes this register as `CNTVCT_EL0`.
+    pub const TIMER_CVAL: Self = Self::sys_reg(3, 3, 14, 0, 2);
+
+    /// PSCI version implemented by KVM.
+    pub const PSCI_VERSION: Self = Self::fw(0);
+    /// SMCCC `ARCH_WORKAROUND_1` (Spectre-v2) state.
+    pub const SMCCC_ARCH_WORKAROUND_1: Self = Self::fw(1);
+    /// SMCCC `ARCH_WORKAROUND_2` (Spectre-v4) state.
+    pub const SMCCC_ARCH_WORKAROUND_2: Self = Self::fw(2);
+    /// SMCCC `ARCH_WORKAROUND_3` (Spectre-BHB) state.
+    pub const SMCCC_ARCH_WORKAROUND_3: Self = Self::fw(3);
+
+    /// Bitmap of standard SMCCC services exposed to the guest.
+    pub const STD_BMAP: Self = Self::fw_feat_bmap(0);
+    /// Bitmap of standard hypervisor SMCCC services exposed to the guest.
+    pub const STD_HYP_BMAP: Self = Self::fw_feat_bmap(1);
+    /// Bitmap of vendor specific hypervisor SMCCC services exposed to the guest.
+    pub const VENDOR_HYP_BMAP: Self = Self::fw_feat_bmap(2);
+
+    /// Bitmap of the SVE vector lengths supported by the vCPU.
+    pub const SVE_VLS: Self =
+        Self(KVM_REG_ARM64 | KVM_REG_SIZE_U512 | KVM_REG_ARM64_SVE as u64 | 0xffff);
+
+    /// Creates a register from a raw `KVM_{GET,SET}_ONE_REG` id.
+    pub const fn from_id(id: u64) -> Self {
+        Self(id)
+    }
+
+    /// Returns the raw id of the register.
+    pub const fn id(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns the size of the register in bytes.
+    ///
+    /// This is the same value returned by [`reg_size`](crate::reg_size).
+    pub fn size(&self) -> usize {
+        reg_size(self.0)
+    }
+
+    /// Creates a core register id from its byte offset in `kvm_regs`.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset of the register in `kvm_regs`, usually obtained with
+    ///   `std::mem::offset_of!`.
+    /// * `size` - One of the `KVM_REG_SIZE_*` constants.
+    pub const fn core(offset: usize, size: u64) -> Self {
+        Self(KVM_REG_ARM64 | size | KVM_REG_ARM_CORE as u64 | (offset / 4) as u64)
+    }
+
+    /// Creates the id of the general purpose register `Xn`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is larger than 30.
+    pub const fn x(n: usize) -> Self {
+        assert!(n < 31, "invalid general purpose register");
+        Self::core(
+            offset_of!(kvm_regs, regs.regs) + n * size_of::<u64>(),
+            KVM_REG_SIZE_U64,
+        )
+    }
+
+    /// Creates the id of the saved program status register at index `n`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is larger than 4.
+    pub const fn spsr(n: usize) -> Self {
+        assert!(n < 5, "invalid SPSR index");
+        Self::core(
+            offset_of!(kvm_regs, spsr) + n * size_of::<u64>(),
+            KVM_REG_SIZE_U64,
+        )
+    }
+
+    /// Creates the id of the 128-bit FP/SIMD register `Vn`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is larger than 31.
+    pub const fn v(n: usize) -> Self {
+        assert!(n < 32, "invalid FP/SIMD register");
+        Self::core(
+            offset_of!(kvm_regs, fp_regs.vregs) + n * size_of::<u128>(),
+            KVM_REG_SIZE_U128,
+        )
+    }
+
+    /// Creates a system register id from its architectural encoding.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the fields does not fit in its encoding.
+    pub const fn sys_reg(op0: u8, op1: u8, crn: u8, crm: u8, op2: u8) -> Self {
+        assert!(op0 < 4 && op1 < 8 && crn < 16 && crm < 16 && op2 < 8);
+        Self(
+            KVM_REG_ARM64
+                | KVM_REG_SIZE_U64
+                | KVM_REG_ARM64_SYSREG as u64
+                | ((op0 as u64) << KVM_REG_ARM64_SYSREG_OP0_SHIFT)
+                | ((op1 as u64) << KVM_REG_ARM64_SYSREG_OP1_SHIFT)
+                | ((crn as u64) << KVM_REG_ARM64_SYSREG_CRN_SHIFT)
+                | ((crm as u64) << KVM_REG_ARM64_SYSREG_CRM_SHIFT)
+                | ((op2 as u64) << KVM_REG_ARM64_SYSREG_OP2_SHIFT),
+        )
+    }
+
+    /// Creates the id of the `CCSIDR_EL1` value selected by `csselr`.
+    pub const fn ccsidr(csselr: u8) -> Self {
+        Self(
+            KVM_REG_ARM64
+                | KVM_REG_SIZE_U32
+                | KVM_REG_ARM_DEMUX as u64
+                | ((KVM_REG_ARM_DEMUX_ID_CCSIDR as u64) << KVM_REG_ARM_DEMUX_ID_SHIFT)
+                | csselr as u64,
+        )
+    }
+
+    /// Creates the id of the firmware pseudo-register at `index`.
+    pub const fn fw(index: u16) -> Self {
+        Self(KVM_REG_ARM64 | KVM_REG_SIZE_U64 | KVM_REG_ARM_FW as u64 | index as u64)
+    }
+
+    /// Creates the id of the firmware feature bitmap register at `index`.
+    pub const fn fw_feat_bmap(index: u16) -> Self {
+        Self(KVM_REG_ARM64 | KVM_REG_SIZE_U64 | KVM_REG_ARM_FW_FEAT_BMAP as u64 | index as u64)
+    }
+
+    /// Creates the id of `slice` of the SVE vector register `Zn`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` or `slice` are out of range.
+    pub const fn sve_zreg(n: u32, slice: u32) -> Self {
+        assert!(n < KVM_ARM64_SVE_NUM_ZREGS && slice < KVM_ARM64_SVE_MAX_SLICES);
+        Self(
+            KVM_REG_ARM64
+                | KVM_REG_SIZE_U2048
+                | (KVM_REG_ARM64_SVE | KVM_REG_ARM64_SVE_ZREG_BASE | (n << 5) | slice) as u64,
+        )
+    }
+
+    /// Creates the id of `slice` of the SVE predicate register `Pn`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` or `slice` are out of range.
+    pub const fn sve_preg(n: u32, slice: u32) -> Self {
+        assert!(n < KVM_ARM64_SVE_NUM_PREGS && slice < KVM_ARM64_SVE_MAX_SLICES);
+        Self(
+            KVM_REG_ARM64
+                | KVM_REG_SIZE_U256
+                | (KVM_REG_ARM64_SVE | KVM_REG_ARM64_SVE_PREG_BASE | (n << 5) | slice) as u64,
+        )
+    }
+
+    /// Creates the id of `slice` of the SVE first fault register.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `slice` is out of range.
+    pub const fn sve_ffr(slice: u32) -> Self {
+        assert!(slice < KVM_ARM64_SVE_MAX_SLICES);
+        Self(
+            KVM_REG_ARM64
+                | KVM_REG_SIZE_U256
+                | (KVM_REG_ARM64_SVE | KVM_REG_ARM64_SVE_FFR_BASE | slice) as u64,
+        )
+    }
+
+    /// Returns the class of the register, or `None` if it is not an arm64 register
+    /// or the class is unknown.
+    pub const fn class(&self) -> Option<Arm64RegClass> {
+        if self.0 & KVM_REG_ARCH_MASK as u64 != KVM_REG_ARM64 {
+            return None;
+        }
+        match (self.0 & KVM_REG_ARM_COPROC_MASK_U64) as u32 {
+            KVM_REG_ARM_CORE => Some(Arm64RegClass::Core),
+            KVM_REG_ARM_DEMUX => Some(Arm64RegClass::Demux),
+            KVM_REG_ARM64_SYSREG => Some(Arm64RegClass::SysReg),
+            KVM_REG_ARM_FW => Some(Arm64RegClass::Firmware),
+            KVM_REG_ARM64_SVE => Some(Arm64RegClass::Sve),
+            KVM_REG_ARM_FW_FEAT_BMAP => Some(Arm64RegClass::FwFeatBmap),
+            _ => None,
+        }
+    }
+
+    /// Returns the `[op0, op1, CRn, CRm, op2]` encoding of a system register, or `None`
+    /// if this is not a system register.
+    pub const fn sys_reg_encoding(&self) -> Option<[u8; 5]> {
+        if !matches!(self.class(), Some(Arm64RegClass::SysReg)) {
+            return None;
+        }
+        let id = self.0 as u32;
+        Some([
+            ((id & KVM_REG_ARM64_SYSREG_OP0_MASK) >> KVM_REG_ARM64_SYSREG_OP0_SHIFT) as u8,
+            ((id & KVM_REG_ARM64_SYSREG_OP1_MASK) >> KVM_REG_ARM64_SYSREG_OP1_SHIFT) as u8,
+            ((id & KVM_REG_ARM64_SYSREG_CRN_MASK) >> KVM_REG_ARM64_SYSREG_CRN_SHIFT) as u8,
+            ((id & KVM_REG_ARM64_SYSREG_CRM_MASK) >> KVM_REG_ARM64_SYSREG_CRM_SHIFT) as u8,
+            ((id & KVM_REG_ARM64_SYSREG_OP2_MASK) >> KVM_REG_ARM64_SYSREG_OP2_SHIFT) as u8,
+        ])
+    }
+
+    fn fmt_core(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = self.size();
+        let offset = (self.0 & 0xffff) as usize * 4;
+        let regs = offset_of!(kvm_regs, regs.regs);
+        let spsr = offset_of!(kvm_regs, spsr);
+        let vregs = offset_of!(kvm_regs, fp_regs.vregs);
+        match (offset, size) {
+            (o, 8) if (regs..regs + 31 * 8).contains(&o) && (o - regs) % 8 == 0 => {
+                write!(f, "X{}", (o - regs) / 8)
+            }
+            (o, 8) if (spsr..spsr + 5 * 8).contains(&o) && (o - spsr) % 8 == 0 => {
+                write!(f, "SPSR[{}]", (o - spsr) / 8)
+            }
+            (o, 16) if (vregs..vregs + 32 * 16).contains(&o) && (o - vregs) % 16 == 0 => {
+                write!(f, "V{}", (o - vregs) / 16)
+            }
+            _ => match *self {
+                Self::SP => write!(f, "SP"),
+                Self::PC => write!(f, "PC"),
+                Self::PSTATE => write!(f, "PSTATE"),
+                Self::SP_EL1 => write!(f, "SP_EL1"),
+                Self::ELR_EL1 => write!(f, "ELR_EL1"),
+                Self::FPSR => write!(f, "FPSR"),
+                Self::FPCR => write!(f, "FPCR"),
+                _ => write!(f, "core[{offset:#x}]"),
+            },
+        }
+    }
+}
+
+impl From<u64> for Arm64Reg {
+    fn from(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+impl From<Arm64Reg> for u64 {
+    fn from(reg: Arm64Reg) -> Self {
+        reg.0
+    }
+}
+
+impl fmt::Display for Arm64Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let index = self.0 & 0xffff;
+        match self.class() {
+            Some(Arm64RegClass::Core) => self.fmt_core(f),
+            Some(Arm64RegClass::SysReg) => {
+                // The class was just checked, so there always is an encoding.
+                let enc = self.sys_reg_encoding().unwrap();
+                match SYS_REG_NAMES.iter().find(|(_, e)| *e == enc) {
+                    Some((name, _)) => write!(f, "{name}"),
+                    None => write!(
+                        f,
+                        "S{}_{}_C{}_C{}_{}",
+                        enc[0], enc[1], enc[2], enc[3], enc[4]
+                    ),
+                }
+            }
+            Some(Arm64RegClass::Demux) => write!(f, "CCSIDR[{}]", self.0 & 0xff),
+            Some(Arm64RegClass::Firmware) => match *self {
+                Self::PSCI_VERSION => write!(f, "PSCI_VERSION"),
+                Self::SMCCC_ARCH_WORKAROUND_1 => write!(f, "SMCCC_ARCH_WORKAROUND_1"),
+                Self::SMCCC_ARCH_WORKAROUND_2 => write!(f, "SMCCC_ARCH_WORKAROUND_2"),
+                Self::SMCCC_ARCH_WORKAROUND_3 => write!(f, "SMCCC_ARCH_WORKAROUND_3"),
+                _ => write!(f, "FW[{index}]"),
+            },
+            Some(Arm64RegClass::FwFeatBmap) => match *self {
+                Self::STD_BMAP => write!(f, "STD_BMAP"),
+                Self::STD_HYP_BMAP => write!(f, "STD_HYP_BMAP"),
+                Self::VENDOR_HYP_BMAP => write!(f, "VENDOR_HYP_BMAP"),
+                _ => write!(f, "FW_FEAT_BMAP[{index}]"),
+            },
+            Some(Arm64RegClass::Sve) => {
+                let slice = index & 0x1f;
+                let n = (index >> 5) & 0x1f;
+                match index as u32 {
+                    _ if *self == Self::SVE_VLS => write!(f, "SVE_VLS"),
+                    i if i < KVM_REG_ARM64_SVE_PREG_BASE => write!(f, "Z{n}[{slice}]"),
+                    i if i < KVM_REG_ARM64_SVE_FFR_BASE => write!(f, "P{n}[{slice}]"),
+                    i if i < KVM_REG_ARM64_SVE_FFR_BASE + KVM_ARM64_SVE_MAX_SLICES => {
+                        write!(f, "FFR[{slice}]")
+                    }
+                    _ => write!(f, "{:#x}", self.0),
+                }
+            }
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_core_regs() {
+        // Values taken from the documentation of `KVM_SET_ONE_REG`.
+        assert_eq!(Arm64Reg::x(0).id(), 0x6030_0000_0010_0000);
+        assert_eq!(Arm64Reg::x(30).id(), 0x6030_0000_0010_003c);
+        assert_eq!(Arm64Reg::SP.id(), 0x6030_0000_0010_003e);
+        assert_eq!(Arm64Reg::PC.id(), 0x6030_0000_0010_0040);
+        assert_eq!(Arm64Reg::PSTATE.id(), 0x6030_0000_0010_0042);
+        assert_eq!(Arm64Reg::SP_EL1.id(), 0x6030_0000_0010_0044);
+        assert_eq!(Arm64Reg::ELR_EL1.id(), 0x6030_0000_0010_0046);
+        assert_eq!(Arm64Reg::spsr(4).id(), 0x6030_0000_0010_0050);
+        assert_eq!(Arm64Reg::v(0).id(), 0x6040_0000_0010_0054);
+        assert_eq!(Arm64Reg::v(31).id(), 0x6040_0000_0010_00d0);
+        assert_eq!(Arm64Reg::FPSR.id(), 0x6020_0000_0010_00d4);
+        assert_eq!(Arm64Reg::FPCR.id(), 0x6020_0000_0010_00d5);
+
+        assert_eq!(Arm64Reg::x(3).size(), 8);
+        assert_eq!(Arm64Reg::v(3).size(), 16);
+        assert_eq!(Arm64Reg::FPCR.size(), 4);
+
+        assert_eq!(Arm64Reg::x(17).to_string(), "X17");
+        assert_eq!(Arm64Reg::spsr(2).to_string(), "SPSR[2]");
+        assert_eq!(Arm64Reg::v(5).to_string(), "V5");
+        assert_eq!(Arm64Reg::PC.to_string(), "PC");
+        assert_eq!(Arm64Reg::FPSR.to_string(), "FPSR");
+    }
+
+    #[test]
+    fn test_sys_regs() {
+        assert_eq!(Arm64Reg::MPIDR_EL1.id(), 0x6030_0000_0013_c005);
+        assert_eq!(Arm64Reg::TIMER_CNT.id(), 0x6030_0000_0013_df1a);
+        assert_eq!(Arm64Reg::MPIDR_EL1.size(), 8);
+        assert_eq!(Arm64Reg::MPIDR_EL1.class(), Some(Arm64RegClass::SysReg));
+        assert_eq!(
+            Arm64Reg::sys_reg(3, 0, 2, 0, 2).sys_reg_encoding(),
+            Some([3, 0, 2, 0, 2])
+        );
+        assert_eq!(Arm64Reg::PC.sys_reg_encoding(), None);
+
+        assert_eq!(Arm64Reg::sys_reg(3, 0, 2, 0, 2).to_string(), "TCR_EL1");
+        assert_eq!(Arm64Reg::TIMER_CVAL.to_string(), "KVM_REG_ARM_TIMER_CVAL");
+        assert_eq!(
+            Arm64Reg::sys_reg(3, 4, 11, 1, 7).to_string(),
+            "S3_4_C11_C1_7"
+        );
+    }
+
+    #[test]
+    fn test_other_regs() {
+        assert_eq!(Arm64Reg::PSCI_VERSION.id(), 0x6030_0000_0014_0000);
+        assert_eq!(Arm64Reg::VENDOR_HYP_BMAP.id(), 0x6030_0000_0016_0002);
+        assert_eq!(Arm64Reg::ccsidr(1).id(), 0x6020_0000_0011_0001);
+        assert_eq!(Arm64Reg::SVE_VLS.id(), 0x6060_0000_0015_ffff);
+        assert_eq!(Arm64Reg::sve_zreg(1, 0).id(), 0x6080_0000_0015_0020);
+        assert_eq!(Arm64Reg::sve_preg(1, 0).id(), 0x6050_0000_0015_0420);
+        assert_eq!(Arm64Reg::sve_ffr(0).id(), 0x6050_0000_0015_0600);
+
+        assert_eq!(Arm64Reg::sve_zreg(0, 0).size(), 256);
+        assert_eq!(Arm64Reg::sve_preg(0, 0).size(), 32);
+        assert_eq!(Arm64Reg::SVE_VLS.size(), 64);
+
+        assert_eq!(Arm64Reg::PSCI_VERSION.to_string(), "PSCI_VERSION");
+        assert_eq!(Arm64Reg::fw(7).to_string(), "FW[7]");
+        assert_eq!(Arm64Reg::STD_BMAP.to_string(), "STD_BMAP");
+        assert_eq!(Arm64Reg::ccsidr(2).to_string(), "CCSIDR[2]");
+        assert_eq!(Arm64Reg::SVE_VLS.to_string(), "SVE_VLS");
+        assert_eq!(Arm64Reg::sve_zreg(31, 1).to_string(), "Z31[1]");
+        assert_eq!(Arm64Reg::sve_preg(15, 0).to_string(), "P15[0]");
+        assert_eq!(Arm64Reg::sve_ffr(0).to_string(), "FFR[0]");
+
+        assert_eq!(Arm64Reg::from_id(0).class(), None);
+        assert_eq!(Arm64Reg::from(0x1234).to_string(), "0x1234");
+        assert_eq!(u64::from(Arm64Reg::PC), 0x6030_0000_0010_0040);
+    }
+}
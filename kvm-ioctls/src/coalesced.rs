@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use kvm_bindings::kvm_coalesced_mmio;
+
+use crate::ioctls::KvmCoalescedIoRing;
+
+/// The address space of a [`CoalescedAccess`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CoalescedAccessKind {
+    /// A write to a memory mapped I/O address.
+    Mmio,
+    /// A write to an I/O port.
+    Pio,
+}
+
+/// A guest write to a coalesced MMIO or PIO zone, read from the coalesced ring.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoalescedAccess {
+    /// Whether the write was to memory or to an I/O port.
+    pub kind: CoalescedAccessKind,
+    /// The guest physical address or the port written to.
+    pub addr: u64,
+    len: u8,
+    data: [u8; 8],
+}
+
+impl CoalescedAccess {
+    /// Returns the data written by the guest, of the size of the access.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..usize::from(self.len)]
+    }
+}
+
+impl From<kvm_coalesced_mmio> for CoalescedAccess {
+    fn from(entry: kvm_coalesced_mmio) -> Self {
+        // SAFETY: Both members of the union are a `u32`, so any bit pattern is valid.
+        let pio = unsafe { entry.__bindgen_anon_1.pio };
+        CoalescedAccess {
+            kind: if pio != 0 {
+                CoalescedAccessKind::Pio
+            } else {
+                CoalescedAccessKind::Mmio
+            },
+            addr: entry.phys_addr,
+            // KVM only coalesces accesses that fit in `data`.
+            len: entry.len.min(entry.data.len() as u32) as u8,
+            data: entry.data,
+        }
+    }
+}
+
+/// An iterator over the entries of the coalesced ring, returned by
+/// `VcpuFd::drain_coalesced_io`.
+///
+/// The iterator stops at the last entry present when it was created, so that it
+/// ends even if other vCPUs keep filling the ring. Each entry is removed from the
+/// ring as it is returned, and the entries not consumed are left in the ring.
+#[derive(Debug)]
+pub struct CoalescedIoDrain<'a> {
+    ring: &'a mut KvmCoalescedIoRing,
+    remaining: usize,
+}
+
+impl<'a> CoalescedIoDrain<'a> {
+    pub(crate) fn new(ring: &'a mut KvmCoalescedIoRing) -> Self {
+        let remaining = ring.len();
+        CoalescedIoDrain { ring, remaining }
+    }
+}
+
+impl Iterator for CoalescedIoDrain<'_> {
+    type Item = CoalescedAccess;
+
+    fn next(&mut self) -> Option<CoalescedAccess> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        self.ring.read_entry().map(CoalescedAccess::from)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalesced_access() {
+        let mut entry = kvm_coalesced_mmio {
+            phys_addr: 0x3f8,
+            len: 1,
+            data: [0x41, 0, 0, 0, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        entry.__bindgen_anon_1.pio = 1;
+        let access = CoalescedAccess::from(entry);
+        assert_eq!(access.kind, CoalescedAccessKind::Pio);
+        assert_eq!(access.addr, 0x3f8);
+        assert_eq!(access.data(), [0x41]);
+
+        entry.__bindgen_anon_1.pio = 0;
+        entry.len = 16;
+        let access = CoalescedAccess::from(entry);
+        assert_eq!(access.kind, CoalescedAccessKind::Mmio);
+        assert_eq!(access.data().len(), 8);
+    }
+}
@@ -0,0 +1,775 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::fmt;
+
+use kvm_bindings::{CpuId, KVM_MAX_CPUID_ENTRIES, Msrs, kvm_cpuid_entry2, kvm_msr_entry};
+use vmm_sys_util::errno;
+
+use crate::cpuid::{CpuidReg, X86Feature, entry_matches};
+use crate::ioctls::Result;
+use crate::ioctls::system::Kvm;
+use crate::msr::{
+    IA32_ARCH_CAPABILITIES, IA32_PERF_CAPABILITIES, IA32_UCODE_REV, IA32_VMX_BASIC,
+    IA32_VMX_CR0_FIXED0, IA32_VMX_CR0_FIXED1, IA32_VMX_CR4_FIXED0, IA32_VMX_CR4_FIXED1,
+    IA32_VMX_ENTRY_CTLS, IA32_VMX_MISC, IA32_VMX_PINBASED_CTLS, IA32_VMX_PROCBASED_CTLS2,
+    IA32_VMX_TRUE_ENTRY_CTLS, IA32_VMX_TRUE_PINBASED_CTLS, IA32_VMX_VMCS_ENUM,
+};
+
+/// How the values of a field reported by several hosts are combined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Merge {
+    /// Bit vector where a set bit means the host provides a feature.
+    And,
+    /// Bit vector where a set bit means the host lacks a feature ("lower is better").
+    Or,
+    /// Numeric field where a higher value means more capabilities.
+    Min,
+    /// Field that must be identical on all hosts, such as the vendor string.
+    Exact,
+    /// Field describing the host rather than its features, such as the cache
+    /// topology. The value of the first host is kept and it is never checked.
+    Keep,
+}
+
+struct CpuidRule {
+    leaf: u32,
+    subleaf: Option<u32>,
+    reg: CpuidReg,
+    mask: u32,
+    merge: Merge,
+}
+
+const fn cpuid_rule(
+    leaf: u32,
+    subleaf: Option<u32>,
+    reg: CpuidReg,
+    mask: u32,
+    merge: Merge,
+) -> CpuidRule {
+    CpuidRule {
+        leaf,
+        subleaf,
+        reg,
+        mask,
+        merge,
+    }
+}
+
+const BASIC_LEAVES: u32 = 0x0;
+const HYPERVISOR_LEAVES: u32 = 0x4000_0000;
+const EXTENDED_LEAVES: u32 = 0x8000_0000;
+
+// Fields that are not plain feature bit vectors. Every other field is combined
+// with `Merge::And`, except for the leaves listed in `keep_leaf`.
+#[rustfmt::skip]
+const CPUID_RULES: &[CpuidRule] = {
+    use CpuidReg::*;
+    use Merge::*;
+    &[
+        // Maximum basic leaf and vendor string.
+        cpuid_rule(0x0, None, Eax, !0, Min),
+        cpuid_rule(0x0, None, Ebx, !0, Exact),
+        cpuid_rule(0x0, None, Ecx, !0, Exact),
+        cpuid_rule(0x0, None, Edx, !0, Exact),
+        // Family, model and stepping; brand index, CLFLUSH size and APIC ID.
+        cpuid_rule(0x1, None, Eax, !0, Keep),
+        cpuid_rule(0x1, None, Ebx, !0, Keep),
+        // Maximum subleaf, FDP_EXCPTN_ONLY and ZERO_FCS_FDS.
+        cpuid_rule(0x7, Some(0), Eax, !0, Min),
+        cpuid_rule(0x7, Some(0), Ebx, (1 << 6) | (1 << 13), Or),
+        // Architectural PMU version, counter numbers and widths. A set bit in
+        // EBX means that the corresponding event is not available.
+        cpuid_rule(0xa, None, Eax, 0x0000_00ff, Min),
+        cpuid_rule(0xa, None, Eax, 0x0000_ff00, Min),
+        cpuid_rule(0xa, None, Eax, 0x00ff_0000, Min),
+        cpuid_rule(0xa, None, Eax, 0xff00_0000, Min),
+        cpuid_rule(0xa, None, Ebx, !0, Or),
+        cpuid_rule(0xa, None, Edx, 0x0000_001f, Min),
+        cpuid_rule(0xa, None, Edx, 0x0000_1fe0, Min),
+        // XSAVE area sizes.
+        cpuid_rule(0xd, Some(0), Ebx, !0, Min),
+        cpuid_rule(0xd, Some(0), Ecx, !0, Min),
+        cpuid_rule(0xd, Some(1), Ebx, !0, Min),
+        // Maximum hypervisor leaf and hypervisor signature.
+        cpuid_rule(HYPERVISOR_LEAVES, None, Eax, !0, Min),
+        cpuid_rule(HYPERVISOR_LEAVES, None, Ebx, !0, Exact),
+        cpuid_rule(HYPERVISOR_LEAVES, None, Ecx, !0, Exact),
+        cpuid_rule(HYPERVISOR_LEAVES, None, Edx, !0, Exact),
+        // Maximum extended leaf and vendor string.
+        cpuid_rule(EXTENDED_LEAVES, None, Eax, !0, Min),
+        cpuid_rule(EXTENDED_LEAVES, None, Ebx, !0, Exact),
+        cpuid_rule(EXTENDED_LEAVES, None, Ecx, !0, Exact),
+        cpuid_rule(EXTENDED_LEAVES, None, Edx, !0, Exact),
+        // Extended signature and brand id.
+        cpuid_rule(0x8000_0001, None, Eax, !0, Keep),
+        cpuid_rule(0x8000_0001, None, Ebx, !0, Keep),
+        // Physical, linear and guest physical address sizes, core count,
+        // INVLPGB and RDPRU limits.
+        cpuid_rule(0x8000_0008, None, Eax, 0x0000_00ff, Min),
+        cpuid_rule(0x8000_0008, None, Eax, 0x0000_ff00, Min),
+        cpuid_rule(0x8000_0008, None, Eax, 0x00ff_0000, Min),
+        cpuid_rule(0x8000_0008, None, Ecx, !0, Keep),
+        cpuid_rule(0x8000_0008, None, Edx, 0x0000_ffff, Min),
+        cpuid_rule(0x8000_0008, None, Edx, 0x03ff_0000, Min),
+    ]
+};
+
+// Leaves describing caches, topology and brand strings.
+fn keep_leaf(leaf: u32, subleaf: u32) -> bool {
+    matches!(
+        leaf,
+        0x2 | 0x4 | 0xb | 0x1f | 0x8000_0002..=0x8000_0006 | 0x8000_001d | 0x8000_001e
+    ) || (leaf == 0xd && subleaf >= 2)
+}
+
+// Returns the fields of `reg` in the `leaf`/`subleaf` entry, together with how
+// they are merged. The masks are disjoint and cover the whole register.
+fn cpuid_fields(leaf: u32, subleaf: u32, reg: CpuidReg) -> Vec<(u32, Merge)> {
+    let mut remaining = !0u32;
+    let mut fields = Vec::new();
+    for rule in CPUID_RULES {
+        if rule.leaf == leaf && rule.subleaf.is_none_or(|s| s == subleaf) && rule.reg == reg {
+            fields.push((rule.mask, rule.merge));
+            remaining &= !rule.mask;
+        }
+    }
+    if remaining != 0 {
+        let merge = if keep_leaf(leaf, subleaf) {
+            Merge::Keep
+        } else {
+            Merge::And
+        };
+        fields.push((remaining, merge));
+    }
+    fields
+}
+
+// VMX capability MSRs holding the allowed 0-settings in the low half and the
+// allowed 1-settings in the high half.
+fn vmx_ctls_msr(index: u32) -> bool {
+    matches!(
+        index,
+        IA32_VMX_PINBASED_CTLS..=IA32_VMX_ENTRY_CTLS
+            | IA32_VMX_PROCBASED_CTLS2
+            | IA32_VMX_TRUE_PINBASED_CTLS..=IA32_VMX_TRUE_ENTRY_CTLS
+    )
+}
+
+// Returns the fields of the feature MSR `index`, together with how they are
+// merged. The masks are disjoint and cover the whole MSR.
+fn msr_fields(index: u32) -> Vec<(u64, Merge)> {
+    use Merge::*;
+    let mut fields = match index {
+        IA32_UCODE_REV => vec![(!0, Keep)],
+        // RSBA and RRSBA report that the host is vulnerable, the other bits that it
+        // is not or that it has a mitigation.
+        IA32_ARCH_CAPABILITIES => vec![((1 << 2) | (1 << 19), Or)],
+        // LBR format.
+        IA32_PERF_CAPABILITIES => vec![(0x3f, Exact)],
+        // VMCS revision, VMCS region size, physical address width and memory type.
+        IA32_VMX_BASIC => vec![
+            (0x7fff_ffff, Exact),
+            (0x1fff << 32, Exact),
+            (1 << 48, Exact),
+            (0xf << 50, Exact),
+        ],
+        // TSC to preemption timer ratio, CR3 targets, MSR list size and MSEG revision.
+        IA32_VMX_MISC => vec![
+            (0x1f, Exact),
+            (0x1ff << 16, Min),
+            (0x7 << 25, Min),
+            (0xffff_ffff << 32, Exact),
+        ],
+        IA32_VMX_CR0_FIXED0 | IA32_VMX_CR4_FIXED0 => vec![(!0, Or)],
+        IA32_VMX_CR0_FIXED1 | IA32_VMX_CR4_FIXED1 => vec![(!0, And)],
+        // Highest VMCS field index.
+        IA32_VMX_VMCS_ENUM => vec![(0x3fe, Min)],
+        index if vmx_ctls_msr(index) => vec![(0xffff_ffff, Or)],
+        _ => Vec::new(),
+    };
+    let remaining = fields.iter().fold(!0u64, |acc, (mask, _)| acc & !mask);
+    if remaining != 0 {
+        fields.push((remaining, And));
+    }
+    fields
+}
+
+// Combines the `mask` field of `values` according to `merge`. Returns `None`
+// if an `Exact` field differs.
+fn merge_field<T>(merge: Merge, mask: T, values: &[T]) -> Option<T>
+where
+    T: Copy + Ord + std::ops::BitAnd<Output = T> + std::ops::BitOr<Output = T>,
+{
+    let mut fields = values.iter().map(|&value| value & mask);
+    let first = fields.next()?;
+    match merge {
+        Merge::And => Some(fields.fold(first, |acc, field| acc & field)),
+        Merge::Or => Some(fields.fold(first, |acc, field| acc | field)),
+        Merge::Min => Some(fields.fold(first, |acc, field| acc.min(field))),
+        Merge::Exact => fields.all(|field| field == first).then_some(first),
+        Merge::Keep => Some(first),
+    }
+}
+
+/// A reason why a host cannot run a guest with a given [`CpuFeatures`] template.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Incompatibility {
+    /// The guest relies on `CPUID` bits that the host does not set, or the host
+    /// sets "lower is better" bits that the guest does not.
+    CpuidBits {
+        /// The `CPUID` leaf.
+        leaf: u32,
+        /// The `CPUID` subleaf.
+        subleaf: u32,
+        /// The output register.
+        reg: CpuidReg,
+        /// The offending bits.
+        mask: u32,
+    },
+    /// A numeric `CPUID` field of the guest is higher than, or differs from, the host one.
+    CpuidField {
+        /// The `CPUID` leaf.
+        leaf: u32,
+        /// The `CPUID` subleaf.
+        subleaf: u32,
+        /// The output register.
+        reg: CpuidReg,
+        /// The bits holding the field.
+        mask: u32,
+        /// The field as found in the guest template, still shifted.
+        guest: u32,
+        /// The field as reported by the host, still shifted.
+        host: u32,
+    },
+    /// The guest relies on feature MSR bits that the host does not set, or the
+    /// host sets "lower is better" bits that the guest does not.
+    MsrBits {
+        /// The MSR index.
+        index: u32,
+        /// The offending bits.
+        mask: u64,
+    },
+    /// A numeric feature MSR field of the guest is higher than, or differs from,
+    /// the host one.
+    MsrField {
+        /// The MSR index.
+        index: u32,
+        /// The bits holding the field.
+        mask: u64,
+        /// The field as found in the guest template, still shifted.
+        guest: u64,
+        /// The field as reported by the host, still shifted.
+        host: u64,
+    },
+}
+
+impl Incompatibility {
+    /// Returns the named features behind a [`Incompatibility::CpuidBits`].
+    ///
+    /// This is empty for other variants and for bits that have no [`X86Feature`].
+    pub fn features(&self) -> Vec<X86Feature> {
+        match *self {
+            Incompatibility::CpuidBits {
+                leaf,
+                subleaf,
+                reg,
+                mask,
+            } => X86Feature::ALL
+                .iter()
+                .copied()
+                .filter(|feature| {
+                    let bit = feature.bit();
+                    bit.leaf == leaf
+                        && bit.subleaf == subleaf
+                        && bit.reg == reg
+                        && mask & (1 << bit.bit) != 0
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Incompatibility::CpuidBits {
+                leaf,
+                subleaf,
+                reg,
+                mask,
+            } => {
+                write!(f, "CPUID {leaf:#x}.{subleaf} {reg:?} bits {mask:#x}")?;
+                let features = self.features();
+                if !features.is_empty() {
+                    let names: Vec<_> = features.iter().map(|f| f.name()).collect();
+                    write!(f, " ({})", names.join(", "))?;
+                }
+                Ok(())
+            }
+            Incompatibility::CpuidField {
+                leaf,
+                subleaf,
+                reg,
+                mask,
+                guest,
+                host,
+            } => write!(
+                f,
+                "CPUID {leaf:#x}.{subleaf} {reg:?} field {mask:#x}: guest {guest:#x}, host {host:#x}"
+            ),
+            Incompatibility::MsrBits { index, mask } => {
+                write!(f, "MSR {index:#x} bits {mask:#x}")
+            }
+            Incompatibility::MsrField {
+                index,
+                mask,
+                guest,
+                host,
+            } => write!(
+                f,
+                "MSR {index:#x} field {mask:#x}: guest {guest:#x}, host {host:#x}"
+            ),
+        }
+    }
+}
+
+/// The CPU features of a host, or of a guest CPU template.
+///
+/// A host snapshot holds the output of [`Kvm::get_supported_cpuid`] and the values
+/// of the MSRs returned by [`Kvm::get_msr_feature_index_list`], as read by
+/// [`Kvm::get_msrs`]. With the `serde` feature of `kvm-bindings` enabled both
+/// fields can be serialized, so that snapshots taken on different hosts can be
+/// gathered in one place and combined with [`CpuFeatures::intersect`].
+///
+/// # Example
+///
+/// ```rust
+/// use kvm_ioctls::{CpuFeatures, Kvm};
+///
+/// let kvm = Kvm::new().unwrap();
+/// let host = CpuFeatures::from_kvm(&kvm).unwrap();
+///
+/// // Snapshots from the other hosts of the pool would be deserialized here.
+/// let pool = CpuFeatures::intersect(&[host.clone(), host.clone()]).unwrap();
+/// assert!(host.incompatibilities(&pool).is_empty());
+/// ```
+#[derive(Clone, Debug)]
+pub struct CpuFeatures {
+    /// The supported `CPUID` entries.
+    pub cpuid: CpuId,
+    /// The values of the feature MSRs.
+    pub msrs: Msrs,
+}
+
+impl CpuFeatures {
+    /// Takes a snapshot of the CPU features supported by KVM on this host.
+    pub fn from_kvm(kvm: &Kvm) -> Result<Self> {
+        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+        let entries: Vec<_> = kvm
+            .get_msr_feature_index_list()?
+            .as_slice()
+            .iter()
+            .map(|&index| kvm_msr_entry {
+                index,
+                ..Default::default()
+            })
+            .collect();
+        let mut msrs = Msrs::from_entries(&entries).map_err(|_| errno::Error::new(libc::ENOMEM))?;
+        let read = kvm.get_msrs(&mut msrs)?;
+        let msrs = Msrs::from_entries(&msrs.as_slice()[..read])
+            .map_err(|_| errno::Error::new(libc::ENOMEM))?;
+        Ok(CpuFeatures { cpuid, msrs })
+    }
+
+    /// Computes the features that all of `hosts` provide.
+    ///
+    /// Feature bits are kept only if every host sets them, "lower is better" bits
+    /// are set if any host sets them and numeric fields such as the maximum leaf,
+    /// the address sizes or the PMU counters take the lowest value. Leaves and
+    /// MSRs that are not reported by every host, and leaves above the lowest
+    /// maximum leaf, are dropped. Fields describing the host rather than its
+    /// features, such as the cache topology, are taken from the first host.
+    ///
+    /// Returns `EINVAL` if `hosts` is empty or if the hosts do not agree on a field
+    /// that cannot be combined, such as the CPU vendor or the VMCS revision.
+    pub fn intersect(hosts: &[CpuFeatures]) -> Result<CpuFeatures> {
+        let (first, others) = hosts
+            .split_first()
+            .ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+
+        let mut entries = Vec::new();
+        for entry in first.cpuid.as_slice() {
+            let Some(matching) = others
+                .iter()
+                .map(|host| {
+                    host.cpuid
+                        .as_slice()
+                        .iter()
+                        .find(|e| entry_matches(e, entry.function, entry.index))
+                })
+                .collect::<Option<Vec<_>>>()
+            else {
+                continue;
+            };
+            let mut merged = *entry;
+            for reg in [CpuidReg::Eax, CpuidReg::Ebx, CpuidReg::Ecx, CpuidReg::Edx] {
+                let mut values = vec![reg.get(entry)];
+                values.extend(matching.iter().map(|e| reg.get(e)));
+                let mut value = 0;
+                for (mask, merge) in cpuid_fields(entry.function, entry.index, reg) {
+                    value |= merge_field(merge, mask, &values)
+                        .ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+                }
+                *reg.get_mut(&mut merged) = value;
+            }
+            entries.push(merged);
+        }
+        let limits = CpuidLimits::new(&entries);
+        entries.retain(|entry| limits.contains(entry));
+
+        let mut msrs = Vec::new();
+        for msr in first.msrs.as_slice() {
+            let Some(matching) = others
+                .iter()
+                .map(|host| host.msrs.as_slice().iter().find(|m| m.index == msr.index))
+                .collect::<Option<Vec<_>>>()
+            else {
+                continue;
+            };
+            let mut values = vec![msr.data];
+            values.extend(matching.iter().map(|m| m.data));
+            let mut data = 0;
+            for (mask, merge) in msr_fields(msr.index) {
+                data |= merge_field(merge, mask, &values)
+                    .ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+            }
+            msrs.push(kvm_msr_entry { data, ..*msr });
+        }
+
+        Ok(CpuFeatures {
+            cpuid: CpuId::from_entries(&entries).map_err(|_| errno::Error::new(libc::ENOMEM))?,
+            msrs: Msrs::from_entries(&msrs).map_err(|_| errno::Error::new(libc::ENOMEM))?,
+        })
+    }
+
+    /// Returns what prevents this host from running a guest configured with `guest`.
+    ///
+    /// `guest` is usually the result of [`CpuFeatures::intersect`], or a `CPUID`
+    /// and MSR template derived from it. Leaves and MSRs that are missing on this
+    /// host are treated as if they were all zeroes. An empty result means that the
+    /// guest can be started on, or migrated to, this host.
+    pub fn incompatibilities(&self, guest: &CpuFeatures) -> Vec<Incompatibility> {
+        let mut result = Vec::new();
+
+        let limits = CpuidLimits::new(self.cpuid.as_slice());
+        for entry in guest.cpuid.as_slice() {
+            let host = self
+                .cpuid
+                .as_slice()
+                .iter()
+                .find(|e| entry_matches(e, entry.function, entry.index))
+                .filter(|e| limits.contains(e))
+                .copied()
+                .unwrap_or_default();
+            for reg in [CpuidReg::Eax, CpuidReg::Ebx, CpuidReg::Ecx, CpuidReg::Edx] {
+                let (guest_value, host_value) = (reg.get(entry), reg.get(&host));
+                for (mask, merge) in cpuid_fields(entry.function, entry.index, reg) {
+                    let (leaf, subleaf) = (entry.function, entry.index);
+                    let (guest, host) = (guest_value & mask, host_value & mask);
+                    let incompatibility = match merge {
+                        Merge::And if guest & !host != 0 => Some(Incompatibility::CpuidBits {
+                            leaf,
+                            subleaf,
+                            reg,
+                            mask: guest & !host,
+                        }),
+                        Merge::Or if host & !guest != 0 => Some(Incompatibility::CpuidBits {
+                            leaf,
+                            subleaf,
+                            reg,
+                            mask: host & !guest,
+                        }),
+                        Merge::Min if guest > host => Some(Incompatibility::CpuidField {
+                            leaf,
+                            subleaf,
+                            reg,
+                            mask,
+                            guest,
+                            host,
+                        }),
+                        Merge::Exact if guest != host => Some(Incompatibility::CpuidField {
+                            leaf,
+                            subleaf,
+                            reg,
+                            mask,
+                            guest,
+                            host,
+                        }),
+                        _ => None,
+                    };
+                    result.extend(incompatibility);
+                }
+            }
+        }
+
+        for msr in guest.msrs.as_slice() {
+            let host = self
+                .msrs
+                .as_slice()
+                .iter()
+                .find(|m| m.index == msr.index)
+                .map_or(0, |m| m.data);
+            let index = msr.index;
+            for (mask, merge) in msr_fields(index) {
+                let (guest, host) = (msr.data & mask, host & mask);
+                let incompatibility = match merge {
+                    Merge::And if guest & !host != 0 => Some(Incompatibility::MsrBits {
+                        index,
+                        mask: guest & !host,
+                    }),
+                    Merge::Or if host & !guest != 0 => Some(Incompatibility::MsrBits {
+                        index,
+                        mask: host & !guest,
+                    }),
+                    Merge::Min if guest > host => Some(Incompatibility::MsrField {
+                        index,
+                        mask,
+                        guest,
+                        host,
+                    }),
+                    Merge::Exact if guest != host => Some(Incompatibility::MsrField {
+                        index,
+                        mask,
+                        guest,
+                        host,
+                    }),
+                    _ => None,
+                };
+                result.extend(incompatibility);
+            }
+        }
+
+        result
+    }
+}
+
+// The maximum leaves and leaf 0x7 subleaf advertised by a set of `CPUID` entries.
+struct CpuidLimits {
+    max_leaf: [Option<u32>; 3],
+    max_leaf7_subleaf: Option<u32>,
+}
+
+impl CpuidLimits {
+    const BASES: [u32; 3] = [BASIC_LEAVES, HYPERVISOR_LEAVES, EXTENDED_LEAVES];
+
+    fn new(entries: &[kvm_cpuid_entry2]) -> Self {
+        let find = |leaf, subleaf| entries.iter().find(|e| entry_matches(e, leaf, subleaf));
+        CpuidLimits {
+            max_leaf: Self::BASES.map(|base| find(base, 0).map(|e| e.eax.max(base))),
+            max_leaf7_subleaf: find(0x7, 0).map(|e| e.eax),
+        }
+    }
+
+    fn contains(&self, entry: &kvm_cpuid_entry2) -> bool {
+        let range = Self::BASES
+            .iter()
+            .rposition(|&base| entry.function >= base)
+            .unwrap();
+        if self.max_leaf[range].is_some_and(|max| entry.function > max) {
+            return false;
+        }
+        !(entry.function == 0x7 && self.max_leaf7_subleaf.is_some_and(|max| entry.index > max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_bindings::KVM_CPUID_FLAG_SIGNIFCANT_INDEX;
+
+    fn leaf(function: u32, index: u32, regs: [u32; 4]) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            index,
+            flags: if function == 0x7 || function == 0xd {
+                KVM_CPUID_FLAG_SIGNIFCANT_INDEX
+            } else {
+                0
+            },
+            eax: regs[0],
+            ebx: regs[1],
+            ecx: regs[2],
+            edx: regs[3],
+            ..Default::default()
+        }
+    }
+
+    fn msr(index: u32, data: u64) -> kvm_msr_entry {
+        kvm_msr_entry {
+            index,
+            data,
+            ..Default::default()
+        }
+    }
+
+    // "GenuineIntel".
+    const INTEL: [u32; 3] = [0x756e_6547, 0x6c65_746e, 0x4965_6e69];
+
+    fn host(max_leaf: u32, leaf7: [u32; 4], phys_bits: u32, arch_caps: u64) -> CpuFeatures {
+        let entries = [
+            leaf(0x0, 0, [max_leaf, INTEL[0], INTEL[2], INTEL[1]]),
+            leaf(0x1, 0, [0x806f8, 0x800, 0x0, 1 << 26]),
+            leaf(0x7, 0, leaf7),
+            leaf(0x7, 1, [1 << 4, 0, 0, 0]),
+            leaf(0x8000_0000, 0, [0x8000_0008, 0, 0, 0]),
+            leaf(0x8000_0008, 0, [0x3000 | phys_bits, 0, 0, 0]),
+            leaf(0xa, 0, [0x0804_3002, 0x0, 0x0, 0x0603]),
+        ];
+        CpuFeatures {
+            cpuid: CpuId::from_entries(&entries).unwrap(),
+            msrs: Msrs::from_entries(&[msr(0x10a, arch_caps), msr(0x482, 0xfff9_fffe_0401_e172)])
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_intersect() {
+        let avx512f = 1 << 16;
+        // Only `b` has RSBA (bit 2) and RRSBA (bit 19) set.
+        let a = host(0x1f, [1, avx512f | (1 << 5), 0, 0], 46, 0b1001);
+        let b = host(0x1f, [2, 1 << 5 | (1 << 13), 0, 0], 52, (1 << 19) | 0b0111);
+        let c = host(0x7, [0, 1 << 5, 0, 0], 48, 0b1111);
+
+        assert_eq!(
+            CpuFeatures::intersect(&[]).unwrap_err().errno(),
+            libc::EINVAL
+        );
+
+        let pool = CpuFeatures::intersect(&[a.clone(), b.clone()]).unwrap();
+        let mut editor_cpuid = pool.cpuid.clone();
+        let editor = crate::CpuidEditor::new(&mut editor_cpuid);
+        assert!(!editor.has_feature(X86Feature::Avx512f));
+        assert!(editor.has_feature(X86Feature::Avx2));
+        // ZERO_FCS_FDS is a "lower is better" bit.
+        assert_eq!(editor.entry(0x7, 0).unwrap().ebx, (1 << 5) | (1 << 13));
+        assert_eq!(editor.entry(0x7, 0).unwrap().eax, 1);
+        assert_eq!(editor.entry(0x8000_0008, 0).unwrap().eax, 0x3000 | 46);
+        // The pool has RSBA and RRSBA set since `b` is vulnerable, and the other
+        // bits of the two hosts are ANDed.
+        assert_eq!(pool.msrs.as_slice()[0].data, (1 << 19) | 0b0101);
+        assert_eq!(pool.msrs.as_slice()[1].data, 0xfff9_fffe_0401_e172);
+
+        // Leaf 0xa is above the maximum leaf of `c` and the subleaf 1 of leaf 0x7
+        // is above its maximum subleaf.
+        let pool = CpuFeatures::intersect(&[a.clone(), b.clone(), c.clone()]).unwrap();
+        let mut editor_cpuid = pool.cpuid.clone();
+        let editor = crate::CpuidEditor::new(&mut editor_cpuid);
+        assert_eq!(editor.entry(0x0, 0).unwrap().eax, 0x7);
+        assert!(editor.entry(0xa, 0).is_none());
+        assert!(editor.entry(0x7, 1).is_none());
+        assert!(editor.entry(0x7, 0).is_some());
+
+        // Hosts from different vendors cannot be combined.
+        let mut amd = c.clone();
+        amd.cpuid.as_mut_slice()[0].ebx = 0x6874_7541;
+        assert_eq!(
+            CpuFeatures::intersect(&[a.clone(), amd])
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+
+        // Differing VMCS revisions cannot be combined either.
+        let mut vmx_a = a.clone();
+        let mut vmx_b = a.clone();
+        vmx_a.msrs = Msrs::from_entries(&[msr(0x480, 0x00da_0400_0000_0004)]).unwrap();
+        vmx_b.msrs = Msrs::from_entries(&[msr(0x480, 0x00da_0400_0000_0001)]).unwrap();
+        assert_eq!(
+            CpuFeatures::intersect(&[vmx_a, vmx_b]).unwrap_err().errno(),
+            libc::EINVAL
+        );
+    }
+
+    #[test]
+    fn test_incompatibilities() {
+        let avx512f = 1 << 16;
+        let a = host(0x1f, [1, avx512f | (1 << 5), 0, 0], 46, 0b1101);
+        let b = host(0x1f, [2, 1 << 5 | (1 << 13), 0, 0], 52, 0b0111);
+        let pool = CpuFeatures::intersect(&[a.clone(), b.clone()]).unwrap();
+        assert!(a.incompatibilities(&pool).is_empty());
+        assert!(b.incompatibilities(&pool).is_empty());
+
+        // `a` as a guest template is too much for `b`.
+        let found = b.incompatibilities(&a);
+        assert_eq!(
+            found,
+            vec![
+                Incompatibility::CpuidBits {
+                    leaf: 0x7,
+                    subleaf: 0,
+                    reg: CpuidReg::Ebx,
+                    mask: 1 << 13,
+                },
+                Incompatibility::CpuidBits {
+                    leaf: 0x7,
+                    subleaf: 0,
+                    reg: CpuidReg::Ebx,
+                    mask: avx512f,
+                },
+                Incompatibility::MsrBits {
+                    index: 0x10a,
+                    mask: 0b1000,
+                },
+            ]
+        );
+        assert_eq!(found[1].features(), vec![X86Feature::Avx512f]);
+        assert_eq!(
+            found[1].to_string(),
+            "CPUID 0x7.0 Ebx bits 0x10000 (avx512f)"
+        );
+
+        // `b` as a guest template is too much for `a`.
+        let found = a.incompatibilities(&b);
+        assert!(found.contains(&Incompatibility::CpuidField {
+            leaf: 0x7,
+            subleaf: 0,
+            reg: CpuidReg::Eax,
+            mask: !0,
+            guest: 2,
+            host: 1,
+        }));
+        assert!(found.contains(&Incompatibility::CpuidField {
+            leaf: 0x8000_0008,
+            subleaf: 0,
+            reg: CpuidReg::Eax,
+            mask: 0xff,
+            guest: 52,
+            host: 46,
+        }));
+        assert_eq!(found.last().unwrap().to_string(), "MSR 0x10a bits 0x2");
+
+        // Leaves above the host maximum leaf are missing.
+        let c = host(0x7, [1, 1 << 5, 0, 0], 46, 0b1101);
+        let found = c.incompatibilities(&a);
+        assert!(found.contains(&Incompatibility::CpuidField {
+            leaf: 0xa,
+            subleaf: 0,
+            reg: CpuidReg::Eax,
+            mask: 0xff,
+            guest: 0x02,
+            host: 0,
+        }));
+    }
+
+    #[test]
+    fn test_from_kvm() {
+        let kvm = Kvm::new().unwrap();
+        let host = CpuFeatures::from_kvm(&kvm).unwrap();
+        assert!(!host.cpuid.as_slice().is_empty());
+
+        let pool = CpuFeatures::intersect(&[host.clone(), host.clone()]).unwrap();
+        assert!(host.incompatibilities(&pool).is_empty());
+        assert!(pool.incompatibilities(&host).is_empty());
+    }
+}
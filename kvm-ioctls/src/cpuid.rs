@@ -0,0 +1,918 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use kvm_bindings::{CpuId, KVM_CPUID_FLAG_SIGNIFCANT_INDEX, kvm_cpuid_entry2};
+use vmm_sys_util::errno;
+
+use crate::ioctls::Result;
+
+/// Returns whether `entry` holds the output of `CPUID` for `leaf` and `subleaf`.
+///
+/// The subleaf is ignored for entries that do not have the
+/// `KVM_CPUID_FLAG_SIGNIFCANT_INDEX` flag set.
+pub(crate) fn entry_matches(entry: &kvm_cpuid_entry2, leaf: u32, subleaf: u32) -> bool {
+    entry.function == leaf
+        && (entry.flags & KVM_CPUID_FLAG_SIGNIFCANT_INDEX == 0 || entry.index == subleaf)
+}
+
+/// A register returned by the `CPUID` instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CpuidReg {
+    /// The `EAX` register.
+    Eax,
+    /// The `EBX` register.
+    Ebx,
+    /// The `ECX` register.
+    Ecx,
+    /// The `EDX` register.
+    Edx,
+}
+
+impl CpuidReg {
+    /// Returns the value of this register in `entry`.
+    pub fn get(self, entry: &kvm_cpuid_entry2) -> u32 {
+        match self {
+            CpuidReg::Eax => entry.eax,
+            CpuidReg::Ebx => entry.ebx,
+            CpuidReg::Ecx => entry.ecx,
+            CpuidReg::Edx => entry.edx,
+        }
+    }
+
+    /// Returns a mutable reference to this register in `entry`.
+    pub fn get_mut(self, entry: &mut kvm_cpuid_entry2) -> &mut u32 {
+        match self {
+            CpuidReg::Eax => &mut entry.eax,
+            CpuidReg::Ebx => &mut entry.ebx,
+            CpuidReg::Ecx => &mut entry.ecx,
+            CpuidReg::Edx => &mut entry.edx,
+        }
+    }
+}
+
+/// Location of a single feature bit in the `CPUID` output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CpuidBit {
+    /// The `CPUID` leaf (input `EAX`).
+    pub leaf: u32,
+    /// The `CPUID` subleaf (input `ECX`).
+    pub subleaf: u32,
+    /// The output register holding the bit.
+    pub reg: CpuidReg,
+    /// The bit number in `reg`.
+    pub bit: u8,
+}
+
+macro_rules! x86_features {
+    ($($variant:ident = ($name:literal, $leaf:literal, $subleaf:literal, $reg:ident, $bit:literal),)*) => {
+        /// x86 CPU features reported through `CPUID`.
+        ///
+        /// The names returned by [`X86Feature::name`] follow the ones used by Linux in
+        /// `/proc/cpuinfo`.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        // We are allowing docs to be missing here because every variant is
+        // named after the Linux name of the feature.
+        #[allow(missing_docs)]
+        #[non_exhaustive]
+        pub enum X86Feature {
+            $($variant,)*
+        }
+
+        impl X86Feature {
+            /// All the features known to this crate.
+            pub const ALL: &'static [X86Feature] = &[$(X86Feature::$variant,)*];
+
+            /// Returns the Linux name of the feature.
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $(X86Feature::$variant => $name,)*
+                }
+            }
+
+            /// Returns where the feature is reported in the `CPUID` output.
+            pub const fn bit(self) -> CpuidBit {
+                match self {
+                    $(X86Feature::$variant => CpuidBit {
+                        leaf: $leaf,
+                        subleaf: $subleaf,
+                        reg: CpuidReg::$reg,
+                        bit: $bit,
+                    },)*
+                }
+            }
+        }
+    };
+}
+
+x86_features! {
+    Fpu = ("fpu", 0x1, 0, Edx, 0),
+    Vme = ("vme", 0x1, 0, Edx, 1),
+    De = ("de", 0x1, 0, Edx, 2),
+    Pse = ("pse", 0x1, 0, Edx, 3),
+    Tsc = ("tsc", 0x1, 0, Edx, 4),
+    Msr = ("msr", 0x1, 0, Edx, 5),
+    Pae = ("pae", 0x1, 0, Edx, 6),
+    Mce = ("mce", 0x1, 0, Edx, 7),
+    Cx8 = ("cx8", 0x1, 0, Edx, 8),
+    Apic = ("apic", 0x1, 0, Edx, 9),
+    Sep = ("sep", 0x1, 0, Edx, 11),
+    Mtrr = ("mtrr", 0x1, 0, Edx, 12),
+    Pge = ("pge", 0x1, 0, Edx, 13),
+    Mca = ("mca", 0x1, 0, Edx, 14),
+    Cmov = ("cmov", 0x1, 0, Edx, 15),
+    Pat = ("pat", 0x1, 0, Edx, 16),
+    Pse36 = ("pse36", 0x1, 0, Edx, 17),
+    Clflush = ("clflush", 0x1, 0, Edx, 19),
+    Mmx = ("mmx", 0x1, 0, Edx, 23),
+    Fxsr = ("fxsr", 0x1, 0, Edx, 24),
+    Sse = ("sse", 0x1, 0, Edx, 25),
+    Sse2 = ("sse2", 0x1, 0, Edx, 26),
+    Ss = ("ss", 0x1, 0, Edx, 27),
+    Ht = ("ht", 0x1, 0, Edx, 28),
+    Sse3 = ("pni", 0x1, 0, Ecx, 0),
+    Pclmulqdq = ("pclmulqdq", 0x1, 0, Ecx, 1),
+    Ssse3 = ("ssse3", 0x1, 0, Ecx, 9),
+    Fma = ("fma", 0x1, 0, Ecx, 12),
+    Cx16 = ("cx16", 0x1, 0, Ecx, 13),
+    Pdcm = ("pdcm", 0x1, 0, Ecx, 15),
+    Pcid = ("pcid", 0x1, 0, Ecx, 17),
+    Sse41 = ("sse4_1", 0x1, 0, Ecx, 19),
+    Sse42 = ("sse4_2", 0x1, 0, Ecx, 20),
+    X2apic = ("x2apic", 0x1, 0, Ecx, 21),
+    Movbe = ("movbe", 0x1, 0, Ecx, 22),
+    Popcnt = ("popcnt", 0x1, 0, Ecx, 23),
+    TscDeadlineTimer = ("tsc_deadline_timer", 0x1, 0, Ecx, 24),
+    Aes = ("aes", 0x1, 0, Ecx, 25),
+    Xsave = ("xsave", 0x1, 0, Ecx, 26),
+    Avx = ("avx", 0x1, 0, Ecx, 28),
+    F16c = ("f16c", 0x1, 0, Ecx, 29),
+    Rdrand = ("rdrand", 0x1, 0, Ecx, 30),
+    Hypervisor = ("hypervisor", 0x1, 0, Ecx, 31),
+    Fsgsbase = ("fsgsbase", 0x7, 0, Ebx, 0),
+    TscAdjust = ("tsc_adjust", 0x7, 0, Ebx, 1),
+    Sgx = ("sgx", 0x7, 0, Ebx, 2),
+    Bmi1 = ("bmi1", 0x7, 0, Ebx, 3),
+    Hle = ("hle", 0x7, 0, Ebx, 4),
+    Avx2 = ("avx2", 0x7, 0, Ebx, 5),
+    Smep = ("smep", 0x7, 0, Ebx, 7),
+    Bmi2 = ("bmi2", 0x7, 0, Ebx, 8),
+    Erms = ("erms", 0x7, 0, Ebx, 9),
+    Invpcid = ("invpcid", 0x7, 0, Ebx, 10),
+    Rtm = ("rtm", 0x7, 0, Ebx, 11),
+    Mpx = ("mpx", 0x7, 0, Ebx, 14),
+    Avx512f = ("avx512f", 0x7, 0, Ebx, 16),
+    Avx512dq = ("avx512dq", 0x7, 0, Ebx, 17),
+    Rdseed = ("rdseed", 0x7, 0, Ebx, 18),
+    Adx = ("adx", 0x7, 0, Ebx, 19),
+    Smap = ("smap", 0x7, 0, Ebx, 20),
+    Avx512ifma = ("avx512ifma", 0x7, 0, Ebx, 21),
+    Clflushopt = ("clflushopt", 0x7, 0, Ebx, 23),
+    Clwb = ("clwb", 0x7, 0, Ebx, 24),
+    Avx512pf = ("avx512pf", 0x7, 0, Ebx, 26),
+    Avx512er = ("avx512er", 0x7, 0, Ebx, 27),
+    Avx512cd = ("avx512cd", 0x7, 0, Ebx, 28),
+    ShaNi = ("sha_ni", 0x7, 0, Ebx, 29),
+    Avx512bw = ("avx512bw", 0x7, 0, Ebx, 30),
+    Avx512vl = ("avx512vl", 0x7, 0, Ebx, 31),
+    Avx512vbmi = ("avx512vbmi", 0x7, 0, Ecx, 1),
+    Umip = ("umip", 0x7, 0, Ecx, 2),
+    Pku = ("pku", 0x7, 0, Ecx, 3),
+    Waitpkg = ("waitpkg", 0x7, 0, Ecx, 5),
+    Avx512vbmi2 = ("avx512_vbmi2", 0x7, 0, Ecx, 6),
+    Gfni = ("gfni", 0x7, 0, Ecx, 8),
+    Vaes = ("vaes", 0x7, 0, Ecx, 9),
+    Vpclmulqdq = ("vpclmulqdq", 0x7, 0, Ecx, 10),
+    Avx512vnni = ("avx512_vnni", 0x7, 0, Ecx, 11),
+    Avx512bitalg = ("avx512_bitalg", 0x7, 0, Ecx, 12),
+    Avx512vpopcntdq = ("avx512_vpopcntdq", 0x7, 0, Ecx, 14),
+    La57 = ("la57", 0x7, 0, Ecx, 16),
+    Rdpid = ("rdpid", 0x7, 0, Ecx, 22),
+    Cldemote = ("cldemote", 0x7, 0, Ecx, 25),
+    Movdiri = ("movdiri", 0x7, 0, Ecx, 27),
+    Movdir64b = ("movdir64b", 0x7, 0, Ecx, 28),
+    Avx5124vnniw = ("avx512_4vnniw", 0x7, 0, Edx, 2),
+    Avx5124fmaps = ("avx512_4fmaps", 0x7, 0, Edx, 3),
+    Fsrm = ("fsrm", 0x7, 0, Edx, 4),
+    Avx512vp2intersect = ("avx512_vp2intersect", 0x7, 0, Edx, 8),
+    MdClear = ("md_clear", 0x7, 0, Edx, 10),
+    Serialize = ("serialize", 0x7, 0, Edx, 14),
+    Tsxldtrk = ("tsxldtrk", 0x7, 0, Edx, 16),
+    AmxBf16 = ("amx_bf16", 0x7, 0, Edx, 22),
+    Avx512fp16 = ("avx512_fp16", 0x7, 0, Edx, 23),
+    AmxTile = ("amx_tile", 0x7, 0, Edx, 24),
+    AmxInt8 = ("amx_int8", 0x7, 0, Edx, 25),
+    SpecCtrl = ("spec_ctrl", 0x7, 0, Edx, 26),
+    IntelStibp = ("intel_stibp", 0x7, 0, Edx, 27),
+    FlushL1d = ("flush_l1d", 0x7, 0, Edx, 28),
+    ArchCapabilities = ("arch_capabilities", 0x7, 0, Edx, 29),
+    CoreCapabilities = ("core_capabilities", 0x7, 0, Edx, 30),
+    SpecCtrlSsbd = ("spec_ctrl_ssbd", 0x7, 0, Edx, 31),
+    AvxVnni = ("avx_vnni", 0x7, 1, Eax, 4),
+    Avx512bf16 = ("avx512_bf16", 0x7, 1, Eax, 5),
+    Fzrm = ("fzrm", 0x7, 1, Eax, 10),
+    Fsrs = ("fsrs", 0x7, 1, Eax, 11),
+    Fsrc = ("fsrc", 0x7, 1, Eax, 12),
+    Xsaveopt = ("xsaveopt", 0xd, 1, Eax, 0),
+    Xsavec = ("xsavec", 0xd, 1, Eax, 1),
+    Xgetbv1 = ("xgetbv1", 0xd, 1, Eax, 2),
+    Xsaves = ("xsaves", 0xd, 1, Eax, 3),
+    Xfd = ("xfd", 0xd, 1, Eax, 4),
+    LahfLm = ("lahf_lm", 0x8000_0001, 0, Ecx, 0),
+    CmpLegacy = ("cmp_legacy", 0x8000_0001, 0, Ecx, 1),
+    Svm = ("svm", 0x8000_0001, 0, Ecx, 2),
+    Extapic = ("extapic", 0x8000_0001, 0, Ecx, 3),
+    Cr8Legacy = ("cr8_legacy", 0x8000_0001, 0, Ecx, 4),
+    Abm = ("abm", 0x8000_0001, 0, Ecx, 5),
+    Sse4a = ("sse4a", 0x8000_0001, 0, Ecx, 6),
+    Misalignsse = ("misalignsse", 0x8000_0001, 0, Ecx, 7),
+    Prefetch3dnow = ("3dnowprefetch", 0x8000_0001, 0, Ecx, 8),
+    Osvw = ("osvw", 0x8000_0001, 0, Ecx, 9),
+    Xop = ("xop", 0x8000_0001, 0, Ecx, 11),
+    Fma4 = ("fma4", 0x8000_0001, 0, Ecx, 16),
+    Tbm = ("tbm", 0x8000_0001, 0, Ecx, 21),
+    Topoext = ("topoext", 0x8000_0001, 0, Ecx, 22),
+    PerfctrCore = ("perfctr_core", 0x8000_0001, 0, Ecx, 23),
+    Syscall = ("syscall", 0x8000_0001, 0, Edx, 11),
+    Nx = ("nx", 0x8000_0001, 0, Edx, 20),
+    Mmxext = ("mmxext", 0x8000_0001, 0, Edx, 22),
+    FxsrOpt = ("fxsr_opt", 0x8000_0001, 0, Edx, 25),
+    Pdpe1gb = ("pdpe1gb", 0x8000_0001, 0, Edx, 26),
+    Rdtscp = ("rdtscp", 0x8000_0001, 0, Edx, 27),
+    Lm = ("lm", 0x8000_0001, 0, Edx, 29),
+    InvariantTsc = ("constant_tsc", 0x8000_0007, 0, Edx, 8),
+    Clzero = ("clzero", 0x8000_0008, 0, Ebx, 0),
+    Xsaveerptr = ("xsaveerptr", 0x8000_0008, 0, Ebx, 2),
+    Wbnoinvd = ("wbnoinvd", 0x8000_0008, 0, Ebx, 9),
+    AmdIbpb = ("ibpb", 0x8000_0008, 0, Ebx, 12),
+    AmdIbrs = ("ibrs", 0x8000_0008, 0, Ebx, 14),
+    AmdStibp = ("stibp", 0x8000_0008, 0, Ebx, 15),
+    AmdSsbd = ("ssbd", 0x8000_0008, 0, Ebx, 24),
+    VirtSsbd = ("virt_ssbd", 0x8000_0008, 0, Ebx, 25),
+    AmdPsfd = ("psfd", 0x8000_0008, 0, Ebx, 28),
+}
+
+impl X86Feature {
+    /// Looks up a feature by its Linux name, e.g. `"avx512f"`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        X86Feature::ALL
+            .iter()
+            .copied()
+            .find(|feature| feature.name().eq_ignore_ascii_case(name))
+    }
+}
+
+use X86Feature::*;
+
+const BASELINE: &[X86Feature] = &[
+    Fpu,
+    Vme,
+    De,
+    Pse,
+    Tsc,
+    Msr,
+    Pae,
+    Mce,
+    Cx8,
+    Apic,
+    Sep,
+    Mtrr,
+    Pge,
+    Mca,
+    Cmov,
+    Pat,
+    Pse36,
+    Clflush,
+    Mmx,
+    Fxsr,
+    Sse,
+    Sse2,
+    // Set by `CpuidEditor::set_topology` for multi-thread topologies.
+    Ht,
+    Syscall,
+    Nx,
+    Lm,
+    Hypervisor,
+    X2apic,
+    TscDeadlineTimer,
+];
+const V2: &[X86Feature] = &[Sse3, Ssse3, Cx16, Sse41, Sse42, Popcnt, LahfLm];
+const V3: &[X86Feature] = &[Avx, Avx2, Bmi1, Bmi2, F16c, Fma, Abm, Movbe, Xsave];
+const V4: &[X86Feature] = &[Avx512f, Avx512bw, Avx512cd, Avx512dq, Avx512vl];
+const SKYLAKE_SERVER: &[X86Feature] = &[
+    Pclmulqdq,
+    Aes,
+    Rdrand,
+    Pcid,
+    Fsgsbase,
+    Smep,
+    Smap,
+    Erms,
+    Invpcid,
+    Rdseed,
+    Adx,
+    Clflushopt,
+    Clwb,
+    Pku,
+    Xsaveopt,
+    Xsavec,
+    Xgetbv1,
+    Pdpe1gb,
+    Rdtscp,
+    Prefetch3dnow,
+    SpecCtrl,
+    SpecCtrlSsbd,
+];
+const CASCADELAKE_SERVER: &[X86Feature] = &[Avx512vnni, ArchCapabilities];
+const ICELAKE_SERVER: &[X86Feature] = &[
+    Avx512vbmi,
+    Umip,
+    Avx512vbmi2,
+    Gfni,
+    Vaes,
+    Vpclmulqdq,
+    Avx512bitalg,
+    Avx512vpopcntdq,
+    La57,
+    Rdpid,
+    Wbnoinvd,
+    ShaNi,
+    Xsaves,
+    MdClear,
+    Fsrm,
+];
+const SAPPHIRE_RAPIDS: &[X86Feature] = &[
+    Serialize, Tsxldtrk, AmxBf16, AmxTile, AmxInt8, Avx512fp16, AvxVnni, Avx512bf16, Movdiri,
+    Movdir64b, Cldemote, Fzrm, Fsrs, Fsrc, Xfd, Avx512ifma,
+];
+const EPYC_ROME: &[X86Feature] = &[
+    Pclmulqdq,
+    Aes,
+    Rdrand,
+    Fsgsbase,
+    Smep,
+    Smap,
+    Rdseed,
+    Adx,
+    Clflushopt,
+    Clwb,
+    ShaNi,
+    Umip,
+    Rdpid,
+    Xsaveopt,
+    Xsavec,
+    Xgetbv1,
+    Xsaves,
+    Pdpe1gb,
+    Rdtscp,
+    Mmxext,
+    FxsrOpt,
+    CmpLegacy,
+    Cr8Legacy,
+    Sse4a,
+    Misalignsse,
+    Prefetch3dnow,
+    Osvw,
+    Topoext,
+    PerfctrCore,
+    Clzero,
+    Xsaveerptr,
+    Wbnoinvd,
+    AmdIbpb,
+    AmdStibp,
+    AmdSsbd,
+];
+const EPYC_MILAN: &[X86Feature] = &[Pcid, Invpcid, Pku, Erms, Fsrm, Vaes, Vpclmulqdq, AmdIbrs];
+const EPYC_GENOA: &[X86Feature] = &[
+    Avx512ifma,
+    Avx512vbmi,
+    Avx512vbmi2,
+    Avx512vnni,
+    Avx512bitalg,
+    Avx512vpopcntdq,
+    Avx512bf16,
+    Gfni,
+    La57,
+    AmdPsfd,
+];
+
+/// Named CPU models used to restrict the features exposed to a guest.
+///
+/// The `X86_64V*` models follow the x86-64 micro-architecture levels defined by the
+/// psABI, and the named models follow the feature sets of the corresponding server parts.
+/// All models include the baseline features every 64-bit KVM guest relies on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CpuModel {
+    /// x86-64-v2: SSE4.2, SSSE3 and POPCNT.
+    X86_64V2,
+    /// x86-64-v3: AVX2, BMI1/2, FMA and MOVBE.
+    X86_64V3,
+    /// x86-64-v4: AVX-512 foundation, BW, CD, DQ and VL.
+    X86_64V4,
+    /// Intel Xeon Scalable (Skylake).
+    SkylakeServer,
+    /// 2nd generation Intel Xeon Scalable (Cascade Lake).
+    CascadelakeServer,
+    /// 3rd generation Intel Xeon Scalable (Ice Lake).
+    IcelakeServer,
+    /// 4th generation Intel Xeon Scalable (Sapphire Rapids).
+    SapphireRapids,
+    /// 2nd generation AMD EPYC (Zen 2).
+    EpycRome,
+    /// 3rd generation AMD EPYC (Zen 3).
+    EpycMilan,
+    /// 4th generation AMD EPYC (Zen 4).
+    EpycGenoa,
+}
+
+impl CpuModel {
+    fn feature_sets(self) -> &'static [&'static [X86Feature]] {
+        match self {
+            CpuModel::X86_64V2 => &[BASELINE, V2],
+            CpuModel::X86_64V3 => &[BASELINE, V2, V3],
+            CpuModel::X86_64V4 => &[BASELINE, V2, V3, V4],
+            CpuModel::SkylakeServer => &[BASELINE, V2, V3, V4, SKYLAKE_SERVER],
+            CpuModel::CascadelakeServer => {
+                &[BASELINE, V2, V3, V4, SKYLAKE_SERVER, CASCADELAKE_SERVER]
+            }
+            CpuModel::IcelakeServer => &[
+                BASELINE,
+                V2,
+                V3,
+                V4,
+                SKYLAKE_SERVER,
+                CASCADELAKE_SERVER,
+                ICELAKE_SERVER,
+            ],
+            CpuModel::SapphireRapids => &[
+                BASELINE,
+                V2,
+                V3,
+                V4,
+                SKYLAKE_SERVER,
+                CASCADELAKE_SERVER,
+                ICELAKE_SERVER,
+                SAPPHIRE_RAPIDS,
+            ],
+            CpuModel::EpycRome => &[BASELINE, V2, V3, EPYC_ROME],
+            CpuModel::EpycMilan => &[BASELINE, V2, V3, EPYC_ROME, EPYC_MILAN],
+            CpuModel::EpycGenoa => &[BASELINE, V2, V3, V4, EPYC_ROME, EPYC_MILAN, EPYC_GENOA],
+        }
+    }
+
+    /// Returns whether `feature` is part of this model.
+    pub fn has_feature(self, feature: X86Feature) -> bool {
+        self.feature_sets().iter().any(|set| set.contains(&feature))
+    }
+}
+
+/// CPU topology of a guest, used to fill in the topology related `CPUID` leaves.
+///
+/// vCPU indexes are assigned to threads first, then cores, dies and sockets, so the vCPU
+/// with index `i` is thread `i % threads_per_core` of its core.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CpuTopology {
+    /// Number of threads in each core.
+    pub threads_per_core: u8,
+    /// Number of cores in each die.
+    pub cores_per_die: u8,
+    /// Number of dies in each socket.
+    pub dies_per_socket: u8,
+    /// Number of sockets.
+    pub sockets: u8,
+}
+
+impl CpuTopology {
+    /// Number of bits needed to represent `n` different values.
+    fn bits(n: u8) -> u32 {
+        u8::BITS - n.saturating_sub(1).leading_zeros()
+    }
+
+    fn thread_bits(&self) -> u32 {
+        Self::bits(self.threads_per_core)
+    }
+
+    fn core_bits(&self) -> u32 {
+        Self::bits(self.cores_per_die)
+    }
+
+    fn die_bits(&self) -> u32 {
+        Self::bits(self.dies_per_socket)
+    }
+
+    fn threads_per_socket(&self) -> u32 {
+        u32::from(self.threads_per_core)
+            * u32::from(self.cores_per_die)
+            * u32::from(self.dies_per_socket)
+    }
+
+    /// Returns the total number of vCPUs in the topology.
+    pub fn num_vcpus(&self) -> u32 {
+        self.threads_per_socket() * u32::from(self.sockets)
+    }
+
+    /// Returns the APIC ID of the vCPU with index `vcpu_index`.
+    ///
+    /// Each level of the topology is given as many APIC ID bits as needed to number its
+    /// children, as required by the architecture.
+    pub fn apic_id(&self, vcpu_index: u32) -> u32 {
+        let threads = u32::from(self.threads_per_core);
+        let cores = u32::from(self.cores_per_die);
+        let dies = u32::from(self.dies_per_socket);
+
+        let thread = vcpu_index % threads;
+        let core = (vcpu_index / threads) % cores;
+        let die = (vcpu_index / (threads * cores)) % dies;
+        let socket = vcpu_index / (threads * cores * dies);
+
+        let core_shift = self.thread_bits();
+        let die_shift = core_shift + self.core_bits();
+        let socket_shift = die_shift + self.die_bits();
+        (socket << socket_shift) | (die << die_shift) | (core << core_shift) | thread
+    }
+
+    fn is_valid(&self) -> bool {
+        self.threads_per_core > 0
+            && self.cores_per_die > 0
+            && self.dies_per_socket > 0
+            && self.sockets > 0
+    }
+}
+
+// Level types reported in `ECX[15:8]` of the extended topology leaves.
+const LEVEL_TYPE_INVALID: u32 = 0;
+const LEVEL_TYPE_SMT: u32 = 1;
+const LEVEL_TYPE_CORE: u32 = 2;
+const LEVEL_TYPE_DIE: u32 = 5;
+
+/// Helper for inspecting and modifying a [`CpuId`] before it is passed to
+/// [`VcpuFd::set_cpuid2`](crate::VcpuFd::set_cpuid2).
+///
+/// # Example
+///
+/// ```rust
+/// use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+/// use kvm_ioctls::{CpuTopology, CpuidEditor, Kvm, X86Feature};
+///
+/// let kvm = Kvm::new().unwrap();
+/// let mut cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+///
+/// let mut editor = CpuidEditor::new(&mut cpuid);
+/// editor.disable_feature(X86Feature::Avx512f);
+/// assert!(!editor.has_feature(X86Feature::Avx512f));
+///
+/// let topology = CpuTopology {
+///     threads_per_core: 2,
+///     cores_per_die: 4,
+///     dies_per_socket: 1,
+///     sockets: 1,
+/// };
+/// editor.set_topology(3, &topology).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct CpuidEditor<'a> {
+    cpuid: &'a mut CpuId,
+}
+
+impl<'a> CpuidEditor<'a> {
+    /// Creates an editor over `cpuid`.
+    pub fn new(cpuid: &'a mut CpuId) -> Self {
+        CpuidEditor { cpuid }
+    }
+
+    /// Returns the entry for `leaf` and `subleaf`, if present.
+    ///
+    /// The subleaf is ignored for entries that do not have the
+    /// `KVM_CPUID_FLAG_SIGNIFCANT_INDEX` flag set.
+    pub fn entry(&self, leaf: u32, subleaf: u32) -> Option<&kvm_cpuid_entry2> {
+        self.cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry_matches(entry, leaf, subleaf))
+    }
+
+    /// Returns a mutable reference to the entry for `leaf` and `subleaf`, if present.
+    pub fn entry_mut(&mut self, leaf: u32, subleaf: u32) -> Option<&mut kvm_cpuid_entry2> {
+        self.cpuid
+            .as_mut_slice()
+            .iter_mut()
+            .find(|entry| entry_matches(entry, leaf, subleaf))
+    }
+
+    /// Replaces the entry with the same leaf and subleaf as `entry`, or appends it.
+    ///
+    /// Returns `ENOMEM` if the entry has to be appended and the `CpuId` is full.
+    pub fn set_entry(&mut self, entry: kvm_cpuid_entry2) -> Result<()> {
+        match self.entry_mut(entry.function, entry.index) {
+            Some(existing) => *existing = entry,
+            None => self
+                .cpuid
+                .push(entry)
+                .map_err(|_| errno::Error::new(libc::ENOMEM))?,
+        }
+        Ok(())
+    }
+
+    /// Returns whether `feature` is reported as available.
+    pub fn has_feature(&self, feature: X86Feature) -> bool {
+        let bit = feature.bit();
+        self.entry(bit.leaf, bit.subleaf)
+            .is_some_and(|entry| bit.reg.get(entry) & (1 << bit.bit) != 0)
+    }
+
+    /// Reports `feature` as available.
+    ///
+    /// Returns `ENOENT` if the leaf holding the feature is not present.
+    pub fn enable_feature(&mut self, feature: X86Feature) -> Result<()> {
+        let bit = feature.bit();
+        let entry = self
+            .entry_mut(bit.leaf, bit.subleaf)
+            .ok_or_else(|| errno::Error::new(libc::ENOENT))?;
+        *bit.reg.get_mut(entry) |= 1 << bit.bit;
+        Ok(())
+    }
+
+    /// Reports `feature` as unavailable.
+    pub fn disable_feature(&mut self, feature: X86Feature) {
+        let bit = feature.bit();
+        if let Some(entry) = self.entry_mut(bit.leaf, bit.subleaf) {
+            *bit.reg.get_mut(entry) &= !(1 << bit.bit);
+        }
+    }
+
+    /// Disables every known feature that is not part of `model`.
+    ///
+    /// Features of the model that are not already available are not enabled, and bits that
+    /// are not described by [`X86Feature`] are left untouched.
+    pub fn mask_to_model(&mut self, model: CpuModel) {
+        for feature in X86Feature::ALL {
+            if !model.has_feature(*feature) {
+                self.disable_feature(*feature);
+            }
+        }
+    }
+
+    /// Fills in the topology leaves for the vCPU with index `vcpu_index`.
+    ///
+    /// This updates the APIC ID and logical processor count in leaf `0x1`, the cache sharing
+    /// information in leaf `0x4`, the extended topology leaves `0xB` and `0x1F`, and the AMD
+    /// leaves `0x8000_0008` and `0x8000_001E`. Leaves that are not present are skipped,
+    /// except for the subleaves of `0xB` and `0x1F` which are added when the leaf exists.
+    ///
+    /// Returns `EINVAL` if `topology` has an empty level or `vcpu_index` does not fit in it,
+    /// or if leaf `0x8000_001E` is present and `topology` has more than 8 dies per socket.
+    pub fn set_topology(&mut self, vcpu_index: u32, topology: &CpuTopology) -> Result<()> {
+        if !topology.is_valid() || vcpu_index >= topology.num_vcpus() {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        // Leaf 0x8000_001E reports the number of nodes per socket minus one in 3 bits.
+        if self.entry(0x8000_001e, 0).is_some() && topology.dies_per_socket > 8 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let apic_id = topology.apic_id(vcpu_index);
+        let threads_per_core = u32::from(topology.threads_per_core);
+        let threads_per_socket = topology.threads_per_socket();
+        let cores_per_socket =
+            u32::from(topology.cores_per_die) * u32::from(topology.dies_per_socket);
+
+        if let Some(entry) = self.entry_mut(0x1, 0) {
+            entry.ebx = (entry.ebx & 0x0000_ffff)
+                | (threads_per_socket.min(0xff) << 16)
+                | ((apic_id & 0xff) << 24);
+            if threads_per_socket > 1 {
+                entry.edx |= 1 << X86Feature::Ht.bit().bit;
+            } else {
+                entry.edx &= !(1 << X86Feature::Ht.bit().bit);
+            }
+        }
+
+        for entry in self.cpuid.as_mut_slice() {
+            if entry.function != 0x4 || entry.eax & 0x1f == 0 {
+                continue;
+            }
+            let cache_level = (entry.eax >> 5) & 0x7;
+            let sharing = if cache_level >= 3 {
+                threads_per_socket
+            } else {
+                threads_per_core
+            };
+            entry.eax = (entry.eax & 0x3fff)
+                | (((sharing - 1) & 0xfff) << 14)
+                | (((cores_per_socket - 1) & 0x3f) << 26);
+        }
+
+        let smt_shift = topology.thread_bits();
+        let core_shift = smt_shift + topology.core_bits();
+        let die_shift = core_shift + topology.die_bits();
+        let level = |index: u32, shift: u32, count: u32, level_type: u32| kvm_cpuid_entry2 {
+            index,
+            flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+            eax: shift,
+            ebx: count,
+            ecx: index | (level_type << 8),
+            edx: apic_id,
+            ..Default::default()
+        };
+
+        if self.entry(0xb, 0).is_some() {
+            for entry in [
+                level(0, smt_shift, threads_per_core, LEVEL_TYPE_SMT),
+                level(1, die_shift, threads_per_socket, LEVEL_TYPE_CORE),
+                level(2, 0, 0, LEVEL_TYPE_INVALID),
+            ] {
+                self.set_entry(kvm_cpuid_entry2 {
+                    function: 0xb,
+                    ..entry
+                })?;
+            }
+        }
+
+        if self.entry(0x1f, 0).is_some() {
+            let threads_per_die = threads_per_core * u32::from(topology.cores_per_die);
+            for entry in [
+                level(0, smt_shift, threads_per_core, LEVEL_TYPE_SMT),
+                level(1, core_shift, threads_per_die, LEVEL_TYPE_CORE),
+                level(2, die_shift, threads_per_socket, LEVEL_TYPE_DIE),
+                level(3, 0, 0, LEVEL_TYPE_INVALID),
+            ] {
+                self.set_entry(kvm_cpuid_entry2 {
+                    function: 0x1f,
+                    ..entry
+                })?;
+            }
+        }
+
+        if let Some(entry) = self.entry_mut(0x8000_0008, 0) {
+            entry.ecx =
+                (entry.ecx & !0xf0ff) | (die_shift << 12) | ((threads_per_socket - 1) & 0xff);
+        }
+
+        if let Some(entry) = self.entry_mut(0x8000_001e, 0) {
+            let core_id = (vcpu_index / threads_per_core) % cores_per_socket;
+            let node_id = (apic_id >> core_shift) & ((1 << topology.die_bits()) - 1);
+            entry.eax = apic_id;
+            entry.ebx = (entry.ebx & !0xffff) | ((threads_per_core - 1) << 8) | (core_id & 0xff);
+            entry.ecx = (entry.ecx & !0x7ff)
+                | (((u32::from(topology.dies_per_socket) - 1) & 0x7) << 8)
+                | (node_id & 0xff);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kvm;
+    use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+
+    fn entry(function: u32, index: u32, flags: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            index,
+            flags,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_features() {
+        let mut cpuid = CpuId::from_entries(&[
+            entry(0x1, 0, 0),
+            entry(0x7, 0, KVM_CPUID_FLAG_SIGNIFCANT_INDEX),
+            entry(0x7, 1, KVM_CPUID_FLAG_SIGNIFCANT_INDEX),
+        ])
+        .unwrap();
+        let mut editor = CpuidEditor::new(&mut cpuid);
+
+        assert!(!editor.has_feature(X86Feature::Avx512f));
+        editor.enable_feature(X86Feature::Avx512f).unwrap();
+        editor.enable_feature(X86Feature::AvxVnni).unwrap();
+        editor.enable_feature(X86Feature::Sse42).unwrap();
+        assert!(editor.has_feature(X86Feature::Avx512f));
+        assert_eq!(editor.entry(0x7, 0).unwrap().ebx, 1 << 16);
+        assert_eq!(editor.entry(0x7, 1).unwrap().eax, 1 << 4);
+        // Leaf 0x1 ignores the subleaf.
+        assert_eq!(editor.entry(0x1, 5).unwrap().ecx, 1 << 20);
+
+        editor.disable_feature(X86Feature::Avx512f);
+        assert!(!editor.has_feature(X86Feature::Avx512f));
+        assert_eq!(
+            editor.enable_feature(X86Feature::Lm).unwrap_err().errno(),
+            libc::ENOENT
+        );
+        // Disabling a feature whose leaf is missing is a no-op.
+        editor.disable_feature(X86Feature::Lm);
+
+        editor.enable_feature(X86Feature::Avx512vl).unwrap();
+        editor.enable_feature(X86Feature::Fpu).unwrap();
+        editor.mask_to_model(CpuModel::X86_64V3);
+        assert!(!editor.has_feature(X86Feature::Avx512vl));
+        assert!(!editor.has_feature(X86Feature::AvxVnni));
+        assert!(editor.has_feature(X86Feature::Fpu));
+        assert!(editor.has_feature(X86Feature::Sse42));
+
+        assert_eq!(X86Feature::from_name("AVX512F"), Some(X86Feature::Avx512f));
+        assert_eq!(X86Feature::from_name("sse4_2"), Some(X86Feature::Sse42));
+        assert_eq!(X86Feature::from_name("foo"), None);
+        assert!(CpuModel::SapphireRapids.has_feature(X86Feature::AmxTile));
+        assert!(!CpuModel::EpycMilan.has_feature(X86Feature::Avx512f));
+    }
+
+    #[test]
+    fn test_topology() {
+        let topology = CpuTopology {
+            threads_per_core: 2,
+            cores_per_die: 3,
+            dies_per_socket: 2,
+            sockets: 2,
+        };
+        assert_eq!(topology.num_vcpus(), 24);
+        // Thread 1 of core 2 of die 1 of socket 0.
+        assert_eq!(topology.apic_id(11), 0b1101);
+        // Thread 0 of core 0 of die 0 of socket 1.
+        assert_eq!(topology.apic_id(12), 0b10000);
+
+        let mut cpuid = CpuId::from_entries(&[
+            kvm_cpuid_entry2 {
+                ebx: 0x0000_0800,
+                ..entry(0x1, 0, 0)
+            },
+            kvm_cpuid_entry2 {
+                eax: 0x1c00_4121,
+                ..entry(0x4, 0, KVM_CPUID_FLAG_SIGNIFCANT_INDEX)
+            },
+            kvm_cpuid_entry2 {
+                eax: 0x1c00_4163,
+                ..entry(0x4, 3, KVM_CPUID_FLAG_SIGNIFCANT_INDEX)
+            },
+            entry(0xb, 0, KVM_CPUID_FLAG_SIGNIFCANT_INDEX),
+            entry(0x1f, 0, KVM_CPUID_FLAG_SIGNIFCANT_INDEX),
+            entry(0x8000_001e, 0, 0),
+        ])
+        .unwrap();
+        let mut editor = CpuidEditor::new(&mut cpuid);
+
+        assert_eq!(
+            editor.set_topology(24, &topology).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        for dies_per_socket in [0, 9] {
+            let topology = CpuTopology {
+                dies_per_socket,
+                ..topology
+            };
+            assert_eq!(
+                editor.set_topology(0, &topology).unwrap_err().errno(),
+                libc::EINVAL
+            );
+        }
+        editor.set_topology(11, &topology).unwrap();
+
+        let leaf1 = editor.entry(0x1, 0).unwrap();
+        assert_eq!(leaf1.ebx, 0x0d0c_0800);
+        assert!(editor.has_feature(X86Feature::Ht));
+        // Masking to a model after setting the topology keeps HT.
+        editor.mask_to_model(CpuModel::X86_64V2);
+        assert!(editor.has_feature(X86Feature::Ht));
+
+        // L1 is shared by the threads of a core, L3 by the whole socket.
+        assert_eq!(editor.entry(0x4, 0).unwrap().eax, 0x1400_4121);
+        assert_eq!(editor.entry(0x4, 3).unwrap().eax, 0x1402_c163);
+
+        let smt = editor.entry(0xb, 0).unwrap();
+        assert_eq!((smt.eax, smt.ebx, smt.ecx, smt.edx), (1, 2, 0x100, 13));
+        let core = editor.entry(0xb, 1).unwrap();
+        assert_eq!((core.eax, core.ebx, core.ecx, core.edx), (4, 12, 0x201, 13));
+        let invalid = editor.entry(0xb, 2).unwrap();
+        assert_eq!((invalid.eax, invalid.ebx, invalid.ecx), (0, 0, 2));
+
+        let die = editor.entry(0x1f, 2).unwrap();
+        assert_eq!((die.eax, die.ebx, die.ecx, die.edx), (4, 12, 0x502, 13));
+        assert_eq!(editor.entry(0x1f, 1).unwrap().eax, 3);
+        assert_eq!(editor.entry(0x1f, 3).unwrap().ecx, 3);
+
+        let amd = editor.entry(0x8000_001e, 0).unwrap();
+        assert_eq!((amd.eax, amd.ebx, amd.ecx), (13, 0x105, 0x101));
+    }
+
+    #[test]
+    fn test_supported_cpuid() {
+        let kvm = Kvm::new().unwrap();
+        let mut cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        let mut editor = CpuidEditor::new(&mut cpuid);
+
+        assert!(editor.has_feature(X86Feature::Lm));
+        editor.mask_to_model(CpuModel::X86_64V2);
+        assert!(!editor.has_feature(X86Feature::Avx));
+
+        let topology = CpuTopology {
+            threads_per_core: 1,
+            cores_per_die: 4,
+            dies_per_socket: 1,
+            sockets: 1,
+        };
+        editor.set_topology(2, &topology).unwrap();
+        assert_eq!(editor.entry(0x1, 0).unwrap().ebx >> 24, 2);
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::mem::MaybeUninit;
+use std::os::unix::io::AsRawFd;
+
+use kvm_bindings::kvm_device_attr;
+use vmm_sys_util::errno;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+
+use crate::ioctls::Result;
+use crate::ioctls::device::DeviceFd;
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::vm::VmFd;
+use crate::kvm_ioctls::{KVM_GET_DEVICE_ATTR, KVM_HAS_DEVICE_ATTR, KVM_SET_DEVICE_ATTR};
+
+/// Plain data that can be exchanged with KVM as the value of a device attribute.
+///
+/// # Safety
+///
+/// The type must have no padding and every bit pattern must be a valid value, so
+/// that it can be filled in by the kernel.
+pub unsafe trait Pod: Copy {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(
+            // SAFETY: Integers have no padding and no invalid bit patterns.
+            unsafe impl Pod for $t {}
+        )*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+// SAFETY: An array of `Pod` values has no padding between its elements.
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+mod private {
+    pub trait Sealed {}
+    impl Sealed for super::DeviceFd {}
+    impl Sealed for super::VmFd {}
+    impl Sealed for super::VcpuFd {}
+}
+
+/// Typed access to the attributes of a KVM device, VM or vCPU.
+///
+/// The methods wrap `KVM_HAS_DEVICE_ATTR`, `KVM_GET_DEVICE_ATTR` and
+/// `KVM_SET_DEVICE_ATTR`, and pass KVM a pointer to a value they own for the
+/// duration of the ioctl. Which file descriptors support device attributes
+/// depends on the architecture and on the kernel.
+///
+/// # Example
+///
+/// ```rust
+/// # use kvm_ioctls::{DeviceAttributes, Kvm};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let vcpu = vm.create_vcpu(0).unwrap();
+///
+/// # #[cfg(target_arch = "x86_64")]
+/// # {
+/// use kvm_bindings::{KVM_VCPU_TSC_CTRL, KVM_VCPU_TSC_OFFSET};
+///
+/// let offset = u64::from(KVM_VCPU_TSC_OFFSET);
+/// if vcpu.has_attr(KVM_VCPU_TSC_CTRL, offset) {
+///     let tsc_offset: u64 = vcpu.get_attr(KVM_VCPU_TSC_CTRL, offset).unwrap();
+///     vcpu.set_attr(KVM_VCPU_TSC_CTRL, offset, &tsc_offset).unwrap();
+/// }
+/// # }
+/// ```
+pub trait DeviceAttributes: AsRawFd + Sized + private::Sealed {
+    /// Returns whether the attribute `attr` of `group` is supported.
+    fn has_attr(&self, group: u32, attr: u64) -> bool {
+        let device_attr = kvm_device_attr {
+            group,
+            attr,
+            ..Default::default()
+        };
+        // SAFETY: We are calling this function with a KVM fd, and KVM ignores `addr`.
+        unsafe { ioctl_with_ref(self, KVM_HAS_DEVICE_ATTR(), &device_attr) == 0 }
+    }
+
+    /// Reads the attribute `attr` of `group`.
+    ///
+    /// `T` must match the size of the attribute. KVM attributes are at most 64-bit
+    /// unless documented otherwise, and at least 64 bits are always reserved for
+    /// the kernel to write to.
+    fn get_attr<T: Pod>(&self, group: u32, attr: u64) -> Result<T> {
+        // A zeroed buffer of at least one u64, suitably aligned for `T`.
+        #[repr(C)]
+        union Buffer<T: Copy> {
+            value: T,
+            _min: u64,
+        }
+        let mut buffer = MaybeUninit::<Buffer<T>>::zeroed();
+        let mut device_attr = kvm_device_attr {
+            group,
+            attr,
+            addr: buffer.as_mut_ptr() as u64,
+            ..Default::default()
+        };
+        // SAFETY: `addr` points to a buffer owned by this function that is large enough
+        // for both `T` and a 64-bit attribute.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_DEVICE_ATTR(), &mut device_attr) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        // SAFETY: The buffer is initialized, and any bit pattern is a valid `T`.
+        Ok(unsafe { buffer.assume_init().value })
+    }
+
+    /// Writes `value` to the attribute `attr` of `group`.
+    fn set_attr<T: Pod>(&self, group: u32, attr: u64, value: &T) -> Result<()> {
+        set_attr_raw(self, group, attr, value as *const T as u64)
+    }
+
+    /// Sets the attribute `attr` of `group`, which takes no value, e.g. a
+    /// control attribute that triggers an action.
+    fn set_attr_empty(&self, group: u32, attr: u64) -> Result<()> {
+        set_attr_raw(self, group, attr, 0)
+    }
+}
+
+// Calls `KVM_SET_DEVICE_ATTR` with `addr`, which must be null or point to a value
+// that outlives the call.
+fn set_attr_raw<F: AsRawFd>(fd: &F, group: u32, attr: u64, addr: u64) -> Result<()> {
+    let device_attr = kvm_device_attr {
+        group,
+        attr,
+        addr,
+        ..Default::default()
+    };
+    // SAFETY: We are calling this function with a KVM fd, and `addr` is either null
+    // or points to a `Pod` value borrowed by the caller, which KVM only reads.
+    let ret = unsafe { ioctl_with_ref(fd, KVM_SET_DEVICE_ATTR(), &device_attr) };
+    if ret != 0 {
+        return Err(errno::Error::last());
+    }
+    Ok(())
+}
+
+impl DeviceAttributes for DeviceFd {}
+impl DeviceAttributes for VmFd {}
+impl DeviceAttributes for VcpuFd {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kvm;
+
+    #[test]
+    fn test_device_attributes() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        assert!(!vm.has_attr(u32::MAX, 0));
+        vcpu.get_attr::<u64>(u32::MAX, 0).unwrap_err();
+        vcpu.set_attr(u32::MAX, 0, &0u64).unwrap_err();
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            use kvm_bindings::{
+                KVM_DEV_VFIO_FILE, KVM_DEV_VFIO_FILE_ADD, KVM_VCPU_TSC_CTRL, KVM_VCPU_TSC_OFFSET,
+                kvm_create_device, kvm_device_type_KVM_DEV_TYPE_VFIO,
+            };
+
+            let offset = u64::from(KVM_VCPU_TSC_OFFSET);
+            if vcpu.has_attr(KVM_VCPU_TSC_CTRL, offset) {
+                let tsc_offset: u64 = vcpu.get_attr(KVM_VCPU_TSC_CTRL, offset).unwrap();
+                vcpu.set_attr(KVM_VCPU_TSC_CTRL, offset, &tsc_offset)
+                    .unwrap();
+                vcpu.get_attr::<[u32; 2]>(KVM_VCPU_TSC_CTRL, offset)
+                    .unwrap();
+            }
+
+            let mut device = kvm_create_device {
+                type_: kvm_device_type_KVM_DEV_TYPE_VFIO,
+                fd: 0,
+                flags: 0,
+            };
+            let device = vm.create_device(&mut device).unwrap();
+            assert!(device.has_attr(KVM_DEV_VFIO_FILE, u64::from(KVM_DEV_VFIO_FILE_ADD)));
+            // The VFIO device expects a file descriptor, and -1 is not one.
+            assert_eq!(
+                device
+                    .set_attr(KVM_DEV_VFIO_FILE, u64::from(KVM_DEV_VFIO_FILE_ADD), &-1i32)
+                    .unwrap_err()
+                    .errno(),
+                libc::EBADF
+            );
+        }
+    }
+}
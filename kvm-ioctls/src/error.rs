@@ -0,0 +1,525 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::fmt;
+
+#[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+use kvm_bindings::kvm_regs;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use kvm_bindings::kvm_vcpu_events;
+#[cfg(target_arch = "aarch64")]
+use kvm_bindings::kvm_vcpu_init;
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{CpuId, Msrs, kvm_lapic_state, kvm_sregs};
+use kvm_bindings::{kvm_create_device, kvm_userspace_memory_region};
+use vmm_sys_util::errno;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use vmm_sys_util::eventfd::EventFd;
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use crate::gsi_routing::GsiRoutingTable;
+use crate::ioctls::device::DeviceFd;
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::vm::{IoEventAddress, VmFd};
+
+/// The argument of a failed ioctl that identifies what it was applied to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorArg {
+    /// A memory slot.
+    Slot(u32),
+    /// A GSI.
+    Gsi(u32),
+    /// The index of an MSR.
+    Msr(u32),
+    /// The ID of a vCPU.
+    Vcpu(u64),
+    /// The ID of a register, as used by `KVM_GET_ONE_REG` and `KVM_SET_ONE_REG`.
+    Reg(u64),
+    /// A guest physical address or I/O port.
+    Addr(u64),
+}
+
+impl fmt::Display for ErrorArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorArg::Slot(slot) => write!(f, "slot {slot}"),
+            ErrorArg::Gsi(gsi) => write!(f, "GSI {gsi}"),
+            ErrorArg::Msr(index) => write!(f, "MSR {index:#x}"),
+            ErrorArg::Vcpu(id) => write!(f, "vCPU {id}"),
+            ErrorArg::Reg(id) => write!(f, "register {id:#x}"),
+            ErrorArg::Addr(addr) => write!(f, "address {addr:#x}"),
+        }
+    }
+}
+
+/// An error returned by a KVM ioctl, with the name of the ioctl and, when
+/// relevant, the argument it failed for.
+///
+/// The wrappers of this crate return a plain `Error`, which is an
+/// `errno::Error`. The views returned by `VmFd::checked` and `VcpuFd::checked`
+/// offer the same wrappers returning an `IoctlError` filled in by the crate.
+/// The errors of the other wrappers can be given a context at the call site
+/// with [`IoctlContext`]. An `IoctlError` converts back into an `Error` or a
+/// `std::io::Error`.
+///
+/// # Example
+///
+/// ```rust
+/// # use kvm_ioctls::{ErrorArg, Kvm};
+/// use kvm_bindings::kvm_userspace_memory_region;
+///
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let region = kvm_userspace_memory_region {
+///     slot: 0,
+///     guest_phys_addr: 0x1001,
+///     memory_size: 0x1000,
+///     ..Default::default()
+/// };
+/// // SAFETY: The region is rejected by KVM as it is not page aligned.
+/// let err = unsafe { vm.checked().set_user_memory_region(region) }.unwrap_err();
+/// assert_eq!(err.arg(), Some(ErrorArg::Slot(0)));
+/// assert_eq!(err.errno(), libc::EINVAL);
+/// assert_eq!(
+///     err.to_string(),
+///     "KVM_SET_USER_MEMORY_REGION failed for slot 0: Invalid argument (os error 22)"
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoctlError {
+    ioctl: &'static str,
+    arg: Option<ErrorArg>,
+    source: errno::Error,
+}
+
+impl IoctlError {
+    /// Creates an error for the failure of `ioctl` with `source`.
+    pub fn new(ioctl: &'static str, source: errno::Error) -> Self {
+        IoctlError {
+            ioctl,
+            arg: None,
+            source,
+        }
+    }
+
+    /// Creates an error for the failure of `ioctl` for `arg` with `source`.
+    pub fn with_arg(ioctl: &'static str, arg: ErrorArg, source: errno::Error) -> Self {
+        IoctlError {
+            ioctl,
+            arg: Some(arg),
+            source,
+        }
+    }
+
+    /// Returns the name of the ioctl that failed.
+    pub fn ioctl(&self) -> &'static str {
+        self.ioctl
+    }
+
+    /// Returns the argument the ioctl failed for, if known.
+    pub fn arg(&self) -> Option<ErrorArg> {
+        self.arg
+    }
+
+    /// Returns the errno the ioctl failed with.
+    pub fn errno(&self) -> i32 {
+        self.source.errno()
+    }
+}
+
+impl fmt::Display for IoctlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed", self.ioctl)?;
+        if let Some(arg) = self.arg {
+            write!(f, " for {arg}")?;
+        }
+        write!(f, ": {}", self.source)
+    }
+}
+
+impl std::error::Error for IoctlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<IoctlError> for errno::Error {
+    fn from(err: IoctlError) -> Self {
+        err.source
+    }
+}
+
+impl From<IoctlError> for std::io::Error {
+    fn from(err: IoctlError) -> Self {
+        std::io::Error::from_raw_os_error(err.errno())
+    }
+}
+
+/// Adds the name of the failed ioctl to the errors returned by the wrappers of
+/// this crate, turning them into [`IoctlError`]s.
+pub trait IoctlContext<T> {
+    /// Names `ioctl` as the cause of the error.
+    fn ioctl_context(self, ioctl: &'static str) -> std::result::Result<T, IoctlError>;
+
+    /// Names `ioctl` as the cause of the error, and `arg` as what it failed for.
+    fn ioctl_context_arg(
+        self,
+        ioctl: &'static str,
+        arg: ErrorArg,
+    ) -> std::result::Result<T, IoctlError>;
+}
+
+impl<T> IoctlContext<T> for std::result::Result<T, errno::Error> {
+    fn ioctl_context(self, ioctl: &'static str) -> std::result::Result<T, IoctlError> {
+        self.map_err(|err| IoctlError::new(ioctl, err))
+    }
+
+    fn ioctl_context_arg(
+        self,
+        ioctl: &'static str,
+        arg: ErrorArg,
+    ) -> std::result::Result<T, IoctlError> {
+        self.map_err(|err| IoctlError::with_arg(ioctl, arg, err))
+    }
+}
+
+/// A view over a [`VmFd`] whose wrappers return an [`IoctlError`] naming the
+/// ioctl that failed and what it failed for, returned by `VmFd::checked`.
+#[derive(Debug, Clone, Copy)]
+pub struct CheckedVmFd<'a>(pub(crate) &'a VmFd);
+
+impl CheckedVmFd<'_> {
+    /// Like `VmFd::set_user_memory_region`, failing for the slot of `region`.
+    ///
+    /// # Safety
+    ///
+    /// See `VmFd::set_user_memory_region`.
+    pub unsafe fn set_user_memory_region(
+        &self,
+        region: kvm_userspace_memory_region,
+    ) -> std::result::Result<(), IoctlError> {
+        // SAFETY: The caller upholds the contract of the wrapper.
+        unsafe { self.0.set_user_memory_region(region) }
+            .ioctl_context_arg("KVM_SET_USER_MEMORY_REGION", ErrorArg::Slot(region.slot))
+    }
+
+    /// Like `VmFd::create_vcpu`, failing for the vCPU `id`.
+    pub fn create_vcpu(&self, id: u64) -> std::result::Result<VcpuFd, IoctlError> {
+        self.0
+            .create_vcpu(id)
+            .ioctl_context_arg("KVM_CREATE_VCPU", ErrorArg::Vcpu(id))
+    }
+
+    /// Like `VmFd::create_device`.
+    pub fn create_device(
+        &self,
+        device: &mut kvm_create_device,
+    ) -> std::result::Result<DeviceFd, IoctlError> {
+        self.0
+            .create_device(device)
+            .ioctl_context("KVM_CREATE_DEVICE")
+    }
+
+    /// Like `VmFd::get_dirty_log`, failing for `slot`.
+    pub fn get_dirty_log(
+        &self,
+        slot: u32,
+        memory_size: usize,
+    ) -> std::result::Result<Vec<u64>, IoctlError> {
+        self.0
+            .get_dirty_log(slot, memory_size)
+            .ioctl_context_arg("KVM_GET_DIRTY_LOG", ErrorArg::Slot(slot))
+    }
+
+    /// Like `VmFd::register_irqfd`, failing for `gsi`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> std::result::Result<(), IoctlError> {
+        self.0
+            .register_irqfd(fd, gsi)
+            .ioctl_context_arg("KVM_IRQFD", ErrorArg::Gsi(gsi))
+    }
+
+    /// Like `VmFd::unregister_irqfd`, failing for `gsi`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> std::result::Result<(), IoctlError> {
+        self.0
+            .unregister_irqfd(fd, gsi)
+            .ioctl_context_arg("KVM_IRQFD", ErrorArg::Gsi(gsi))
+    }
+
+    /// Like `VmFd::set_irq_line`, failing for the GSI `irq`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub fn set_irq_line(&self, irq: u32, active: bool) -> std::result::Result<(), IoctlError> {
+        self.0
+            .set_irq_line(irq, active)
+            .ioctl_context_arg("KVM_IRQ_LINE", ErrorArg::Gsi(irq))
+    }
+
+    /// Like `VmFd::set_gsi_routing_table`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub fn set_gsi_routing_table(
+        &self,
+        table: &GsiRoutingTable,
+    ) -> std::result::Result<(), IoctlError> {
+        self.0
+            .set_gsi_routing_table(table)
+            .ioctl_context("KVM_SET_GSI_ROUTING")
+    }
+
+    /// Like `VmFd::register_coalesced_mmio`, failing for the address or port of
+    /// `addr`.
+    pub fn register_coalesced_mmio(
+        &self,
+        addr: IoEventAddress,
+        size: u32,
+    ) -> std::result::Result<(), IoctlError> {
+        let (IoEventAddress::Pio(raw) | IoEventAddress::Mmio(raw)) = addr;
+        self.0
+            .register_coalesced_mmio(addr, size)
+            .ioctl_context_arg("KVM_REGISTER_COALESCED_MMIO", ErrorArg::Addr(raw))
+    }
+}
+
+/// A view over a [`VcpuFd`] whose wrappers return an [`IoctlError`] naming the
+/// ioctl that failed and what it failed for, returned by `VcpuFd::checked`.
+#[derive(Debug, Clone, Copy)]
+pub struct CheckedVcpuFd<'a>(pub(crate) &'a VcpuFd);
+
+impl CheckedVcpuFd<'_> {
+    /// Like `VcpuFd::get_regs`.
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+    pub fn get_regs(&self) -> std::result::Result<kvm_regs, IoctlError> {
+        self.0.get_regs().ioctl_context("KVM_GET_REGS")
+    }
+
+    /// Like `VcpuFd::set_regs`.
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+    pub fn set_regs(&self, regs: &kvm_regs) -> std::result::Result<(), IoctlError> {
+        self.0.set_regs(regs).ioctl_context("KVM_SET_REGS")
+    }
+
+    /// Like `VcpuFd::get_sregs`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_sregs(&self) -> std::result::Result<kvm_sregs, IoctlError> {
+        self.0.get_sregs().ioctl_context("KVM_GET_SREGS")
+    }
+
+    /// Like `VcpuFd::set_sregs`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_sregs(&self, sregs: &kvm_sregs) -> std::result::Result<(), IoctlError> {
+        self.0.set_sregs(sregs).ioctl_context("KVM_SET_SREGS")
+    }
+
+    /// Like `VcpuFd::get_cpuid2`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_cpuid2(&self, num_entries: usize) -> std::result::Result<CpuId, IoctlError> {
+        self.0
+            .get_cpuid2(num_entries)
+            .ioctl_context("KVM_GET_CPUID2")
+    }
+
+    /// Like `VcpuFd::set_cpuid2`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_cpuid2(&self, cpuid: &CpuId) -> std::result::Result<(), IoctlError> {
+        self.0.set_cpuid2(cpuid).ioctl_context("KVM_SET_CPUID2")
+    }
+
+    /// Like `VcpuFd::get_lapic`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_lapic(&self) -> std::result::Result<kvm_lapic_state, IoctlError> {
+        self.0.get_lapic().ioctl_context("KVM_GET_LAPIC")
+    }
+
+    /// Like `VcpuFd::set_lapic`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_lapic(&self, klapic: &kvm_lapic_state) -> std::result::Result<(), IoctlError> {
+        self.0.set_lapic(klapic).ioctl_context("KVM_SET_LAPIC")
+    }
+
+    /// Like `VcpuFd::get_msrs`, but a short read is an error with `EINVAL`, which
+    /// names the first MSR that KVM could not read.
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_msrs(&self, msrs: &mut Msrs) -> std::result::Result<usize, IoctlError> {
+        let read = self.0.get_msrs(msrs);
+        check_msrs("KVM_GET_MSRS", msrs, read)
+    }
+
+    /// Like `VcpuFd::set_msrs`, but a short write is an error with `EINVAL`, which
+    /// names the first MSR that KVM could not write.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_msrs(&self, msrs: &Msrs) -> std::result::Result<usize, IoctlError> {
+        let written = self.0.set_msrs(msrs);
+        check_msrs("KVM_SET_MSRS", msrs, written)
+    }
+
+    /// Like `VcpuFd::set_vcpu_events`.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn set_vcpu_events(
+        &self,
+        vcpu_events: &kvm_vcpu_events,
+    ) -> std::result::Result<(), IoctlError> {
+        self.0
+            .set_vcpu_events(vcpu_events)
+            .ioctl_context("KVM_SET_VCPU_EVENTS")
+    }
+
+    /// Like `VcpuFd::vcpu_init`.
+    #[cfg(target_arch = "aarch64")]
+    pub fn vcpu_init(&self, kvi: &kvm_vcpu_init) -> std::result::Result<(), IoctlError> {
+        self.0.vcpu_init(kvi).ioctl_context("KVM_ARM_VCPU_INIT")
+    }
+
+    /// Like `VcpuFd::get_one_reg`, failing for `reg_id`.
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    pub fn get_one_reg(
+        &self,
+        reg_id: u64,
+        data: &mut [u8],
+    ) -> std::result::Result<usize, IoctlError> {
+        self.0
+            .get_one_reg(reg_id, data)
+            .ioctl_context_arg("KVM_GET_ONE_REG", ErrorArg::Reg(reg_id))
+    }
+
+    /// Like `VcpuFd::set_one_reg`, failing for `reg_id`.
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    pub fn set_one_reg(&self, reg_id: u64, data: &[u8]) -> std::result::Result<usize, IoctlError> {
+        self.0
+            .set_one_reg(reg_id, data)
+            .ioctl_context_arg("KVM_SET_ONE_REG", ErrorArg::Reg(reg_id))
+    }
+}
+
+// Turns the number of MSRs accessed by `ioctl` into an error naming the MSR it
+// stopped at, if it did not access all of `msrs`.
+#[cfg(target_arch = "x86_64")]
+fn check_msrs(
+    ioctl: &'static str,
+    msrs: &Msrs,
+    done: std::result::Result<usize, errno::Error>,
+) -> std::result::Result<usize, IoctlError> {
+    let done = done.ioctl_context(ioctl)?;
+    match msrs.as_slice().get(done) {
+        Some(msr) => Err(IoctlError::with_arg(
+            ioctl,
+            ErrorArg::Msr(msr.index),
+            errno::Error::new(libc::EINVAL),
+        )),
+        None => Ok(done),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kvm;
+    #[cfg(target_arch = "x86_64")]
+    use kvm_bindings::kvm_msr_entry;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_error_context() {
+        let res: std::result::Result<(), _> = Err(errno::Error::new(libc::EINVAL));
+        let err = res.ioctl_context("KVM_SET_CPUID2").unwrap_err();
+        assert_eq!(err.ioctl(), "KVM_SET_CPUID2");
+        assert_eq!(err.arg(), None);
+        assert_eq!(
+            err.to_string(),
+            "KVM_SET_CPUID2 failed: Invalid argument (os error 22)"
+        );
+
+        let res: std::result::Result<(), _> = Err(errno::Error::new(libc::EBUSY));
+        let err = res
+            .ioctl_context_arg("KVM_IRQFD", ErrorArg::Gsi(5))
+            .unwrap_err();
+        assert_eq!(err.arg(), Some(ErrorArg::Gsi(5)));
+        assert_eq!(
+            err.to_string(),
+            "KVM_IRQFD failed for GSI 5: Device or resource busy (os error 16)"
+        );
+        assert_eq!(
+            err.source().unwrap().downcast_ref::<errno::Error>(),
+            Some(&errno::Error::new(libc::EBUSY))
+        );
+        assert_eq!(errno::Error::from(err).errno(), libc::EBUSY);
+        assert_eq!(std::io::Error::from(err).raw_os_error(), Some(libc::EBUSY));
+
+        let ok: std::result::Result<u32, errno::Error> = Ok(1);
+        assert_eq!(
+            ok.ioctl_context_arg("KVM_SET_MSRS", ErrorArg::Msr(0x10)),
+            Ok(1)
+        );
+        assert_eq!(ErrorArg::Msr(0x10).to_string(), "MSR 0x10");
+    }
+
+    #[test]
+    fn test_checked_wrappers() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let region = kvm_userspace_memory_region {
+            slot: 3,
+            guest_phys_addr: 0x1001,
+            memory_size: 0x1000,
+            ..Default::default()
+        };
+        // SAFETY: KVM rejects the region as it is not page aligned.
+        let err = unsafe { vm.checked().set_user_memory_region(region) }.unwrap_err();
+        assert_eq!(err.ioctl(), "KVM_SET_USER_MEMORY_REGION");
+        assert_eq!(err.arg(), Some(ErrorArg::Slot(3)));
+        assert_eq!(err.errno(), libc::EINVAL);
+
+        let vcpu = vm.checked().create_vcpu(0).unwrap();
+        let err = vm.checked().create_vcpu(0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "KVM_CREATE_VCPU failed for vCPU 0: File exists (os error 17)"
+        );
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            // There is no local APIC without an in-kernel irqchip.
+            let err = vcpu.checked().get_lapic().unwrap_err();
+            assert_eq!(err.ioctl(), "KVM_GET_LAPIC");
+            assert_eq!(err.errno(), libc::EINVAL);
+
+            // KVM stops at the MSR it does not know, and the error names it.
+            let entries = [0x174, 0x4b56_4dff, 0x175].map(|index| kvm_msr_entry {
+                index,
+                ..Default::default()
+            });
+            let mut msrs = Msrs::from_entries(&entries).unwrap();
+            let err = vcpu.checked().get_msrs(&mut msrs).unwrap_err();
+            assert_eq!(err.ioctl(), "KVM_GET_MSRS");
+            assert_eq!(err.arg(), Some(ErrorArg::Msr(0x4b56_4dff)));
+            assert_eq!(err.errno(), libc::EINVAL);
+            let err = vcpu.checked().set_msrs(&msrs).unwrap_err();
+            assert_eq!(err.ioctl(), "KVM_SET_MSRS");
+            assert_eq!(err.arg(), Some(ErrorArg::Msr(0x4b56_4dff)));
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        drop(vcpu);
+    }
+}
@@ -0,0 +1,343 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::os::raw::c_int;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use kvm_bindings::*;
+use vmm_sys_util::errno;
+
+/// The number of exit reasons counted separately by [`ExitStats`]. Greater exit
+/// reasons are counted together, in [`ExitStatsSnapshot::other`].
+const EXIT_REASONS: usize = 64;
+
+/// The MMIO exits counted by [`ExitStats`] are grouped by pages of this size.
+const MMIO_PAGE_SIZE: u64 = 0x1000;
+
+/// What a `KVM_RUN` call returned, passed to an [`ExitObserver`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitEvent {
+    /// A `KVM_EXIT_IO` exit.
+    Io {
+        /// The port accessed.
+        port: u16,
+        /// Whether the guest wrote to the port.
+        write: bool,
+    },
+    /// A `KVM_EXIT_MMIO` exit.
+    Mmio {
+        /// The guest physical address accessed.
+        addr: u64,
+        /// Whether the guest wrote to the address.
+        write: bool,
+    },
+    /// Any other exit, with its `KVM_EXIT_*` reason. A run interrupted by a signal
+    /// is reported as `KVM_EXIT_INTR`.
+    Other(u32),
+    /// `KVM_RUN` failed with this error.
+    Error(errno::Error),
+}
+
+impl ExitEvent {
+    /// Reads the exit of a `KVM_RUN` call that returned `ret` and set `errno`.
+    pub(crate) fn new(run: &kvm_run, ret: c_int, errno: errno::Error) -> Self {
+        if ret != 0 {
+            return match errno.errno() {
+                libc::EINTR => ExitEvent::Other(KVM_EXIT_INTR),
+                libc::EFAULT | libc::EHWPOISON if run.exit_reason == KVM_EXIT_MEMORY_FAULT => {
+                    ExitEvent::Other(KVM_EXIT_MEMORY_FAULT)
+                }
+                _ => ExitEvent::Error(errno),
+            };
+        }
+        match run.exit_reason {
+            KVM_EXIT_IO => {
+                // SAFETY: The exit reason tells which union field to use.
+                let io = unsafe { run.__bindgen_anon_1.io };
+                ExitEvent::Io {
+                    port: io.port,
+                    write: u32::from(io.direction) == KVM_EXIT_IO_OUT,
+                }
+            }
+            KVM_EXIT_MMIO => {
+                // SAFETY: The exit reason tells which union field to use.
+                let mmio = unsafe { run.__bindgen_anon_1.mmio };
+                ExitEvent::Mmio {
+                    addr: mmio.phys_addr,
+                    write: mmio.is_write != 0,
+                }
+            }
+            reason => ExitEvent::Other(reason),
+        }
+    }
+
+    /// Returns the `KVM_EXIT_*` reason of the exit, or `None` if `KVM_RUN` failed.
+    pub fn reason(&self) -> Option<u32> {
+        match self {
+            ExitEvent::Io { .. } => Some(KVM_EXIT_IO),
+            ExitEvent::Mmio { .. } => Some(KVM_EXIT_MMIO),
+            ExitEvent::Other(reason) => Some(*reason),
+            ExitEvent::Error(_) => None,
+        }
+    }
+}
+
+/// A hook called after each `KVM_RUN` of a vCPU, set with
+/// `VcpuFd::set_exit_observer`.
+///
+/// It is called on the thread running the vCPU, before the exit is returned, so
+/// it should be quick.
+pub trait ExitObserver: Debug + Send + Sync {
+    /// Called with the exit of a `KVM_RUN` call, and the time spent in it.
+    fn on_exit(&self, exit: ExitEvent, elapsed: Duration);
+}
+
+#[derive(Debug, Default)]
+struct ReasonCounters {
+    count: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+impl ReasonCounters {
+    fn add(&self, elapsed: Duration) {
+        let ns = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> ExitReasonStats {
+        ExitReasonStats {
+            count: self.count.load(Ordering::Relaxed),
+            total: Duration::from_nanos(self.total_ns.load(Ordering::Relaxed)),
+            max: Duration::from_nanos(self.max_ns.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total_ns.store(0, Ordering::Relaxed);
+        self.max_ns.store(0, Ordering::Relaxed);
+    }
+}
+
+/// An [`ExitObserver`] counting the exits of a vCPU by reason, I/O port and MMIO
+/// page, and the time spent in `KVM_RUN` for each reason.
+///
+/// The counters by reason are atomic, and the counters by port and page are
+/// behind a lock taken on each I/O and MMIO exit and while a snapshot is taken.
+/// [`snapshot`](Self::snapshot) can be called from any thread, but sharing one
+/// `ExitStats` between several vCPUs makes their I/O and MMIO exits contend on
+/// that lock, so each vCPU should have its own.
+#[derive(Debug)]
+pub struct ExitStats {
+    reasons: [ReasonCounters; EXIT_REASONS],
+    other: ReasonCounters,
+    errors: AtomicU64,
+    pio: Mutex<BTreeMap<u16, u64>>,
+    mmio: Mutex<BTreeMap<u64, u64>>,
+}
+
+/// The count and the time spent in `KVM_RUN` of the exits of one reason.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExitReasonStats {
+    /// The number of exits.
+    pub count: u64,
+    /// The total time spent in `KVM_RUN`.
+    pub total: Duration,
+    /// The longest time spent in a `KVM_RUN`.
+    pub max: Duration,
+}
+
+impl ExitReasonStats {
+    /// Returns the average time spent in `KVM_RUN`, or zero if there was no exit.
+    pub fn mean(&self) -> Duration {
+        u32::try_from(self.count)
+            .ok()
+            .and_then(|count| self.total.checked_div(count))
+            .unwrap_or_default()
+    }
+}
+
+/// The counters of an [`ExitStats`] at one point in time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExitStatsSnapshot {
+    /// The exits by `KVM_EXIT_*` reason, for the reasons that happened.
+    pub reasons: BTreeMap<u32, ExitReasonStats>,
+    /// The exits with a reason too large to be counted separately.
+    pub other: ExitReasonStats,
+    /// The number of failed `KVM_RUN` calls.
+    pub errors: u64,
+    /// The number of `KVM_EXIT_IO` exits by port.
+    pub pio: BTreeMap<u16, u64>,
+    /// The number of `KVM_EXIT_MMIO` exits by guest physical page address.
+    pub mmio: BTreeMap<u64, u64>,
+}
+
+impl ExitStatsSnapshot {
+    /// Returns the total number of exits, including the failed runs.
+    pub fn total(&self) -> u64 {
+        self.reasons.values().map(|stats| stats.count).sum::<u64>() + self.other.count + self.errors
+    }
+}
+
+impl Default for ExitStats {
+    fn default() -> Self {
+        ExitStats {
+            reasons: std::array::from_fn(|_| ReasonCounters::default()),
+            other: ReasonCounters::default(),
+            errors: AtomicU64::new(0),
+            pio: Mutex::new(BTreeMap::new()),
+            mmio: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl ExitStats {
+    /// Creates an `ExitStats` with all counters at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the current counters.
+    pub fn snapshot(&self) -> ExitStatsSnapshot {
+        let reasons = self
+            .reasons
+            .iter()
+            .enumerate()
+            .map(|(reason, counters)| (reason as u32, counters.load()))
+            .filter(|(_, stats)| stats.count != 0)
+            .collect();
+        ExitStatsSnapshot {
+            reasons,
+            other: self.other.load(),
+            errors: self.errors.load(Ordering::Relaxed),
+            pio: self.pio.lock().unwrap().clone(),
+            mmio: self.mmio.lock().unwrap().clone(),
+        }
+    }
+
+    /// Sets all counters back to zero.
+    pub fn reset(&self) {
+        for counters in &self.reasons {
+            counters.reset();
+        }
+        self.other.reset();
+        self.errors.store(0, Ordering::Relaxed);
+        self.pio.lock().unwrap().clear();
+        self.mmio.lock().unwrap().clear();
+    }
+}
+
+impl ExitObserver for ExitStats {
+    fn on_exit(&self, exit: ExitEvent, elapsed: Duration) {
+        let Some(reason) = exit.reason() else {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        self.reasons
+            .get(reason as usize)
+            .unwrap_or(&self.other)
+            .add(elapsed);
+        match exit {
+            ExitEvent::Io { port, .. } => *self.pio.lock().unwrap().entry(port).or_default() += 1,
+            ExitEvent::Mmio { addr, .. } => {
+                let page = addr & !(MMIO_PAGE_SIZE - 1);
+                *self.mmio.lock().unwrap().entry(page).or_default() += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_event() {
+        let mut run = kvm_run {
+            exit_reason: KVM_EXIT_IO,
+            ..Default::default()
+        };
+        run.__bindgen_anon_1.io.port = 0x3f8;
+        run.__bindgen_anon_1.io.direction = KVM_EXIT_IO_OUT as u8;
+        let ok = errno::Error::new(0);
+        assert_eq!(
+            ExitEvent::new(&run, 0, ok),
+            ExitEvent::Io {
+                port: 0x3f8,
+                write: true
+            }
+        );
+
+        run.exit_reason = KVM_EXIT_HLT;
+        assert_eq!(ExitEvent::new(&run, 0, ok), ExitEvent::Other(KVM_EXIT_HLT));
+        let intr = ExitEvent::new(&run, -1, errno::Error::new(libc::EINTR));
+        assert_eq!(intr.reason(), Some(KVM_EXIT_INTR));
+        // The exit reason is stale when `KVM_RUN` fails.
+        let fault = errno::Error::new(libc::EFAULT);
+        assert_eq!(ExitEvent::new(&run, -1, fault), ExitEvent::Error(fault));
+        run.exit_reason = KVM_EXIT_MEMORY_FAULT;
+        assert_eq!(
+            ExitEvent::new(&run, -1, fault),
+            ExitEvent::Other(KVM_EXIT_MEMORY_FAULT)
+        );
+        assert_eq!(ExitEvent::Error(fault).reason(), None);
+    }
+
+    #[test]
+    fn test_exit_stats() {
+        let stats = ExitStats::new();
+        let ms = Duration::from_millis;
+        stats.on_exit(
+            ExitEvent::Io {
+                port: 0x3f8,
+                write: true,
+            },
+            ms(1),
+        );
+        stats.on_exit(
+            ExitEvent::Io {
+                port: 0x3f8,
+                write: false,
+            },
+            ms(3),
+        );
+        stats.on_exit(
+            ExitEvent::Mmio {
+                addr: 0xfee0_0030,
+                write: true,
+            },
+            ms(2),
+        );
+        stats.on_exit(ExitEvent::Other(KVM_EXIT_HLT), ms(5));
+        stats.on_exit(ExitEvent::Other(EXIT_REASONS as u32 - 1), ms(1));
+        stats.on_exit(ExitEvent::Other(1000), ms(1));
+        stats.on_exit(ExitEvent::Error(errno::Error::new(libc::EFAULT)), ms(1));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total(), 7);
+        assert_eq!(snapshot.errors, 1);
+        let io = snapshot.reasons[&KVM_EXIT_IO];
+        assert_eq!(
+            (io.count, io.total, io.max, io.mean()),
+            (2, ms(4), ms(3), ms(2))
+        );
+        assert_eq!(snapshot.reasons[&KVM_EXIT_MMIO].count, 1);
+        assert_eq!(snapshot.reasons[&KVM_EXIT_HLT].max, ms(5));
+        // Reasons too large to be counted separately are not mixed with another one.
+        assert_eq!(snapshot.reasons[&(EXIT_REASONS as u32 - 1)].count, 1);
+        assert_eq!(snapshot.other.count, 1);
+        assert_eq!(snapshot.pio, BTreeMap::from([(0x3f8, 2)]));
+        assert_eq!(snapshot.mmio, BTreeMap::from([(0xfee0_0000, 1)]));
+        assert_eq!(ExitReasonStats::default().mean(), Duration::ZERO);
+
+        stats.reset();
+        assert_eq!(stats.snapshot(), ExitStatsSnapshot::default());
+    }
+}
@@ -0,0 +1,397 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::BTreeMap;
+
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{
+    KVM_IOAPIC_NUM_PINS, KVM_IRQ_ROUTING_HV_SINT, KVM_IRQ_ROUTING_XEN_EVTCHN, KVM_IRQCHIP_IOAPIC,
+    KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+};
+use kvm_bindings::{
+    KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI, KVM_MSI_VALID_DEVID, KvmIrqRouting,
+    kvm_irq_routing_entry,
+};
+use vmm_sys_util::errno;
+
+use crate::ioctls::Result;
+
+/// Highest GSI accepted by `KVM_SET_GSI_ROUTING`, plus one (`KVM_MAX_IRQ_ROUTES`).
+pub const MAX_GSI_ROUTES: u32 = 4096;
+
+// Number of pins of the in-kernel irqchip (`KVM_IRQCHIP_NUM_PINS`).
+#[cfg(target_arch = "aarch64")]
+const IRQCHIP_NUM_PINS: u32 = 1020 - 32;
+#[cfg(target_arch = "riscv64")]
+const IRQCHIP_NUM_PINS: u32 = 1024;
+
+// Number of synthetic interrupt sources of a Hyper-V SynIC.
+#[cfg(target_arch = "x86_64")]
+const HV_SINT_COUNT: u32 = 16;
+
+/// Destination of a GSI, as described by one `kvm_irq_routing_entry`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GsiRoute {
+    /// A pin of an in-kernel interrupt controller.
+    Irqchip {
+        /// The interrupt controller, e.g. `KVM_IRQCHIP_IOAPIC` on x86_64.
+        irqchip: u32,
+        /// The pin of the interrupt controller.
+        pin: u32,
+    },
+    /// A message signaled interrupt.
+    Msi {
+        /// The address of the message.
+        address: u64,
+        /// The payload of the message.
+        data: u32,
+        /// The ID of the device sending the message, needed by the GICv3 ITS.
+        devid: Option<u32>,
+    },
+    /// A synthetic interrupt source of a Hyper-V SynIC.
+    #[cfg(target_arch = "x86_64")]
+    HvSint {
+        /// The index of the target vCPU.
+        vcpu: u32,
+        /// The synthetic interrupt source.
+        sint: u32,
+    },
+    /// A Xen event channel port, delivered with the 2-level ABI.
+    #[cfg(target_arch = "x86_64")]
+    XenEvtchn {
+        /// The event channel port.
+        port: u32,
+        /// The Xen ID of the target vCPU.
+        vcpu: u32,
+    },
+}
+
+impl GsiRoute {
+    // Applies the checks done by KVM when it builds its routing table.
+    fn validate(&self) -> Result<()> {
+        let valid = match *self {
+            #[cfg(target_arch = "x86_64")]
+            GsiRoute::Irqchip { irqchip, pin } => match irqchip {
+                KVM_IRQCHIP_PIC_MASTER | KVM_IRQCHIP_PIC_SLAVE => pin < 8,
+                KVM_IRQCHIP_IOAPIC => pin < KVM_IOAPIC_NUM_PINS,
+                _ => false,
+            },
+            #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+            GsiRoute::Irqchip { irqchip, pin } => irqchip == 0 && pin < IRQCHIP_NUM_PINS,
+            GsiRoute::Msi { .. } => true,
+            #[cfg(target_arch = "x86_64")]
+            GsiRoute::HvSint { sint, .. } => sint < HV_SINT_COUNT,
+            #[cfg(target_arch = "x86_64")]
+            GsiRoute::XenEvtchn { .. } => true,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(errno::Error::new(libc::EINVAL))
+        }
+    }
+
+    fn to_entry(self, gsi: u32) -> kvm_irq_routing_entry {
+        let mut entry = kvm_irq_routing_entry {
+            gsi,
+            ..Default::default()
+        };
+        match self {
+            GsiRoute::Irqchip { irqchip, pin } => {
+                entry.type_ = KVM_IRQ_ROUTING_IRQCHIP;
+                entry.u.irqchip.irqchip = irqchip;
+                entry.u.irqchip.pin = pin;
+            }
+            GsiRoute::Msi {
+                address,
+                data,
+                devid,
+            } => {
+                entry.type_ = KVM_IRQ_ROUTING_MSI;
+                entry.u.msi.address_lo = address as u32;
+                entry.u.msi.address_hi = (address >> 32) as u32;
+                entry.u.msi.data = data;
+                if let Some(devid) = devid {
+                    entry.flags = KVM_MSI_VALID_DEVID;
+                    entry.u.msi.__bindgen_anon_1.devid = devid;
+                }
+            }
+            #[cfg(target_arch = "x86_64")]
+            GsiRoute::HvSint { vcpu, sint } => {
+                entry.type_ = KVM_IRQ_ROUTING_HV_SINT;
+                entry.u.hv_sint.vcpu = vcpu;
+                entry.u.hv_sint.sint = sint;
+            }
+            #[cfg(target_arch = "x86_64")]
+            GsiRoute::XenEvtchn { port, vcpu } => {
+                entry.type_ = KVM_IRQ_ROUTING_XEN_EVTCHN;
+                entry.u.xen_evtchn.port = port;
+                entry.u.xen_evtchn.vcpu = vcpu;
+            }
+        }
+        entry
+    }
+}
+
+/// GSI routing table, to be installed with `VmFd::set_gsi_routing_table`.
+///
+/// `KVM_SET_GSI_ROUTING` replaces the whole routing table of the VM, so the table
+/// keeps every route and is modified locally, then pushed in a single ioctl.
+/// Routes are checked as they are added, following the rules of KVM: a GSI can
+/// be routed to at most one pin of each interrupt controller, and a GSI routed
+/// to anything else cannot have other routes.
+///
+/// # Example
+///
+/// ```rust
+/// # use kvm_ioctls::{GsiRoute, GsiRoutingTable, Kvm};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// # #[cfg(target_arch = "x86_64")]
+/// vm.create_irq_chip().unwrap();
+///
+/// let mut table = GsiRoutingTable::new();
+/// table.add_msi(24, 0xfee0_0000, 0x4021, None).unwrap();
+/// # #[cfg(target_arch = "x86_64")]
+/// vm.set_gsi_routing_table(&table).unwrap();
+///
+/// table.update(24, GsiRoute::Msi { address: 0xfee0_1000, data: 0x4022, devid: None })
+///     .unwrap();
+/// # #[cfg(target_arch = "x86_64")]
+/// vm.set_gsi_routing_table(&table).unwrap();
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GsiRoutingTable {
+    routes: BTreeMap<u32, Vec<GsiRoute>>,
+}
+
+impl GsiRoutingTable {
+    /// Creates an empty routing table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route for `gsi`.
+    ///
+    /// Fails with `EINVAL` if `gsi` or the route are invalid, and with `EEXIST` if
+    /// the route conflicts with the routes already present for `gsi`.
+    pub fn add(&mut self, gsi: u32, route: GsiRoute) -> Result<()> {
+        if gsi >= MAX_GSI_ROUTES {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        route.validate()?;
+        let routes = self.routes.entry(gsi).or_default();
+        let conflict = routes.iter().any(|existing| match (existing, &route) {
+            (GsiRoute::Irqchip { irqchip: a, .. }, GsiRoute::Irqchip { irqchip: b, .. }) => a == b,
+            _ => true,
+        });
+        if conflict {
+            return Err(errno::Error::new(libc::EEXIST));
+        }
+        routes.push(route);
+        Ok(())
+    }
+
+    /// Routes `gsi` to `pin` of the in-kernel interrupt controller `irqchip`.
+    ///
+    /// On x86_64, a legacy IRQ is usually routed to both a PIC and the IOAPIC.
+    pub fn add_irqchip(&mut self, gsi: u32, irqchip: u32, pin: u32) -> Result<()> {
+        self.add(gsi, GsiRoute::Irqchip { irqchip, pin })
+    }
+
+    /// Routes `gsi` to an MSI with the given `address` and `data`, sent by the
+    /// device `devid` if any.
+    pub fn add_msi(&mut self, gsi: u32, address: u64, data: u32, devid: Option<u32>) -> Result<()> {
+        self.add(
+            gsi,
+            GsiRoute::Msi {
+                address,
+                data,
+                devid,
+            },
+        )
+    }
+
+    /// Routes `gsi` to the synthetic interrupt source `sint` of the SynIC of `vcpu`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn add_hv_sint(&mut self, gsi: u32, vcpu: u32, sint: u32) -> Result<()> {
+        self.add(gsi, GsiRoute::HvSint { vcpu, sint })
+    }
+
+    /// Routes `gsi` to the Xen event channel `port`, delivered to `vcpu`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn add_xen_evtchn(&mut self, gsi: u32, port: u32, vcpu: u32) -> Result<()> {
+        self.add(gsi, GsiRoute::XenEvtchn { port, vcpu })
+    }
+
+    /// Replaces the routes of `gsi` with `route`.
+    ///
+    /// The table is left unchanged if `route` is invalid.
+    pub fn update(&mut self, gsi: u32, route: GsiRoute) -> Result<()> {
+        let old = self.routes.remove(&gsi);
+        self.add(gsi, route).inspect_err(|_| {
+            if let Some(old) = old {
+                self.routes.insert(gsi, old);
+            }
+        })
+    }
+
+    /// Removes the routes of `gsi` and returns them.
+    pub fn remove(&mut self, gsi: u32) -> Vec<GsiRoute> {
+        self.routes.remove(&gsi).unwrap_or_default()
+    }
+
+    /// Returns the routes of `gsi`.
+    pub fn routes(&self, gsi: u32) -> &[GsiRoute] {
+        self.routes.get(&gsi).map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns an iterator over the routed GSIs, in increasing order.
+    pub fn gsis(&self) -> impl Iterator<Item = u32> + '_ {
+        self.routes.keys().copied()
+    }
+
+    /// Returns the number of routes in the table.
+    pub fn len(&self) -> usize {
+        self.routes.values().map(Vec::len).sum()
+    }
+
+    /// Returns whether the table has no route.
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Returns the table as a `KvmIrqRouting` for `VmFd::set_gsi_routing`.
+    ///
+    /// Fails with `E2BIG` if the table has more routes than `KvmIrqRouting` can hold.
+    pub fn to_kvm_irq_routing(&self) -> Result<KvmIrqRouting> {
+        let entries: Vec<kvm_irq_routing_entry> = self
+            .routes
+            .iter()
+            .flat_map(|(&gsi, routes)| routes.iter().map(move |route| route.to_entry(gsi)))
+            .collect();
+        KvmIrqRouting::from_entries(&entries).map_err(|_| errno::Error::new(libc::E2BIG))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gsi_routing_table() {
+        let mut table = GsiRoutingTable::new();
+        assert!(table.is_empty());
+
+        assert_eq!(
+            table
+                .add_msi(MAX_GSI_ROUTES, 0, 0, None)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        table.add_msi(5, 0xfee0_0000, 0x31, Some(7)).unwrap();
+        assert_eq!(
+            table
+                .add_msi(5, 0xfee0_0000, 0x32, None)
+                .unwrap_err()
+                .errno(),
+            libc::EEXIST
+        );
+        assert_eq!(
+            table.add_irqchip(5, 0, 5).unwrap_err().errno(),
+            libc::EEXIST
+        );
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            table.add_irqchip(4, KVM_IRQCHIP_PIC_MASTER, 4).unwrap();
+            table.add_irqchip(4, KVM_IRQCHIP_IOAPIC, 4).unwrap();
+            assert_eq!(
+                table
+                    .add_irqchip(4, KVM_IRQCHIP_IOAPIC, 5)
+                    .unwrap_err()
+                    .errno(),
+                libc::EEXIST
+            );
+            assert_eq!(
+                table
+                    .add_irqchip(9, KVM_IRQCHIP_PIC_SLAVE, 8)
+                    .unwrap_err()
+                    .errno(),
+                libc::EINVAL
+            );
+            assert_eq!(
+                table.add_hv_sint(10, 0, 16).unwrap_err().errno(),
+                libc::EINVAL
+            );
+            table.add_hv_sint(10, 1, 2).unwrap();
+            assert_eq!(table.routes(4).len(), 2);
+            assert_eq!(table.gsis().collect::<Vec<_>>(), [4, 5, 10]);
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            table.add_irqchip(4, 0, 4).unwrap();
+            assert_eq!(
+                table.add_irqchip(9, 1, 0).unwrap_err().errno(),
+                libc::EINVAL
+            );
+        }
+
+        // A failed update leaves the routes in place.
+        let old = table.routes(4).to_vec();
+        let bad = GsiRoute::Irqchip { irqchip: 7, pin: 0 };
+        assert_eq!(table.update(4, bad).unwrap_err().errno(), libc::EINVAL);
+        assert_eq!(table.routes(4), old);
+
+        let msi = GsiRoute::Msi {
+            address: 0x1_fee0_1000,
+            data: 0x41,
+            devid: None,
+        };
+        table.update(4, msi).unwrap();
+        assert_eq!(table.routes(4), [msi]);
+        assert_eq!(table.remove(4), [msi]);
+        assert!(table.remove(4).is_empty());
+
+        let routing = table.to_kvm_irq_routing().unwrap();
+        assert_eq!(routing.as_slice().len(), table.len());
+        let entry = &routing.as_slice()[0];
+        assert_eq!(entry.gsi, 5);
+        assert_eq!(entry.type_, KVM_IRQ_ROUTING_MSI);
+        assert_eq!(entry.flags, KVM_MSI_VALID_DEVID);
+        // SAFETY: The entry holds an MSI route.
+        unsafe {
+            assert_eq!(entry.u.msi.address_lo, 0xfee0_0000);
+            assert_eq!(entry.u.msi.data, 0x31);
+            assert_eq!(entry.u.msi.__bindgen_anon_1.devid, 7);
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_set_gsi_routing_table() {
+        let kvm = crate::Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+
+        let mut table = GsiRoutingTable::new();
+        for irq in 0..16 {
+            if irq != 2 {
+                table
+                    .add_irqchip(irq, KVM_IRQCHIP_PIC_MASTER + irq / 8, irq % 8)
+                    .unwrap();
+            }
+            table.add_irqchip(irq, KVM_IRQCHIP_IOAPIC, irq).unwrap();
+        }
+        table.add_msi(24, 0xfee0_0000, 0x4021, None).unwrap();
+        vm.set_gsi_routing_table(&table).unwrap();
+
+        let msi = GsiRoute::Msi {
+            address: 0xfee0_0000,
+            data: 0x4022,
+            devid: None,
+        };
+        table.update(24, msi).unwrap();
+        vm.set_gsi_routing_table(&table).unwrap();
+    }
+}
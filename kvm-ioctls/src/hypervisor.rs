@@ -0,0 +1,315 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use std::os::raw::c_int;
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use kvm_bindings::kvm_msi;
+#[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+use kvm_bindings::kvm_regs;
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::kvm_sregs;
+use kvm_bindings::kvm_userspace_memory_region;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::cap::Cap;
+use crate::ioctls::Result;
+use crate::ioctls::system::Kvm;
+use crate::ioctls::vcpu::{VcpuExit, VcpuFd};
+use crate::ioctls::vm::{IoEventAddress, VmFd};
+
+/// The operations of a hypervisor on which VMs are created.
+///
+/// This is implemented by [`Kvm`], and by the fake `mock::MockKvm` when the
+/// `mock` feature is enabled, so that code written against this trait can be
+/// unit tested without `/dev/kvm`.
+///
+/// # Example
+///
+/// ```rust
+/// use kvm_ioctls::{Cap, Hypervisor, Kvm, Vm};
+///
+/// fn create_vm<H: Hypervisor>(hypervisor: &H) -> H::Vm {
+///     assert!(hypervisor.check_extension(Cap::UserMemory));
+///     let vm = hypervisor.create_vm().unwrap();
+///     vm.create_vcpu(0).unwrap();
+///     vm
+/// }
+///
+/// let vm = create_vm(&Kvm::new().unwrap());
+/// ```
+pub trait Hypervisor {
+    /// The type of the VMs created by the hypervisor.
+    type Vm: Vm;
+
+    /// Returns whether the capability `c` is supported, as done by
+    /// `Kvm::check_extension`.
+    fn check_extension(&self, c: Cap) -> bool;
+
+    /// Returns the recommended maximum number of vCPUs of a VM.
+    fn get_nr_vcpus(&self) -> usize;
+
+    /// Returns the maximum number of memory slots of a VM.
+    fn get_nr_memslots(&self) -> usize;
+
+    /// Creates a VM, as done by `Kvm::create_vm`.
+    fn create_vm(&self) -> Result<Self::Vm>;
+}
+
+/// The operations of a VM used by a VMM to set up its memory, interrupts and
+/// vCPUs.
+///
+/// This is implemented by [`VmFd`], and by the fake `mock::MockVm` when the
+/// `mock` feature is enabled.
+pub trait Vm {
+    /// The type of the vCPUs of the VM.
+    type Vcpu: Vcpu;
+
+    /// Returns whether the capability `c` is supported by the VM, as done by
+    /// `VmFd::check_extension`.
+    fn check_extension(&self, c: Cap) -> bool;
+
+    /// Creates the vCPU `id`, as done by `VmFd::create_vcpu`.
+    fn create_vcpu(&self, id: u64) -> Result<Self::Vcpu>;
+
+    /// Creates, modifies or deletes a memory slot, as done by
+    /// `VmFd::set_user_memory_region`.
+    ///
+    /// # Safety
+    ///
+    /// The memory described by `user_memory_region` must stay valid while it is
+    /// mapped in the guest.
+    unsafe fn set_user_memory_region(
+        &self,
+        user_memory_region: kvm_userspace_memory_region,
+    ) -> Result<()>;
+
+    /// Registers `fd` to trigger the `gsi` IRQ, as done by `VmFd::register_irqfd`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()>;
+
+    /// Unregisters an irqfd, as done by `VmFd::unregister_irqfd`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()>;
+
+    /// Registers `fd` to be signaled on writes of `datamatch` to `addr`, as done by
+    /// `VmFd::register_ioevent`.
+    fn register_ioevent<T: Into<u64>>(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: T,
+    ) -> Result<()>;
+
+    /// Unregisters an ioeventfd, as done by `VmFd::unregister_ioevent`.
+    fn unregister_ioevent<T: Into<u64>>(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: T,
+    ) -> Result<()>;
+
+    /// Injects an MSI, as done by `VmFd::signal_msi`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn signal_msi(&self, msi: kvm_msi) -> Result<c_int>;
+
+    /// Sets the level of the `irq` line, as done by `VmFd::set_irq_line`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn set_irq_line(&self, irq: u32, active: bool) -> Result<()>;
+}
+
+/// The operations of a vCPU used by a VMM to set up and run it.
+///
+/// This is implemented by [`VcpuFd`], and by the fake `mock::MockVcpu` when the
+/// `mock` feature is enabled.
+pub trait Vcpu {
+    /// Runs the vCPU until its next exit, as done by `VcpuFd::run`.
+    fn run(&mut self) -> Result<VcpuExit<'_>>;
+
+    /// Sets the `immediate_exit` flag of the vCPU, as done by
+    /// `VcpuFd::set_kvm_immediate_exit`.
+    fn set_kvm_immediate_exit(&mut self, val: u8);
+
+    /// Returns the general purpose registers, as done by `VcpuFd::get_regs`.
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+    fn get_regs(&self) -> Result<kvm_regs>;
+
+    /// Sets the general purpose registers, as done by `VcpuFd::set_regs`.
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+    fn set_regs(&self, regs: &kvm_regs) -> Result<()>;
+
+    /// Returns the special registers, as done by `VcpuFd::get_sregs`.
+    #[cfg(target_arch = "x86_64")]
+    fn get_sregs(&self) -> Result<kvm_sregs>;
+
+    /// Sets the special registers, as done by `VcpuFd::set_sregs`.
+    #[cfg(target_arch = "x86_64")]
+    fn set_sregs(&self, sregs: &kvm_sregs) -> Result<()>;
+
+    /// Reads the register `reg_id` into `data`, as done by `VcpuFd::get_one_reg`.
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    fn get_one_reg(&self, reg_id: u64, data: &mut [u8]) -> Result<usize>;
+
+    /// Writes `data` to the register `reg_id`, as done by `VcpuFd::set_one_reg`.
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    fn set_one_reg(&self, reg_id: u64, data: &[u8]) -> Result<usize>;
+}
+
+impl Hypervisor for Kvm {
+    type Vm = VmFd;
+
+    fn check_extension(&self, c: Cap) -> bool {
+        Kvm::check_extension(self, c)
+    }
+
+    fn get_nr_vcpus(&self) -> usize {
+        Kvm::get_nr_vcpus(self)
+    }
+
+    fn get_nr_memslots(&self) -> usize {
+        Kvm::get_nr_memslots(self)
+    }
+
+    fn create_vm(&self) -> Result<VmFd> {
+        Kvm::create_vm(self)
+    }
+}
+
+impl Vm for VmFd {
+    type Vcpu = VcpuFd;
+
+    fn check_extension(&self, c: Cap) -> bool {
+        VmFd::check_extension(self, c)
+    }
+
+    fn create_vcpu(&self, id: u64) -> Result<VcpuFd> {
+        VmFd::create_vcpu(self, id)
+    }
+
+    unsafe fn set_user_memory_region(
+        &self,
+        user_memory_region: kvm_userspace_memory_region,
+    ) -> Result<()> {
+        // SAFETY: The caller upholds the requirements of the trait method, which are
+        // the same as the ones of `VmFd::set_user_memory_region`.
+        unsafe { VmFd::set_user_memory_region(self, user_memory_region) }
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        VmFd::register_irqfd(self, fd, gsi)
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        VmFd::unregister_irqfd(self, fd, gsi)
+    }
+
+    fn register_ioevent<T: Into<u64>>(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: T,
+    ) -> Result<()> {
+        VmFd::register_ioevent(self, fd, addr, datamatch)
+    }
+
+    fn unregister_ioevent<T: Into<u64>>(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: T,
+    ) -> Result<()> {
+        VmFd::unregister_ioevent(self, fd, addr, datamatch)
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn signal_msi(&self, msi: kvm_msi) -> Result<c_int> {
+        VmFd::signal_msi(self, msi)
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn set_irq_line(&self, irq: u32, active: bool) -> Result<()> {
+        VmFd::set_irq_line(self, irq, active)
+    }
+}
+
+impl Vcpu for VcpuFd {
+    fn run(&mut self) -> Result<VcpuExit<'_>> {
+        VcpuFd::run(self)
+    }
+
+    fn set_kvm_immediate_exit(&mut self, val: u8) {
+        VcpuFd::set_kvm_immediate_exit(self, val)
+    }
+
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+    fn get_regs(&self) -> Result<kvm_regs> {
+        VcpuFd::get_regs(self)
+    }
+
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+    fn set_regs(&self, regs: &kvm_regs) -> Result<()> {
+        VcpuFd::set_regs(self, regs)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_sregs(&self) -> Result<kvm_sregs> {
+        VcpuFd::get_sregs(self)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_sregs(&self, sregs: &kvm_sregs) -> Result<()> {
+        VcpuFd::set_sregs(self, sregs)
+    }
+
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    fn get_one_reg(&self, reg_id: u64, data: &mut [u8]) -> Result<usize> {
+        VcpuFd::get_one_reg(self, reg_id, data)
+    }
+
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    fn set_one_reg(&self, reg_id: u64, data: &[u8]) -> Result<usize> {
+        VcpuFd::set_one_reg(self, reg_id, data)
+    }
+}
@@ -8,6 +8,7 @@
 use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::ptr::{NonNull, null_mut};
+use std::sync::atomic::AtomicU8;
 
 use kvm_bindings::{
     KVM_COALESCED_MMIO_PAGE_OFFSET, kvm_coalesced_mmio, kvm_coalesced_mmio_ring, kvm_run,
@@ -84,6 +85,13 @@ impl KvmCoalescedIoRing {
         unsafe { self.addr.as_mut() }
     }
 
+    /// Returns the number of entries in the MMIO ring.
+    pub(crate) fn len(&mut self) -> usize {
+        let ring_max = self.ring_max() as u32;
+        let ring = self.ring_mut();
+        ((ring.last + ring_max - ring.first) % ring_max) as usize
+    }
+
     /// Reads a single entry from the MMIO ring.
     ///
     /// # Returns
@@ -179,6 +187,18 @@ impl KvmRunWrapper {
         // be aliased.
         unsafe { self.kvm_run_ptr.as_mut() }
     }
+
+    /// Returns a pointer to `kvm_run`, valid for the whole mapping.
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut kvm_run {
+        self.kvm_run_ptr.as_ptr()
+    }
+
+    /// Returns the `immediate_exit` flag of `kvm_run`, which can be set from any thread.
+    pub(crate) fn immediate_exit(&self) -> &AtomicU8 {
+        // SAFETY: The flag is a `u8` in the memory we mapped, which lives as long as `self`, and
+        // `AtomicU8` has the same size and alignment as `u8`.
+        unsafe { AtomicU8::from_ptr(&raw mut (*self.kvm_run_ptr.as_ptr()).immediate_exit) }
+    }
 }
 
 impl AsRef<kvm_run> for KvmRunWrapper {
@@ -14,18 +14,36 @@ pub use kvm_bindings::nested::KvmNestedStateBuffer;
 use kvm_bindings::*;
 use libc::EINVAL;
 use std::fs::File;
+use std::os::raw::c_int;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Instant;
 
+use crate::coalesced::CoalescedIoDrain;
+use crate::error::CheckedVcpuFd;
+use crate::exit_stats::{ExitEvent, ExitObserver};
 use crate::ioctls::{KvmCoalescedIoRing, KvmRunWrapper, Result};
+use crate::kicker::VcpuKicker;
 use crate::kvm_ioctls::*;
+#[cfg(target_arch = "x86_64")]
+use crate::msr::MsrError;
+#[cfg(target_arch = "riscv64")]
+use crate::riscv_reg::{RiscvReg, isa_ext_id, isa_ext_name, sbi_ext_id, sbi_ext_name};
 use vmm_sys_util::errno;
-use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref};
 #[cfg(target_arch = "x86_64")]
 use {
     std::num::NonZeroUsize,
-    vmm_sys_util::ioctl::{ioctl_with_mut_ptr, ioctl_with_ptr, ioctl_with_val},
+    vmm_sys_util::ioctl::{ioctl_with_mut_ptr, ioctl_with_val},
 };
 
+/// The number of MSRs accessed by each `KVM_{GET,SET}_MSRS` call of
+/// [`VcpuFd::get_all_msrs`] and [`VcpuFd::set_all_msrs`]. KVM rejects batches of
+/// `KVM_MAX_MSR_ENTRIES` or more MSRs with `E2BIG`.
+#[cfg(target_arch = "x86_64")]
+const MSRS_PER_BATCH: usize = KVM_MAX_MSR_ENTRIES - 1;
+
 /// Helper method to obtain the size of the register through its id
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 pub fn reg_size(reg_id: u64) -> usize {
@@ -94,6 +112,24 @@ bitflags::bitflags! {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+bitflags::bitflags! {
+    /// The `flags` of the `kvm_run` structure, which describe the state of the vCPU
+    /// at its last exit. See [`VcpuFd::run_flags`].
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct RunFlags: u16 {
+        /// Corresponds to [`KVM_RUN_X86_SMM`]. The vCPU is in system management
+        /// mode.
+        const Smm = KVM_RUN_X86_SMM as u16;
+        /// Corresponds to [`KVM_RUN_X86_BUS_LOCK`]. The guest triggered a bus lock,
+        /// which is reported when `KVM_CAP_X86_BUS_LOCK_EXIT` is enabled.
+        const BusLock = KVM_RUN_X86_BUS_LOCK as u16;
+        /// Corresponds to [`KVM_RUN_X86_GUEST_MODE`]. The vCPU was running a nested
+        /// guest, which is reported when `KVM_CAP_X86_GUEST_MODE` is supported.
+        const GuestMode = KVM_RUN_X86_GUEST_MODE as u16;
+    }
+}
+
 /// Reasons for vCPU exits.
 ///
 /// The exit reasons are mapped to the `KVM_EXIT_*` defines in the
@@ -135,7 +171,8 @@ pub enum VcpuExit<'a> {
         u64, /* hardware_entry_failure_reason */
         u32, /* cpu */
     ),
-    /// Corresponds to KVM_EXIT_INTR.
+    /// Corresponds to KVM_EXIT_INTR, or to `KVM_RUN` failing with `EINTR` because
+    /// of a signal or of `immediate_exit`, e.g. after a [`VcpuKicker::kick`].
     Intr,
     /// Corresponds to KVM_EXIT_SET_TPR.
     SetTpr,
@@ -197,6 +234,8 @@ pub struct VcpuFd {
     kvm_run_ptr: KvmRunWrapper,
     /// A pointer to the coalesced MMIO page
     coalesced_mmio_ring: Option<KvmCoalescedIoRing>,
+    /// Called after each `KVM_RUN`
+    exit_observer: Option<Arc<dyn ExitObserver>>,
 }
 
 /// KVM Sync Registers used to tell KVM which registers to sync
@@ -744,6 +783,88 @@ impl VcpuFd {
         Ok(ret as usize)
     }
 
+    /// Reads the MSRs with the given indices, in as many `KVM_GET_MSRS` calls as
+    /// needed.
+    ///
+    /// Unlike [`get_msrs`](Self::get_msrs), a short read is an error, which reports
+    /// the index of the MSR that KVM could not read.
+    ///
+    /// # Arguments
+    ///
+    /// * `indices` - Indices of the MSRs to read, for example the output of
+    ///   [`msr::msrs_to_save`](crate::msr::msrs_to_save).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::{Kvm, msr};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// // Some paravirtual MSRs can only be written with an in-kernel local APIC.
+    /// vm.create_irq_chip().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    ///
+    /// let supported = kvm.get_msr_index_list().unwrap();
+    /// let msrs = vcpu
+    ///     .get_all_msrs(&msr::msrs_to_save(supported.as_slice()))
+    ///     .unwrap();
+    /// vcpu.set_all_msrs(&msrs).unwrap();
+    /// ```
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_all_msrs(
+        &self,
+        indices: &[u32],
+    ) -> std::result::Result<Vec<kvm_msr_entry>, MsrError> {
+        let mut entries = Vec::with_capacity(indices.len());
+        for chunk in indices.chunks(MSRS_PER_BATCH) {
+            let chunk: Vec<_> = chunk
+                .iter()
+                .map(|&index| kvm_msr_entry {
+                    index,
+                    ..Default::default()
+                })
+                .collect();
+            let mut msrs =
+                Msrs::from_entries(&chunk).map_err(|_| errno::Error::new(libc::ENOMEM))?;
+            let read = self.get_msrs(&mut msrs)?;
+            entries.extend_from_slice(&msrs.as_slice()[..read]);
+            if read < chunk.len() {
+                return Err(MsrError::Failed {
+                    index: chunk[read].index,
+                    done: entries.len(),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Writes the given MSRs, in as many `KVM_SET_MSRS` calls as needed.
+    ///
+    /// Unlike [`set_msrs`](Self::set_msrs), a short write is an error, which reports
+    /// the index of the MSR that KVM could not write. The MSRs before it are
+    /// written anyway.
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - The MSRs to write, for example the output of
+    ///   [`get_all_msrs`](Self::get_all_msrs).
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_all_msrs(&self, entries: &[kvm_msr_entry]) -> std::result::Result<(), MsrError> {
+        let mut done = 0;
+        for chunk in entries.chunks(MSRS_PER_BATCH) {
+            let msrs = Msrs::from_entries(chunk).map_err(|_| errno::Error::new(libc::ENOMEM))?;
+            let written = self.set_msrs(&msrs)?;
+            done += written;
+            if written < chunk.len() {
+                return Err(MsrError::Failed {
+                    index: chunk[written].index,
+                    done,
+                });
+            }
+        }
+        Ok(())
+    }
+
     /// Returns the vcpu's current "multiprocessing state".
     ///
     /// See the documentation for `KVM_GET_MP_STATE` in the
@@ -1421,6 +1542,180 @@ impl VcpuFd {
         Ok(reg_size)
     }
 
+    /// Returns the ids of all the registers reported by `KVM_GET_REG_LIST`.
+    ///
+    /// The list is grown as needed, so the caller does not need to know the number
+    /// of registers beforehand.
+    #[cfg(target_arch = "riscv64")]
+    fn reg_ids(&self) -> Result<Vec<u64>> {
+        let mut reg_list = RegList::new(0).map_err(|_| errno::Error::new(libc::ENOMEM))?;
+        loop {
+            match self.get_reg_list(&mut reg_list) {
+                Ok(()) => return Ok(reg_list.as_slice().to_vec()),
+                Err(err) if err.errno() == libc::E2BIG => {
+                    // On E2BIG the kernel reports the number of registers in `n`.
+                    let n = reg_list.as_fam_struct_ref().n as usize;
+                    reg_list = RegList::new(n).map_err(|_| errno::Error::new(libc::ENOMEM))?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Reads a single `unsigned long` sized register, used by the riscv64 extension helpers.
+    #[cfg(target_arch = "riscv64")]
+    fn get_ulong_reg(&self, reg: RiscvReg) -> Result<u64> {
+        let mut data = [0u8; 8];
+        self.get_one_reg(reg.id(), &mut data)?;
+        Ok(u64::from_le_bytes(data))
+    }
+
+    /// Returns the names of the ISA extensions that can be queried and configured on this vCPU.
+    ///
+    /// The list is built from the `KVM_REG_RISCV_ISA_EXT` registers reported by
+    /// [`get_reg_list`](VcpuFd::get_reg_list). Extensions that are not known to this crate
+    /// are left out of the list.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    ///
+    /// # #[cfg(target_arch = "riscv64")]
+    /// # {
+    /// let exts = vcpu.get_isa_ext_list().unwrap();
+    /// assert!(exts.contains(&"i"));
+    /// # }
+    /// ```
+    #[cfg(target_arch = "riscv64")]
+    pub fn get_isa_ext_list(&self) -> Result<Vec<&'static str>> {
+        Ok(self
+            .reg_ids()?
+            .into_iter()
+            .filter_map(|id| RiscvReg::from_id(id).as_isa_ext())
+            .filter_map(isa_ext_name)
+            .collect())
+    }
+
+    /// Returns whether the ISA extension called `name` is enabled on this vCPU.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Lowercase name of the extension, e.g. `"zicbom"`. Returns `EINVAL` if the
+    ///   extension is not known to this crate.
+    #[cfg(target_arch = "riscv64")]
+    pub fn isa_ext_enabled(&self, name: &str) -> Result<bool> {
+        let ext = isa_ext_id(name).ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+        Ok(self.get_ulong_reg(RiscvReg::isa_ext(ext))? != 0)
+    }
+
+    /// Enables the ISA extension called `name` on this vCPU.
+    ///
+    /// ISA extensions can only be changed before the vCPU runs for the first time.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Lowercase name of the extension, e.g. `"zicbom"`. Returns `EINVAL` if the
+    ///   extension is not known to this crate.
+    #[cfg(target_arch = "riscv64")]
+    pub fn enable_isa_ext(&self, name: &str) -> Result<()> {
+        let ext = isa_ext_id(name).ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+        self.set_one_reg(RiscvReg::isa_ext(ext).id(), &1u64.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Disables the ISA extension called `name` on this vCPU.
+    ///
+    /// ISA extensions can only be changed before the vCPU runs for the first time.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Lowercase name of the extension, e.g. `"zicbom"`. Returns `EINVAL` if the
+    ///   extension is not known to this crate.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    ///
+    /// # #[cfg(target_arch = "riscv64")]
+    /// # {
+    /// if vcpu.get_isa_ext_list().unwrap().contains(&"sstc") {
+    ///     vcpu.disable_isa_ext("sstc").unwrap();
+    ///     assert!(!vcpu.isa_ext_enabled("sstc").unwrap());
+    /// }
+    /// # }
+    /// ```
+    #[cfg(target_arch = "riscv64")]
+    pub fn disable_isa_ext(&self, name: &str) -> Result<()> {
+        let ext = isa_ext_id(name).ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+        self.set_one_reg(RiscvReg::isa_ext(ext).id(), &0u64.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Returns the names of the SBI extensions that can be queried and configured on this vCPU.
+    ///
+    /// The list is built from the `KVM_REG_RISCV_SBI_EXT` registers reported by
+    /// [`get_reg_list`](VcpuFd::get_reg_list). Extensions that are not known to this crate
+    /// are left out of the list.
+    #[cfg(target_arch = "riscv64")]
+    pub fn get_sbi_ext_list(&self) -> Result<Vec<&'static str>> {
+        Ok(self
+            .reg_ids()?
+            .into_iter()
+            .filter_map(|id| RiscvReg::from_id(id).as_sbi_ext())
+            .filter_map(sbi_ext_name)
+            .collect())
+    }
+
+    /// Returns whether the SBI extension called `name` is enabled on this vCPU.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Lowercase name of the extension, e.g. `"hsm"`. Returns `EINVAL` if the
+    ///   extension is not known to this crate.
+    #[cfg(target_arch = "riscv64")]
+    pub fn sbi_ext_enabled(&self, name: &str) -> Result<bool> {
+        let ext = sbi_ext_id(name).ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+        Ok(self.get_ulong_reg(RiscvReg::sbi_ext(ext))? != 0)
+    }
+
+    /// Enables the SBI extension called `name` on this vCPU.
+    ///
+    /// SBI extensions can only be changed before the vCPU runs for the first time.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Lowercase name of the extension, e.g. `"hsm"`. Returns `EINVAL` if the
+    ///   extension is not known to this crate.
+    #[cfg(target_arch = "riscv64")]
+    pub fn enable_sbi_ext(&self, name: &str) -> Result<()> {
+        let ext = sbi_ext_id(name).ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+        self.set_one_reg(RiscvReg::sbi_ext(ext).id(), &1u64.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Disables the SBI extension called `name` on this vCPU.
+    ///
+    /// SBI extensions can only be changed before the vCPU runs for the first time.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Lowercase name of the extension, e.g. `"hsm"`. Returns `EINVAL` if the
+    ///   extension is not known to this crate.
+    #[cfg(target_arch = "riscv64")]
+    pub fn disable_sbi_ext(&self, name: &str) -> Result<()> {
+        let ext = sbi_ext_id(name).ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+        self.set_one_reg(RiscvReg::sbi_ext(ext).id(), &0u64.to_le_bytes())?;
+        Ok(())
+    }
+
     /// Notify the guest about the vCPU being paused.
     ///
     /// See the documentation for `KVM_KVMCLOCK_CTRL` in the
@@ -1513,28 +1808,112 @@ impl VcpuFd {
     /// # }
     /// ```
     pub fn run(&mut self) -> Result<VcpuExit<'_>> {
+        let (ret, errno) = self.run_raw();
+        self.decode_exit(ret, errno)
+    }
+
+    /// Calls `KVM_RUN`, and returns its return value and the `errno` it set, to be
+    /// passed to [`decode_exit`](Self::decode_exit).
+    ///
+    /// The exit observer, if any, is called before returning.
+    pub(crate) fn run_raw(&mut self) -> (c_int, errno::Error) {
+        let start = self.exit_observer.is_some().then(Instant::now);
         // SAFETY: Safe because we know that our file is a vCPU fd and we verify the return result.
         let ret = unsafe { ioctl(self, KVM_RUN()) };
+        let errno = errno::Error::last();
+        if let (Some(observer), Some(start)) = (&self.exit_observer, start) {
+            let elapsed = start.elapsed();
+            observer.on_exit(
+                ExitEvent::new(self.kvm_run_ptr.as_ref(), ret, errno),
+                elapsed,
+            );
+        }
+        (ret, errno)
+    }
+
+    /// Sets the observer called after each `KVM_RUN`, with the exit and the time
+    /// spent in `KVM_RUN`, or removes it with `None`.
+    ///
+    /// The time is only measured while an observer is set.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::{ExitStats, Kvm};
+    /// use std::sync::Arc;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mut vcpu = vm.create_vcpu(0).unwrap();
+    /// let stats = Arc::new(ExitStats::new());
+    /// vcpu.set_exit_observer(Some(stats.clone()));
+    ///
+    /// vcpu.set_kvm_immediate_exit(1);
+    /// vcpu.run().unwrap();
+    /// assert_eq!(stats.snapshot().total(), 1);
+    /// ```
+    pub fn set_exit_observer(&mut self, observer: Option<Arc<dyn ExitObserver>>) {
+        self.exit_observer = observer;
+    }
+
+    /// Decodes the exit of the last `KVM_RUN`, which returned `ret` and set `errno`.
+    ///
+    /// This only reads `kvm_run`, and can be called again to get the same exit.
+    pub(crate) fn decode_exit(&mut self, ret: c_int, errno: errno::Error) -> Result<VcpuExit<'_>> {
+        // SAFETY: `kvm_run` is at the start of the mapping of the vCPU, which holds the I/O
+        // data, and the exit borrows `self`.
+        unsafe { Self::decode_run(self.kvm_run_mut_ptr(), ret, errno) }
+    }
+
+    /// Returns a pointer to `kvm_run`, to be passed to [`decode_run`](Self::decode_run).
+    /// The pointer covers the whole mapping of the vCPU, including the I/O data.
+    pub(crate) fn kvm_run_mut_ptr(&mut self) -> *mut kvm_run {
+        self.kvm_run_ptr.as_mut_ptr()
+    }
+
+    /// Decodes the exit in `run_ptr` of a `KVM_RUN` that returned `ret` and set `errno`.
+    ///
+    /// # Safety
+    ///
+    /// `run_ptr` must point to a `kvm_run` that is not accessed by other means for `'a`. For
+    /// a `KVM_EXIT_IO` exit, the `io.count * io.size` bytes at `io.data_offset` from it must be
+    /// part of the same buffer.
+    pub(crate) unsafe fn decode_run<'a>(
+        run_ptr: *mut kvm_run,
+        ret: c_int,
+        errno: errno::Error,
+    ) -> Result<VcpuExit<'a>> {
+        if ret != 0 && errno.errno() == libc::EINTR {
+            // Clear the flag so that the next run enters the guest. It is written atomically by
+            // `VcpuKicker::kick` from other threads. A kick that lands before this store is
+            // reported by this exit, and a later one by the next run.
+            // SAFETY: The caller guarantees that the pointer is valid, and `AtomicU8` has the
+            // same size and alignment as `u8`.
+            unsafe { AtomicU8::from_ptr(&raw mut (*run_ptr).immediate_exit) }
+                .store(0, Ordering::SeqCst);
+            return Ok(VcpuExit::Intr);
+        }
+        // SAFETY: The caller guarantees that the pointer is valid and not aliased.
+        let run = unsafe { &mut *run_ptr };
         if ret == 0 {
-            let run = self.kvm_run_ptr.as_mut_ref();
             match run.exit_reason {
                 // make sure you treat all possible exit reasons from include/uapi/linux/kvm.h corresponding
                 // when upgrading to a different kernel version
                 KVM_EXIT_UNKNOWN => Ok(VcpuExit::Unknown),
                 KVM_EXIT_EXCEPTION => Ok(VcpuExit::Exception),
                 KVM_EXIT_IO => {
-                    let run_start = run as *mut kvm_run as *mut u8;
+                    let run_start = run_ptr.cast::<u8>();
                     // SAFETY: Safe because the exit_reason (which comes from the kernel) told us
                     // which union field to use.
                     let io = unsafe { run.__bindgen_anon_1.io };
                     let port = io.port;
                     let data_size = io.count as usize * io.size as usize;
                     // SAFETY: The data_offset is defined by the kernel to be some number of bytes
-                    // into the kvm_run stucture, which we have fully mmap'd.
+                    // into the kvm_run stucture, which the caller guarantees to be in the buffer.
                     let data_ptr = unsafe { run_start.offset(io.data_offset as isize) };
                     let data_slice =
-                        // SAFETY: The slice's lifetime is limited to the lifetime of this vCPU, which is equal
-                        // to the mmap of the `kvm_run` struct that this is slicing from.
+                        // SAFETY: The slice's lifetime is limited to `'a`, for which the caller
+                        // guarantees that the buffer of the `kvm_run` struct is not otherwise used.
                         unsafe { std::slice::from_raw_parts_mut::<u8>(data_ptr, data_size) };
                     match u32::from(io.direction) {
                         KVM_EXIT_IO_IN => Ok(VcpuExit::IoIn(port, data_slice)),
@@ -1644,8 +2023,6 @@ impl VcpuFd {
                 r => Ok(VcpuExit::Unsupported(r)),
             }
         } else {
-            let errno = errno::Error::last();
-            let run = self.kvm_run_ptr.as_mut_ref();
             // From https://docs.kernel.org/virt/kvm/api.html#kvm-run :
             //
             // KVM_EXIT_MEMORY_FAULT is unique among all KVM exit reasons in that it accompanies
@@ -1671,6 +2048,12 @@ impl VcpuFd {
         }
     }
 
+    /// Returns a view over the vCPU whose wrappers return an
+    /// [`IoctlError`](crate::IoctlError) naming the ioctl that failed.
+    pub fn checked(&self) -> CheckedVcpuFd<'_> {
+        CheckedVcpuFd(self)
+    }
+
     /// Returns a mutable reference to the kvm_run structure
     pub fn get_kvm_run(&mut self) -> &mut kvm_run {
         self.kvm_run_ptr.as_mut_ref()
@@ -1678,8 +2061,174 @@ impl VcpuFd {
 
     /// Sets the `immediate_exit` flag on the `kvm_run` struct associated with this vCPU to `val`.
     pub fn set_kvm_immediate_exit(&mut self, val: u8) {
-        let kvm_run = self.kvm_run_ptr.as_mut_ref();
-        kvm_run.immediate_exit = val;
+        self.kvm_run_ptr
+            .immediate_exit()
+            .store(val, Ordering::SeqCst);
+    }
+
+    /// Requests a `VcpuExit::IrqWindowOpen` exit as soon as the guest can accept
+    /// an interrupt, or cancels the request.
+    ///
+    /// This is used when interrupts are injected with `KVM_INTERRUPT`, either by a
+    /// PIC emulated in userspace or, with a split irqchip, for `ExtINT` interrupts.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mut vcpu = vm.create_vcpu(0).unwrap();
+    /// if !vcpu.ready_for_interrupt_injection() {
+    ///     vcpu.request_interrupt_window(true);
+    /// }
+    /// ```
+    #[cfg(target_arch = "x86_64")]
+    pub fn request_interrupt_window(&mut self, request: bool) {
+        self.kvm_run_ptr.as_mut_ref().request_interrupt_window = u8::from(request);
+    }
+
+    /// Returns whether an interrupt can be injected with `KVM_INTERRUPT` before
+    /// the next run.
+    ///
+    /// This is only updated by `KVM_RUN`, and only meaningful without an in-kernel
+    /// local APIC, or with a split irqchip.
+    #[cfg(target_arch = "x86_64")]
+    pub fn ready_for_interrupt_injection(&self) -> bool {
+        self.kvm_run_ptr.as_ref().ready_for_interrupt_injection != 0
+    }
+
+    /// Returns the interrupt flag (`RFLAGS.IF`) of the vCPU at its last exit.
+    ///
+    /// This is only valid without an in-kernel irqchip.
+    #[cfg(target_arch = "x86_64")]
+    pub fn if_flag(&self) -> bool {
+        self.kvm_run_ptr.as_ref().if_flag != 0
+    }
+
+    /// Returns the value of `CR8`, the task priority register, at the last exit.
+    ///
+    /// This is only valid without an in-kernel local APIC.
+    #[cfg(target_arch = "x86_64")]
+    pub fn cr8(&self) -> u64 {
+        self.kvm_run_ptr.as_ref().cr8
+    }
+
+    /// Sets the value of `CR8` loaded on the next run.
+    ///
+    /// This is only used by KVM without an in-kernel local APIC.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_cr8(&mut self, cr8: u64) {
+        self.kvm_run_ptr.as_mut_ref().cr8 = cr8;
+    }
+
+    /// Returns the value of the `IA32_APIC_BASE` MSR at the last exit.
+    ///
+    /// This is only valid without an in-kernel local APIC.
+    #[cfg(target_arch = "x86_64")]
+    pub fn apic_base(&self) -> u64 {
+        self.kvm_run_ptr.as_ref().apic_base
+    }
+
+    /// Returns the [`RunFlags`] describing the state of the vCPU at its last exit.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::{Kvm, RunFlags};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// assert!(!vcpu.run_flags().contains(RunFlags::Smm));
+    /// ```
+    #[cfg(target_arch = "x86_64")]
+    pub fn run_flags(&self) -> RunFlags {
+        RunFlags::from_bits_truncate(self.kvm_run_ptr.as_ref().flags)
+    }
+
+    /// Returns a [`VcpuKicker`] that makes this vCPU exit to userspace from any
+    /// thread, by setting `immediate_exit` and sending the signal `signum`.
+    ///
+    /// This must be called from the thread that runs the vCPU, where `signum` must
+    /// not be blocked while in `KVM_RUN`. The signal needs a handler, which can be
+    /// installed with [`VcpuKicker::install_signal_handler`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::{Kvm, VcpuExit, VcpuKicker};
+    /// use vmm_sys_util::signal::SIGRTMIN;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mut vcpu = vm.create_vcpu(0).unwrap();
+    ///
+    /// VcpuKicker::install_signal_handler(SIGRTMIN()).unwrap();
+    /// let kicker = vcpu.kicker(SIGRTMIN()).unwrap();
+    /// std::thread::spawn(move || kicker.kick().unwrap()).join().unwrap();
+    /// assert!(matches!(vcpu.run(), Ok(VcpuExit::Intr)));
+    /// ```
+    pub fn kicker(&self, signum: c_int) -> Result<VcpuKicker> {
+        let run = KvmRunWrapper::mmap_from_fd(self, self.kvm_run_ptr.mmap_size)?;
+        Ok(VcpuKicker::new(run, signum))
+    }
+
+    /// Sets the signals blocked while the vCPU is in `KVM_RUN`, replacing the signal
+    /// mask of the thread for the duration of the call.
+    ///
+    /// A signal blocked in the thread and unblocked in `sigset` is only delivered
+    /// in `KVM_RUN`, where it makes [`run`](Self::run) return `VcpuExit::Intr`, so
+    /// a kick sent before the vCPU enters the guest is not lost.
+    ///
+    /// See the documentation for `KVM_SET_SIGNAL_MASK`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sigset` - The kernel's `sigset_t`, which is `KVM_SIGSET_SIZE` bytes long.
+    ///   KVM fails with `EINVAL` for any other length.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_bindings::SigSet;
+    /// use vmm_sys_util::signal::SIGRTMIN;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    ///
+    /// // Block SIGRTMIN while the vCPU runs. Signal `n` is bit `n - 1` of the set.
+    /// let mask = 1u64 << (SIGRTMIN() - 1);
+    /// let sigset = SigSet::from_entries(&mask.to_ne_bytes()).unwrap();
+    /// vcpu.set_signal_mask(&sigset).unwrap();
+    /// ```
+    pub fn set_signal_mask(&self, sigset: &SigSet) -> Result<()> {
+        // SAFETY: Here we trust the kernel not to read past the end of the kvm_signal_mask
+        // struct.
+        let ret =
+            unsafe { ioctl_with_ptr(self, KVM_SET_SIGNAL_MASK(), sigset.as_fam_struct_ptr()) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Removes the signal mask set with [`set_signal_mask`](Self::set_signal_mask),
+    /// so that `KVM_RUN` keeps the signal mask of the thread.
+    pub fn clear_signal_mask(&self) -> Result<()> {
+        // SAFETY: A null pointer tells KVM to remove the signal mask, and nothing is read.
+        let ret = unsafe {
+            ioctl_with_ptr(
+                self,
+                KVM_SET_SIGNAL_MASK(),
+                std::ptr::null::<kvm_signal_mask>(),
+            )
+        };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
     }
 
     /// Returns the vCPU TSC frequency in KHz or an error if the host has unstable TSC.
@@ -2095,6 +2644,37 @@ impl VcpuFd {
             .ok_or(errno::Error::new(libc::EIO))
             .map(|ring| ring.read_entry())
     }
+
+    /// Returns an iterator that removes the entries of the coalesced MMIO and PIO
+    /// ring, as [`CoalescedAccess`]es.
+    ///
+    /// [`map_coalesced_mmio_ring()`](VcpuFd::map_coalesced_mmio_ring) must have been
+    /// called beforehand, otherwise this fails with `EIO`. The ring is shared by
+    /// all the vCPUs of the VM, and should be drained before handling an exit that
+    /// depends on the device state, such as an MMIO read.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::{Cap, CoalescedAccessKind, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mut vcpu = vm.create_vcpu(0).unwrap();
+    /// if kvm.check_extension(Cap::CoalescedMmio) {
+    ///     vcpu.map_coalesced_mmio_ring().unwrap();
+    ///     for access in vcpu.drain_coalesced_io().unwrap() {
+    ///         if access.kind == CoalescedAccessKind::Pio {
+    ///             println!("out {:#x}, {:?}", access.addr, access.data());
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn drain_coalesced_io(&mut self) -> Result<CoalescedIoDrain<'_>> {
+        self.coalesced_mmio_ring
+            .as_mut()
+            .ok_or(errno::Error::new(libc::EIO))
+            .map(CoalescedIoDrain::new)
+    }
 }
 
 /// Helper function to create a new `VcpuFd`.
@@ -2107,6 +2687,7 @@ pub fn new_vcpu(vcpu: File, kvm_run_ptr: KvmRunWrapper) -> VcpuFd {
         vcpu,
         kvm_run_ptr,
         coalesced_mmio_ring: None,
+        exit_observer: None,
     }
 }
 
@@ -2366,6 +2947,64 @@ mod tests {
         }
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_get_set_all_msrs() {
+        use crate::msr;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        // More MSRs than fit in a single `Msrs`.
+        let indices: Vec<u32> = (0..2 * MSRS_PER_BATCH as u32 + 10)
+            .map(|i| msr::IA32_SYSENTER_CS + i % 3)
+            .collect();
+        let mut entries = vcpu.get_all_msrs(&indices).unwrap();
+        assert_eq!(entries.len(), indices.len());
+        for entry in entries.iter_mut() {
+            entry.data = u64::from(entry.index);
+        }
+        vcpu.set_all_msrs(&entries).unwrap();
+        let entries = vcpu
+            .get_all_msrs(&[msr::IA32_SYSENTER_ESP, msr::IA32_SYSENTER_EIP])
+            .unwrap();
+        assert_eq!(entries[0].data, u64::from(msr::IA32_SYSENTER_ESP));
+        assert_eq!(entries[1].data, u64::from(msr::IA32_SYSENTER_EIP));
+
+        let supported = kvm.get_msr_index_list().unwrap();
+        vcpu.get_all_msrs(&msr::msrs_to_save(supported.as_slice()))
+            .unwrap();
+
+        // 0xdead_beef is not an MSR.
+        let mut indices = indices;
+        indices[MSRS_PER_BATCH + 2] = 0xdead_beef;
+        assert_eq!(
+            vcpu.get_all_msrs(&indices).unwrap_err(),
+            MsrError::Failed {
+                index: 0xdead_beef,
+                done: MSRS_PER_BATCH + 2,
+            }
+        );
+        let entries = [
+            kvm_msr_entry {
+                index: msr::IA32_SYSENTER_CS,
+                ..Default::default()
+            },
+            kvm_msr_entry {
+                index: 0xdead_beef,
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            vcpu.set_all_msrs(&entries).unwrap_err(),
+            MsrError::Failed {
+                index: 0xdead_beef,
+                done: 1,
+            }
+        );
+    }
+
     #[cfg(any(
         target_arch = "x86_64",
         target_arch = "aarch64",
@@ -2958,6 +3597,40 @@ mod tests {
         vcpu.get_reg_list(&mut reg_list).unwrap();
     }
 
+    #[test]
+    #[cfg(target_arch = "riscv64")]
+    fn test_isa_and_sbi_exts() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let isa_exts = vcpu.get_isa_ext_list().unwrap();
+        assert!(isa_exts.contains(&"i"));
+        assert!(vcpu.isa_ext_enabled("i").unwrap());
+        assert_eq!(
+            vcpu.isa_ext_enabled("foo").unwrap_err().errno(),
+            libc::EINVAL
+        );
+        if isa_exts.contains(&"sstc") {
+            vcpu.disable_isa_ext("sstc").unwrap();
+            assert!(!vcpu.isa_ext_enabled("sstc").unwrap());
+            vcpu.enable_isa_ext("sstc").unwrap();
+            assert!(vcpu.isa_ext_enabled("sstc").unwrap());
+        }
+
+        let sbi_exts = vcpu.get_sbi_ext_list().unwrap();
+        if sbi_exts.contains(&"pmu") {
+            vcpu.disable_sbi_ext("pmu").unwrap();
+            assert!(!vcpu.sbi_ext_enabled("pmu").unwrap());
+            vcpu.enable_sbi_ext("pmu").unwrap();
+            assert!(vcpu.sbi_ext_enabled("pmu").unwrap());
+        }
+        assert_eq!(
+            vcpu.enable_sbi_ext("foo").unwrap_err().errno(),
+            libc::EINVAL
+        );
+    }
+
     #[test]
     fn test_get_kvm_run() {
         let kvm = Kvm::new().unwrap();
@@ -2967,6 +3640,51 @@ mod tests {
         assert_eq!(vcpu.get_kvm_run().immediate_exit, 1);
     }
 
+    #[test]
+    fn test_set_signal_mask() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        // KVM only accepts a mask of the size of the kernel's sigset_t.
+        let sigset = SigSet::new(KVM_SIGSET_SIZE - 1).unwrap();
+        assert_eq!(
+            vcpu.set_signal_mask(&sigset).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        vcpu.set_signal_mask(&SigSet::new(KVM_SIGSET_SIZE).unwrap())
+            .unwrap();
+        vcpu.clear_signal_mask().unwrap();
+
+        // A signal blocked in the thread but not in KVM_RUN, and sent before the run,
+        // wakes up a halted vCPU, which would otherwise stay in KVM_RUN.
+        #[cfg(target_arch = "x86_64")]
+        {
+            use crate::VcpuKicker;
+            use kvm_bindings::{KVM_MP_STATE_HALTED, kvm_mp_state};
+            use vmm_sys_util::signal::{SIGRTMIN, block_signal, unblock_signal};
+
+            let vm = kvm.create_vm().unwrap();
+            vm.create_irq_chip().unwrap();
+            let mut vcpu = vm.create_vcpu(0).unwrap();
+            vcpu.set_mp_state(kvm_mp_state {
+                mp_state: KVM_MP_STATE_HALTED,
+            })
+            .unwrap();
+            vcpu.set_signal_mask(&SigSet::new(KVM_SIGSET_SIZE).unwrap())
+                .unwrap();
+
+            let signum = SIGRTMIN() + 1;
+            VcpuKicker::install_signal_handler(signum).unwrap();
+            block_signal(signum).unwrap();
+            // SAFETY: The signal has a handler, and is blocked in this thread.
+            assert_eq!(unsafe { libc::raise(signum) }, 0);
+            assert!(matches!(vcpu.run(), Ok(VcpuExit::Intr)));
+            // The signal is still pending, and is handled once unblocked.
+            unblock_signal(signum).unwrap();
+        }
+    }
+
     #[test]
     fn test_set_kvm_immediate_exit() {
         let kvm = Kvm::new().unwrap();
@@ -2977,6 +3695,61 @@ mod tests {
         assert_eq!(vcpu.kvm_run_ptr.as_ref().immediate_exit, 1);
     }
 
+    #[test]
+    fn test_exit_observer() {
+        use crate::exit_stats::ExitStats;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let stats = Arc::new(ExitStats::new());
+        vcpu.set_exit_observer(Some(stats.clone()));
+        for _ in 0..3 {
+            vcpu.set_kvm_immediate_exit(1);
+            assert!(matches!(vcpu.run(), Ok(VcpuExit::Intr)));
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total(), 3);
+        assert_eq!(snapshot.reasons[&KVM_EXIT_INTR].count, 3);
+
+        // Nothing is recorded once the observer is removed.
+        vcpu.set_exit_observer(None);
+        vcpu.set_kvm_immediate_exit(1);
+        vcpu.run().unwrap();
+        assert_eq!(stats.snapshot().total(), 3);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_kvm_run_fields() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+
+        vcpu.request_interrupt_window(true);
+        assert_eq!(vcpu.kvm_run_ptr.as_ref().request_interrupt_window, 1);
+        vcpu.request_interrupt_window(false);
+        assert_eq!(vcpu.kvm_run_ptr.as_ref().request_interrupt_window, 0);
+        vcpu.set_cr8(0xf);
+        assert_eq!(vcpu.cr8(), 0xf);
+
+        // Unknown flags are ignored.
+        vcpu.kvm_run_ptr.as_mut_ref().flags =
+            (KVM_RUN_X86_SMM | KVM_RUN_X86_GUEST_MODE) as u16 | 0x8000;
+        assert_eq!(vcpu.run_flags(), RunFlags::Smm | RunFlags::GuestMode);
+
+        // Without an in-kernel irqchip, KVM reports the interrupt state on every
+        // exit, even without entering the guest. Interrupts are disabled at reset.
+        vcpu.set_kvm_immediate_exit(1);
+        assert!(matches!(vcpu.run(), Ok(VcpuExit::Intr)));
+        assert!(vcpu.run_flags().is_empty());
+        assert!(!vcpu.if_flag());
+        assert!(!vcpu.ready_for_interrupt_injection());
+        // The run loaded CR8, and the APIC base is the default one of the BSP.
+        assert_eq!(vcpu.get_sregs().unwrap().cr8, 0xf);
+        assert_eq!(vcpu.apic_base(), 0xfee0_0900);
+    }
+
     #[test]
     #[cfg(target_arch = "x86_64")]
     fn test_enable_cap() {
@@ -3549,6 +4322,71 @@ mod tests {
         assert_eq!(data, (DATA as u8).to_le_bytes());
     }
 
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_drain_coalesced_io() {
+        use crate::CoalescedAccessKind;
+        use std::io::Write;
+
+        const PORT: u16 = 0x2c;
+
+        #[rustfmt::skip]
+        let code = [
+            0xe6, 0x2c,   // out 0x2c, al
+            0xfe, 0xc0,   // inc al
+            0xe6, 0x2c,   // out 0x2c, al
+            0xf4,         // hlt
+        ];
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let mem_size = 0x4000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x1000;
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        // SAFETY: The memory is mapped above and stays valid for the whole test.
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        assert_eq!(vcpu.drain_coalesced_io().unwrap_err().errno(), libc::EIO);
+        vm.register_coalesced_pio(PORT, 1).unwrap();
+        vcpu.map_coalesced_mmio_ring().unwrap();
+
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rip = guest_addr;
+        regs.rax = 0x39;
+        regs.rflags = 2;
+        vcpu.set_regs(&regs).unwrap();
+        let mut sregs = vcpu.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.set_sregs(&sregs).unwrap();
+
+        assert!(matches!(vcpu.run().unwrap(), VcpuExit::Hlt));
+        let drain = vcpu.drain_coalesced_io().unwrap();
+        assert_eq!(drain.size_hint(), (0, Some(2)));
+        let accesses: Vec<_> = drain.collect();
+        assert_eq!(accesses.len(), 2);
+        for (access, data) in accesses.iter().zip([0x39, 0x3a]) {
+            assert_eq!(access.kind, CoalescedAccessKind::Pio);
+            assert_eq!(access.addr, u64::from(PORT));
+            assert_eq!(access.data(), [data]);
+        }
+        assert_eq!(vcpu.drain_coalesced_io().unwrap().count(), 0);
+
+        vm.unregister_coalesced_pio(PORT, 1).unwrap();
+    }
+
     #[test]
     #[cfg(target_arch = "x86_64")]
     fn test_coalesced_mmio() {
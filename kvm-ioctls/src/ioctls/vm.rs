@@ -16,11 +16,20 @@ use std::os::raw::{c_int, c_ulong};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 use crate::cap::Cap;
+use crate::error::CheckedVmFd;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use crate::gsi_routing::GsiRoutingTable;
 use crate::ioctls::device::DeviceFd;
 use crate::ioctls::device::new_device;
 use crate::ioctls::vcpu::VcpuFd;
 use crate::ioctls::vcpu::new_vcpu;
 use crate::ioctls::{KvmRunWrapper, Result};
+#[cfg(target_arch = "x86_64")]
+use crate::irqchip::{IoapicState, PicState};
 use crate::kvm_ioctls::*;
 use vmm_sys_util::errno;
 use vmm_sys_util::eventfd::EventFd;
@@ -34,7 +43,7 @@ use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
 ///
 /// The `IoEventAddress` is used for specifying the type when registering an event
 /// in [register_ioevent](struct.VmFd.html#method.register_ioevent).
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IoEventAddress {
     /// Representation of an programmable I/O address.
     Pio(u64),
@@ -410,6 +419,87 @@ impl VmFd {
         }
     }
 
+    /// X86 specific call to retrieve the state of the in-kernel master or slave PIC.
+    ///
+    /// This is a typed wrapper over [`get_irqchip`](Self::get_irqchip).
+    ///
+    /// # Arguments
+    ///
+    /// * `master` - Whether to retrieve the master PIC rather than the slave one.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    ///
+    /// vm.create_irq_chip().unwrap();
+    /// let mut pic = vm.get_pic(true).unwrap();
+    /// pic.set_masked(0, true);
+    /// vm.set_pic(&pic).unwrap();
+    /// ```
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_pic(&self, master: bool) -> Result<PicState> {
+        let mut irqchip = kvm_irqchip {
+            chip_id: if master {
+                KVM_IRQCHIP_PIC_MASTER
+            } else {
+                KVM_IRQCHIP_PIC_SLAVE
+            },
+            ..Default::default()
+        };
+        self.get_irqchip(&mut irqchip)?;
+        // SAFETY: KVM fills in the `pic` member for the PIC chip ids.
+        Ok(PicState::new(master, unsafe { irqchip.chip.pic }))
+    }
+
+    /// X86 specific call to set the state of the in-kernel master or slave PIC.
+    ///
+    /// This is a typed wrapper over [`set_irqchip`](Self::set_irqchip).
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_pic(&self, pic: &PicState) -> Result<()> {
+        self.set_irqchip(&pic.to_irqchip())
+    }
+
+    /// X86 specific call to retrieve the state of the in-kernel IOAPIC.
+    ///
+    /// This is a typed wrapper over [`get_irqchip`](Self::get_irqchip).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    ///
+    /// vm.create_irq_chip().unwrap();
+    /// let ioapic = vm.get_ioapic().unwrap();
+    /// for (pin, entry) in ioapic.redirection_entries().enumerate() {
+    ///     if !entry.masked() {
+    ///         println!("pin {pin} -> vector {:#x}", entry.vector());
+    ///     }
+    /// }
+    /// ```
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_ioapic(&self) -> Result<IoapicState> {
+        let mut irqchip = kvm_irqchip {
+            chip_id: KVM_IRQCHIP_IOAPIC,
+            ..Default::default()
+        };
+        self.get_irqchip(&mut irqchip)?;
+        // SAFETY: KVM fills in the `ioapic` member for the IOAPIC chip id.
+        Ok(IoapicState::new(unsafe { irqchip.chip.ioapic }))
+    }
+
+    /// X86 specific call to set the state of the in-kernel IOAPIC.
+    ///
+    /// This is a typed wrapper over [`set_irqchip`](Self::set_irqchip).
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_ioapic(&self, ioapic: &IoapicState) -> Result<()> {
+        self.set_irqchip(&ioapic.to_irqchip())
+    }
+
     /// Creates a PIT as per the `KVM_CREATE_PIT2` ioctl.
     ///
     /// # Arguments
@@ -781,6 +871,19 @@ impl VmFd {
         }
     }
 
+    /// Replaces the GSI routing table of the VM with `table`.
+    ///
+    /// This is a typed wrapper over [`set_gsi_routing`](Self::set_gsi_routing).
+    /// See [`GsiRoutingTable`] for an example.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub fn set_gsi_routing_table(&self, table: &GsiRoutingTable) -> Result<()> {
+        self.set_gsi_routing(&table.to_kvm_irq_routing()?)
+    }
+
     /// Registers an event to be signaled whenever a certain address is written to.
     ///
     /// See the documentation for `KVM_IOEVENTFD`.
@@ -1311,6 +1414,24 @@ impl VmFd {
         }
     }
 
+    /// Returns a view over the VM whose wrappers return an
+    /// [`IoctlError`](crate::IoctlError) naming the ioctl that failed.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::{ErrorArg, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let _vcpu = vm.checked().create_vcpu(0).unwrap();
+    /// let err = vm.checked().create_vcpu(0).unwrap_err();
+    /// assert_eq!(err.ioctl(), "KVM_CREATE_VCPU");
+    /// assert_eq!(err.arg(), Some(ErrorArg::Vcpu(0)));
+    /// ```
+    pub fn checked(&self) -> CheckedVmFd<'_> {
+        CheckedVmFd(self)
+    }
+
     /// Creates a new KVM vCPU file descriptor and maps the memory corresponding
     /// its `kvm_run` structure.
     ///
@@ -1535,6 +1656,33 @@ impl VmFd {
         }
     }
 
+    /// Enables the split irqchip, where KVM emulates the local APICs and leaves the
+    /// IOAPIC and PICs to userspace, with `KVM_CAP_SPLIT_IRQCHIP`.
+    ///
+    /// GSIs `0..num_ioapic_pins` are reserved for the pins of the userspace IOAPIC,
+    /// which delivers its interrupts as MSIs routed through them. See
+    /// [`IoapicRouting`](crate::IoapicRouting).
+    ///
+    /// This fails with `EEXIST` if the VM already has an irqchip or a vCPU.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// vm.enable_split_irqchip(24).unwrap();
+    /// ```
+    #[cfg(target_arch = "x86_64")]
+    pub fn enable_split_irqchip(&self, num_ioapic_pins: u32) -> Result<()> {
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_SPLIT_IRQCHIP,
+            ..Default::default()
+        };
+        cap.args[0] = u64::from(num_ioapic_pins);
+        self.enable_cap(&cap)
+    }
+
     /// Get the `kvm_run` size.
     pub fn run_size(&self) -> usize {
         self.run_size
@@ -2050,6 +2198,37 @@ impl VmFd {
         Ok(())
     }
 
+    /// Registers the I/O ports `port..port + size` for coalesced PIO. Writes to
+    /// these ports are appended to the coalesced ring instead of causing a
+    /// `VcpuExit::IoOut`, and are read with [`VcpuFd::drain_coalesced_io()`].
+    ///
+    /// Needs `KVM_CAP_COALESCED_PIO` ([`Cap::CoalescedPio`](crate::Cap::CoalescedPio)).
+    /// This is [`register_coalesced_mmio()`](VmFd::register_coalesced_mmio) with an
+    /// `IoEventAddress::Pio` address.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if kvm.check_extension(Cap::CoalescedPio) {
+    ///     // Coalesce the writes to the data register of a serial port.
+    ///     vm.register_coalesced_pio(0x3f8, 1).unwrap();
+    /// }
+    /// ```
+    #[cfg(target_arch = "x86_64")]
+    pub fn register_coalesced_pio(&self, port: u16, size: u32) -> Result<()> {
+        self.register_coalesced_mmio(IoEventAddress::Pio(u64::from(port)), size)
+    }
+
+    /// Unregisters I/O ports previously registered with
+    /// [`register_coalesced_pio()`](VmFd::register_coalesced_pio).
+    #[cfg(target_arch = "x86_64")]
+    pub fn unregister_coalesced_pio(&self, port: u16, size: u32) -> Result<()> {
+        self.unregister_coalesced_mmio(IoEventAddress::Pio(u64::from(port)), size)
+    }
+
     /// Sets a specified piece of vm configuration and/or state.
     ///
     /// See the documentation for `KVM_SET_DEVICE_ATTR` in
@@ -2891,6 +3070,27 @@ mod tests {
         vm.enable_cap(&cap).unwrap();
     }
 
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_enable_split_irqchip() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.enable_split_irqchip(24).unwrap();
+        assert_eq!(
+            vm.enable_split_irqchip(24).unwrap_err().errno(),
+            libc::EEXIST
+        );
+        assert_eq!(vm.create_irq_chip().unwrap_err().errno(), libc::EEXIST);
+
+        // The irqchip cannot be enabled once a vCPU exists.
+        let vm = kvm.create_vm().unwrap();
+        let _vcpu = vm.create_vcpu(0).unwrap();
+        assert_eq!(
+            vm.enable_split_irqchip(24).unwrap_err().errno(),
+            libc::EEXIST
+        );
+    }
+
     #[test]
     #[cfg(any(
         target_arch = "x86_64",
@@ -0,0 +1,707 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use kvm_bindings::{
+    KVM_IOAPIC_NUM_PINS, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+    kvm_ioapic_state, kvm_ioapic_state__bindgen_ty_1, kvm_irqchip, kvm_pic_state,
+};
+
+use vmm_sys_util::errno;
+
+use crate::gsi_routing::{GsiRoute, GsiRoutingTable};
+use crate::ioctls::Result;
+use crate::ioctls::vm::VmFd;
+use crate::lapic::DeliveryMode;
+
+// Address of the MSIs sent to the local APICs, and fields of the address and data.
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+const MSI_ADDRESS_DEST_ID_SHIFT: u64 = 12;
+const MSI_ADDRESS_DEST_MODE_LOGICAL: u64 = 1 << 2;
+const MSI_DATA_DELIVERY_MODE_SHIFT: u32 = 8;
+const MSI_DATA_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+/// State of one of the two 8259 PICs emulated by KVM.
+///
+/// The masks returned by the accessors have one bit per IRQ line of the chip,
+/// with line 0 in bit 0. On the master PIC, line 2 cascades to the slave PIC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PicState {
+    master: bool,
+    state: kvm_pic_state,
+}
+
+impl PicState {
+    /// Creates a view over the state of the master PIC if `master` is true, of the
+    /// slave PIC otherwise.
+    pub fn new(master: bool, state: kvm_pic_state) -> Self {
+        PicState { master, state }
+    }
+
+    /// Returns whether this is the master PIC.
+    pub fn is_master(&self) -> bool {
+        self.master
+    }
+
+    /// Returns the underlying `kvm_pic_state`.
+    pub fn as_raw(&self) -> &kvm_pic_state {
+        &self.state
+    }
+
+    /// Returns a mutable reference to the underlying `kvm_pic_state`.
+    pub fn as_raw_mut(&mut self) -> &mut kvm_pic_state {
+        &mut self.state
+    }
+
+    /// Returns a `kvm_irqchip` holding this state, to be passed to `VmFd::set_irqchip`.
+    pub fn to_irqchip(&self) -> kvm_irqchip {
+        let mut irqchip = kvm_irqchip {
+            chip_id: if self.master {
+                KVM_IRQCHIP_PIC_MASTER
+            } else {
+                KVM_IRQCHIP_PIC_SLAVE
+            },
+            ..Default::default()
+        };
+        irqchip.chip.pic = self.state;
+        irqchip
+    }
+
+    /// Returns the vector base programmed by `ICW2`.
+    pub fn irq_base(&self) -> u8 {
+        self.state.irq_base
+    }
+
+    /// Returns the vector delivered for `line`.
+    pub fn vector(&self, line: u8) -> u8 {
+        self.state.irq_base.wrapping_add(line & 0x7)
+    }
+
+    /// Returns the interrupt request register.
+    pub fn irr(&self) -> u8 {
+        self.state.irr
+    }
+
+    /// Returns the in-service register.
+    pub fn isr(&self) -> u8 {
+        self.state.isr
+    }
+
+    /// Returns the interrupt mask register.
+    pub fn imr(&self) -> u8 {
+        self.state.imr
+    }
+
+    /// Returns the edge/level control register, where a set bit marks a level
+    /// triggered line.
+    pub fn elcr(&self) -> u8 {
+        self.state.elcr
+    }
+
+    /// Returns whether `line` is masked.
+    pub fn masked(&self, line: u8) -> bool {
+        self.state.imr & (1 << (line & 0x7)) != 0
+    }
+
+    /// Masks or unmasks `line`.
+    pub fn set_masked(&mut self, line: u8, masked: bool) {
+        let bit = 1 << (line & 0x7);
+        if masked {
+            self.state.imr |= bit;
+        } else {
+            self.state.imr &= !bit;
+        }
+    }
+
+    /// Returns whether `line` is level triggered.
+    pub fn level_triggered(&self, line: u8) -> bool {
+        self.state.elcr & (1 << (line & 0x7)) != 0
+    }
+
+    /// Sets `line` as level triggered if `level` is true, edge triggered otherwise.
+    ///
+    /// Lines that cannot be level triggered, according to the ELCR mask, are left
+    /// unchanged.
+    pub fn set_level_triggered(&mut self, line: u8, level: bool) {
+        let bit = (1 << (line & 0x7)) & self.state.elcr_mask;
+        if level {
+            self.state.elcr |= bit;
+        } else {
+            self.state.elcr &= !bit;
+        }
+    }
+}
+
+/// Interrupt input pin polarity of an IOAPIC redirection entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Polarity {
+    /// Active high.
+    ActiveHigh,
+    /// Active low.
+    ActiveLow,
+}
+
+/// An entry of the IOAPIC redirection table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RedirectionEntry(pub u64);
+
+impl RedirectionEntry {
+    const VECTOR_MASK: u64 = 0xff;
+    const DELIVERY_MODE_SHIFT: u64 = 8;
+    const DELIVERY_MODE_MASK: u64 = 0x7 << Self::DELIVERY_MODE_SHIFT;
+    const DEST_MODE_LOGICAL: u64 = 1 << 11;
+    const DELIVERY_STATUS: u64 = 1 << 12;
+    const POLARITY_LOW: u64 = 1 << 13;
+    const REMOTE_IRR: u64 = 1 << 14;
+    const LEVEL_TRIGGERED: u64 = 1 << 15;
+    const MASKED: u64 = 1 << 16;
+    const DESTINATION_SHIFT: u64 = 56;
+    const DESTINATION_MASK: u64 = 0xff << Self::DESTINATION_SHIFT;
+
+    fn with_bits(self, bits: u64, set: bool) -> Self {
+        RedirectionEntry(if set { self.0 | bits } else { self.0 & !bits })
+    }
+
+    /// Returns the interrupt vector.
+    pub fn vector(self) -> u8 {
+        (self.0 & Self::VECTOR_MASK) as u8
+    }
+
+    /// Returns the entry with the interrupt vector set to `vector`.
+    pub fn with_vector(self, vector: u8) -> Self {
+        RedirectionEntry((self.0 & !Self::VECTOR_MASK) | u64::from(vector))
+    }
+
+    /// Returns the delivery mode, or `None` for reserved encodings.
+    pub fn delivery_mode(self) -> Option<DeliveryMode> {
+        match (self.0 & Self::DELIVERY_MODE_MASK) >> Self::DELIVERY_MODE_SHIFT {
+            0 => Some(DeliveryMode::Fixed),
+            1 => Some(DeliveryMode::LowestPriority),
+            2 => Some(DeliveryMode::Smi),
+            4 => Some(DeliveryMode::Nmi),
+            5 => Some(DeliveryMode::Init),
+            7 => Some(DeliveryMode::ExtInt),
+            _ => None,
+        }
+    }
+
+    /// Returns the entry with the delivery mode set to `mode`.
+    pub fn with_delivery_mode(self, mode: DeliveryMode) -> Self {
+        RedirectionEntry(
+            (self.0 & !Self::DELIVERY_MODE_MASK) | ((mode as u64) << Self::DELIVERY_MODE_SHIFT),
+        )
+    }
+
+    /// Returns whether [`destination`](Self::destination) is a logical destination
+    /// rather than an APIC ID.
+    pub fn logical_destination(self) -> bool {
+        self.0 & Self::DEST_MODE_LOGICAL != 0
+    }
+
+    /// Returns the entry with the destination mode set to logical if `logical` is
+    /// true, physical otherwise.
+    pub fn with_logical_destination(self, logical: bool) -> Self {
+        self.with_bits(Self::DEST_MODE_LOGICAL, logical)
+    }
+
+    /// Returns whether an interrupt is waiting to be delivered.
+    pub fn delivery_pending(self) -> bool {
+        self.0 & Self::DELIVERY_STATUS != 0
+    }
+
+    /// Returns the input pin polarity.
+    pub fn polarity(self) -> Polarity {
+        if self.0 & Self::POLARITY_LOW != 0 {
+            Polarity::ActiveLow
+        } else {
+            Polarity::ActiveHigh
+        }
+    }
+
+    /// Returns the entry with the input pin polarity set to `polarity`.
+    pub fn with_polarity(self, polarity: Polarity) -> Self {
+        self.with_bits(Self::POLARITY_LOW, polarity == Polarity::ActiveLow)
+    }
+
+    /// Returns whether a level triggered interrupt was accepted and awaits an EOI.
+    pub fn remote_irr(self) -> bool {
+        self.0 & Self::REMOTE_IRR != 0
+    }
+
+    /// Returns whether the interrupt is level triggered.
+    pub fn level_triggered(self) -> bool {
+        self.0 & Self::LEVEL_TRIGGERED != 0
+    }
+
+    /// Returns the entry with the trigger mode set to level if `level` is true,
+    /// edge otherwise.
+    pub fn with_level_triggered(self, level: bool) -> Self {
+        self.with_bits(Self::LEVEL_TRIGGERED, level)
+    }
+
+    /// Returns whether the interrupt is masked.
+    pub fn masked(self) -> bool {
+        self.0 & Self::MASKED != 0
+    }
+
+    /// Returns the entry with the interrupt masked if `masked` is true.
+    pub fn with_masked(self, masked: bool) -> Self {
+        self.with_bits(Self::MASKED, masked)
+    }
+
+    /// Returns the destination APIC ID or logical destination.
+    pub fn destination(self) -> u8 {
+        (self.0 >> Self::DESTINATION_SHIFT) as u8
+    }
+
+    /// Returns the entry with the destination set to `destination`.
+    pub fn with_destination(self, destination: u8) -> Self {
+        RedirectionEntry(
+            (self.0 & !Self::DESTINATION_MASK)
+                | (u64::from(destination) << Self::DESTINATION_SHIFT),
+        )
+    }
+}
+
+/// State of the IOAPIC emulated by KVM.
+#[derive(Clone, Copy, Debug)]
+pub struct IoapicState(kvm_ioapic_state);
+
+impl IoapicState {
+    /// The number of pins of the IOAPIC.
+    pub const NUM_PINS: usize = KVM_IOAPIC_NUM_PINS as usize;
+
+    /// Creates a view over `state`.
+    pub fn new(state: kvm_ioapic_state) -> Self {
+        IoapicState(state)
+    }
+
+    /// Returns the underlying `kvm_ioapic_state`.
+    pub fn as_raw(&self) -> &kvm_ioapic_state {
+        &self.0
+    }
+
+    /// Returns a mutable reference to the underlying `kvm_ioapic_state`.
+    pub fn as_raw_mut(&mut self) -> &mut kvm_ioapic_state {
+        &mut self.0
+    }
+
+    /// Returns a `kvm_irqchip` holding this state, to be passed to `VmFd::set_irqchip`.
+    pub fn to_irqchip(&self) -> kvm_irqchip {
+        let mut irqchip = kvm_irqchip {
+            chip_id: KVM_IRQCHIP_IOAPIC,
+            ..Default::default()
+        };
+        irqchip.chip.ioapic = self.0;
+        irqchip
+    }
+
+    /// Returns the guest physical address of the IOAPIC registers.
+    pub fn base_address(&self) -> u64 {
+        self.0.base_address
+    }
+
+    /// Returns the IOAPIC ID.
+    pub fn id(&self) -> u8 {
+        self.0.id as u8
+    }
+
+    /// Returns the pins with a pending interrupt, with pin 0 in bit 0.
+    pub fn irr(&self) -> u32 {
+        self.0.irr
+    }
+
+    /// Returns the redirection table entry of `pin`, or `None` if `pin` does not exist.
+    pub fn redirection_entry(&self, pin: usize) -> Option<RedirectionEntry> {
+        self.0.redirtbl.get(pin).map(|entry| {
+            // SAFETY: Both members of the union are plain data covering all its
+            // 8 bytes, so any bit pattern is a valid `u64`.
+            RedirectionEntry(unsafe { entry.bits })
+        })
+    }
+
+    /// Sets the redirection table entry of `pin`.
+    ///
+    /// Returns `false`, without changing anything, if `pin` does not exist.
+    pub fn set_redirection_entry(&mut self, pin: usize, entry: RedirectionEntry) -> bool {
+        match self.0.redirtbl.get_mut(pin) {
+            Some(slot) => {
+                *slot = kvm_ioapic_state__bindgen_ty_1 { bits: entry.0 };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns an iterator over the redirection table entries, in pin order.
+    pub fn redirection_entries(&self) -> impl Iterator<Item = RedirectionEntry> + '_ {
+        (0..Self::NUM_PINS).filter_map(|pin| self.redirection_entry(pin))
+    }
+}
+
+/// The redirection table of an IOAPIC emulated in userspace with a split irqchip,
+/// and the MSI routes of its pins.
+///
+/// With [`VmFd::enable_split_irqchip`], the interrupts of the userspace IOAPIC are
+/// delivered to the in-kernel local APICs as MSIs, through GSI `pin` for each
+/// pin. KVM uses the routes of these GSIs to find the vectors of level triggered
+/// interrupts, and exits with `VcpuExit::IoapicEoi` when the guest sends an EOI
+/// for one of them.
+///
+/// `IoapicRouting` turns each unmasked redirection entry into the MSI route of
+/// its pin, and removes the route of masked pins, so that an irqfd on GSI `pin`
+/// follows the programming of the guest. It also emulates the remote IRR bit,
+/// which stops a level triggered pin from sending another interrupt until the
+/// EOI.
+///
+/// # Example
+///
+/// ```rust
+/// # use kvm_ioctls::{GsiRoutingTable, IoapicRouting, Kvm, RedirectionEntry, VcpuExit};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// vm.enable_split_irqchip(24).unwrap();
+///
+/// let mut ioapic = IoapicRouting::new(24);
+/// let mut table = GsiRoutingTable::new();
+///
+/// // The guest programs pin 9 as a level triggered interrupt.
+/// let entry = RedirectionEntry::default()
+///     .with_vector(0x41)
+///     .with_level_triggered(true);
+/// ioapic.set_redirection_entry(9, entry).unwrap();
+/// ioapic.sync(&vm, &mut table).unwrap();
+///
+/// // The line of pin 9 is asserted: the interrupt is sent through GSI 9, and the
+/// // next ones wait for the EOI, reported as `VcpuExit::IoapicEoi(0x41)`.
+/// assert!(ioapic.trigger(9));
+/// assert!(!ioapic.trigger(9));
+/// assert_eq!(ioapic.handle_eoi(0x41), [9]);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IoapicRouting {
+    entries: Vec<RedirectionEntry>,
+}
+
+impl IoapicRouting {
+    /// Creates the routing of an IOAPIC with `num_pins` pins, which should be the
+    /// number passed to `VmFd::enable_split_irqchip`. All the pins are masked.
+    pub fn new(num_pins: u32) -> Self {
+        IoapicRouting {
+            entries: vec![RedirectionEntry::default().with_masked(true); num_pins as usize],
+        }
+    }
+
+    /// Returns the number of pins of the IOAPIC.
+    pub fn num_pins(&self) -> u32 {
+        self.entries.len() as u32
+    }
+
+    /// Returns the redirection table entry of `pin`, or `None` if `pin` does not exist.
+    pub fn redirection_entry(&self, pin: u32) -> Option<RedirectionEntry> {
+        self.entries.get(pin as usize).copied()
+    }
+
+    /// Sets the redirection table entry of `pin`, as written by the guest.
+    ///
+    /// The delivery status and remote IRR bits are read-only, and keep their value,
+    /// except that remote IRR is cleared when the pin becomes edge triggered, like
+    /// KVM's IOAPIC does. Guests rely on this to recover a pin stuck waiting for an
+    /// EOI. Fails with `EINVAL` if `pin` does not exist.
+    pub fn set_redirection_entry(&mut self, pin: u32, entry: RedirectionEntry) -> Result<()> {
+        let slot = self
+            .entries
+            .get_mut(pin as usize)
+            .ok_or(errno::Error::new(libc::EINVAL))?;
+        let read_only = RedirectionEntry::DELIVERY_STATUS | RedirectionEntry::REMOTE_IRR;
+        *slot = RedirectionEntry((entry.0 & !read_only) | (slot.0 & read_only));
+        if !slot.level_triggered() {
+            *slot = slot.with_bits(RedirectionEntry::REMOTE_IRR, false);
+        }
+        Ok(())
+    }
+
+    /// Returns the MSI route of `pin`, or `None` if the pin is masked, does not
+    /// exist, or has a delivery mode that cannot be sent as an MSI, such as
+    /// `ExtInt`.
+    pub fn msi_route(&self, pin: u32) -> Option<GsiRoute> {
+        let entry = self.redirection_entry(pin)?;
+        if entry.masked() {
+            return None;
+        }
+        let mode = match entry.delivery_mode()? {
+            DeliveryMode::ExtInt => return None,
+            mode => mode as u32,
+        };
+        let mut address =
+            MSI_ADDRESS_BASE | (u64::from(entry.destination()) << MSI_ADDRESS_DEST_ID_SHIFT);
+        if entry.logical_destination() {
+            address |= MSI_ADDRESS_DEST_MODE_LOGICAL;
+        }
+        let mut data = u32::from(entry.vector()) | (mode << MSI_DATA_DELIVERY_MODE_SHIFT);
+        // KVM only reports the EOIs of the vectors of level triggered routes.
+        if entry.level_triggered() {
+            data |= MSI_DATA_LEVEL_TRIGGERED;
+        }
+        Some(GsiRoute::Msi {
+            address,
+            data,
+            devid: None,
+        })
+    }
+
+    /// Updates the routes of GSIs `0..num_pins` in `table` to match the redirection
+    /// table, leaving the other GSIs alone.
+    ///
+    /// Returns whether `table` changed, and needs to be installed again.
+    pub fn update_routes(&self, table: &mut GsiRoutingTable) -> Result<bool> {
+        let mut changed = false;
+        for pin in 0..self.num_pins() {
+            match self.msi_route(pin) {
+                Some(route) if table.routes(pin) != [route] => {
+                    table.update(pin, route)?;
+                    changed = true;
+                }
+                Some(_) => {}
+                None => changed |= !table.remove(pin).is_empty(),
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Updates the routes of the pins in `table` and, if they changed, installs it
+    /// with `VmFd::set_gsi_routing_table`.
+    pub fn sync(&self, vm: &VmFd, table: &mut GsiRoutingTable) -> Result<()> {
+        if self.update_routes(table)? {
+            vm.set_gsi_routing_table(table)?;
+        }
+        Ok(())
+    }
+
+    /// Records that the line of `pin` is asserted, and returns whether an interrupt
+    /// should be sent through GSI `pin`.
+    ///
+    /// No interrupt is sent for a masked pin, or for a level triggered pin waiting
+    /// for an EOI. Otherwise, the remote IRR bit of a level triggered pin is set.
+    /// The polarity of the pin is left to the caller.
+    pub fn trigger(&mut self, pin: u32) -> bool {
+        let Some(entry) = self.entries.get_mut(pin as usize) else {
+            return false;
+        };
+        if entry.masked() || entry.remote_irr() {
+            return false;
+        }
+        if entry.level_triggered() {
+            *entry = entry.with_bits(RedirectionEntry::REMOTE_IRR, true);
+        }
+        true
+    }
+
+    /// Handles the EOI of `vector`, reported by `VcpuExit::IoapicEoi`, by clearing
+    /// the remote IRR bit of the level triggered pins with this vector.
+    ///
+    /// Returns these pins, whose lines should be checked again, and passed to
+    /// [`trigger`](Self::trigger) if they are still asserted.
+    pub fn handle_eoi(&mut self, vector: u8) -> Vec<u32> {
+        let mut pins = Vec::new();
+        for (pin, entry) in self.entries.iter_mut().enumerate() {
+            if entry.remote_irr() && entry.vector() == vector {
+                *entry = entry.with_bits(RedirectionEntry::REMOTE_IRR, false);
+                pins.push(pin as u32);
+            }
+        }
+        pins
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kvm;
+
+    #[test]
+    fn test_redirection_entry() {
+        let entry = RedirectionEntry::default()
+            .with_vector(0x30)
+            .with_delivery_mode(DeliveryMode::LowestPriority)
+            .with_logical_destination(true)
+            .with_polarity(Polarity::ActiveLow)
+            .with_level_triggered(true)
+            .with_masked(true)
+            .with_destination(0xf);
+        assert_eq!(entry.0, 0x0f00_0000_0001_a930);
+        assert_eq!(entry.vector(), 0x30);
+        assert_eq!(entry.delivery_mode(), Some(DeliveryMode::LowestPriority));
+        assert!(entry.logical_destination());
+        assert_eq!(entry.polarity(), Polarity::ActiveLow);
+        assert!(entry.level_triggered());
+        assert!(entry.masked());
+        assert_eq!(entry.destination(), 0xf);
+        assert!(!entry.delivery_pending());
+        assert!(!entry.remote_irr());
+
+        let entry = entry
+            .with_masked(false)
+            .with_polarity(Polarity::ActiveHigh)
+            .with_delivery_mode(DeliveryMode::ExtInt);
+        assert_eq!(entry.0, 0x0f00_0000_0000_8f30);
+        assert_eq!(RedirectionEntry(0x600).delivery_mode(), None);
+        assert!(RedirectionEntry(0x5000).delivery_pending());
+        assert!(RedirectionEntry(0x5000).remote_irr());
+    }
+
+    #[test]
+    fn test_pic_state() {
+        let mut pic = PicState::new(
+            true,
+            kvm_pic_state {
+                irq_base: 0x20,
+                elcr_mask: 0xf8,
+                ..Default::default()
+            },
+        );
+        assert_eq!(pic.vector(3), 0x23);
+        pic.set_masked(3, true);
+        assert!(pic.masked(3));
+        assert_eq!(pic.imr(), 0x8);
+        pic.set_masked(3, false);
+        assert_eq!(pic.imr(), 0);
+
+        // Lines 0 to 2 of the master PIC cannot be level triggered.
+        pic.set_level_triggered(1, true);
+        pic.set_level_triggered(5, true);
+        assert!(!pic.level_triggered(1));
+        assert!(pic.level_triggered(5));
+        assert_eq!(pic.elcr(), 0x20);
+
+        let irqchip = pic.to_irqchip();
+        assert_eq!(irqchip.chip_id, KVM_IRQCHIP_PIC_MASTER);
+        // SAFETY: `pic` is the member written by `to_irqchip`.
+        assert_eq!(unsafe { irqchip.chip.pic }, *pic.as_raw());
+    }
+
+    #[test]
+    fn test_irqchip_views() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+
+        let mut ioapic = vm.get_ioapic().unwrap();
+        assert_eq!(ioapic.base_address(), 0xfec0_0000);
+        assert_eq!(ioapic.redirection_entries().count(), IoapicState::NUM_PINS);
+        assert!(ioapic.redirection_entry(IoapicState::NUM_PINS).is_none());
+        assert!(ioapic.redirection_entries().all(|entry| entry.masked()));
+
+        let entry = RedirectionEntry::default()
+            .with_vector(0x41)
+            .with_level_triggered(true)
+            .with_destination(1);
+        assert!(ioapic.set_redirection_entry(9, entry));
+        assert!(!ioapic.set_redirection_entry(IoapicState::NUM_PINS, entry));
+        vm.set_ioapic(&ioapic).unwrap();
+        let ioapic = vm.get_ioapic().unwrap();
+        assert_eq!(ioapic.redirection_entry(9), Some(entry));
+
+        let master = vm.get_pic(true).unwrap();
+        let slave = vm.get_pic(false).unwrap();
+        assert!(master.is_master());
+        assert!(!slave.is_master());
+
+        let mut slave = slave;
+        slave.set_masked(4, true);
+        vm.set_pic(&slave).unwrap();
+        assert!(vm.get_pic(false).unwrap().masked(4));
+        assert!(!vm.get_pic(true).unwrap().masked(4));
+    }
+
+    #[test]
+    fn test_ioapic_routing() {
+        let mut ioapic = IoapicRouting::new(24);
+        assert_eq!(ioapic.num_pins(), 24);
+        assert!(ioapic.redirection_entry(24).is_none());
+        assert!(ioapic.msi_route(3).is_none());
+        assert!(!ioapic.trigger(3));
+
+        let entry = RedirectionEntry::default()
+            .with_vector(0x41)
+            .with_logical_destination(true)
+            .with_level_triggered(true)
+            .with_destination(2);
+        assert_eq!(
+            ioapic.set_redirection_entry(24, entry).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        ioapic.set_redirection_entry(3, entry).unwrap();
+        ioapic
+            .set_redirection_entry(4, entry.with_level_triggered(false))
+            .unwrap();
+        ioapic
+            .set_redirection_entry(5, entry.with_delivery_mode(DeliveryMode::ExtInt))
+            .unwrap();
+        assert_eq!(
+            ioapic.msi_route(3),
+            Some(GsiRoute::Msi {
+                address: 0xfee0_2004,
+                data: 0x8041,
+                devid: None,
+            })
+        );
+        assert!(ioapic.msi_route(5).is_none());
+
+        // Only the routes of the unmasked pins are set, and other GSIs are kept.
+        let mut table = GsiRoutingTable::new();
+        table.add_msi(24, 0xfee0_0000, 0x30, None).unwrap();
+        table.add_msi(6, 0xfee0_0000, 0x31, None).unwrap();
+        assert!(ioapic.update_routes(&mut table).unwrap());
+        assert_eq!(table.gsis().collect::<Vec<_>>(), [3, 4, 24]);
+        assert!(!ioapic.update_routes(&mut table).unwrap());
+
+        // A level triggered pin sends one interrupt until the EOI of its vector.
+        assert!(ioapic.trigger(3));
+        assert!(!ioapic.trigger(3));
+        assert!(ioapic.redirection_entry(3).unwrap().remote_irr());
+        // The guest cannot clear the remote IRR bit.
+        ioapic.set_redirection_entry(3, entry).unwrap();
+        assert!(ioapic.redirection_entry(3).unwrap().remote_irr());
+        assert!(ioapic.trigger(4));
+        assert!(ioapic.trigger(4));
+        assert!(ioapic.handle_eoi(0x42).is_empty());
+        assert_eq!(ioapic.handle_eoi(0x41), [3]);
+        assert!(ioapic.trigger(3));
+
+        // Switching a pin waiting for an EOI to edge triggered clears remote IRR.
+        assert!(!ioapic.trigger(3));
+        ioapic
+            .set_redirection_entry(3, entry.with_level_triggered(false))
+            .unwrap();
+        assert!(!ioapic.redirection_entry(3).unwrap().remote_irr());
+        assert!(ioapic.trigger(3));
+        assert!(ioapic.trigger(3));
+
+        // Masking a pin removes its route.
+        ioapic
+            .set_redirection_entry(4, entry.with_masked(true))
+            .unwrap();
+        assert!(!ioapic.trigger(4));
+        assert!(ioapic.update_routes(&mut table).unwrap());
+        assert!(table.routes(4).is_empty());
+    }
+
+    #[test]
+    fn test_ioapic_routing_split_irqchip() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.enable_split_irqchip(24).unwrap();
+        let _vcpu = vm.create_vcpu(0).unwrap();
+
+        let mut ioapic = IoapicRouting::new(24);
+        let mut table = GsiRoutingTable::new();
+        for pin in 0..24 {
+            let entry = RedirectionEntry::default()
+                .with_vector(0x30 + pin as u8)
+                .with_level_triggered(pin % 2 == 1);
+            ioapic.set_redirection_entry(pin, entry).unwrap();
+        }
+        ioapic.sync(&vm, &mut table).unwrap();
+        assert_eq!(table.len(), 24);
+        ioapic.sync(&vm, &mut table).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::Ordering;
+
+use vmm_sys_util::errno;
+use vmm_sys_util::signal::register_signal_handler;
+
+use crate::ioctls::{KvmRunWrapper, Result};
+
+/// A handle that makes a vCPU exit to userspace from any thread, returned by
+/// `VcpuFd::kicker`.
+///
+/// [`kick`](Self::kick) first sets the `immediate_exit` flag of the vCPU, then
+/// signals the thread that runs it. A vCPU in `KVM_RUN` is interrupted by the
+/// signal, and a vCPU about to enter it sees the flag, so a kick is never lost:
+/// the next `VcpuFd::run` returns `VcpuExit::Intr` and clears the flag.
+///
+/// The kicker maps the `kvm_run` structure of the vCPU on its own, so it stays
+/// valid after the `VcpuFd` is dropped. It identifies the thread by its ID, and a
+/// kick after that thread exits fails with `ESRCH`, unless the ID was reused by
+/// another thread of the process.
+#[derive(Debug)]
+pub struct VcpuKicker {
+    run: KvmRunWrapper,
+    pid: libc::pid_t,
+    tid: libc::pid_t,
+    signum: c_int,
+}
+
+extern "C" fn handle_kick(_: c_int, _: *mut libc::siginfo_t, _: *mut c_void) {}
+
+impl VcpuKicker {
+    pub(crate) fn new(run: KvmRunWrapper, signum: c_int) -> Self {
+        // SAFETY: These syscalls have no arguments and cannot fail.
+        let (pid, tid) = unsafe { (libc::getpid(), libc::syscall(libc::SYS_gettid)) };
+        VcpuKicker {
+            run,
+            pid,
+            tid: tid as libc::pid_t,
+            signum,
+        }
+    }
+
+    /// Installs a handler that does nothing for `signum`, so that the signal
+    /// interrupts `KVM_RUN` instead of terminating the process.
+    ///
+    /// The handler is installed for the whole process, and should be for a signal
+    /// not used otherwise, such as a real-time signal.
+    pub fn install_signal_handler(signum: c_int) -> Result<()> {
+        register_signal_handler(signum, handle_kick)
+    }
+
+    /// Returns the signal sent to the vCPU thread.
+    pub fn signum(&self) -> c_int {
+        self.signum
+    }
+
+    /// Makes the vCPU exit to userspace, or not enter the guest on its next run.
+    pub fn kick(&self) -> Result<()> {
+        // The flag must be visible before the signal is received, in case the vCPU
+        // thread is about to enter `KVM_RUN`.
+        self.run.immediate_exit().store(1, Ordering::SeqCst);
+        // SAFETY: tgkill only sends a signal to the thread `tid` of our process, whose
+        // handler was installed by the caller.
+        let ret = unsafe { libc::syscall(libc::SYS_tgkill, self.pid, self.tid, self.signum) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Kvm, VcpuExit};
+    use vmm_sys_util::signal::SIGRTMIN;
+
+    #[test]
+    fn test_vcpu_kicker() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        VcpuKicker::install_signal_handler(SIGRTMIN()).unwrap();
+
+        // A kick before the run makes it return immediately, and is consumed by it.
+        let kicker = vcpu.kicker(SIGRTMIN()).unwrap();
+        assert_eq!(kicker.signum(), SIGRTMIN());
+        kicker.kick().unwrap();
+        assert_eq!(vcpu.get_kvm_run().immediate_exit, 1);
+        assert!(matches!(vcpu.run(), Ok(VcpuExit::Intr)));
+        assert_eq!(vcpu.get_kvm_run().immediate_exit, 0);
+
+        // A kick from another thread interrupts a halted vCPU, which would otherwise
+        // stay in KVM_RUN.
+        #[cfg(target_arch = "x86_64")]
+        {
+            use kvm_bindings::{KVM_MP_STATE_HALTED, kvm_mp_state};
+            use std::sync::mpsc;
+            use std::thread;
+            use std::time::Duration;
+
+            let vm = kvm.create_vm().unwrap();
+            vm.create_irq_chip().unwrap();
+            let mut vcpu = vm.create_vcpu(0).unwrap();
+            vcpu.set_mp_state(kvm_mp_state {
+                mp_state: KVM_MP_STATE_HALTED,
+            })
+            .unwrap();
+
+            let (tx, rx) = mpsc::channel();
+            let handle = thread::spawn(move || {
+                tx.send(vcpu.kicker(SIGRTMIN()).unwrap()).unwrap();
+                matches!(vcpu.run(), Ok(VcpuExit::Intr))
+            });
+            let kicker = rx.recv().unwrap();
+            // The kick works whether the vCPU has entered KVM_RUN or not.
+            thread::sleep(Duration::from_millis(10));
+            kicker.kick().unwrap();
+            assert!(handle.join().unwrap());
+            drop(vm);
+            // The kicker outlives the vCPU and its VM. The exit cleared the flag, and
+            // the vCPU thread is gone.
+            assert_eq!(kicker.run.immediate_exit().load(Ordering::SeqCst), 0);
+            assert_eq!(kicker.kick().unwrap_err().errno(), libc::ESRCH);
+        }
+    }
+}
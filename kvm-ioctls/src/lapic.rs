@@ -0,0 +1,599 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use kvm_bindings::kvm_lapic_state;
+
+/// A 32-bit register of the local APIC, identified by its offset in the APIC page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LapicReg {
+    /// Local APIC ID.
+    Id = 0x20,
+    /// Local APIC version.
+    Version = 0x30,
+    /// Task priority.
+    Tpr = 0x80,
+    /// Arbitration priority.
+    Apr = 0x90,
+    /// Processor priority.
+    Ppr = 0xa0,
+    /// End of interrupt.
+    Eoi = 0xb0,
+    /// Remote read.
+    Rrd = 0xc0,
+    /// Logical destination.
+    Ldr = 0xd0,
+    /// Destination format.
+    Dfr = 0xe0,
+    /// Spurious interrupt vector.
+    Svr = 0xf0,
+    /// Error status.
+    Esr = 0x280,
+    /// Corrected machine check interrupt LVT entry.
+    LvtCmci = 0x2f0,
+    /// Interrupt command, low half.
+    IcrLow = 0x300,
+    /// Interrupt command, high half.
+    IcrHigh = 0x310,
+    /// Timer LVT entry.
+    LvtTimer = 0x320,
+    /// Thermal sensor LVT entry.
+    LvtThermal = 0x330,
+    /// Performance monitoring counters LVT entry.
+    LvtPerf = 0x340,
+    /// `LINT0` pin LVT entry.
+    LvtLint0 = 0x350,
+    /// `LINT1` pin LVT entry.
+    LvtLint1 = 0x360,
+    /// Error LVT entry.
+    LvtError = 0x370,
+    /// Timer initial count.
+    TimerInitialCount = 0x380,
+    /// Timer current count.
+    TimerCurrentCount = 0x390,
+    /// Timer divide configuration.
+    TimerDivideConfig = 0x3e0,
+}
+
+/// An entry of the local vector table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Lvt {
+    /// Corrected machine check interrupt.
+    Cmci,
+    /// Local APIC timer.
+    Timer,
+    /// Thermal sensor.
+    Thermal,
+    /// Performance monitoring counters.
+    Perf,
+    /// `LINT0` pin.
+    Lint0,
+    /// `LINT1` pin.
+    Lint1,
+    /// Internal APIC errors.
+    Error,
+}
+
+impl Lvt {
+    /// Returns the register holding this entry.
+    pub fn reg(self) -> LapicReg {
+        match self {
+            Lvt::Cmci => LapicReg::LvtCmci,
+            Lvt::Timer => LapicReg::LvtTimer,
+            Lvt::Thermal => LapicReg::LvtThermal,
+            Lvt::Perf => LapicReg::LvtPerf,
+            Lvt::Lint0 => LapicReg::LvtLint0,
+            Lvt::Lint1 => LapicReg::LvtLint1,
+            Lvt::Error => LapicReg::LvtError,
+        }
+    }
+}
+
+/// Delivery mode of an interrupt, as found in LVT entries and in the ICR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeliveryMode {
+    /// Deliver the vector in the entry.
+    Fixed = 0,
+    /// Deliver to the lowest priority processor (ICR only).
+    LowestPriority = 1,
+    /// System management interrupt.
+    Smi = 2,
+    /// Non-maskable interrupt.
+    Nmi = 4,
+    /// INIT signal.
+    Init = 5,
+    /// Startup IPI (ICR only).
+    StartUp = 6,
+    /// External interrupt, with the vector supplied by the 8259 PIC.
+    ExtInt = 7,
+}
+
+/// Mode of the local APIC timer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimerMode {
+    /// Count down once from the initial count.
+    OneShot = 0,
+    /// Reload the initial count whenever it reaches zero.
+    Periodic = 1,
+    /// Fire when the TSC reaches `IA32_TSC_DEADLINE`.
+    TscDeadline = 2,
+}
+
+/// The value of a local vector table entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LvtEntry(pub u32);
+
+impl LvtEntry {
+    const VECTOR_MASK: u32 = 0xff;
+    const DELIVERY_MODE_SHIFT: u32 = 8;
+    const DELIVERY_MODE_MASK: u32 = 0x7 << Self::DELIVERY_MODE_SHIFT;
+    const LEVEL_TRIGGERED: u32 = 1 << 15;
+    const MASKED: u32 = 1 << 16;
+    const TIMER_MODE_SHIFT: u32 = 17;
+    const TIMER_MODE_MASK: u32 = 0x3 << Self::TIMER_MODE_SHIFT;
+
+    /// Returns the interrupt vector.
+    pub fn vector(self) -> u8 {
+        (self.0 & Self::VECTOR_MASK) as u8
+    }
+
+    /// Returns the entry with the interrupt vector set to `vector`.
+    pub fn with_vector(self, vector: u8) -> Self {
+        LvtEntry((self.0 & !Self::VECTOR_MASK) | u32::from(vector))
+    }
+
+    /// Returns the delivery mode, or `None` for reserved encodings.
+    pub fn delivery_mode(self) -> Option<DeliveryMode> {
+        match (self.0 & Self::DELIVERY_MODE_MASK) >> Self::DELIVERY_MODE_SHIFT {
+            0 => Some(DeliveryMode::Fixed),
+            1 => Some(DeliveryMode::LowestPriority),
+            2 => Some(DeliveryMode::Smi),
+            4 => Some(DeliveryMode::Nmi),
+            5 => Some(DeliveryMode::Init),
+            6 => Some(DeliveryMode::StartUp),
+            7 => Some(DeliveryMode::ExtInt),
+            _ => None,
+        }
+    }
+
+    /// Returns the entry with the delivery mode set to `mode`.
+    pub fn with_delivery_mode(self, mode: DeliveryMode) -> Self {
+        LvtEntry(
+            (self.0 & !Self::DELIVERY_MODE_MASK) | ((mode as u32) << Self::DELIVERY_MODE_SHIFT),
+        )
+    }
+
+    /// Returns whether the interrupt is level triggered.
+    pub fn level_triggered(self) -> bool {
+        self.0 & Self::LEVEL_TRIGGERED != 0
+    }
+
+    /// Returns the entry with the trigger mode set to level if `level` is true,
+    /// edge otherwise.
+    pub fn with_level_triggered(self, level: bool) -> Self {
+        LvtEntry(set_bits(self.0, Self::LEVEL_TRIGGERED, level))
+    }
+
+    /// Returns whether the interrupt is masked.
+    pub fn masked(self) -> bool {
+        self.0 & Self::MASKED != 0
+    }
+
+    /// Returns the entry with the interrupt masked if `masked` is true.
+    pub fn with_masked(self, masked: bool) -> Self {
+        LvtEntry(set_bits(self.0, Self::MASKED, masked))
+    }
+
+    /// Returns the timer mode, or `None` for the reserved encoding.
+    ///
+    /// This is only meaningful for the [`Lvt::Timer`] entry.
+    pub fn timer_mode(self) -> Option<TimerMode> {
+        match (self.0 & Self::TIMER_MODE_MASK) >> Self::TIMER_MODE_SHIFT {
+            0 => Some(TimerMode::OneShot),
+            1 => Some(TimerMode::Periodic),
+            2 => Some(TimerMode::TscDeadline),
+            _ => None,
+        }
+    }
+
+    /// Returns the entry with the timer mode set to `mode`.
+    pub fn with_timer_mode(self, mode: TimerMode) -> Self {
+        LvtEntry((self.0 & !Self::TIMER_MODE_MASK) | ((mode as u32) << Self::TIMER_MODE_SHIFT))
+    }
+}
+
+fn set_bits(value: u32, bits: u32, set: bool) -> u32 {
+    if set { value | bits } else { value & !bits }
+}
+
+/// A typed view over the `kvm_lapic_state` returned by `VcpuFd::get_lapic`.
+///
+/// The registers are stored in little-endian order at their offset in the APIC
+/// page. The ID register holds the xAPIC ID in bits 31:24, unless the APIC is in
+/// x2APIC mode and `KVM_CAP_X2APIC_API` was enabled with
+/// `KVM_X2APIC_API_USE_32BIT_IDS`, in which case it holds the full 32-bit x2APIC ID.
+/// Use [`LapicState::new_x2apic`] for the latter.
+///
+/// # Example
+///
+/// ```rust
+/// use kvm_ioctls::{DeliveryMode, Kvm, LapicState, Lvt};
+///
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// vm.create_irq_chip().unwrap();
+/// let vcpu = vm.create_vcpu(0).unwrap();
+///
+/// // Route the 8259 PIC to LINT0 and NMIs to LINT1, like a legacy BIOS does.
+/// let mut lapic = LapicState::new(vcpu.get_lapic().unwrap());
+/// let lint0 = lapic.lvt(Lvt::Lint0).with_delivery_mode(DeliveryMode::ExtInt);
+/// lapic.set_lvt(Lvt::Lint0, lint0);
+/// let lint1 = lapic.lvt(Lvt::Lint1).with_delivery_mode(DeliveryMode::Nmi);
+/// lapic.set_lvt(Lvt::Lint1, lint1);
+/// vcpu.set_lapic(lapic.as_raw()).unwrap();
+///
+/// assert_eq!(lapic.apic_id(), 0);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LapicState {
+    state: kvm_lapic_state,
+    x2apic_ids: bool,
+}
+
+impl LapicState {
+    const ISR: usize = 0x100;
+    const TMR: usize = 0x180;
+    const IRR: usize = 0x200;
+    const XAPIC_ID_SHIFT: u32 = 24;
+
+    /// Creates a view over `state`, where the ID register holds an xAPIC ID.
+    pub fn new(state: kvm_lapic_state) -> Self {
+        LapicState {
+            state,
+            x2apic_ids: false,
+        }
+    }
+
+    /// Creates a view over `state`, where the ID register holds a 32-bit x2APIC ID.
+    pub fn new_x2apic(state: kvm_lapic_state) -> Self {
+        LapicState {
+            state,
+            x2apic_ids: true,
+        }
+    }
+
+    /// Returns the underlying `kvm_lapic_state`, to be passed to `VcpuFd::set_lapic`.
+    pub fn as_raw(&self) -> &kvm_lapic_state {
+        &self.state
+    }
+
+    /// Consumes the view and returns the underlying `kvm_lapic_state`.
+    pub fn into_raw(self) -> kvm_lapic_state {
+        self.state
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let bytes = &self.state.regs[offset..offset + 4];
+        u32::from_le_bytes([
+            bytes[0] as u8,
+            bytes[1] as u8,
+            bytes[2] as u8,
+            bytes[3] as u8,
+        ])
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        for (reg, byte) in self.state.regs[offset..offset + 4]
+            .iter_mut()
+            .zip(value.to_le_bytes())
+        {
+            *reg = byte as _;
+        }
+    }
+
+    /// Returns the value of `reg`.
+    pub fn reg(&self, reg: LapicReg) -> u32 {
+        self.read(reg as usize)
+    }
+
+    /// Sets the value of `reg`.
+    pub fn set_reg(&mut self, reg: LapicReg, value: u32) {
+        self.write(reg as usize, value)
+    }
+
+    /// Returns the local APIC ID.
+    pub fn apic_id(&self) -> u32 {
+        let id = self.reg(LapicReg::Id);
+        if self.x2apic_ids {
+            id
+        } else {
+            id >> Self::XAPIC_ID_SHIFT
+        }
+    }
+
+    /// Sets the local APIC ID.
+    ///
+    /// Only the low 8 bits of `id` are kept for an xAPIC ID.
+    pub fn set_apic_id(&mut self, id: u32) {
+        let id = if self.x2apic_ids {
+            id
+        } else {
+            (id & 0xff) << Self::XAPIC_ID_SHIFT
+        };
+        self.set_reg(LapicReg::Id, id)
+    }
+
+    /// Returns the APIC version, from bits 7:0 of the version register.
+    pub fn version(&self) -> u8 {
+        self.reg(LapicReg::Version) as u8
+    }
+
+    /// Returns the number of LVT entries, from bits 23:16 of the version register.
+    pub fn max_lvt_entries(&self) -> u8 {
+        ((self.reg(LapicReg::Version) >> 16) as u8).wrapping_add(1)
+    }
+
+    /// Returns the task priority.
+    pub fn tpr(&self) -> u8 {
+        self.reg(LapicReg::Tpr) as u8
+    }
+
+    /// Sets the task priority.
+    pub fn set_tpr(&mut self, tpr: u8) {
+        self.set_reg(LapicReg::Tpr, u32::from(tpr))
+    }
+
+    /// Returns the processor priority.
+    pub fn ppr(&self) -> u8 {
+        self.reg(LapicReg::Ppr) as u8
+    }
+
+    /// Returns the spurious interrupt vector register.
+    pub fn svr(&self) -> u32 {
+        self.reg(LapicReg::Svr)
+    }
+
+    /// Returns whether the APIC is software enabled, from bit 8 of the spurious
+    /// interrupt vector register.
+    pub fn software_enabled(&self) -> bool {
+        self.svr() & (1 << 8) != 0
+    }
+
+    /// Enables or disables the APIC in software.
+    pub fn set_software_enabled(&mut self, enabled: bool) {
+        let svr = set_bits(self.svr(), 1 << 8, enabled);
+        self.set_reg(LapicReg::Svr, svr)
+    }
+
+    /// Returns the interrupt command register, with the destination in the high half.
+    pub fn icr(&self) -> u64 {
+        u64::from(self.reg(LapicReg::IcrHigh)) << 32 | u64::from(self.reg(LapicReg::IcrLow))
+    }
+
+    /// Sets the interrupt command register.
+    pub fn set_icr(&mut self, icr: u64) {
+        self.set_reg(LapicReg::IcrLow, icr as u32);
+        self.set_reg(LapicReg::IcrHigh, (icr >> 32) as u32);
+    }
+
+    /// Returns the `lvt` entry of the local vector table.
+    pub fn lvt(&self, lvt: Lvt) -> LvtEntry {
+        LvtEntry(self.reg(lvt.reg()))
+    }
+
+    /// Sets the `lvt` entry of the local vector table.
+    pub fn set_lvt(&mut self, lvt: Lvt, entry: LvtEntry) {
+        self.set_reg(lvt.reg(), entry.0)
+    }
+
+    fn vector_bit(&self, base: usize, vector: u8) -> bool {
+        let offset = base + usize::from(vector / 32) * 0x10;
+        self.read(offset) & (1 << (vector % 32)) != 0
+    }
+
+    fn set_vector_bit(&mut self, base: usize, vector: u8, set: bool) {
+        let offset = base + usize::from(vector / 32) * 0x10;
+        let value = set_bits(self.read(offset), 1 << (vector % 32), set);
+        self.write(offset, value)
+    }
+
+    /// Returns whether `vector` is in service (`ISR`).
+    pub fn in_service(&self, vector: u8) -> bool {
+        self.vector_bit(Self::ISR, vector)
+    }
+
+    /// Returns whether `vector` is level triggered (`TMR`).
+    pub fn level_triggered(&self, vector: u8) -> bool {
+        self.vector_bit(Self::TMR, vector)
+    }
+
+    /// Returns whether `vector` is pending (`IRR`).
+    pub fn pending(&self, vector: u8) -> bool {
+        self.vector_bit(Self::IRR, vector)
+    }
+
+    /// Marks `vector` as pending (`IRR`), or clears it.
+    pub fn set_pending(&mut self, vector: u8, pending: bool) {
+        self.set_vector_bit(Self::IRR, vector, pending)
+    }
+
+    /// Returns the initial count of the timer.
+    pub fn timer_initial_count(&self) -> u32 {
+        self.reg(LapicReg::TimerInitialCount)
+    }
+
+    /// Sets the initial count of the timer.
+    pub fn set_timer_initial_count(&mut self, count: u32) {
+        self.set_reg(LapicReg::TimerInitialCount, count)
+    }
+
+    /// Returns the current count of the timer.
+    pub fn timer_current_count(&self) -> u32 {
+        self.reg(LapicReg::TimerCurrentCount)
+    }
+
+    /// Returns the divisor applied to the bus clock by the timer.
+    pub fn timer_divisor(&self) -> u32 {
+        let dcr = self.reg(LapicReg::TimerDivideConfig);
+        match (dcr & 0x3) | ((dcr & 0x8) >> 1) {
+            0x7 => 1,
+            value => 2 << value,
+        }
+    }
+
+    /// Sets the divisor applied to the bus clock by the timer.
+    ///
+    /// `divisor` is rounded down to a power of two between 1 and 128.
+    pub fn set_timer_divisor(&mut self, divisor: u32) {
+        let value = match divisor.clamp(1, 128).ilog2() {
+            0 => 0x7,
+            log => log - 1,
+        };
+        self.set_reg(
+            LapicReg::TimerDivideConfig,
+            (value & 0x3) | ((value & 0x4) << 1),
+        )
+    }
+}
+
+impl From<kvm_lapic_state> for LapicState {
+    fn from(state: kvm_lapic_state) -> Self {
+        LapicState::new(state)
+    }
+}
+
+impl From<LapicState> for kvm_lapic_state {
+    fn from(lapic: LapicState) -> Self {
+        lapic.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Kvm, msr};
+    use kvm_bindings::{
+        KVM_CAP_X2APIC_API, KVM_MAX_CPUID_ENTRIES, KVM_X2APIC_API_USE_32BIT_IDS, Msrs,
+        kvm_enable_cap, kvm_msr_entry,
+    };
+
+    #[test]
+    fn test_lapic_state() {
+        let mut lapic = LapicState::new(kvm_lapic_state::default());
+        lapic.set_apic_id(0x1_2345);
+        assert_eq!(lapic.reg(LapicReg::Id), 0x4500_0000);
+        assert_eq!(lapic.apic_id(), 0x45);
+        assert_eq!(lapic.as_raw().regs[0x23], 0x45);
+
+        let mut x2apic = LapicState::new_x2apic(lapic.into_raw());
+        assert_eq!(x2apic.apic_id(), 0x4500_0000);
+        x2apic.set_apic_id(0x1_2345);
+        assert_eq!(x2apic.reg(LapicReg::Id), 0x1_2345);
+
+        lapic.set_icr(0x0300_0000_0000_4630);
+        assert_eq!(lapic.reg(LapicReg::IcrLow), 0x4630);
+        assert_eq!(lapic.reg(LapicReg::IcrHigh), 0x0300_0000);
+        assert_eq!(lapic.icr(), 0x0300_0000_0000_4630);
+
+        lapic.set_pending(0xec, true);
+        assert!(lapic.pending(0xec));
+        assert!(!lapic.pending(0xed));
+        assert_eq!(lapic.reg(LapicReg::Id), 0x4500_0000);
+        assert_eq!(lapic.as_raw().regs[0x200 + 7 * 0x10 + 1], 0x10);
+        lapic.set_pending(0xec, false);
+        assert!(!lapic.pending(0xec));
+
+        for divisor in [1, 2, 4, 8, 16, 32, 64, 128] {
+            lapic.set_timer_divisor(divisor);
+            assert_eq!(lapic.timer_divisor(), divisor);
+        }
+        lapic.set_reg(LapicReg::TimerDivideConfig, 0xb);
+        assert_eq!(lapic.timer_divisor(), 1);
+
+        let timer = LvtEntry::default()
+            .with_vector(0xef)
+            .with_timer_mode(TimerMode::TscDeadline)
+            .with_masked(true);
+        lapic.set_lvt(Lvt::Timer, timer);
+        assert_eq!(lapic.reg(LapicReg::LvtTimer), 0x5_00ef);
+        let timer = lapic.lvt(Lvt::Timer);
+        assert_eq!(timer.vector(), 0xef);
+        assert_eq!(timer.timer_mode(), Some(TimerMode::TscDeadline));
+        assert_eq!(timer.delivery_mode(), Some(DeliveryMode::Fixed));
+        assert!(timer.masked());
+        assert!(!timer.with_masked(false).masked());
+
+        let lint1 = LvtEntry(0x0001_0700).with_delivery_mode(DeliveryMode::Nmi);
+        assert_eq!(lint1, LvtEntry(0x0001_0400));
+        assert_eq!(LvtEntry(0x300).delivery_mode(), None);
+        assert!(
+            LvtEntry::default()
+                .with_level_triggered(true)
+                .level_triggered()
+        );
+    }
+
+    #[test]
+    fn test_lapic_vcpu() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        let vcpu = vm.create_vcpu(3).unwrap();
+
+        let mut lapic = LapicState::new(vcpu.get_lapic().unwrap());
+        assert_eq!(lapic.apic_id(), 3);
+        assert!(lapic.max_lvt_entries() >= 6);
+        lapic.set_lvt(
+            Lvt::Lint0,
+            lapic
+                .lvt(Lvt::Lint0)
+                .with_delivery_mode(DeliveryMode::ExtInt),
+        );
+        lapic.set_lvt(
+            Lvt::Lint1,
+            lapic.lvt(Lvt::Lint1).with_delivery_mode(DeliveryMode::Nmi),
+        );
+        lapic.set_tpr(0x20);
+        vcpu.set_lapic(lapic.as_raw()).unwrap();
+
+        let lapic = LapicState::from(vcpu.get_lapic().unwrap());
+        assert_eq!(
+            lapic.lvt(Lvt::Lint0).delivery_mode(),
+            Some(DeliveryMode::ExtInt)
+        );
+        assert_eq!(
+            lapic.lvt(Lvt::Lint1).delivery_mode(),
+            Some(DeliveryMode::Nmi)
+        );
+        assert_eq!(lapic.tpr(), 0x20);
+    }
+
+    #[test]
+    fn test_lapic_x2apic_ids() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_X2APIC_API,
+            args: [u64::from(KVM_X2APIC_API_USE_32BIT_IDS), 0, 0, 0],
+            ..Default::default()
+        };
+        vm.enable_cap(&cap).unwrap();
+        let vcpu = vm.create_vcpu(300).unwrap();
+
+        // The APIC is still in xAPIC mode after reset.
+        let lapic = LapicState::new(vcpu.get_lapic().unwrap());
+        assert_eq!(lapic.apic_id(), 300 & 0xff);
+
+        // Switch to x2APIC mode, which needs x2APIC in the guest CPUID.
+        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        vcpu.set_cpuid2(&cpuid).unwrap();
+        let apic_base = kvm_msr_entry {
+            index: msr::IA32_APIC_BASE,
+            data: 0xfee0_0000 | (1 << 11) | (1 << 10),
+            ..Default::default()
+        };
+        vcpu.set_msrs(&Msrs::from_entries(&[apic_base]).unwrap())
+            .unwrap();
+        let lapic = LapicState::new_x2apic(vcpu.get_lapic().unwrap());
+        assert_eq!(lapic.apic_id(), 300);
+    }
+}
@@ -229,18 +229,100 @@
 
 #[macro_use]
 mod kvm_ioctls;
+#[cfg(target_arch = "aarch64")]
+mod arm64_reg;
 mod cap;
+mod coalesced;
+#[cfg(target_arch = "x86_64")]
+mod cpu_features;
+#[cfg(target_arch = "x86_64")]
+mod cpuid;
+mod device_attr;
+mod error;
+mod exit_stats;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod gsi_routing;
+mod hypervisor;
 mod ioctls;
+#[cfg(target_arch = "x86_64")]
+mod irqchip;
+mod kicker;
+#[cfg(target_arch = "x86_64")]
+mod lapic;
+mod memslot;
+#[cfg(feature = "mock")]
+pub mod mock;
+#[cfg(target_arch = "x86_64")]
+pub mod msr;
+mod registration;
+mod replay;
+#[cfg(target_arch = "riscv64")]
+mod riscv_aia;
+#[cfg(target_arch = "riscv64")]
+mod riscv_reg;
+#[cfg(feature = "runner")]
+pub mod runner;
+mod vfio;
+#[cfg(target_arch = "aarch64")]
+mod vgic;
+#[cfg(target_arch = "x86_64")]
+mod xsave;
 
+#[cfg(target_arch = "aarch64")]
+pub use arm64_reg::{Arm64Reg, Arm64RegClass};
 pub use cap::Cap;
+pub use coalesced::{CoalescedAccess, CoalescedAccessKind, CoalescedIoDrain};
+#[cfg(target_arch = "x86_64")]
+pub use cpu_features::{CpuFeatures, Incompatibility};
+#[cfg(target_arch = "x86_64")]
+pub use cpuid::{CpuModel, CpuTopology, CpuidBit, CpuidEditor, CpuidReg, X86Feature};
+pub use device_attr::{DeviceAttributes, Pod};
+pub use error::{CheckedVcpuFd, CheckedVmFd, ErrorArg, IoctlContext, IoctlError};
+pub use exit_stats::{ExitEvent, ExitObserver, ExitReasonStats, ExitStats, ExitStatsSnapshot};
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub use gsi_routing::{GsiRoute, GsiRoutingTable, MAX_GSI_ROUTES};
+pub use hypervisor::{Hypervisor, Vcpu, Vm};
 pub use ioctls::device::DeviceFd;
 pub use ioctls::system::Kvm;
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 pub use ioctls::vcpu::reg_size;
 pub use ioctls::vcpu::{HypercallExit, VcpuExit, VcpuFd};
+#[cfg(target_arch = "x86_64")]
+pub use irqchip::{IoapicRouting, IoapicState, PicState, Polarity, RedirectionEntry};
+pub use kicker::VcpuKicker;
+#[cfg(target_arch = "x86_64")]
+pub use lapic::{DeliveryMode, LapicReg, LapicState, Lvt, LvtEntry, TimerMode};
+pub use memslot::{MemSlot, MemSlotFlags, MemoryMapping, MemorySlots};
+pub use registration::IoEventRegistration;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub use registration::IrqFdRegistration;
+pub use replay::{ExitRecord, ExitRecorder, ExitReplayer, ExitTrace};
+#[cfg(target_arch = "riscv64")]
+pub use riscv_aia::{AiaMode, RiscvAia, RiscvAiaBuilder, RiscvAiaState};
+#[cfg(target_arch = "riscv64")]
+pub use riscv_reg::{RiscvReg, RiscvRegClass};
+pub use vfio::VfioKvmDevice;
+#[cfg(target_arch = "aarch64")]
+pub use vgic::{Its, ItsState, VgicV3, VgicV3Builder, VgicV3CpuState, VgicV3State};
+#[cfg(target_arch = "x86_64")]
+pub use xsave::{XsaveArea, XsaveError, XsaveLayout, XstateComponent, XstateComponentInfo};
 
 #[cfg(target_arch = "x86_64")]
-pub use ioctls::vcpu::{KvmNestedStateBuffer, MsrExitReason, ReadMsrExit, SyncReg, WriteMsrExit};
+pub use ioctls::vcpu::{
+    KvmNestedStateBuffer, MsrExitReason, ReadMsrExit, RunFlags, SyncReg, WriteMsrExit,
+};
 
 pub use ioctls::vm::{IoEventAddress, NoDatamatch, VmFd};
 #[cfg(target_arch = "x86_64")]
@@ -0,0 +1,492 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::ptr::{self, NonNull};
+use std::sync::Mutex;
+
+use bitflags::bitflags;
+use kvm_bindings::{
+    KVM_MEM_GUEST_MEMFD, KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_READONLY, kvm_userspace_memory_region,
+    kvm_userspace_memory_region2,
+};
+use vmm_sys_util::errno;
+
+use crate::ioctls::Result;
+use crate::ioctls::vm::VmFd;
+
+bitflags! {
+    /// Flags of a memory slot allocated by [`MemorySlots`].
+    #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+    pub struct MemSlotFlags: u32 {
+        /// Track the pages written by the guest, see [`MemSlot::get_dirty_log`].
+        const LOG_DIRTY_PAGES = KVM_MEM_LOG_DIRTY_PAGES;
+        /// Forbid guest writes, which cause MMIO exits instead (`KVM_CAP_READONLY_MEM`).
+        const READONLY = KVM_MEM_READONLY;
+    }
+}
+
+fn page_size() -> Result<usize> {
+    // SAFETY: We trust the sysconf libc function and we're calling it with a correct parameter.
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        -1 => Err(errno::Error::last()),
+        ps => Ok(ps as usize),
+    }
+}
+
+/// Anonymous host memory mapping, unmapped when dropped.
+///
+/// The mapping is shared with the guest once it is added to a [`MemSlot`], so it is
+/// only accessed through copies, never through references.
+#[derive(Debug)]
+pub struct MemoryMapping {
+    addr: NonNull<u8>,
+    size: usize,
+}
+
+// SAFETY: The mapping is owned by the struct and only accessed through raw copies,
+// which are not subject to aliasing rules.
+unsafe impl Send for MemoryMapping {}
+// SAFETY: See above.
+unsafe impl Sync for MemoryMapping {}
+
+impl MemoryMapping {
+    /// Maps `size` bytes of zeroed private anonymous memory.
+    ///
+    /// Fails with `EINVAL` if `size` is zero or not a multiple of the page size.
+    pub fn new(size: usize) -> Result<Self> {
+        if size == 0 || size % page_size()? != 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        // SAFETY: We are creating a new anonymous mapping, which does not affect any
+        // existing memory, and we check the return value.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(errno::Error::last());
+        }
+        Ok(MemoryMapping {
+            // SAFETY: mmap never returns a null mapping on success.
+            addr: unsafe { NonNull::new_unchecked(addr as *mut u8) },
+            size,
+        })
+    }
+
+    /// Returns the host address of the mapping.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr.as_ptr()
+    }
+
+    /// Returns the size of the mapping in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(errno::Error::new(libc::EINVAL)),
+        }
+    }
+
+    /// Copies `data` into the mapping at `offset`.
+    pub fn write_at(&self, offset: usize, data: &[u8]) -> Result<()> {
+        self.check_range(offset, data.len())?;
+        // SAFETY: The range was checked to be within the mapping, which cannot overlap
+        // `data` since it is never borrowed.
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.as_ptr().add(offset), data.len()) };
+        Ok(())
+    }
+
+    /// Copies the bytes of the mapping at `offset` into `data`.
+    pub fn read_at(&self, offset: usize, data: &mut [u8]) -> Result<()> {
+        self.check_range(offset, data.len())?;
+        // SAFETY: The range was checked to be within the mapping, which cannot overlap
+        // `data` since it is never borrowed.
+        unsafe {
+            ptr::copy_nonoverlapping(self.as_ptr().add(offset), data.as_mut_ptr(), data.len())
+        };
+        Ok(())
+    }
+}
+
+impl Drop for MemoryMapping {
+    fn drop(&mut self) {
+        // SAFETY: We own the mapping, which is not used after this point.
+        unsafe { libc::munmap(self.addr.as_ptr() as *mut libc::c_void, self.size) };
+    }
+}
+
+// Guest physical range of an allocated slot, by slot id.
+type SlotMap = BTreeMap<u32, (u64, u64)>;
+
+/// Allocator of the memory slots of a VM.
+///
+/// Slots are handed out as [`MemSlot`] guards that own their host mapping and
+/// delete the slot when dropped, before the mapping is unmapped. The guest physical
+/// ranges of the slots are checked not to overlap before calling
+/// `KVM_SET_USER_MEMORY_REGION`. `V` is either a reference to the `VmFd` or a smart
+/// pointer to it, such as `Arc<VmFd>`.
+///
+/// # Example
+///
+/// ```rust
+/// # use kvm_ioctls::{Kvm, MemSlotFlags, MemoryMapping, MemorySlots};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let slots = MemorySlots::new(&vm, kvm.get_nr_memslots());
+///
+/// let ram = MemoryMapping::new(0x10_0000).unwrap();
+/// let slot = slots
+///     .insert(0, ram, MemSlotFlags::LOG_DIRTY_PAGES)
+///     .unwrap();
+/// assert!(slot.get_dirty_log().unwrap().iter().all(|&word| word == 0));
+///
+/// // Overlapping slots are rejected.
+/// let rom = MemoryMapping::new(0x1000).unwrap();
+/// assert!(slots.insert(0xf_f000, rom, MemSlotFlags::READONLY).is_err());
+/// ```
+#[derive(Debug)]
+pub struct MemorySlots<V: Borrow<VmFd>> {
+    vm: V,
+    nr_slots: u32,
+    slots: Mutex<SlotMap>,
+}
+
+impl<V: Borrow<VmFd>> MemorySlots<V> {
+    /// Creates an allocator for the slots `0..nr_slots` of `vm`, usually
+    /// `Kvm::get_nr_memslots()`.
+    ///
+    /// The slots must not be used outside of the allocator.
+    pub fn new(vm: V, nr_slots: usize) -> Self {
+        MemorySlots {
+            vm,
+            nr_slots: u32::try_from(nr_slots).unwrap_or(u32::MAX),
+            slots: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the VM of the slots.
+    pub fn vm(&self) -> &VmFd {
+        self.vm.borrow()
+    }
+
+    /// Returns the number of allocated slots.
+    pub fn len(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    /// Returns whether no slot is allocated.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the id of the slot containing `guest_phys_addr`, if any.
+    pub fn find(&self, guest_phys_addr: u64) -> Option<u32> {
+        let slots = self.slots.lock().unwrap();
+        slots
+            .iter()
+            .find(|(_, (start, end))| (*start..*end).contains(&guest_phys_addr))
+            .map(|(slot, _)| *slot)
+    }
+
+    /// Maps `mapping` at `guest_phys_addr` in a new slot.
+    ///
+    /// Fails with `EINVAL` if `guest_phys_addr` is not page aligned or the slot
+    /// would end past the guest physical address space, with `EEXIST` if it
+    /// overlaps another slot and with `ENOSPC` if there is no free slot.
+    pub fn insert(
+        &self,
+        guest_phys_addr: u64,
+        mapping: MemoryMapping,
+        flags: MemSlotFlags,
+    ) -> Result<MemSlot<'_, V>> {
+        self.insert_region(guest_phys_addr, mapping, flags, None)
+    }
+
+    /// Maps `mapping` at `guest_phys_addr` in a new slot whose private memory is
+    /// backed by `guest_memfd` from `offset`, using `KVM_SET_USER_MEMORY_REGION2`.
+    ///
+    /// `guest_memfd` must be created by `VmFd::create_guest_memfd` on the same VM.
+    /// KVM keeps its own reference to the file, which can be closed afterwards.
+    pub fn insert_guest_memfd(
+        &self,
+        guest_phys_addr: u64,
+        mapping: MemoryMapping,
+        flags: MemSlotFlags,
+        guest_memfd: &File,
+        offset: u64,
+    ) -> Result<MemSlot<'_, V>> {
+        self.insert_region(guest_phys_addr, mapping, flags, Some((guest_memfd, offset)))
+    }
+
+    fn insert_region(
+        &self,
+        guest_phys_addr: u64,
+        mapping: MemoryMapping,
+        flags: MemSlotFlags,
+        guest_memfd: Option<(&File, u64)>,
+    ) -> Result<MemSlot<'_, V>> {
+        let size = mapping.size() as u64;
+        let end = match guest_phys_addr.checked_add(size) {
+            Some(end) if guest_phys_addr % page_size()? as u64 == 0 => end,
+            _ => return Err(errno::Error::new(libc::EINVAL)),
+        };
+
+        let slot = {
+            let mut slots = self.slots.lock().unwrap();
+            if slots
+                .values()
+                .any(|&(start, slot_end)| guest_phys_addr < slot_end && start < end)
+            {
+                return Err(errno::Error::new(libc::EEXIST));
+            }
+            let slot = (0..self.nr_slots)
+                .find(|slot| !slots.contains_key(slot))
+                .ok_or(errno::Error::new(libc::ENOSPC))?;
+            slots.insert(slot, (guest_phys_addr, end));
+            slot
+        };
+
+        // The slot is released by the drop of `memslot` if KVM rejects it.
+        let memslot = MemSlot {
+            slots: self,
+            slot,
+            guest_phys_addr,
+            flags,
+            guest_memfd: guest_memfd.map(|(file, offset)| (file.as_raw_fd() as u32, offset)),
+            mapping,
+        };
+        memslot.set_region(size)?;
+        Ok(memslot)
+    }
+}
+
+/// Memory slot allocated by [`MemorySlots`], deleted when dropped.
+#[derive(Debug)]
+pub struct MemSlot<'a, V: Borrow<VmFd>> {
+    slots: &'a MemorySlots<V>,
+    slot: u32,
+    guest_phys_addr: u64,
+    flags: MemSlotFlags,
+    // The guest_memfd and offset used by KVM_SET_USER_MEMORY_REGION2, if any.
+    guest_memfd: Option<(u32, u64)>,
+    mapping: MemoryMapping,
+}
+
+impl<V: Borrow<VmFd>> MemSlot<'_, V> {
+    /// Returns the id of the slot.
+    pub fn slot(&self) -> u32 {
+        self.slot
+    }
+
+    /// Returns the guest physical address of the slot.
+    pub fn guest_phys_addr(&self) -> u64 {
+        self.guest_phys_addr
+    }
+
+    /// Returns the size of the slot in bytes.
+    pub fn size(&self) -> u64 {
+        self.mapping.size() as u64
+    }
+
+    /// Returns the flags of the slot.
+    pub fn flags(&self) -> MemSlotFlags {
+        self.flags
+    }
+
+    /// Returns whether the slot is backed by a guest_memfd.
+    pub fn has_guest_memfd(&self) -> bool {
+        self.guest_memfd.is_some()
+    }
+
+    /// Returns the host mapping of the slot.
+    pub fn mapping(&self) -> &MemoryMapping {
+        &self.mapping
+    }
+
+    /// Starts or stops dirty page tracking for the slot.
+    pub fn set_dirty_logging(&mut self, enable: bool) -> Result<()> {
+        let old = self.flags;
+        self.flags.set(MemSlotFlags::LOG_DIRTY_PAGES, enable);
+        self.set_region(self.size())
+            .inspect_err(|_| self.flags = old)
+    }
+
+    /// Returns the bitmap of the pages of the slot written since the last call,
+    /// as returned by `VmFd::get_dirty_log`.
+    ///
+    /// Fails with `ENOENT` if dirty page tracking is not enabled.
+    pub fn get_dirty_log(&self) -> Result<Vec<u64>> {
+        if !self.flags.contains(MemSlotFlags::LOG_DIRTY_PAGES) {
+            return Err(errno::Error::new(libc::ENOENT));
+        }
+        self.slots
+            .vm()
+            .get_dirty_log(self.slot, self.mapping.size())
+    }
+
+    /// Returns the guest physical addresses of the pages of the slot written since
+    /// the last call to this function or [`get_dirty_log`](Self::get_dirty_log).
+    pub fn dirty_pages(&self) -> Result<Vec<u64>> {
+        let page_size = page_size()? as u64;
+        let bitmap = self.get_dirty_log()?;
+        Ok(bitmap
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &word)| {
+                (0..64)
+                    .filter(move |bit| word & (1 << bit) != 0)
+                    .map(move |bit| (i as u64 * 64 + bit) * page_size)
+            })
+            .map(|offset| self.guest_phys_addr + offset)
+            .collect())
+    }
+
+    // Creates, updates or, with a zero size, deletes the slot.
+    fn set_region(&self, size: u64) -> Result<()> {
+        let vm = self.slots.vm();
+        let userspace_addr = self.mapping.as_ptr() as u64;
+        match self.guest_memfd {
+            None => {
+                let region = kvm_userspace_memory_region {
+                    slot: self.slot,
+                    flags: self.flags.bits(),
+                    guest_phys_addr: self.guest_phys_addr,
+                    memory_size: size,
+                    userspace_addr,
+                };
+                // SAFETY: The mapping is owned by the slot, which deletes the memory region
+                // before it is unmapped, and the allocator checked that the region does
+                // not overlap another slot.
+                unsafe { vm.set_user_memory_region(region) }
+            }
+            Some((guest_memfd, guest_memfd_offset)) => {
+                let region = kvm_userspace_memory_region2 {
+                    slot: self.slot,
+                    flags: self.flags.bits() | KVM_MEM_GUEST_MEMFD,
+                    guest_phys_addr: self.guest_phys_addr,
+                    memory_size: size,
+                    userspace_addr,
+                    guest_memfd_offset,
+                    guest_memfd,
+                    ..Default::default()
+                };
+                // SAFETY: As above. KVM only uses `guest_memfd` when creating the slot,
+                // and then it is a guest_memfd provided by the caller.
+                unsafe { vm.set_user_memory_region2(region) }
+            }
+        }
+    }
+}
+
+impl<V: Borrow<VmFd>> Drop for MemSlot<'_, V> {
+    fn drop(&mut self) {
+        // Deleting a slot only fails if it does not exist, in which case the kernel
+        // does not reference the mapping either.
+        let _ = self.set_region(0);
+        self.slots.slots.lock().unwrap().remove(&self.slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kvm;
+
+    #[test]
+    fn test_memory_mapping() {
+        assert_eq!(MemoryMapping::new(0).unwrap_err().errno(), libc::EINVAL);
+        assert_eq!(
+            MemoryMapping::new(0x1001).unwrap_err().errno(),
+            libc::EINVAL
+        );
+
+        let mapping = MemoryMapping::new(0x2000).unwrap();
+        assert_eq!(mapping.size(), 0x2000);
+        mapping.write_at(0x1ffe, &[0xaa, 0x55]).unwrap();
+        assert_eq!(
+            mapping.write_at(0x1fff, &[0, 0]).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        let mut data = [0; 3];
+        mapping.read_at(0x1ffd, &mut data).unwrap();
+        assert_eq!(data, [0, 0xaa, 0x55]);
+    }
+
+    #[test]
+    fn test_memory_slots() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let slots = MemorySlots::new(&vm, 2);
+
+        let low = slots
+            .insert(
+                0,
+                MemoryMapping::new(0x4000).unwrap(),
+                MemSlotFlags::empty(),
+            )
+            .unwrap();
+        assert_eq!(low.slot(), 0);
+        assert_eq!(low.get_dirty_log().unwrap_err().errno(), libc::ENOENT);
+
+        let mapping = || MemoryMapping::new(0x1000).unwrap();
+        assert_eq!(
+            slots
+                .insert(0x3000, mapping(), MemSlotFlags::empty())
+                .unwrap_err()
+                .errno(),
+            libc::EEXIST
+        );
+        assert_eq!(
+            slots
+                .insert(0x4800, mapping(), MemSlotFlags::empty())
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            slots
+                .insert(!0xfff, mapping(), MemSlotFlags::empty())
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+
+        let mut high = slots
+            .insert(0x10_0000, mapping(), MemSlotFlags::empty())
+            .unwrap();
+        assert_eq!(high.slot(), 1);
+        assert_eq!(slots.find(0x10_0fff), Some(1));
+        assert_eq!(slots.find(0x10_1000), None);
+        assert_eq!(
+            slots
+                .insert(0x20_0000, mapping(), MemSlotFlags::empty())
+                .unwrap_err()
+                .errno(),
+            libc::ENOSPC
+        );
+
+        high.set_dirty_logging(true).unwrap();
+        assert_eq!(high.get_dirty_log().unwrap(), [0]);
+        assert!(high.dirty_pages().unwrap().is_empty());
+
+        // The slot id and the guest physical range are reused after a drop.
+        drop(low);
+        assert_eq!(slots.len(), 1);
+        let low = slots
+            .insert(0x2000, mapping(), MemSlotFlags::empty())
+            .unwrap();
+        assert_eq!(low.slot(), 0);
+    }
+}
@@ -0,0 +1,849 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A fake hypervisor for unit testing code written against the [`Hypervisor`],
+//! [`Vm`] and [`Vcpu`] traits without `/dev/kvm`.
+//!
+//! [`MockKvm`] creates [`MockVm`]s, which create [`MockVcpu`]s. A VM and its
+//! vCPUs share a state that records every call made through the traits as a
+//! [`MockCall`], keeps the memory slots, irqfds and ioeventfds registered by the
+//! code under test, and holds the [`MockExit`]s scripted for each vCPU. The
+//! bookkeeping fails with the error KVM would return in the common cases, such
+//! as `EEXIST` for overlapping memory slots.
+//!
+//! # Example
+//!
+//! ```rust
+//! use kvm_ioctls::mock::{MockCall, MockExit, MockKvm};
+//! use kvm_ioctls::{Hypervisor, Vcpu, VcpuExit, Vm};
+//!
+//! let kvm = MockKvm::new();
+//! let vm = kvm.create_vm().unwrap();
+//! let mut vcpu = vm.create_vcpu(0).unwrap();
+//! vm.push_exits(0, [MockExit::IoOut(0x3f8, vec![b'a']), MockExit::Hlt]);
+//!
+//! assert!(matches!(vcpu.run(), Ok(VcpuExit::IoOut(0x3f8, b"a"))));
+//! assert!(matches!(vcpu.run(), Ok(VcpuExit::Hlt)));
+//! assert_eq!(vm.calls()[0], MockCall::CreateVcpu(0));
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::os::raw::c_ulong;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use std::os::raw::c_int;
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use kvm_bindings::kvm_msi;
+#[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+use kvm_bindings::kvm_regs;
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::kvm_sregs;
+use kvm_bindings::kvm_userspace_memory_region;
+use vmm_sys_util::errno;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::cap::Cap;
+use crate::hypervisor::{Hypervisor, Vcpu, Vm};
+use crate::ioctls::Result;
+use crate::ioctls::vcpu::VcpuExit;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+use crate::ioctls::vcpu::reg_size;
+use crate::ioctls::vm::IoEventAddress;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// A call made to a [`MockVm`] or to one of its [`MockVcpu`]s, as recorded in
+/// [`MockVm::calls`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockCall {
+    /// `create_vcpu(id)`.
+    CreateVcpu(u64),
+    /// `set_user_memory_region(region)`.
+    SetUserMemoryRegion(kvm_userspace_memory_region),
+    /// `register_irqfd(fd, gsi)`.
+    RegisterIrqfd {
+        /// The file descriptor of the `EventFd`.
+        fd: RawFd,
+        /// The GSI triggered by the `EventFd`.
+        gsi: u32,
+    },
+    /// `unregister_irqfd(fd, gsi)`.
+    UnregisterIrqfd {
+        /// The file descriptor of the `EventFd`.
+        fd: RawFd,
+        /// The GSI triggered by the `EventFd`.
+        gsi: u32,
+    },
+    /// `register_ioevent(fd, addr, datamatch)`.
+    RegisterIoevent {
+        /// The file descriptor of the `EventFd`.
+        fd: RawFd,
+        /// The address of the ioeventfd.
+        addr: IoEventAddress,
+        /// The datamatch of the ioeventfd, if any.
+        datamatch: Option<u64>,
+    },
+    /// `unregister_ioevent(fd, addr, datamatch)`.
+    UnregisterIoevent {
+        /// The file descriptor of the `EventFd`.
+        fd: RawFd,
+        /// The address of the ioeventfd.
+        addr: IoEventAddress,
+        /// The datamatch of the ioeventfd, if any.
+        datamatch: Option<u64>,
+    },
+    /// `signal_msi(msi)`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    SignalMsi(kvm_msi),
+    /// `set_irq_line(irq, active)`.
+    SetIrqLine {
+        /// The IRQ line.
+        irq: u32,
+        /// The level of the line.
+        active: bool,
+    },
+    /// `run()` on the vCPU with the given ID.
+    Run(u64),
+    /// `set_regs(regs)` on the vCPU with the given ID.
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+    SetRegs(u64, kvm_regs),
+    /// `set_sregs(sregs)` on the vCPU with the given ID.
+    #[cfg(target_arch = "x86_64")]
+    SetSregs(u64, kvm_sregs),
+    /// `set_one_reg(reg_id, data)` on the vCPU with the given ID.
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    SetOneReg {
+        /// The ID of the vCPU.
+        vcpu: u64,
+        /// The ID of the register.
+        reg_id: u64,
+        /// The value of the register.
+        data: Vec<u8>,
+    },
+}
+
+/// The result of a call to [`MockVcpu::run`], scripted with [`MockVm::push_exit`].
+///
+/// Exits that carry data from the guest own it, and exits that expect data from
+/// the VMM give its size. The data of the last exit can be read back with
+/// [`MockVcpu::data`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MockExit {
+    /// `VcpuExit::IoIn`, reading the given number of bytes from a port.
+    IoIn(u16, usize),
+    /// `VcpuExit::IoOut`, writing the bytes to a port.
+    IoOut(u16, Vec<u8>),
+    /// `VcpuExit::MmioRead`, reading the given number of bytes from an address.
+    MmioRead(u64, usize),
+    /// `VcpuExit::MmioWrite`, writing the bytes to an address.
+    MmioWrite(u64, Vec<u8>),
+    /// `VcpuExit::Hlt`.
+    Hlt,
+    /// `VcpuExit::Shutdown`.
+    Shutdown,
+    /// `VcpuExit::IrqWindowOpen`.
+    IrqWindowOpen,
+    /// `VcpuExit::Intr`.
+    Intr,
+    /// `VcpuExit::Unknown`.
+    Unknown,
+    /// `VcpuExit::FailEntry` with the hardware failure reason and the CPU.
+    FailEntry(u64, u32),
+    /// `VcpuExit::SystemEvent` with the event type and data.
+    SystemEvent(u32, Vec<u64>),
+    /// `VcpuExit::IoapicEoi` with the vector.
+    IoapicEoi(u8),
+    /// `VcpuExit::MemoryFault`.
+    MemoryFault {
+        /// The flags of the fault.
+        flags: u64,
+        /// The guest physical address of the fault.
+        gpa: u64,
+        /// The size of the fault.
+        size: u64,
+    },
+    /// `run` fails with the given errno.
+    Error(i32),
+}
+
+/// A fake [`Hypervisor`], creating [`MockVm`]s.
+///
+/// Clones of a `MockKvm` share the list of VMs it created.
+#[derive(Clone, Debug)]
+pub struct MockKvm {
+    extensions: Vec<c_ulong>,
+    nr_vcpus: usize,
+    nr_memslots: usize,
+    vms: Arc<Mutex<Vec<MockVm>>>,
+}
+
+impl Default for MockKvm {
+    fn default() -> Self {
+        MockKvm::new()
+    }
+}
+
+impl MockKvm {
+    /// Creates a hypervisor supporting 32 memory slots and 8 vCPUs per VM, and no
+    /// capability but `Cap::UserMemory`, `Cap::Irqfd` and `Cap::Ioeventfd`.
+    pub fn new() -> Self {
+        MockKvm {
+            extensions: vec![
+                Cap::UserMemory as c_ulong,
+                Cap::Irqfd as c_ulong,
+                Cap::Ioeventfd as c_ulong,
+            ],
+            nr_vcpus: 8,
+            nr_memslots: 32,
+            vms: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Makes the capability `c` supported by the hypervisor and its VMs.
+    pub fn with_extension(mut self, c: Cap) -> Self {
+        self.extensions.push(c as c_ulong);
+        self
+    }
+
+    /// Sets the maximum number of vCPUs of the VMs.
+    pub fn with_nr_vcpus(mut self, nr_vcpus: usize) -> Self {
+        self.nr_vcpus = nr_vcpus;
+        self
+    }
+
+    /// Sets the maximum number of memory slots of the VMs.
+    pub fn with_nr_memslots(mut self, nr_memslots: usize) -> Self {
+        self.nr_memslots = nr_memslots;
+        self
+    }
+
+    /// Returns the VMs created so far, sharing their state with the ones returned
+    /// by `create_vm`.
+    pub fn vms(&self) -> Vec<MockVm> {
+        self.vms.lock().unwrap().clone()
+    }
+}
+
+impl Hypervisor for MockKvm {
+    type Vm = MockVm;
+
+    fn check_extension(&self, c: Cap) -> bool {
+        self.extensions.contains(&(c as c_ulong))
+    }
+
+    fn get_nr_vcpus(&self) -> usize {
+        self.nr_vcpus
+    }
+
+    fn get_nr_memslots(&self) -> usize {
+        self.nr_memslots
+    }
+
+    fn create_vm(&self) -> Result<MockVm> {
+        let vm = MockVm {
+            extensions: self.extensions.clone(),
+            nr_vcpus: self.nr_vcpus,
+            nr_memslots: self.nr_memslots,
+            state: Arc::new(Mutex::new(VmState::default())),
+        };
+        self.vms.lock().unwrap().push(vm.clone());
+        Ok(vm)
+    }
+}
+
+#[derive(Debug, Default)]
+struct VmState {
+    calls: Vec<MockCall>,
+    vcpus: Vec<u64>,
+    regions: BTreeMap<u32, kvm_userspace_memory_region>,
+    irqfds: Vec<(RawFd, u32)>,
+    ioevents: Vec<(RawFd, IoEventAddress, Option<u64>)>,
+    exits: BTreeMap<u64, VecDeque<MockExit>>,
+}
+
+impl VmState {
+    fn set_user_memory_region(
+        &mut self,
+        region: kvm_userspace_memory_region,
+        nr_memslots: usize,
+    ) -> Result<()> {
+        if region.slot as usize >= nr_memslots {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        if region.memory_size == 0 {
+            self.regions.remove(&region.slot);
+            return Ok(());
+        }
+        let end = region
+            .guest_phys_addr
+            .checked_add(region.memory_size)
+            .ok_or(errno::Error::new(libc::EINVAL))?;
+        if (region.guest_phys_addr | region.memory_size | region.userspace_addr) % PAGE_SIZE != 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        if let Some(old) = self.regions.get(&region.slot) {
+            // Memory slots can be moved or have their flags changed, but not be resized.
+            if old.memory_size != region.memory_size {
+                return Err(errno::Error::new(libc::EINVAL));
+            }
+        }
+        let overlaps = self.regions.values().any(|other| {
+            other.slot != region.slot
+                && region.guest_phys_addr < other.guest_phys_addr + other.memory_size
+                && other.guest_phys_addr < end
+        });
+        if overlaps {
+            return Err(errno::Error::new(libc::EEXIST));
+        }
+        self.regions.insert(region.slot, region);
+        Ok(())
+    }
+}
+
+/// A fake [`Vm`] created by [`MockKvm`].
+///
+/// Clones of a `MockVm` share its state, so that a test can keep a clone to
+/// script vCPU exits and inspect the state while the code under test owns the
+/// VM.
+#[derive(Clone, Debug)]
+pub struct MockVm {
+    extensions: Vec<c_ulong>,
+    nr_vcpus: usize,
+    nr_memslots: usize,
+    state: Arc<Mutex<VmState>>,
+}
+
+impl MockVm {
+    fn state(&self) -> MutexGuard<'_, VmState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Returns the calls made to the VM and its vCPUs, in order.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state().calls.clone()
+    }
+
+    /// Forgets the calls recorded so far.
+    pub fn clear_calls(&self) {
+        self.state().calls.clear();
+    }
+
+    /// Returns the memory slots of the VM, ordered by slot.
+    pub fn memory_regions(&self) -> Vec<kvm_userspace_memory_region> {
+        self.state().regions.values().copied().collect()
+    }
+
+    /// Returns the registered irqfds, as `(fd, gsi)` pairs.
+    pub fn irqfds(&self) -> Vec<(RawFd, u32)> {
+        self.state().irqfds.clone()
+    }
+
+    /// Returns the registered ioeventfds, as `(fd, addr, datamatch)` tuples.
+    pub fn ioevents(&self) -> Vec<(RawFd, IoEventAddress, Option<u64>)> {
+        self.state().ioevents.clone()
+    }
+
+    /// Adds `exit` to the exits returned by the next runs of the vCPU `id`.
+    ///
+    /// Once the scripted exits are consumed, `run` fails with `ENODATA`.
+    pub fn push_exit(&self, id: u64, exit: MockExit) {
+        self.state().exits.entry(id).or_default().push_back(exit);
+    }
+
+    /// Adds `exits` to the exits returned by the next runs of the vCPU `id`.
+    pub fn push_exits(&self, id: u64, exits: impl IntoIterator<Item = MockExit>) {
+        self.state().exits.entry(id).or_default().extend(exits);
+    }
+}
+
+fn datamatch<T: Into<u64>>(datamatch: T) -> Option<u64> {
+    // `NoDatamatch` is a zero-sized type, as in `VmFd::register_ioevent`.
+    if std::mem::size_of::<T>() > 0 {
+        Some(datamatch.into())
+    } else {
+        None
+    }
+}
+
+impl Vm for MockVm {
+    type Vcpu = MockVcpu;
+
+    fn check_extension(&self, c: Cap) -> bool {
+        self.extensions.contains(&(c as c_ulong))
+    }
+
+    fn create_vcpu(&self, id: u64) -> Result<MockVcpu> {
+        let mut state = self.state();
+        state.calls.push(MockCall::CreateVcpu(id));
+        if id as usize >= self.nr_vcpus {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        if state.vcpus.contains(&id) {
+            return Err(errno::Error::new(libc::EEXIST));
+        }
+        state.vcpus.push(id);
+        Ok(MockVcpu {
+            id,
+            state: self.state.clone(),
+            immediate_exit: 0,
+            data: Vec::new(),
+            event_data: Vec::new(),
+            #[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+            regs: Mutex::new(kvm_regs::default()),
+            #[cfg(target_arch = "x86_64")]
+            sregs: Mutex::new(kvm_sregs::default()),
+            #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+            one_regs: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    unsafe fn set_user_memory_region(
+        &self,
+        user_memory_region: kvm_userspace_memory_region,
+    ) -> Result<()> {
+        let mut state = self.state();
+        state
+            .calls
+            .push(MockCall::SetUserMemoryRegion(user_memory_region));
+        state.set_user_memory_region(user_memory_region, self.nr_memslots)
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        let fd = fd.as_raw_fd();
+        let mut state = self.state();
+        state.calls.push(MockCall::RegisterIrqfd { fd, gsi });
+        if state.irqfds.iter().any(|&(other, _)| other == fd) {
+            return Err(errno::Error::new(libc::EBUSY));
+        }
+        state.irqfds.push((fd, gsi));
+        Ok(())
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        let fd = fd.as_raw_fd();
+        let mut state = self.state();
+        state.calls.push(MockCall::UnregisterIrqfd { fd, gsi });
+        // Like KVM, unregistering an unknown irqfd is not an error.
+        state.irqfds.retain(|&irqfd| irqfd != (fd, gsi));
+        Ok(())
+    }
+
+    fn register_ioevent<T: Into<u64>>(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: T,
+    ) -> Result<()> {
+        let (fd, addr, datamatch) = (fd.as_raw_fd(), *addr, self::datamatch(datamatch));
+        let mut state = self.state();
+        state.calls.push(MockCall::RegisterIoevent {
+            fd,
+            addr,
+            datamatch,
+        });
+        if state
+            .ioevents
+            .iter()
+            .any(|&(_, other_addr, other_datamatch)| {
+                other_addr == addr && other_datamatch == datamatch
+            })
+        {
+            return Err(errno::Error::new(libc::EEXIST));
+        }
+        state.ioevents.push((fd, addr, datamatch));
+        Ok(())
+    }
+
+    fn unregister_ioevent<T: Into<u64>>(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: T,
+    ) -> Result<()> {
+        let ioevent = (fd.as_raw_fd(), *addr, self::datamatch(datamatch));
+        let mut state = self.state();
+        state.calls.push(MockCall::UnregisterIoevent {
+            fd: ioevent.0,
+            addr: ioevent.1,
+            datamatch: ioevent.2,
+        });
+        let index = state
+            .ioevents
+            .iter()
+            .position(|&other| other == ioevent)
+            .ok_or(errno::Error::new(libc::ENOENT))?;
+        state.ioevents.remove(index);
+        Ok(())
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn signal_msi(&self, msi: kvm_msi) -> Result<c_int> {
+        self.state().calls.push(MockCall::SignalMsi(msi));
+        // The MSI is always delivered.
+        Ok(1)
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn set_irq_line(&self, irq: u32, active: bool) -> Result<()> {
+        self.state()
+            .calls
+            .push(MockCall::SetIrqLine { irq, active });
+        Ok(())
+    }
+}
+
+/// A fake [`Vcpu`] created by [`MockVm`], whose runs return the exits scripted
+/// with [`MockVm::push_exit`].
+///
+/// The registers are stored in the vCPU, and read back as they were last set.
+#[derive(Debug)]
+pub struct MockVcpu {
+    id: u64,
+    state: Arc<Mutex<VmState>>,
+    immediate_exit: u8,
+    data: Vec<u8>,
+    event_data: Vec<u64>,
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+    regs: Mutex<kvm_regs>,
+    #[cfg(target_arch = "x86_64")]
+    sregs: Mutex<kvm_sregs>,
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    one_regs: Mutex<BTreeMap<u64, Vec<u8>>>,
+}
+
+impl MockVcpu {
+    /// Returns the ID of the vCPU.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the data of the last exit, including the bytes written by the VMM
+    /// on `IoIn` and `MmioRead` exits.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn record(&self, call: MockCall) {
+        self.state.lock().unwrap().calls.push(call);
+    }
+}
+
+impl Vcpu for MockVcpu {
+    fn run(&mut self) -> Result<VcpuExit<'_>> {
+        let exit = {
+            let mut state = self.state.lock().unwrap();
+            state.calls.push(MockCall::Run(self.id));
+            // Like `VcpuFd::run`, the vCPU does not enter the guest when `immediate_exit`
+            // is set, and the flag is cleared.
+            if self.immediate_exit != 0 {
+                self.immediate_exit = 0;
+                return Ok(VcpuExit::Intr);
+            }
+            state
+                .exits
+                .get_mut(&self.id)
+                .and_then(VecDeque::pop_front)
+                .ok_or(errno::Error::new(libc::ENODATA))?
+        };
+        Ok(match exit {
+            MockExit::IoIn(port, len) => {
+                self.data = vec![0; len];
+                VcpuExit::IoIn(port, &mut self.data)
+            }
+            MockExit::IoOut(port, data) => {
+                self.data = data;
+                VcpuExit::IoOut(port, &self.data)
+            }
+            MockExit::MmioRead(addr, len) => {
+                self.data = vec![0; len];
+                VcpuExit::MmioRead(addr, &mut self.data)
+            }
+            MockExit::MmioWrite(addr, data) => {
+                self.data = data;
+                VcpuExit::MmioWrite(addr, &self.data)
+            }
+            MockExit::Hlt => VcpuExit::Hlt,
+            MockExit::Shutdown => VcpuExit::Shutdown,
+            MockExit::IrqWindowOpen => VcpuExit::IrqWindowOpen,
+            MockExit::Intr => VcpuExit::Intr,
+            MockExit::Unknown => VcpuExit::Unknown,
+            MockExit::FailEntry(reason, cpu) => VcpuExit::FailEntry(reason, cpu),
+            MockExit::SystemEvent(type_, data) => {
+                self.event_data = data;
+                VcpuExit::SystemEvent(type_, &self.event_data)
+            }
+            MockExit::IoapicEoi(vector) => VcpuExit::IoapicEoi(vector),
+            MockExit::MemoryFault { flags, gpa, size } => {
+                VcpuExit::MemoryFault { flags, gpa, size }
+            }
+            MockExit::Error(code) => return Err(errno::Error::new(code)),
+        })
+    }
+
+    fn set_kvm_immediate_exit(&mut self, val: u8) {
+        self.immediate_exit = val;
+    }
+
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+    fn get_regs(&self) -> Result<kvm_regs> {
+        Ok(*self.regs.lock().unwrap())
+    }
+
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+    fn set_regs(&self, regs: &kvm_regs) -> Result<()> {
+        self.record(MockCall::SetRegs(self.id, *regs));
+        *self.regs.lock().unwrap() = *regs;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_sregs(&self) -> Result<kvm_sregs> {
+        Ok(*self.sregs.lock().unwrap())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_sregs(&self, sregs: &kvm_sregs) -> Result<()> {
+        self.record(MockCall::SetSregs(self.id, *sregs));
+        *self.sregs.lock().unwrap() = *sregs;
+        Ok(())
+    }
+
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    fn get_one_reg(&self, reg_id: u64, data: &mut [u8]) -> Result<usize> {
+        let size = reg_size(reg_id);
+        if data.len() < size {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let one_regs = self.one_regs.lock().unwrap();
+        let value = one_regs
+            .get(&reg_id)
+            .ok_or(errno::Error::new(libc::ENOENT))?;
+        data[..size].copy_from_slice(value);
+        Ok(size)
+    }
+
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    fn set_one_reg(&self, reg_id: u64, data: &[u8]) -> Result<usize> {
+        let size = reg_size(reg_id);
+        if data.len() < size {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let value = data[..size].to_vec();
+        self.record(MockCall::SetOneReg {
+            vcpu: self.id,
+            reg_id,
+            data: value.clone(),
+        });
+        self.one_regs.lock().unwrap().insert(reg_id, value);
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::vm::NoDatamatch;
+    use libc::EFD_NONBLOCK;
+
+    #[test]
+    fn test_mock_memory_regions() {
+        let kvm = MockKvm::new().with_nr_memslots(2);
+        let vm = kvm.create_vm().unwrap();
+        let region = |slot, guest_phys_addr, memory_size| kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr,
+            memory_size,
+            userspace_addr: 0x7f00_0000_0000,
+            flags: 0,
+        };
+        let set = |region| {
+            // SAFETY: The mock does not access the memory.
+            unsafe { vm.set_user_memory_region(region) }
+        };
+
+        set(region(0, 0, 0x10000)).unwrap();
+        assert_eq!(
+            set(region(1, 0x8000, 0x10000)).unwrap_err().errno(),
+            libc::EEXIST
+        );
+        assert_eq!(
+            set(region(1, 0x10000, 0x100)).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            set(region(2, 0x10000, 0x1000)).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        set(region(1, 0x10000, 0x1000)).unwrap();
+        // Moving a slot is allowed, resizing it is not.
+        set(region(1, 0x20000, 0x1000)).unwrap();
+        assert_eq!(
+            set(region(1, 0x20000, 0x2000)).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            vm.memory_regions(),
+            vec![region(0, 0, 0x10000), region(1, 0x20000, 0x1000)]
+        );
+        set(region(0, 0, 0)).unwrap();
+        assert_eq!(vm.memory_regions(), vec![region(1, 0x20000, 0x1000)]);
+        assert_eq!(vm.calls().len(), 8);
+        assert_eq!(kvm.vms().len(), 1);
+    }
+
+    #[test]
+    fn test_mock_irqfds_and_ioevents() {
+        let vm = MockKvm::new().create_vm().unwrap();
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let fd = evtfd.as_raw_fd();
+
+        vm.register_irqfd(&evtfd, 4).unwrap();
+        assert_eq!(
+            vm.register_irqfd(&evtfd, 5).unwrap_err().errno(),
+            libc::EBUSY
+        );
+        assert_eq!(vm.irqfds(), vec![(fd, 4)]);
+        vm.unregister_irqfd(&evtfd, 4).unwrap();
+        assert!(vm.irqfds().is_empty());
+
+        let addr = IoEventAddress::Mmio(0x1000);
+        vm.register_ioevent(&evtfd, &addr, 0x1234u32).unwrap();
+        assert_eq!(
+            vm.register_ioevent(&evtfd, &addr, 0x1234u32)
+                .unwrap_err()
+                .errno(),
+            libc::EEXIST
+        );
+        vm.register_ioevent(&evtfd, &IoEventAddress::Pio(0xf4), NoDatamatch)
+            .unwrap();
+        assert_eq!(
+            vm.ioevents(),
+            vec![
+                (fd, addr, Some(0x1234)),
+                (fd, IoEventAddress::Pio(0xf4), None)
+            ]
+        );
+        assert_eq!(
+            vm.unregister_ioevent(&evtfd, &addr, 0u32)
+                .unwrap_err()
+                .errno(),
+            libc::ENOENT
+        );
+        vm.unregister_ioevent(&evtfd, &addr, 0x1234u32).unwrap();
+        assert_eq!(vm.ioevents().len(), 1);
+
+        vm.set_irq_line(4, true).unwrap();
+        assert_eq!(
+            vm.calls().last(),
+            Some(&MockCall::SetIrqLine {
+                irq: 4,
+                active: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_mock_vcpu_run() {
+        let vm = MockKvm::new().with_nr_vcpus(2).create_vm().unwrap();
+        let mut vcpu = vm.create_vcpu(1).unwrap();
+        assert_eq!(vm.create_vcpu(1).unwrap_err().errno(), libc::EEXIST);
+        assert_eq!(vm.create_vcpu(2).unwrap_err().errno(), libc::EINVAL);
+
+        vm.push_exits(
+            1,
+            [
+                MockExit::IoIn(0x60, 1),
+                MockExit::MmioWrite(0x1000, vec![1, 2]),
+                MockExit::Error(libc::EFAULT),
+            ],
+        );
+        match vcpu.run().unwrap() {
+            VcpuExit::IoIn(0x60, data) => data[0] = 0xaa,
+            exit => panic!("unexpected exit {exit:?}"),
+        }
+        assert_eq!(vcpu.data(), [0xaa]);
+        assert!(matches!(
+            vcpu.run(),
+            Ok(VcpuExit::MmioWrite(0x1000, [1, 2]))
+        ));
+        assert_eq!(vcpu.run().unwrap_err().errno(), libc::EFAULT);
+        assert_eq!(vcpu.run().unwrap_err().errno(), libc::ENODATA);
+
+        vm.push_exit(1, MockExit::Hlt);
+        vcpu.set_kvm_immediate_exit(1);
+        assert!(matches!(vcpu.run(), Ok(VcpuExit::Intr)));
+        assert!(matches!(vcpu.run(), Ok(VcpuExit::Hlt)));
+        assert_eq!(
+            vm.calls()
+                .iter()
+                .filter(|&call| *call == MockCall::Run(1))
+                .count(),
+            6
+        );
+    }
+
+    #[test]
+    fn test_mock_vcpu_regs() {
+        let vm = MockKvm::new().create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut regs = vcpu.get_regs().unwrap();
+            regs.rip = 0x1000;
+            vcpu.set_regs(&regs).unwrap();
+            assert_eq!(vcpu.get_regs().unwrap().rip, 0x1000);
+            let mut sregs = vcpu.get_sregs().unwrap();
+            sregs.cr0 = 1;
+            vcpu.set_sregs(&sregs).unwrap();
+            assert_eq!(vcpu.get_sregs().unwrap().cr0, 1);
+            assert_eq!(vm.calls().last(), Some(&MockCall::SetSregs(0, sregs)));
+        }
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        {
+            use kvm_bindings::KVM_REG_SIZE_U64;
+
+            let reg_id = KVM_REG_SIZE_U64 | 0x10;
+            let mut data = [0u8; 8];
+            assert_eq!(
+                vcpu.get_one_reg(reg_id, &mut data).unwrap_err().errno(),
+                libc::ENOENT
+            );
+            vcpu.set_one_reg(reg_id, &0x1000u64.to_le_bytes()).unwrap();
+            assert_eq!(vcpu.get_one_reg(reg_id, &mut data).unwrap(), 8);
+            assert_eq!(u64::from_le_bytes(data), 0x1000);
+        }
+    }
+}
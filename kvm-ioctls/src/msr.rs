@@ -0,0 +1,455 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Indices of well-known x86 model-specific registers.
+//!
+//! The names follow the Linux ones, without the `MSR_` prefix. The module also
+//! provides [`MSRS_TO_SAVE`], a curated list of the MSRs that hold vCPU state, and
+//! [`msrs_to_save`] to restrict it to the MSRs that KVM supports on a host.
+
+use std::fmt;
+
+use vmm_sys_util::errno;
+
+/// Time Stamp Counter.
+pub const IA32_TSC: u32 = 0x10;
+/// Platform ID.
+pub const IA32_PLATFORM_ID: u32 = 0x17;
+/// Local APIC base address and enable bits.
+pub const IA32_APIC_BASE: u32 = 0x1b;
+/// Feature control (VMX and SGX enablement, lock bit).
+pub const IA32_FEATURE_CONTROL: u32 = 0x3a;
+/// TSC adjustment.
+pub const IA32_TSC_ADJUST: u32 = 0x3b;
+/// Speculation control.
+pub const IA32_SPEC_CTRL: u32 = 0x48;
+/// Prediction command.
+pub const IA32_PRED_CMD: u32 = 0x49;
+/// Microcode revision.
+pub const IA32_UCODE_REV: u32 = 0x8b;
+/// SMM base address.
+pub const IA32_SMBASE: u32 = 0x9e;
+/// Number of SMIs.
+pub const SMI_COUNT: u32 = 0x34;
+/// Platform information.
+pub const PLATFORM_INFO: u32 = 0xce;
+/// User wait control.
+pub const IA32_UMWAIT_CONTROL: u32 = 0xe1;
+/// MTRR capabilities.
+pub const MTRR_CAP: u32 = 0xfe;
+/// Architectural capabilities.
+pub const IA32_ARCH_CAPABILITIES: u32 = 0x10a;
+/// Flush command.
+pub const IA32_FLUSH_CMD: u32 = 0x10b;
+/// TSX control.
+pub const IA32_TSX_CTRL: u32 = 0x122;
+/// Miscellaneous features enables.
+pub const MISC_FEATURES_ENABLES: u32 = 0x140;
+/// `SYSENTER` code segment.
+pub const IA32_SYSENTER_CS: u32 = 0x174;
+/// `SYSENTER` stack pointer.
+pub const IA32_SYSENTER_ESP: u32 = 0x175;
+/// `SYSENTER` instruction pointer.
+pub const IA32_SYSENTER_EIP: u32 = 0x176;
+/// Machine check capabilities.
+pub const IA32_MCG_CAP: u32 = 0x179;
+/// Machine check status.
+pub const IA32_MCG_STATUS: u32 = 0x17a;
+/// Machine check control.
+pub const IA32_MCG_CTL: u32 = 0x17b;
+/// Miscellaneous enables.
+pub const IA32_MISC_ENABLE: u32 = 0x1a0;
+/// Extended feature disable.
+pub const IA32_XFD: u32 = 0x1c4;
+/// Extended feature disable error code.
+pub const IA32_XFD_ERR: u32 = 0x1c5;
+/// Debug control.
+pub const IA32_DEBUGCTL: u32 = 0x1d9;
+/// Base of the first variable-range MTRR; see [`mtrr_phys_base`].
+pub const MTRR_PHYS_BASE0: u32 = 0x200;
+/// Mask of the first variable-range MTRR; see [`mtrr_phys_mask`].
+pub const MTRR_PHYS_MASK0: u32 = 0x201;
+/// Fixed-range MTRR for 0x00000-0x7ffff.
+pub const MTRR_FIX_64K_00000: u32 = 0x250;
+/// Fixed-range MTRR for 0x80000-0x9ffff.
+pub const MTRR_FIX_16K_80000: u32 = 0x258;
+/// Fixed-range MTRR for 0xa0000-0xbffff.
+pub const MTRR_FIX_16K_A0000: u32 = 0x259;
+/// Fixed-range MTRR for 0xc0000-0xc7fff.
+pub const MTRR_FIX_4K_C0000: u32 = 0x268;
+/// Fixed-range MTRR for 0xc8000-0xcffff.
+pub const MTRR_FIX_4K_C8000: u32 = 0x269;
+/// Fixed-range MTRR for 0xd0000-0xd7fff.
+pub const MTRR_FIX_4K_D0000: u32 = 0x26a;
+/// Fixed-range MTRR for 0xd8000-0xdffff.
+pub const MTRR_FIX_4K_D8000: u32 = 0x26b;
+/// Fixed-range MTRR for 0xe0000-0xe7fff.
+pub const MTRR_FIX_4K_E0000: u32 = 0x26c;
+/// Fixed-range MTRR for 0xe8000-0xeffff.
+pub const MTRR_FIX_4K_E8000: u32 = 0x26d;
+/// Fixed-range MTRR for 0xf0000-0xf7fff.
+pub const MTRR_FIX_4K_F0000: u32 = 0x26e;
+/// Fixed-range MTRR for 0xf8000-0xfffff.
+pub const MTRR_FIX_4K_F8000: u32 = 0x26f;
+/// Page Attribute Table.
+pub const IA32_CR_PAT: u32 = 0x277;
+/// Default MTRR memory type.
+pub const MTRR_DEF_TYPE: u32 = 0x2ff;
+/// Performance monitoring capabilities.
+pub const IA32_PERF_CAPABILITIES: u32 = 0x345;
+/// TSC deadline for the local APIC timer.
+pub const IA32_TSC_DEADLINE: u32 = 0x6e0;
+/// Supervisor protection keys.
+pub const IA32_PKRS: u32 = 0x6e1;
+/// Bounds configuration for MPX.
+pub const IA32_BNDCFGS: u32 = 0xd90;
+/// Supervisor state components enabled in `XSS`.
+pub const IA32_XSS: u32 = 0xda0;
+
+/// Basic VMX information.
+pub const IA32_VMX_BASIC: u32 = 0x480;
+/// Pin-based VM-execution controls.
+pub const IA32_VMX_PINBASED_CTLS: u32 = 0x481;
+/// Primary processor-based VM-execution controls.
+pub const IA32_VMX_PROCBASED_CTLS: u32 = 0x482;
+/// VM-exit controls.
+pub const IA32_VMX_EXIT_CTLS: u32 = 0x483;
+/// VM-entry controls.
+pub const IA32_VMX_ENTRY_CTLS: u32 = 0x484;
+/// Miscellaneous VMX data.
+pub const IA32_VMX_MISC: u32 = 0x485;
+/// CR0 bits fixed to 1 in VMX operation.
+pub const IA32_VMX_CR0_FIXED0: u32 = 0x486;
+/// CR0 bits allowed to be 1 in VMX operation.
+pub const IA32_VMX_CR0_FIXED1: u32 = 0x487;
+/// CR4 bits fixed to 1 in VMX operation.
+pub const IA32_VMX_CR4_FIXED0: u32 = 0x488;
+/// CR4 bits allowed to be 1 in VMX operation.
+pub const IA32_VMX_CR4_FIXED1: u32 = 0x489;
+/// Highest VMCS field index.
+pub const IA32_VMX_VMCS_ENUM: u32 = 0x48a;
+/// Secondary processor-based VM-execution controls.
+pub const IA32_VMX_PROCBASED_CTLS2: u32 = 0x48b;
+/// EPT and VPID capabilities.
+pub const IA32_VMX_EPT_VPID_CAP: u32 = 0x48c;
+/// Pin-based VM-execution controls, including default-1 bits.
+pub const IA32_VMX_TRUE_PINBASED_CTLS: u32 = 0x48d;
+/// Primary processor-based VM-execution controls, including default-1 bits.
+pub const IA32_VMX_TRUE_PROCBASED_CTLS: u32 = 0x48e;
+/// VM-exit controls, including default-1 bits.
+pub const IA32_VMX_TRUE_EXIT_CTLS: u32 = 0x48f;
+/// VM-entry controls, including default-1 bits.
+pub const IA32_VMX_TRUE_ENTRY_CTLS: u32 = 0x490;
+/// VM-function controls.
+pub const IA32_VMX_VMFUNC: u32 = 0x491;
+
+/// Extended feature enables.
+pub const EFER: u32 = 0xc000_0080;
+/// `SYSCALL` target segments.
+pub const STAR: u32 = 0xc000_0081;
+/// Long mode `SYSCALL` target.
+pub const LSTAR: u32 = 0xc000_0082;
+/// Compatibility mode `SYSCALL` target.
+pub const CSTAR: u32 = 0xc000_0083;
+/// `SYSCALL` flags mask.
+pub const SYSCALL_MASK: u32 = 0xc000_0084;
+/// `FS` segment base.
+pub const FS_BASE: u32 = 0xc000_0100;
+/// `GS` segment base.
+pub const GS_BASE: u32 = 0xc000_0101;
+/// `GS` base swapped in by `SWAPGS`.
+pub const KERNEL_GS_BASE: u32 = 0xc000_0102;
+/// Auxiliary TSC value returned by `RDTSCP` and `RDPID`.
+pub const TSC_AUX: u32 = 0xc000_0103;
+/// AMD SVM control.
+pub const VM_CR: u32 = 0xc001_0114;
+/// AMD host save area physical address.
+pub const VM_HSAVE_PA: u32 = 0xc001_0117;
+/// AMD virtualized speculation control.
+pub const AMD64_VIRT_SPEC_CTRL: u32 = 0xc001_011f;
+
+/// Legacy kvmclock wall clock.
+pub const KVM_WALL_CLOCK: u32 = 0x11;
+/// Legacy kvmclock system time.
+pub const KVM_SYSTEM_TIME: u32 = 0x12;
+/// kvmclock wall clock.
+pub const KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+/// kvmclock system time.
+pub const KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+/// Asynchronous page fault enablement.
+pub const KVM_ASYNC_PF_EN: u32 = 0x4b56_4d02;
+/// Steal time accounting area.
+pub const KVM_STEAL_TIME: u32 = 0x4b56_4d03;
+/// Paravirtual end of interrupt.
+pub const KVM_PV_EOI_EN: u32 = 0x4b56_4d04;
+/// Halt polling control.
+pub const KVM_POLL_CONTROL: u32 = 0x4b56_4d05;
+/// Asynchronous page fault interrupt vector.
+pub const KVM_ASYNC_PF_INT: u32 = 0x4b56_4d06;
+/// Asynchronous page fault acknowledgement.
+pub const KVM_ASYNC_PF_ACK: u32 = 0x4b56_4d07;
+/// Migration control.
+pub const KVM_MIGRATION_CONTROL: u32 = 0x4b56_4d08;
+
+/// Hyper-V guest OS identity.
+pub const HV_GUEST_OS_ID: u32 = 0x4000_0000;
+/// Hyper-V hypercall page.
+pub const HV_HYPERCALL: u32 = 0x4000_0001;
+/// Hyper-V virtual processor index.
+pub const HV_VP_INDEX: u32 = 0x4000_0002;
+/// Hyper-V system reset.
+pub const HV_RESET: u32 = 0x4000_0003;
+/// Hyper-V virtual processor run time.
+pub const HV_VP_RUNTIME: u32 = 0x4000_0010;
+/// Hyper-V partition reference counter.
+pub const HV_TIME_REF_COUNT: u32 = 0x4000_0020;
+/// Hyper-V reference TSC page.
+pub const HV_REFERENCE_TSC: u32 = 0x4000_0021;
+/// Hyper-V TSC frequency.
+pub const HV_TSC_FREQUENCY: u32 = 0x4000_0022;
+/// Hyper-V APIC timer frequency.
+pub const HV_APIC_FREQUENCY: u32 = 0x4000_0023;
+/// Hyper-V virtual processor assist page.
+pub const HV_VP_ASSIST_PAGE: u32 = 0x4000_0073;
+/// Hyper-V synthetic interrupt controller control.
+pub const HV_SCONTROL: u32 = 0x4000_0080;
+/// Hyper-V synthetic interrupt controller version.
+pub const HV_SVERSION: u32 = 0x4000_0081;
+/// Hyper-V synthetic interrupt event flags page.
+pub const HV_SIEFP: u32 = 0x4000_0082;
+/// Hyper-V synthetic interrupt message page.
+pub const HV_SIMP: u32 = 0x4000_0083;
+/// Hyper-V end of message.
+pub const HV_EOM: u32 = 0x4000_0084;
+/// Hyper-V synthetic interrupt source 0; see [`hv_sint`].
+pub const HV_SINT0: u32 = 0x4000_0090;
+/// Hyper-V synthetic timer 0 configuration; see [`hv_stimer_config`].
+pub const HV_STIMER0_CONFIG: u32 = 0x4000_00b0;
+/// Hyper-V synthetic timer 0 count; see [`hv_stimer_count`].
+pub const HV_STIMER0_COUNT: u32 = 0x4000_00b1;
+/// Hyper-V first crash parameter; see [`hv_crash_p`].
+pub const HV_CRASH_P0: u32 = 0x4000_0100;
+/// Hyper-V crash control.
+pub const HV_CRASH_CTL: u32 = 0x4000_0105;
+/// Hyper-V reenlightenment control.
+pub const HV_REENLIGHTENMENT_CONTROL: u32 = 0x4000_0106;
+/// Hyper-V TSC emulation control.
+pub const HV_TSC_EMULATION_CONTROL: u32 = 0x4000_0107;
+/// Hyper-V TSC emulation status.
+pub const HV_TSC_EMULATION_STATUS: u32 = 0x4000_0108;
+/// Hyper-V TSC invariant control.
+pub const HV_TSC_INVARIANT_CONTROL: u32 = 0x4000_0118;
+
+/// Number of variable-range MTRRs exposed by KVM.
+pub const KVM_NR_VAR_MTRR: u32 = 8;
+/// Number of Hyper-V synthetic interrupt sources.
+pub const HV_SINT_COUNT: u32 = 16;
+/// Number of Hyper-V synthetic timers.
+pub const HV_STIMER_COUNT: u32 = 4;
+/// Number of Hyper-V crash parameters.
+pub const HV_CRASH_PARAMS: u32 = 5;
+
+/// Returns the base MSR of the variable-range MTRR `n`.
+pub const fn mtrr_phys_base(n: u32) -> u32 {
+    MTRR_PHYS_BASE0 + 2 * n
+}
+
+/// Returns the mask MSR of the variable-range MTRR `n`.
+pub const fn mtrr_phys_mask(n: u32) -> u32 {
+    MTRR_PHYS_MASK0 + 2 * n
+}
+
+/// Returns the MSR of the Hyper-V synthetic interrupt source `n`.
+pub const fn hv_sint(n: u32) -> u32 {
+    HV_SINT0 + n
+}
+
+/// Returns the configuration MSR of the Hyper-V synthetic timer `n`.
+pub const fn hv_stimer_config(n: u32) -> u32 {
+    HV_STIMER0_CONFIG + 2 * n
+}
+
+/// Returns the count MSR of the Hyper-V synthetic timer `n`.
+pub const fn hv_stimer_count(n: u32) -> u32 {
+    HV_STIMER0_COUNT + 2 * n
+}
+
+/// Returns the MSR of the Hyper-V crash parameter `n`.
+pub const fn hv_crash_p(n: u32) -> u32 {
+    HV_CRASH_P0 + n
+}
+
+// Expands `(first, count)` ranges of consecutive MSRs.
+const fn msr_ranges<const N: usize>(ranges: &[(u32, u32)]) -> [u32; N] {
+    let mut msrs = [0; N];
+    let mut i = 0;
+    let mut r = 0;
+    while r < ranges.len() {
+        let (first, count) = ranges[r];
+        let mut n = 0;
+        while n < count {
+            msrs[i] = first + n;
+            i += 1;
+            n += 1;
+        }
+        r += 1;
+    }
+    assert!(i == N);
+    msrs
+}
+
+/// MSRs that are always emulated by KVM even though `KVM_GET_MSR_INDEX_LIST` does
+/// not report them.
+const ALWAYS_SAVED: [u32; 29] = msr_ranges(&[
+    (MTRR_DEF_TYPE, 1),
+    (MTRR_PHYS_BASE0, 2 * KVM_NR_VAR_MTRR),
+    (MTRR_FIX_64K_00000, 1),
+    (MTRR_FIX_16K_80000, 2),
+    (MTRR_FIX_4K_C0000, 8),
+    (IA32_CR_PAT, 1),
+]);
+
+const SAVED_IF_SUPPORTED: [u32; 97] = msr_ranges(&[
+    // Architectural state.
+    (IA32_TSC, 1),
+    (IA32_APIC_BASE, 1),
+    (IA32_FEATURE_CONTROL, 1),
+    (IA32_TSC_ADJUST, 1),
+    (IA32_SPEC_CTRL, 1),
+    (IA32_SMBASE, 1),
+    (SMI_COUNT, 1),
+    (PLATFORM_INFO, 1),
+    (IA32_UMWAIT_CONTROL, 1),
+    (IA32_ARCH_CAPABILITIES, 1),
+    (IA32_TSX_CTRL, 1),
+    (MISC_FEATURES_ENABLES, 1),
+    (IA32_SYSENTER_CS, 3),
+    (IA32_MCG_STATUS, 2),
+    (IA32_MISC_ENABLE, 1),
+    (IA32_XFD, 2),
+    (IA32_DEBUGCTL, 1),
+    (IA32_PERF_CAPABILITIES, 1),
+    (IA32_TSC_DEADLINE, 2),
+    (IA32_BNDCFGS, 1),
+    (IA32_XSS, 1),
+    (EFER, 5),
+    (FS_BASE, 4),
+    (VM_CR, 1),
+    (VM_HSAVE_PA, 1),
+    (AMD64_VIRT_SPEC_CTRL, 1),
+    // kvmclock and paravirtual features.
+    (KVM_WALL_CLOCK, 2),
+    (KVM_WALL_CLOCK_NEW, 9),
+    // Hyper-V.
+    (HV_GUEST_OS_ID, 4),
+    (HV_VP_RUNTIME, 1),
+    (HV_TIME_REF_COUNT, 4),
+    (HV_VP_ASSIST_PAGE, 1),
+    (HV_SCONTROL, 4),
+    (HV_SINT0, HV_SINT_COUNT),
+    (HV_STIMER0_CONFIG, 2 * HV_STIMER_COUNT),
+    (HV_CRASH_P0, HV_CRASH_PARAMS + 1),
+    (HV_REENLIGHTENMENT_CONTROL, 3),
+    (HV_TSC_INVARIANT_CONTROL, 1),
+]);
+
+/// The MSRs that hold vCPU state worth saving on migration or snapshot.
+///
+/// Write-only MSRs, such as [`IA32_PRED_CMD`], and read-only capability MSRs, such
+/// as the VMX ones, are not included. Use [`msrs_to_save`] to restrict this list to
+/// what KVM supports on a host.
+pub const MSRS_TO_SAVE: &[u32] = &{
+    let mut msrs = [0; ALWAYS_SAVED.len() + SAVED_IF_SUPPORTED.len()];
+    let mut i = 0;
+    while i < ALWAYS_SAVED.len() {
+        msrs[i] = ALWAYS_SAVED[i];
+        i += 1;
+    }
+    while i < msrs.len() {
+        msrs[i] = SAVED_IF_SUPPORTED[i - ALWAYS_SAVED.len()];
+        i += 1;
+    }
+    msrs
+};
+
+/// Returns the MSRs of [`MSRS_TO_SAVE`] that are supported according to `supported`.
+///
+/// `supported` is usually the output of `Kvm::get_msr_index_list`. The MTRRs and the
+/// PAT are always kept because KVM emulates them without listing them.
+///
+/// # Example
+///
+/// ```rust
+/// use kvm_ioctls::{Kvm, msr};
+///
+/// let kvm = Kvm::new().unwrap();
+/// let supported = kvm.get_msr_index_list().unwrap();
+/// let to_save = msr::msrs_to_save(supported.as_slice());
+/// assert!(to_save.contains(&msr::IA32_SYSENTER_CS));
+/// assert!(to_save.contains(&msr::IA32_CR_PAT));
+/// ```
+pub fn msrs_to_save(supported: &[u32]) -> Vec<u32> {
+    MSRS_TO_SAVE
+        .iter()
+        .copied()
+        .filter(|index| ALWAYS_SAVED.contains(index) || supported.contains(index))
+        .collect()
+}
+
+/// Error returned by `VcpuFd::get_all_msrs` and `VcpuFd::set_all_msrs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrError {
+    /// The `KVM_GET_MSRS` or `KVM_SET_MSRS` ioctl failed.
+    Ioctl(errno::Error),
+    /// KVM could not access the MSR `index`.
+    Failed {
+        /// The index of the MSR that could not be accessed.
+        index: u32,
+        /// The number of MSRs that were accessed successfully before it.
+        done: usize,
+    },
+}
+
+impl fmt::Display for MsrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsrError::Ioctl(err) => write!(f, "MSR ioctl failed: {err}"),
+            MsrError::Failed { index, done } => {
+                write!(f, "cannot access MSR {index:#x} after {done} MSRs")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MsrError {}
+
+impl From<errno::Error> for MsrError {
+    fn from(err: errno::Error) -> Self {
+        MsrError::Ioctl(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_msrs_to_save() {
+        assert_eq!(mtrr_phys_mask(7), 0x20f);
+        assert_eq!(hv_sint(15), 0x4000_009f);
+        assert_eq!(hv_stimer_count(3), 0x4000_00b7);
+        assert_eq!(hv_crash_p(4), 0x4000_0104);
+
+        for (i, index) in MSRS_TO_SAVE.iter().enumerate() {
+            assert!(!MSRS_TO_SAVE[i + 1..].contains(index), "{index:#x}");
+        }
+        assert!(MSRS_TO_SAVE.contains(&MTRR_FIX_4K_F8000));
+        assert!(MSRS_TO_SAVE.contains(&mtrr_phys_mask(KVM_NR_VAR_MTRR - 1)));
+        assert!(MSRS_TO_SAVE.contains(&KVM_MIGRATION_CONTROL));
+        assert!(MSRS_TO_SAVE.contains(&HV_CRASH_CTL));
+        assert!(!MSRS_TO_SAVE.contains(&IA32_PRED_CMD));
+
+        let to_save = msrs_to_save(&[IA32_TSC, KVM_STEAL_TIME, 0x1234]);
+        assert_eq!(to_save.len(), ALWAYS_SAVED.len() + 2);
+        assert_eq!(&to_save[ALWAYS_SAVED.len()..], &[IA32_TSC, KVM_STEAL_TIME]);
+    }
+}
@@ -0,0 +1,268 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::borrow::Borrow;
+
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::ioctls::Result;
+use crate::ioctls::vm::{IoEventAddress, VmFd};
+
+/// An irqfd registered with `KVM_IRQFD`, deassigned when dropped.
+///
+/// The registration keeps a duplicate of the `EventFd` that triggers the IRQ, so
+/// the caller can close its own copy at any time. `V` is either a reference to
+/// the `VmFd` or a smart pointer to it, such as `Arc<VmFd>`.
+///
+/// # Example
+///
+/// ```rust
+/// # use kvm_ioctls::{IrqFdRegistration, Kvm};
+/// # use libc::EFD_NONBLOCK;
+/// # use vmm_sys_util::eventfd::EventFd;
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// # #[cfg(target_arch = "x86_64")]
+/// # {
+/// vm.create_irq_chip().unwrap();
+///
+/// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+/// let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
+/// let irqfd = IrqFdRegistration::with_resample(&vm, &evtfd, resamplefd, 4).unwrap();
+/// assert!(irqfd.resamplefd().is_some());
+///
+/// // Deassigns the irqfd, so that it can be registered again.
+/// drop(irqfd);
+/// vm.register_irqfd(&evtfd, 4).unwrap();
+/// # }
+/// ```
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+#[derive(Debug)]
+pub struct IrqFdRegistration<V: Borrow<VmFd>> {
+    vm: V,
+    fd: EventFd,
+    resamplefd: Option<EventFd>,
+    gsi: u32,
+    registered: bool,
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+impl<V: Borrow<VmFd>> IrqFdRegistration<V> {
+    /// Registers `fd` to trigger the `gsi` IRQ, as done by `VmFd::register_irqfd`.
+    pub fn new(vm: V, fd: &EventFd, gsi: u32) -> Result<Self> {
+        let fd = fd.try_clone()?;
+        vm.borrow().register_irqfd(&fd, gsi)?;
+        Ok(IrqFdRegistration {
+            vm,
+            fd,
+            resamplefd: None,
+            gsi,
+            registered: true,
+        })
+    }
+
+    /// Registers `fd` to assert the level-triggered `gsi` IRQ, as done by
+    /// `VmFd::register_irqfd_with_resample`.
+    ///
+    /// The registration takes ownership of `resamplefd`, which is notified when the
+    /// guest acknowledges the IRQ.
+    pub fn with_resample(vm: V, fd: &EventFd, resamplefd: EventFd, gsi: u32) -> Result<Self> {
+        let fd = fd.try_clone()?;
+        vm.borrow()
+            .register_irqfd_with_resample(&fd, &resamplefd, gsi)?;
+        Ok(IrqFdRegistration {
+            vm,
+            fd,
+            resamplefd: Some(resamplefd),
+            gsi,
+            registered: true,
+        })
+    }
+
+    /// Returns the `EventFd` that triggers the IRQ.
+    pub fn fd(&self) -> &EventFd {
+        &self.fd
+    }
+
+    /// Returns the `EventFd` notified on resample, if any.
+    pub fn resamplefd(&self) -> Option<&EventFd> {
+        self.resamplefd.as_ref()
+    }
+
+    /// Returns the GSI triggered by the `EventFd`.
+    pub fn gsi(&self) -> u32 {
+        self.gsi
+    }
+
+    /// Deassigns the irqfd, reporting the error that `drop` would ignore.
+    pub fn unregister(mut self) -> Result<()> {
+        self.registered = false;
+        self.vm.borrow().unregister_irqfd(&self.fd, self.gsi)
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+impl<V: Borrow<VmFd>> Drop for IrqFdRegistration<V> {
+    fn drop(&mut self) {
+        if self.registered {
+            let _ = self.vm.borrow().unregister_irqfd(&self.fd, self.gsi);
+        }
+    }
+}
+
+/// An ioeventfd registered with `KVM_IOEVENTFD`, deassigned when dropped.
+///
+/// The registration keeps a duplicate of the `EventFd` and the address and
+/// datamatch it was registered with. `V` is either a reference to the `VmFd` or
+/// a smart pointer to it, such as `Arc<VmFd>`, and `T` is the type of the
+/// datamatch as in `VmFd::register_ioevent`.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use kvm_ioctls::{IoEventAddress, IoEventRegistration, Kvm, NoDatamatch};
+/// # use libc::EFD_NONBLOCK;
+/// # use vmm_sys_util::eventfd::EventFd;
+/// let kvm = Kvm::new().unwrap();
+/// let vm = Arc::new(kvm.create_vm().unwrap());
+/// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+///
+/// let doorbell =
+///     IoEventRegistration::new(vm.clone(), &evtfd, IoEventAddress::Mmio(0x1000), 0x1u32)
+///         .unwrap();
+/// let kick = IoEventRegistration::new(vm, &evtfd, IoEventAddress::Pio(0xf4), NoDatamatch)
+///     .unwrap();
+/// kick.unregister().unwrap();
+/// # drop(doorbell);
+/// ```
+#[derive(Debug)]
+pub struct IoEventRegistration<V: Borrow<VmFd>, T: Into<u64> + Copy> {
+    vm: V,
+    fd: EventFd,
+    addr: IoEventAddress,
+    datamatch: T,
+    registered: bool,
+}
+
+impl<V: Borrow<VmFd>, T: Into<u64> + Copy> IoEventRegistration<V, T> {
+    /// Registers `fd` to be signaled on writes of `datamatch` to `addr`, as done by
+    /// `VmFd::register_ioevent`.
+    pub fn new(vm: V, fd: &EventFd, addr: IoEventAddress, datamatch: T) -> Result<Self> {
+        let fd = fd.try_clone()?;
+        vm.borrow().register_ioevent(&fd, &addr, datamatch)?;
+        Ok(IoEventRegistration {
+            vm,
+            fd,
+            addr,
+            datamatch,
+            registered: true,
+        })
+    }
+
+    /// Returns the `EventFd` signaled on writes.
+    pub fn fd(&self) -> &EventFd {
+        &self.fd
+    }
+
+    /// Returns the address the `EventFd` is registered to.
+    pub fn addr(&self) -> IoEventAddress {
+        self.addr
+    }
+
+    /// Returns the value that must be written to signal the `EventFd`.
+    pub fn datamatch(&self) -> T {
+        self.datamatch
+    }
+
+    /// Deassigns the ioeventfd, reporting the error that `drop` would ignore.
+    pub fn unregister(mut self) -> Result<()> {
+        self.registered = false;
+        self.vm
+            .borrow()
+            .unregister_ioevent(&self.fd, &self.addr, self.datamatch)
+    }
+}
+
+impl<V: Borrow<VmFd>, T: Into<u64> + Copy> Drop for IoEventRegistration<V, T> {
+    fn drop(&mut self) {
+        if self.registered {
+            let _ = self
+                .vm
+                .borrow()
+                .unregister_ioevent(&self.fd, &self.addr, self.datamatch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kvm;
+    use crate::ioctls::vm::NoDatamatch;
+    use libc::EFD_NONBLOCK;
+    use std::sync::Arc;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_irqfd_registration() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+
+        let irqfd = IrqFdRegistration::new(&vm, &evtfd, 4).unwrap();
+        assert_eq!(irqfd.gsi(), 4);
+        assert!(irqfd.resamplefd().is_none());
+        // The same eventfd cannot be registered twice.
+        assert_eq!(
+            vm.register_irqfd(&evtfd, 4).unwrap_err().errno(),
+            libc::EBUSY
+        );
+        drop(irqfd);
+        vm.register_irqfd(&evtfd, 4).unwrap();
+        vm.unregister_irqfd(&evtfd, 4).unwrap();
+
+        let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let irqfd = IrqFdRegistration::with_resample(&vm, &evtfd, resamplefd, 5).unwrap();
+        assert!(irqfd.resamplefd().is_some());
+        irqfd.unregister().unwrap();
+        vm.register_irqfd(&evtfd, 5).unwrap();
+    }
+
+    #[test]
+    fn test_ioevent_registration() {
+        let kvm = Kvm::new().unwrap();
+        let vm = Arc::new(kvm.create_vm().unwrap());
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let addr = IoEventAddress::Mmio(0x1000);
+
+        let ioevent = IoEventRegistration::new(vm.clone(), &evtfd, addr, 0x1234u32).unwrap();
+        assert_eq!(ioevent.datamatch(), 0x1234);
+        assert_eq!(
+            vm.register_ioevent(&evtfd, &addr, 0x1234u32)
+                .unwrap_err()
+                .errno(),
+            libc::EEXIST
+        );
+        drop(ioevent);
+        vm.register_ioevent(&evtfd, &addr, 0x1234u32).unwrap();
+
+        let ioevent =
+            IoEventRegistration::new(&*vm, &evtfd, IoEventAddress::Pio(0xf4), NoDatamatch).unwrap();
+        ioevent.unregister().unwrap();
+        vm.register_ioevent(&evtfd, &IoEventAddress::Pio(0xf4), NoDatamatch)
+            .unwrap();
+    }
+}
@@ -0,0 +1,487 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::mem::{align_of, size_of};
+use std::os::raw::c_int;
+
+use kvm_bindings::{KVM_EXIT_IO, kvm_run};
+use vmm_sys_util::errno;
+
+use crate::ioctls::Result;
+use crate::ioctls::vcpu::{VcpuExit, VcpuFd};
+
+/// The first bytes of a serialized [`ExitTrace`].
+const TRACE_MAGIC: &[u8; 8] = b"KVMEXIT1";
+
+/// The largest offset of the I/O data from the start of `kvm_run` accepted in a
+/// trace. KVM puts the data in the page following `kvm_run`.
+const MAX_IO_DATA_OFFSET: u64 = 1 << 20;
+
+// The replay buffer is made of `u64`s to align `kvm_run`.
+const _: () = assert!(align_of::<kvm_run>() <= align_of::<u64>());
+
+/// One `KVM_RUN` call recorded by an [`ExitRecorder`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExitRecord {
+    ret: c_int,
+    errno: c_int,
+    run: Vec<u8>,
+    io_data: Vec<u8>,
+    response: Vec<u8>,
+}
+
+impl ExitRecord {
+    /// Returns the data written back by userspace to complete the exit: the
+    /// buffer of a `VcpuExit::IoIn` or `VcpuExit::MmioRead`, the return value of
+    /// a `VcpuExit::Hypercall`, or the error flag of a `VcpuExit::X86Wrmsr` and
+    /// of a `VcpuExit::X86Rdmsr` followed by its data, in little endian. It is
+    /// empty for the other exits.
+    pub fn response(&self) -> &[u8] {
+        &self.response
+    }
+
+    /// Returns the `KVM_EXIT_IO` data offset and length of the record, if any.
+    fn io_range(&self) -> Option<(u64, usize)> {
+        if self.ret != 0 {
+            return None;
+        }
+        // SAFETY: `run` holds the bytes of a `kvm_run`, which is plain data.
+        let run: kvm_run = unsafe { std::ptr::read_unaligned(self.run.as_ptr().cast()) };
+        if run.exit_reason != KVM_EXIT_IO {
+            return None;
+        }
+        // SAFETY: The exit reason tells which union field to use.
+        let io = unsafe { run.__bindgen_anon_1.io };
+        Some((io.data_offset, io.count as usize * usize::from(io.size)))
+    }
+}
+
+/// The exits of a vCPU recorded by an [`ExitRecorder`], to be replayed by an
+/// [`ExitReplayer`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExitTrace {
+    records: Vec<ExitRecord>,
+}
+
+impl ExitTrace {
+    /// Returns the records of the trace, in the order of the runs.
+    pub fn records(&self) -> &[ExitRecord] {
+        &self.records
+    }
+
+    /// Serializes the trace, to be read back with
+    /// [`from_bytes`](Self::from_bytes).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = TRACE_MAGIC.to_vec();
+        for record in &self.records {
+            bytes.extend_from_slice(&record.ret.to_le_bytes());
+            bytes.extend_from_slice(&record.errno.to_le_bytes());
+            for data in [&record.run, &record.io_data, &record.response] {
+                bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(data);
+            }
+        }
+        bytes
+    }
+
+    /// Reads a trace serialized with [`to_bytes`](Self::to_bytes).
+    ///
+    /// Fails with `EINVAL` if the bytes are not a trace recorded with the same
+    /// `kvm_run` layout.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        if take(&mut bytes, TRACE_MAGIC.len())? != TRACE_MAGIC {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let mut records = Vec::new();
+        while !bytes.is_empty() {
+            let record = ExitRecord {
+                ret: take_i32(&mut bytes)?,
+                errno: take_i32(&mut bytes)?,
+                run: take_vec(&mut bytes)?,
+                io_data: take_vec(&mut bytes)?,
+                response: take_vec(&mut bytes)?,
+            };
+            if record.run.len() != size_of::<kvm_run>() {
+                return Err(errno::Error::new(libc::EINVAL));
+            }
+            // The replayer puts the I/O data after `kvm_run`, where it must not
+            // overlap it.
+            if let Some((offset, len)) = record.io_range() {
+                if offset < size_of::<kvm_run>() as u64
+                    || offset > MAX_IO_DATA_OFFSET
+                    || len != record.io_data.len()
+                {
+                    return Err(errno::Error::new(libc::EINVAL));
+                }
+            }
+            records.push(record);
+        }
+        Ok(ExitTrace { records })
+    }
+}
+
+fn take<'b>(bytes: &mut &'b [u8], len: usize) -> Result<&'b [u8]> {
+    if bytes.len() < len {
+        return Err(errno::Error::new(libc::EINVAL));
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Ok(head)
+}
+
+fn take_i32(bytes: &mut &[u8]) -> Result<i32> {
+    Ok(i32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+}
+
+fn take_vec(bytes: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap());
+    Ok(take(bytes, len as usize)?.to_vec())
+}
+
+// Returns the data written back by userspace to complete `exit`, as described in
+// `ExitRecord::response`.
+fn response(exit: &VcpuExit) -> Vec<u8> {
+    match exit {
+        VcpuExit::IoIn(_, data) | VcpuExit::MmioRead(_, data) => data.to_vec(),
+        VcpuExit::Hypercall(hypercall) => hypercall.ret.to_le_bytes().to_vec(),
+        VcpuExit::X86Rdmsr(msr) => {
+            let mut response = vec![*msr.error];
+            response.extend_from_slice(&msr.data.to_le_bytes());
+            response
+        }
+        VcpuExit::X86Wrmsr(msr) => vec![*msr.error],
+        _ => Vec::new(),
+    }
+}
+
+/// Runs a vCPU and records its exits in an [`ExitTrace`], with the data written
+/// back by userspace to complete them.
+///
+/// The data written back to an exit is read when the vCPU runs again, or when
+/// the recorder is finished.
+#[derive(Debug)]
+pub struct ExitRecorder {
+    vcpu: VcpuFd,
+    trace: ExitTrace,
+    // Whether the response of the last record is still to be read.
+    pending: bool,
+}
+
+impl ExitRecorder {
+    /// Creates a recorder running `vcpu`.
+    pub fn new(vcpu: VcpuFd) -> Self {
+        ExitRecorder {
+            vcpu,
+            trace: ExitTrace::default(),
+            pending: false,
+        }
+    }
+
+    /// Returns the vCPU.
+    pub fn vcpu(&self) -> &VcpuFd {
+        &self.vcpu
+    }
+
+    /// Returns the vCPU, e.g. to set its registers between runs.
+    pub fn vcpu_mut(&mut self) -> &mut VcpuFd {
+        &mut self.vcpu
+    }
+
+    /// Runs the vCPU like `VcpuFd::run`, and records the exit.
+    pub fn run(&mut self) -> Result<VcpuExit<'_>> {
+        self.record_response();
+        let (ret, errno) = self.vcpu.run_raw();
+        let run = self.vcpu.get_kvm_run();
+        // SAFETY: `kvm_run` is plain data, so all its bytes can be read.
+        let run = unsafe {
+            std::slice::from_raw_parts((run as *const kvm_run).cast::<u8>(), size_of::<kvm_run>())
+        };
+        let mut record = ExitRecord {
+            ret,
+            errno: if ret == 0 { 0 } else { errno.errno() },
+            run: run.to_vec(),
+            io_data: Vec::new(),
+            response: Vec::new(),
+        };
+        let exit = self.vcpu.decode_exit(ret, errno);
+        match &exit {
+            Ok(VcpuExit::IoIn(_, data)) => record.io_data = data.to_vec(),
+            Ok(VcpuExit::IoOut(_, data)) => record.io_data = data.to_vec(),
+            _ => {}
+        }
+        self.trace.records.push(record);
+        self.pending = ret == 0;
+        exit
+    }
+
+    /// Reads the data written back to the last exit, before the vCPU runs again.
+    fn record_response(&mut self) {
+        if !std::mem::take(&mut self.pending) {
+            return;
+        }
+        let Some(record) = self.trace.records.last_mut() else {
+            return;
+        };
+        // `kvm_run` still holds the exit, which is decoded again.
+        if let Ok(exit) = self
+            .vcpu
+            .decode_exit(record.ret, errno::Error::new(record.errno))
+        {
+            record.response = response(&exit);
+        }
+    }
+
+    /// Returns the vCPU and the trace, with the data written back to the last exit.
+    pub fn finish(mut self) -> (VcpuFd, ExitTrace) {
+        self.record_response();
+        (self.vcpu, self.trace)
+    }
+}
+
+/// Replays the exits of an [`ExitTrace`] without KVM, decoded as `VcpuFd::run`
+/// decodes them, e.g. to run a device model against a recorded guest in a unit
+/// test.
+#[derive(Debug)]
+pub struct ExitReplayer {
+    records: Vec<ExitRecord>,
+    next: usize,
+    // The `kvm_run` of the last exit returned, followed by its I/O data.
+    buf: Vec<u64>,
+}
+
+impl ExitReplayer {
+    /// Creates a replayer returning the exits of `trace`.
+    pub fn new(trace: ExitTrace) -> Self {
+        ExitReplayer {
+            records: trace.records,
+            next: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Returns the number of exits left to replay.
+    pub fn remaining(&self) -> usize {
+        self.records.len() - self.next
+    }
+
+    /// Returns the next exit of the trace, or `None` at the end of the trace.
+    ///
+    /// The data of the exit can be written to like the data of the exits of
+    /// `VcpuFd::run`, and compared to the recording with
+    /// [`response_matches`](Self::response_matches).
+    pub fn run(&mut self) -> Option<Result<VcpuExit<'_>>> {
+        let record = self.records.get(self.next)?;
+        self.next += 1;
+
+        let mut len = size_of::<kvm_run>();
+        let io_range = record.io_range();
+        if let Some((offset, io_len)) = io_range {
+            len = len.max(offset as usize + io_len);
+        }
+        self.buf.clear();
+        self.buf.resize(len.div_ceil(size_of::<u64>()), 0);
+        // SAFETY: The buffer is made of `len` bytes or more of initialized integers.
+        let bytes = unsafe { std::slice::from_raw_parts_mut(self.buf.as_mut_ptr().cast(), len) };
+        bytes[..record.run.len()].copy_from_slice(&record.run);
+        if let Some((offset, io_len)) = io_range {
+            let offset = offset as usize;
+            bytes[offset..offset + io_len].copy_from_slice(&record.io_data);
+        }
+        Some(self.decode())
+    }
+
+    /// Returns the data written back to the last exit returned by
+    /// [`run`](Self::run) when it was recorded.
+    pub fn recorded_response(&self) -> Option<&[u8]> {
+        let index = self.next.checked_sub(1)?;
+        Some(self.records[index].response())
+    }
+
+    /// Returns whether the data written back to the last exit returned by
+    /// [`run`](Self::run) is the data written back when it was recorded.
+    pub fn response_matches(&mut self) -> bool {
+        let Some(index) = self.next.checked_sub(1) else {
+            return true;
+        };
+        let response = match self.decode() {
+            Ok(exit) => response(&exit),
+            Err(_) => Vec::new(),
+        };
+        response == self.records[index].response
+    }
+
+    // Decodes the exit of the last record, loaded in the buffer.
+    fn decode(&mut self) -> Result<VcpuExit<'_>> {
+        let record = &self.records[self.next - 1];
+        // SAFETY: The buffer is aligned for `kvm_run` and holds it, followed by the
+        // I/O data that `ExitTrace::from_bytes` checked to not overlap it, and the
+        // exit borrows the buffer.
+        unsafe {
+            VcpuFd::decode_run(
+                self.buf.as_mut_ptr().cast(),
+                record.ret,
+                errno::Error::new(record.errno),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::undocumented_unsafe_blocks)]
+
+    use super::*;
+
+    #[test]
+    fn test_trace_bytes() {
+        let mut run = kvm_run {
+            exit_reason: KVM_EXIT_IO,
+            ..Default::default()
+        };
+        run.__bindgen_anon_1.io.size = 1;
+        run.__bindgen_anon_1.io.count = 1;
+        run.__bindgen_anon_1.io.data_offset = 0x1000;
+        let run_bytes = unsafe {
+            std::slice::from_raw_parts((&run as *const kvm_run).cast::<u8>(), size_of::<kvm_run>())
+        };
+        let record = ExitRecord {
+            ret: 0,
+            errno: 0,
+            run: run_bytes.to_vec(),
+            io_data: vec![0x41],
+            response: vec![0x41],
+        };
+        let trace = ExitTrace {
+            records: vec![record.clone()],
+        };
+        let bytes = trace.to_bytes();
+        assert_eq!(ExitTrace::from_bytes(&bytes).unwrap(), trace);
+        assert_eq!(ExitTrace::from_bytes(TRACE_MAGIC).unwrap().records(), []);
+
+        let invalid = |records: Vec<ExitRecord>| {
+            let bytes = ExitTrace { records }.to_bytes();
+            ExitTrace::from_bytes(&bytes).unwrap_err().errno()
+        };
+        // The I/O data has to match the exit and to be after `kvm_run`.
+        let mut bad = record.clone();
+        bad.io_data.clear();
+        assert_eq!(invalid(vec![bad]), libc::EINVAL);
+        let mut bad = record.clone();
+        run.__bindgen_anon_1.io.data_offset = 8;
+        bad.run = unsafe {
+            std::slice::from_raw_parts((&run as *const kvm_run).cast::<u8>(), size_of::<kvm_run>())
+        }
+        .to_vec();
+        assert_eq!(invalid(vec![bad]), libc::EINVAL);
+        let mut bad = record;
+        bad.run.pop();
+        assert_eq!(invalid(vec![bad]), libc::EINVAL);
+        assert_eq!(
+            ExitTrace::from_bytes(&bytes[..bytes.len() - 1])
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            ExitTrace::from_bytes(b"KVMEXIT0").unwrap_err().errno(),
+            libc::EINVAL
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_record_replay() {
+        use crate::Kvm;
+        use kvm_bindings::kvm_userspace_memory_region;
+        use std::ptr::null_mut;
+
+        #[rustfmt::skip]
+        let code: &[u8] = &[
+            0xe4, 0x2d,             // in al, 0x2d
+            0xe6, 0x2e,             // out 0x2e, al
+            0x8a, 0x07,             // mov al, [bx]
+            0xe6, 0x2e,             // out 0x2e, al
+            0xf4,                   // hlt
+        ];
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let mem_size = 0x4000;
+        let load_addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                mem_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(load_addr, libc::MAP_FAILED);
+        let guest_addr: u64 = 0x1000;
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+            std::ptr::copy_nonoverlapping(code.as_ptr(), load_addr.cast(), code.len());
+        }
+
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut sregs = vcpu.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rip = guest_addr;
+        regs.rbx = 0x8000;
+        regs.rflags = 2;
+        vcpu.set_regs(&regs).unwrap();
+
+        // A device model answering reads with `value`, and logging the port writes.
+        let handle = |exit: VcpuExit, value: u8, out: &mut Vec<u8>| match exit {
+            VcpuExit::IoIn(0x2d, data) => data.fill(value),
+            VcpuExit::MmioRead(0x8000, data) => data.fill(value + 1),
+            VcpuExit::IoOut(0x2e, data) => out.extend_from_slice(data),
+            exit => panic!("unexpected exit: {exit:?}"),
+        };
+
+        let mut recorder = ExitRecorder::new(vcpu);
+        let mut out = Vec::new();
+        loop {
+            match recorder.run().unwrap() {
+                VcpuExit::Hlt => break,
+                exit => handle(exit, 0x41, &mut out),
+            }
+        }
+        assert_eq!(out, [0x41, 0x42]);
+        let (_, trace) = recorder.finish();
+        let responses: Vec<_> = trace.records().iter().map(|r| r.response()).collect();
+        assert_eq!(responses, [&[0x41][..], &[], &[0x42], &[], &[]]);
+
+        // The same model gives the same responses, and sees the same writes.
+        let trace = ExitTrace::from_bytes(&trace.to_bytes()).unwrap();
+        let mut replayer = ExitReplayer::new(trace.clone());
+        let mut out = Vec::new();
+        while let Some(exit) = replayer.run() {
+            match exit.unwrap() {
+                VcpuExit::Hlt => assert_eq!(replayer.remaining(), 0),
+                exit => handle(exit, 0x41, &mut out),
+            }
+            assert!(replayer.response_matches());
+        }
+        assert_eq!(out, [0x41, 0x42]);
+
+        // A different model diverges on the first read.
+        let mut replayer = ExitReplayer::new(trace);
+        handle(replayer.run().unwrap().unwrap(), 0x50, &mut Vec::new());
+        assert!(!replayer.response_matches());
+        assert_eq!(replayer.recorded_response(), Some(&[0x41][..]));
+
+        drop(vm);
+        assert_eq!(unsafe { libc::munmap(load_addr, mem_size) }, 0);
+    }
+}
@@ -0,0 +1,435 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use kvm_bindings::{
+    KVM_DEV_RISCV_AIA_ADDR_APLIC, KVM_DEV_RISCV_AIA_CONFIG_GROUP_BITS,
+    KVM_DEV_RISCV_AIA_CONFIG_GROUP_SHIFT, KVM_DEV_RISCV_AIA_CONFIG_GUEST_BITS,
+    KVM_DEV_RISCV_AIA_CONFIG_HART_BITS, KVM_DEV_RISCV_AIA_CONFIG_IDS,
+    KVM_DEV_RISCV_AIA_CONFIG_MODE, KVM_DEV_RISCV_AIA_CONFIG_SRCS, KVM_DEV_RISCV_AIA_CTRL_INIT,
+    KVM_DEV_RISCV_AIA_GRP_ADDR, KVM_DEV_RISCV_AIA_GRP_APLIC, KVM_DEV_RISCV_AIA_GRP_CONFIG,
+    KVM_DEV_RISCV_AIA_GRP_CTRL, KVM_DEV_RISCV_AIA_GRP_IMSIC, KVM_DEV_RISCV_AIA_IMSIC_ISEL_BITS,
+    KVM_DEV_RISCV_AIA_MODE_AUTO, KVM_DEV_RISCV_AIA_MODE_EMUL, KVM_DEV_RISCV_AIA_MODE_HWACCEL,
+    KVM_DEV_RISCV_IMSIC_SIZE, kvm_create_device, kvm_device_type_KVM_DEV_TYPE_RISCV_AIA,
+};
+use vmm_sys_util::errno;
+
+use crate::device_attr::DeviceAttributes;
+use crate::ioctls::Result;
+use crate::ioctls::device::DeviceFd;
+use crate::ioctls::vm::VmFd;
+
+// APLIC registers.
+const APLIC_DOMAINCFG: u32 = 0x0000;
+const APLIC_SOURCECFG_BASE: u32 = 0x0004;
+const APLIC_SETIP_BASE: u32 = 0x1c00;
+const APLIC_SETIE_BASE: u32 = 0x1e00;
+const APLIC_TARGET_BASE: u32 = 0x3004;
+
+// IMSIC registers, selected through `siselect`. On RV64, only the even-numbered
+// `eip` and `eie` registers exist, each holding 64 interrupt identities.
+const IMSIC_EIDELIVERY: u64 = 0x70;
+const IMSIC_EITHRESHOLD: u64 = 0x72;
+const IMSIC_EIP0: u64 = 0x80;
+const IMSIC_EIE0: u64 = 0xc0;
+
+/// How the IMSICs of the guest are implemented.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AiaMode {
+    /// The interrupt files are emulated in software by KVM.
+    Emul,
+    /// The guest interrupt files of the host IMSICs are used.
+    HwAccel,
+    /// KVM uses guest interrupt files when available, and emulation otherwise.
+    Auto,
+}
+
+impl AiaMode {
+    fn to_raw(self) -> u64 {
+        u64::from(match self {
+            AiaMode::Emul => KVM_DEV_RISCV_AIA_MODE_EMUL,
+            AiaMode::HwAccel => KVM_DEV_RISCV_AIA_MODE_HWACCEL,
+            AiaMode::Auto => KVM_DEV_RISCV_AIA_MODE_AUTO,
+        })
+    }
+
+    fn from_raw(mode: u64) -> Option<Self> {
+        match u32::try_from(mode).ok()? {
+            KVM_DEV_RISCV_AIA_MODE_EMUL => Some(AiaMode::Emul),
+            KVM_DEV_RISCV_AIA_MODE_HWACCEL => Some(AiaMode::HwAccel),
+            KVM_DEV_RISCV_AIA_MODE_AUTO => Some(AiaMode::Auto),
+            _ => None,
+        }
+    }
+}
+
+/// Builder of an in-kernel RISC-V AIA, made of an APLIC for wired interrupts and
+/// of an IMSIC per hart for MSIs.
+///
+/// The AIA is initialized by [`build`](Self::build), which must be called after
+/// all the vCPUs are created. Settings that are not given keep the KVM defaults.
+///
+/// # Example
+///
+/// ```rust
+/// # use kvm_ioctls::Kvm;
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let vcpus: Vec<_> = (0..2).map(|id| vm.create_vcpu(id).unwrap()).collect();
+///
+/// # #[cfg(target_arch = "riscv64")]
+/// # {
+/// use kvm_ioctls::{AiaMode, RiscvAiaBuilder};
+///
+/// let aia = RiscvAiaBuilder::new(2)
+///     .mode(AiaMode::Emul)
+///     .ids(255)
+///     .sources(32)
+///     .aplic(0x0c00_0000)
+///     .imsic(0x2800_0000)
+///     .build(&vm)
+///     .unwrap();
+/// let state = aia.save().unwrap();
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RiscvAiaBuilder {
+    nr_harts: u32,
+    mode: Option<AiaMode>,
+    nr_ids: Option<u32>,
+    nr_sources: Option<u32>,
+    group: Option<(u32, u32)>,
+    hart_bits: Option<u32>,
+    guest_bits: Option<u32>,
+    aplic_addr: Option<u64>,
+    imsic_base: Option<u64>,
+    imsic_addrs: Vec<u64>,
+}
+
+impl RiscvAiaBuilder {
+    /// Creates a builder for the AIA of a VM with `nr_harts` vCPUs.
+    pub fn new(nr_harts: u32) -> Self {
+        RiscvAiaBuilder {
+            nr_harts,
+            ..Default::default()
+        }
+    }
+
+    /// Sets how the IMSICs are implemented.
+    pub fn mode(mut self, mode: AiaMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Sets the number of MSI identities of each IMSIC, which plus one must be a
+    /// multiple of 64.
+    pub fn ids(mut self, nr_ids: u32) -> Self {
+        self.nr_ids = Some(nr_ids);
+        self
+    }
+
+    /// Sets the number of wired interrupt sources of the APLIC.
+    pub fn sources(mut self, nr_sources: u32) -> Self {
+        self.nr_sources = Some(nr_sources);
+        self
+    }
+
+    /// Splits the harts in `1 << group_bits` groups, whose IMSICs are selected by
+    /// the bits of the IMSIC addresses starting at `group_shift`.
+    pub fn groups(mut self, group_bits: u32, group_shift: u32) -> Self {
+        self.group = Some((group_bits, group_shift));
+        self
+    }
+
+    /// Sets the number of bits of the IMSIC addresses selecting a hart in a group.
+    ///
+    /// By default, this is the number of bits needed for `nr_harts` harts when
+    /// the IMSICs are placed with [`imsic`](Self::imsic).
+    pub fn hart_bits(mut self, hart_bits: u32) -> Self {
+        self.hart_bits = Some(hart_bits);
+        self
+    }
+
+    /// Sets the number of bits of the IMSIC addresses selecting a guest interrupt
+    /// file, for nested virtualization.
+    pub fn guest_bits(mut self, guest_bits: u32) -> Self {
+        self.guest_bits = Some(guest_bits);
+        self
+    }
+
+    /// Places the APLIC at `addr`.
+    pub fn aplic(mut self, addr: u64) -> Self {
+        self.aplic_addr = Some(addr);
+        self
+    }
+
+    /// Places the IMSICs of the harts contiguously from `base`, in a single group.
+    pub fn imsic(mut self, base: u64) -> Self {
+        self.imsic_base = Some(base);
+        self
+    }
+
+    /// Places the IMSIC of each hart at the given address.
+    pub fn imsic_addrs(mut self, addrs: Vec<u64>) -> Self {
+        self.imsic_addrs = addrs;
+        self
+    }
+
+    fn imsic_addrs_of_harts(&self) -> Result<Vec<u64>> {
+        match self.imsic_base {
+            Some(base) if self.imsic_addrs.is_empty() => {
+                let stride = u64::from(KVM_DEV_RISCV_IMSIC_SIZE) << self.guest_bits.unwrap_or(0);
+                Ok((0..u64::from(self.nr_harts))
+                    .map(|hart| base + hart * stride)
+                    .collect())
+            }
+            None if self.imsic_addrs.len() == self.nr_harts as usize => {
+                Ok(self.imsic_addrs.clone())
+            }
+            _ => Err(errno::Error::new(libc::EINVAL)),
+        }
+    }
+
+    /// Creates and initializes the AIA.
+    ///
+    /// Fails with `EINVAL` if the IMSICs are not placed with exactly one of
+    /// [`imsic`](Self::imsic) and [`imsic_addrs`](Self::imsic_addrs), the latter
+    /// with an address for each hart.
+    pub fn build(self, vm: &VmFd) -> Result<RiscvAia> {
+        let imsic_addrs = self.imsic_addrs_of_harts()?;
+        let hart_bits = self.hart_bits.or_else(|| {
+            self.imsic_base
+                .map(|_| u32::BITS - self.nr_harts.saturating_sub(1).leading_zeros())
+        });
+
+        let mut device = kvm_create_device {
+            type_: kvm_device_type_KVM_DEV_TYPE_RISCV_AIA,
+            fd: 0,
+            flags: 0,
+        };
+        let fd = vm.create_device(&mut device)?;
+
+        let mut config = vec![
+            (
+                KVM_DEV_RISCV_AIA_CONFIG_MODE,
+                self.mode.map(AiaMode::to_raw),
+            ),
+            (KVM_DEV_RISCV_AIA_CONFIG_IDS, self.nr_ids.map(u64::from)),
+            (
+                KVM_DEV_RISCV_AIA_CONFIG_SRCS,
+                self.nr_sources.map(u64::from),
+            ),
+        ];
+        if let Some((group_bits, group_shift)) = self.group {
+            config.push((KVM_DEV_RISCV_AIA_CONFIG_GROUP_BITS, Some(group_bits.into())));
+            config.push((
+                KVM_DEV_RISCV_AIA_CONFIG_GROUP_SHIFT,
+                Some(group_shift.into()),
+            ));
+        }
+        config.push((KVM_DEV_RISCV_AIA_CONFIG_HART_BITS, hart_bits.map(u64::from)));
+        config.push((
+            KVM_DEV_RISCV_AIA_CONFIG_GUEST_BITS,
+            self.guest_bits.map(u64::from),
+        ));
+        for (attr, value) in config {
+            if let Some(value) = value {
+                // Configuration attributes are `unsigned long` values.
+                fd.set_attr(KVM_DEV_RISCV_AIA_GRP_CONFIG, u64::from(attr), &value)?;
+            }
+        }
+
+        if let Some(addr) = self.aplic_addr {
+            fd.set_attr(
+                KVM_DEV_RISCV_AIA_GRP_ADDR,
+                u64::from(KVM_DEV_RISCV_AIA_ADDR_APLIC),
+                &addr,
+            )?;
+        }
+        for (hart, addr) in imsic_addrs.iter().enumerate() {
+            // KVM_DEV_RISCV_AIA_ADDR_IMSIC(hart)
+            let attr = 1 + hart as u64;
+            fd.set_attr(KVM_DEV_RISCV_AIA_GRP_ADDR, attr, addr)?;
+        }
+
+        fd.set_attr_empty(
+            KVM_DEV_RISCV_AIA_GRP_CTRL,
+            u64::from(KVM_DEV_RISCV_AIA_CTRL_INIT),
+        )?;
+        Ok(RiscvAia {
+            fd,
+            nr_harts: self.nr_harts,
+        })
+    }
+}
+
+/// State of the APLIC and IMSICs of an AIA, as returned by [`RiscvAia::save`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RiscvAiaState {
+    /// APLIC registers, as `(offset, value)` pairs.
+    pub aplic: Vec<(u32, u32)>,
+    /// IMSIC registers of each hart, as `(siselect, value)` pairs.
+    pub imsics: Vec<Vec<(u64, u64)>>,
+}
+
+/// An in-kernel RISC-V AIA created by [`RiscvAiaBuilder`].
+///
+/// Once it is initialized, MSIs can be sent to the harts with `VmFd::signal_msi`
+/// and irqfds, and the wired interrupts of the APLIC with `VmFd::set_irq_line`.
+#[derive(Debug)]
+pub struct RiscvAia {
+    fd: DeviceFd,
+    nr_harts: u32,
+}
+
+impl RiscvAia {
+    /// Returns the file descriptor of the AIA device.
+    pub fn device_fd(&self) -> &DeviceFd {
+        &self.fd
+    }
+
+    fn config(&self, attr: u32) -> Result<u64> {
+        self.fd
+            .get_attr(KVM_DEV_RISCV_AIA_GRP_CONFIG, u64::from(attr))
+    }
+
+    /// Returns how the IMSICs are implemented, as chosen by KVM in `Auto` mode.
+    pub fn mode(&self) -> Result<AiaMode> {
+        AiaMode::from_raw(self.config(KVM_DEV_RISCV_AIA_CONFIG_MODE)?)
+            .ok_or(errno::Error::new(libc::EINVAL))
+    }
+
+    /// Returns the number of MSI identities of each IMSIC.
+    pub fn nr_ids(&self) -> Result<u32> {
+        Ok(self.config(KVM_DEV_RISCV_AIA_CONFIG_IDS)? as u32)
+    }
+
+    /// Returns the number of wired interrupt sources of the APLIC.
+    pub fn nr_sources(&self) -> Result<u32> {
+        Ok(self.config(KVM_DEV_RISCV_AIA_CONFIG_SRCS)? as u32)
+    }
+
+    // Returns the APLIC registers, in the order they are restored. The domain is
+    // enabled last.
+    fn aplic_offsets(&self) -> Result<Vec<u32>> {
+        let nr_sources = self.nr_sources()?;
+        // Source 0 does not exist, but has a bit in the pending and enable words.
+        let nr_words = (nr_sources + 1).div_ceil(32);
+        let mut offsets = Vec::new();
+        for base in [APLIC_SOURCECFG_BASE, APLIC_TARGET_BASE] {
+            offsets.extend((0..nr_sources).map(|i| base + 4 * i));
+        }
+        for base in [APLIC_SETIP_BASE, APLIC_SETIE_BASE] {
+            offsets.extend((0..nr_words).map(|i| base + 4 * i));
+        }
+        offsets.push(APLIC_DOMAINCFG);
+        Ok(offsets)
+    }
+
+    // Returns the IMSIC registers, in the order they are restored. Interrupt
+    // delivery is enabled last.
+    fn imsic_isels(&self) -> Result<Vec<u64>> {
+        let nr_words = u64::from(self.nr_ids()? + 1) / 64;
+        let mut isels = Vec::new();
+        for base in [IMSIC_EIP0, IMSIC_EIE0] {
+            isels.extend((0..nr_words).map(|i| base + 2 * i));
+        }
+        isels.extend([IMSIC_EITHRESHOLD, IMSIC_EIDELIVERY]);
+        Ok(isels)
+    }
+
+    fn imsic_attr(hart: u32, isel: u64) -> u64 {
+        (u64::from(hart) << KVM_DEV_RISCV_AIA_IMSIC_ISEL_BITS) | isel
+    }
+
+    /// Saves the state of the APLIC and of the IMSIC of every hart.
+    ///
+    /// The vCPUs must not be running.
+    pub fn save(&self) -> Result<RiscvAiaState> {
+        let mut aplic = Vec::new();
+        for offset in self.aplic_offsets()? {
+            let value = self
+                .fd
+                .get_attr(KVM_DEV_RISCV_AIA_GRP_APLIC, u64::from(offset))?;
+            aplic.push((offset, value));
+        }
+
+        let isels = self.imsic_isels()?;
+        let mut imsics = Vec::new();
+        for hart in 0..self.nr_harts {
+            let mut imsic = Vec::new();
+            for &isel in &isels {
+                let value = self
+                    .fd
+                    .get_attr(KVM_DEV_RISCV_AIA_GRP_IMSIC, Self::imsic_attr(hart, isel))?;
+                imsic.push((isel, value));
+            }
+            imsics.push(imsic);
+        }
+        Ok(RiscvAiaState { aplic, imsics })
+    }
+
+    /// Restores a state returned by [`save`](Self::save) into this AIA, which
+    /// must have been built with the same configuration.
+    pub fn restore(&self, state: &RiscvAiaState) -> Result<()> {
+        if state.imsics.len() != self.nr_harts as usize {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        for &(offset, value) in &state.aplic {
+            self.fd
+                .set_attr(KVM_DEV_RISCV_AIA_GRP_APLIC, u64::from(offset), &value)?;
+        }
+        for (hart, imsic) in (0..).zip(&state.imsics) {
+            for &(isel, value) in imsic {
+                self.fd.set_attr(
+                    KVM_DEV_RISCV_AIA_GRP_IMSIC,
+                    Self::imsic_attr(hart, isel),
+                    &value,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kvm;
+
+    #[test]
+    fn test_riscv_aia_save_restore() {
+        let kvm = Kvm::new().unwrap();
+        let builder = RiscvAiaBuilder::new(2)
+            .mode(AiaMode::Emul)
+            .ids(127)
+            .sources(32)
+            .aplic(0x0c00_0000)
+            .imsic(0x2800_0000);
+
+        let vm = kvm.create_vm().unwrap();
+        let _vcpus: Vec<_> = (0..2).map(|id| vm.create_vcpu(id).unwrap()).collect();
+        assert_eq!(
+            RiscvAiaBuilder::new(2)
+                .imsic_addrs(vec![0x2800_0000])
+                .build(&vm)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        let aia = builder.clone().build(&vm).unwrap();
+        assert_eq!(aia.mode().unwrap(), AiaMode::Emul);
+        assert_eq!(aia.nr_ids().unwrap(), 127);
+        assert_eq!(aia.nr_sources().unwrap(), 32);
+        let state = aia.save().unwrap();
+        // 32 sources and targets, 2 pending and 2 enable words and domaincfg.
+        assert_eq!(state.aplic.len(), 69);
+        // 2 pending and 2 enable registers, eithreshold and eidelivery.
+        assert_eq!(state.imsics, vec![state.imsics[0].clone(); 2]);
+        assert_eq!(state.imsics[0].len(), 6);
+
+        let vm = kvm.create_vm().unwrap();
+        let _vcpus: Vec<_> = (0..2).map(|id| vm.create_vcpu(id).unwrap()).collect();
+        let aia = builder.build(&vm).unwrap();
+        aia.restore(&state).unwrap();
+        assert_eq!(aia.save().unwrap(), state);
+    }
+}
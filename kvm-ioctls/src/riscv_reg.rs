@@ -0,0 +1,597 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::mem::offset_of;
+
+use kvm_bindings::*;
+
+use crate::ioctls::vcpu::reg_size;
+
+const KVM_REG_RISCV_U64: u64 = KVM_REG_RISCV as u64;
+// `unsigned long` registers are 64 bits wide on riscv64.
+const KVM_REG_SIZE_ULONG: u64 = KVM_REG_SIZE_U64;
+const ULONG_SIZE: usize = size_of::<std::os::raw::c_ulong>();
+
+/// Names of the ISA extensions, indexed by their `KVM_RISCV_ISA_EXT_*` id.
+const ISA_EXT_NAMES: &[&str] = &[
+    "a",
+    "c",
+    "d",
+    "f",
+    "h",
+    "i",
+    "m",
+    "svpbmt",
+    "sstc",
+    "svinval",
+    "zihintpause",
+    "zicbom",
+    "zicboz",
+    "zbb",
+    "ssaia",
+    "v",
+    "svnapot",
+    "zba",
+    "zbs",
+    "zicntr",
+    "zicsr",
+    "zifencei",
+    "zihpm",
+    "smstateen",
+    "zicond",
+    "zbc",
+    "zbkb",
+    "zbkc",
+    "zbkx",
+    "zknd",
+    "zkne",
+    "zknh",
+    "zkr",
+    "zksed",
+    "zksh",
+    "zkt",
+    "zvbb",
+    "zvbc",
+    "zvkb",
+    "zvkg",
+    "zvkned",
+    "zvknha",
+    "zvknhb",
+    "zvksed",
+    "zvksh",
+    "zvkt",
+    "zfh",
+    "zfhmin",
+    "zihintntl",
+    "zvfh",
+    "zvfhmin",
+    "zfa",
+    "ztso",
+    "zacas",
+    "sscofpmf",
+    "zimop",
+    "zca",
+    "zcb",
+    "zcd",
+    "zcf",
+    "zcmop",
+    "zawrs",
+    "smnpm",
+    "ssnpm",
+    "svade",
+    "svadu",
+    "svvptc",
+    "zabha",
+    "ziccrse",
+    "zaamo",
+    "zalrsc",
+];
+
+/// Names of the SBI extensions, indexed by their `KVM_RISCV_SBI_EXT_*` id.
+const SBI_EXT_NAMES: &[&str] = &[
+    "v01",
+    "time",
+    "ipi",
+    "rfence",
+    "srst",
+    "hsm",
+    "pmu",
+    "experimental",
+    "vendor",
+    "dbcn",
+    "sta",
+    "susp",
+];
+
+// Make sure the tables above are updated when the bindings are regenerated.
+const _: () = assert!(ISA_EXT_NAMES.len() == KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_MAX as usize);
+const _: () = assert!(SBI_EXT_NAMES.len() == KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_MAX as usize);
+
+/// Returns the `KVM_RISCV_ISA_EXT_*` id of the ISA extension called `name`.
+pub(crate) fn isa_ext_id(name: &str) -> Option<u32> {
+    ISA_EXT_NAMES
+        .iter()
+        .position(|ext| ext.eq_ignore_ascii_case(name))
+        .map(|id| id as u32)
+}
+
+/// Returns the name of the ISA extension with the `KVM_RISCV_ISA_EXT_*` id `id`.
+pub(crate) fn isa_ext_name(id: u32) -> Option<&'static str> {
+    ISA_EXT_NAMES.get(id as usize).copied()
+}
+
+/// Returns the `KVM_RISCV_SBI_EXT_*` id of the SBI extension called `name`.
+pub(crate) fn sbi_ext_id(name: &str) -> Option<u32> {
+    SBI_EXT_NAMES
+        .iter()
+        .position(|ext| ext.eq_ignore_ascii_case(name))
+        .map(|id| id as u32)
+}
+
+/// Returns the name of the SBI extension with the `KVM_RISCV_SBI_EXT_*` id `id`.
+pub(crate) fn sbi_ext_name(id: u32) -> Option<&'static str> {
+    SBI_EXT_NAMES.get(id as usize).copied()
+}
+
+/// Class of a riscv64 register, as encoded in its `KVM_REG_RISCV_TYPE_MASK` bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RiscvRegClass {
+    /// Configuration register from `kvm_riscv_config` (`KVM_REG_RISCV_CONFIG`).
+    Config,
+    /// Core register from `kvm_riscv_core` (`KVM_REG_RISCV_CORE`).
+    Core,
+    /// Control and status register (`KVM_REG_RISCV_CSR`).
+    Csr,
+    /// Timer register from `kvm_riscv_timer` (`KVM_REG_RISCV_TIMER`).
+    Timer,
+    /// Single precision floating point register (`KVM_REG_RISCV_FP_F`).
+    FpF,
+    /// Double precision floating point register (`KVM_REG_RISCV_FP_D`).
+    FpD,
+    /// ISA extension register (`KVM_REG_RISCV_ISA_EXT`).
+    IsaExt,
+    /// SBI extension register (`KVM_REG_RISCV_SBI_EXT`).
+    SbiExt,
+    /// Vector register or vector CSR (`KVM_REG_RISCV_VECTOR`).
+    Vector,
+    /// SBI extension state register (`KVM_REG_RISCV_SBI_STATE`).
+    SbiState,
+}
+
+/// Identifier of a riscv64 vCPU register as used by `KVM_GET_ONE_REG`/`KVM_SET_ONE_REG`.
+///
+/// The constructors encode the architecture, size, type and subtype bits so that callers do
+/// not have to assemble register ids by hand. Registers that are fields of one of the KVM
+/// register structures are created from the byte offset of the field, usually obtained with
+/// `std::mem::offset_of!`. The raw id can be obtained with [`RiscvReg::id`] and passed to
+/// [`VcpuFd::get_one_reg`](crate::VcpuFd::get_one_reg) and
+/// [`VcpuFd::set_one_reg`](crate::VcpuFd::set_one_reg).
+///
+/// # Example
+///
+/// ```rust
+/// # #[cfg(target_arch = "riscv64")]
+/// # {
+/// use kvm_bindings::kvm_riscv_csr;
+/// use kvm_ioctls::RiscvReg;
+/// use std::mem::offset_of;
+///
+/// assert_eq!(RiscvReg::PC.id(), 0x8030_0000_0200_0000);
+/// assert_eq!(RiscvReg::x(10).id(), 0x8030_0000_0200_000a);
+/// let satp = RiscvReg::csr(offset_of!(kvm_riscv_csr, satp));
+/// assert_eq!(satp.size(), 8);
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RiscvReg(u64);
+
+impl RiscvReg {
+    /// Base ISA of the vCPU.
+    pub const ISA: Self = Self::config(offset_of!(kvm_riscv_config, isa));
+    /// Zicbom cache block size.
+    pub const ZICBOM_BLOCK_SIZE: Self =
+        Self::config(offset_of!(kvm_riscv_config, zicbom_block_size));
+    /// Machine vendor id.
+    pub const MVENDORID: Self = Self::config(offset_of!(kvm_riscv_config, mvendorid));
+    /// Machine architecture id.
+    pub const MARCHID: Self = Self::config(offset_of!(kvm_riscv_config, marchid));
+    /// Machine implementation id.
+    pub const MIMPID: Self = Self::config(offset_of!(kvm_riscv_config, mimpid));
+    /// Zicboz cache block size.
+    pub const ZICBOZ_BLOCK_SIZE: Self =
+        Self::config(offset_of!(kvm_riscv_config, zicboz_block_size));
+    /// Supported `satp` modes.
+    pub const SATP_MODE: Self = Self::config(offset_of!(kvm_riscv_config, satp_mode));
+
+    /// Program counter.
+    pub const PC: Self = Self::core(offset_of!(kvm_riscv_core, regs.pc));
+    /// Privilege mode of the vCPU.
+    pub const MODE: Self = Self::core(offset_of!(kvm_riscv_core, mode));
+
+    /// Timer frequency.
+    pub const TIMER_FREQUENCY: Self = Self::timer(offset_of!(kvm_riscv_timer, frequency));
+    /// Current timer value.
+    pub const TIMER_TIME: Self = Self::timer(offset_of!(kvm_riscv_timer, time));
+    /// Timer compare value.
+    pub const TIMER_COMPARE: Self = Self::timer(offset_of!(kvm_riscv_timer, compare));
+    /// Timer state (`KVM_RISCV_TIMER_STATE_*`).
+    pub const TIMER_STATE: Self = Self::timer(offset_of!(kvm_riscv_timer, state));
+
+    /// Single precision floating point control and status register.
+    pub const FP_F_FCSR: Self = Self(
+        KVM_REG_RISCV_U64
+            | KVM_REG_SIZE_U32
+            | KVM_REG_RISCV_FP_F as u64
+            | (offset_of!(__riscv_f_ext_state, fcsr) / size_of::<u32>()) as u64,
+    );
+    /// Double precision floating point control and status register.
+    pub const FP_D_FCSR: Self = Self(
+        KVM_REG_RISCV_U64
+            | KVM_REG_SIZE_U32
+            | KVM_REG_RISCV_FP_D as u64
+            | (offset_of!(__riscv_d_ext_state, fcsr) / size_of::<u64>()) as u64,
+    );
+
+    /// Vector start index.
+    pub const VSTART: Self = Self::vector_csr(offset_of!(__riscv_v_ext_state, vstart));
+    /// Vector length.
+    pub const VL: Self = Self::vector_csr(offset_of!(__riscv_v_ext_state, vl));
+    /// Vector data type.
+    pub const VTYPE: Self = Self::vector_csr(offset_of!(__riscv_v_ext_state, vtype));
+    /// Vector control and status register.
+    pub const VCSR: Self = Self::vector_csr(offset_of!(__riscv_v_ext_state, vcsr));
+    /// Vector register length in bytes.
+    pub const VLENB: Self = Self::vector_csr(offset_of!(__riscv_v_ext_state, vlenb));
+
+    /// Creates a register from a raw `KVM_{GET,SET}_ONE_REG` id.
+    pub const fn from_id(id: u64) -> Self {
+        Self(id)
+    }
+
+    /// Returns the raw id of the register.
+    pub const fn id(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns the size of the register in bytes.
+    ///
+    /// This is the same value returned by [`reg_size`](crate::reg_size).
+    pub fn size(&self) -> usize {
+        reg_size(self.0)
+    }
+
+    const fn ulong(type_: u32, offset: usize) -> Self {
+        Self(KVM_REG_RISCV_U64 | KVM_REG_SIZE_ULONG | type_ as u64 | (offset / ULONG_SIZE) as u64)
+    }
+
+    /// Creates a configuration register id from its byte offset in `kvm_riscv_config`.
+    pub const fn config(offset: usize) -> Self {
+        Self::ulong(KVM_REG_RISCV_CONFIG, offset)
+    }
+
+    /// Creates a core register id from its byte offset in `kvm_riscv_core`.
+    pub const fn core(offset: usize) -> Self {
+        Self::ulong(KVM_REG_RISCV_CORE, offset)
+    }
+
+    /// Creates the id of the general purpose register `xn`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0 or larger than 31. `x0` is hardwired to zero and KVM uses its slot
+    /// for the program counter, see [`RiscvReg::PC`].
+    pub const fn x(n: usize) -> Self {
+        assert!(n > 0 && n < 32, "invalid general purpose register");
+        Self::core(offset_of!(kvm_riscv_core, regs) + n * ULONG_SIZE)
+    }
+
+    /// Creates a general CSR id from its byte offset in `kvm_riscv_csr`.
+    pub const fn csr(offset: usize) -> Self {
+        Self::ulong(KVM_REG_RISCV_CSR | KVM_REG_RISCV_CSR_GENERAL, offset)
+    }
+
+    /// Creates an AIA CSR id from its byte offset in `kvm_riscv_aia_csr`.
+    pub const fn aia_csr(offset: usize) -> Self {
+        Self::ulong(KVM_REG_RISCV_CSR | KVM_REG_RISCV_CSR_AIA, offset)
+    }
+
+    /// Creates a Smstateen CSR id from its byte offset in `kvm_riscv_smstateen_csr`.
+    pub const fn smstateen_csr(offset: usize) -> Self {
+        Self::ulong(KVM_REG_RISCV_CSR | KVM_REG_RISCV_CSR_SMSTATEEN, offset)
+    }
+
+    /// Creates a timer register id from its byte offset in `kvm_riscv_timer`.
+    pub const fn timer(offset: usize) -> Self {
+        Self(
+            KVM_REG_RISCV_U64
+                | KVM_REG_SIZE_U64
+                | KVM_REG_RISCV_TIMER as u64
+                | (offset / size_of::<u64>()) as u64,
+        )
+    }
+
+    /// Creates the id of the single precision floating point register `fn`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is larger than 31.
+    pub const fn fp_f(n: usize) -> Self {
+        assert!(n < 32, "invalid floating point register");
+        Self(KVM_REG_RISCV_U64 | KVM_REG_SIZE_U32 | KVM_REG_RISCV_FP_F as u64 | n as u64)
+    }
+
+    /// Creates the id of the double precision floating point register `fn`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is larger than 31.
+    pub const fn fp_d(n: usize) -> Self {
+        assert!(n < 32, "invalid floating point register");
+        Self(KVM_REG_RISCV_U64 | KVM_REG_SIZE_U64 | KVM_REG_RISCV_FP_D as u64 | n as u64)
+    }
+
+    /// Creates a vector CSR id from its byte offset in `__riscv_v_ext_state`.
+    pub const fn vector_csr(offset: usize) -> Self {
+        Self::ulong(KVM_REG_RISCV_VECTOR, offset)
+    }
+
+    /// Creates the id of the vector register `vn`.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Index of the vector register.
+    /// * `vlenb` - Length of a vector register in bytes, as read from [`RiscvReg::VLENB`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is larger than 31 or `vlenb` is not a power of two.
+    pub const fn vector(n: usize, vlenb: u64) -> Self {
+        assert!(n < 32, "invalid vector register");
+        assert!(vlenb.is_power_of_two(), "invalid vector register length");
+        Self(
+            KVM_REG_RISCV_U64
+                | ((vlenb.trailing_zeros() as u64) << KVM_REG_SIZE_SHIFT)
+                | KVM_REG_RISCV_VECTOR as u64
+                | (size_of::<__riscv_v_ext_state>() / ULONG_SIZE + n) as u64,
+        )
+    }
+
+    /// Creates the id of the register that enables or disables a single ISA extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `ext` - One of the `KVM_RISCV_ISA_EXT_*` ids.
+    pub const fn isa_ext(ext: u32) -> Self {
+        Self(
+            KVM_REG_RISCV_U64
+                | KVM_REG_SIZE_ULONG
+                | (KVM_REG_RISCV_ISA_EXT | KVM_REG_RISCV_ISA_SINGLE) as u64
+                | ext as u64,
+        )
+    }
+
+    /// Creates the id of the register that enables several ISA extensions at once.
+    ///
+    /// Bit `i` of the register at index `word` stands for the ISA extension with id
+    /// `word * 64 + i`.
+    pub const fn isa_ext_multi_en(word: u32) -> Self {
+        Self(
+            KVM_REG_RISCV_U64
+                | KVM_REG_SIZE_ULONG
+                | (KVM_REG_RISCV_ISA_EXT | KVM_REG_RISCV_ISA_MULTI_EN) as u64
+                | word as u64,
+        )
+    }
+
+    /// Creates the id of the register that disables several ISA extensions at once.
+    ///
+    /// Bit `i` of the register at index `word` stands for the ISA extension with id
+    /// `word * 64 + i`.
+    pub const fn isa_ext_multi_dis(word: u32) -> Self {
+        Self(
+            KVM_REG_RISCV_U64
+                | KVM_REG_SIZE_ULONG
+                | (KVM_REG_RISCV_ISA_EXT | KVM_REG_RISCV_ISA_MULTI_DIS) as u64
+                | word as u64,
+        )
+    }
+
+    /// Creates the id of the register that enables or disables a single SBI extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `ext` - One of the `KVM_RISCV_SBI_EXT_*` ids.
+    pub const fn sbi_ext(ext: u32) -> Self {
+        Self(
+            KVM_REG_RISCV_U64
+                | KVM_REG_SIZE_ULONG
+                | (KVM_REG_RISCV_SBI_EXT | KVM_REG_RISCV_SBI_SINGLE) as u64
+                | ext as u64,
+        )
+    }
+
+    /// Creates the id of the register that enables several SBI extensions at once.
+    pub const fn sbi_ext_multi_en(word: u32) -> Self {
+        Self(
+            KVM_REG_RISCV_U64
+                | KVM_REG_SIZE_ULONG
+                | (KVM_REG_RISCV_SBI_EXT | KVM_REG_RISCV_SBI_MULTI_EN) as u64
+                | word as u64,
+        )
+    }
+
+    /// Creates the id of the register that disables several SBI extensions at once.
+    pub const fn sbi_ext_multi_dis(word: u32) -> Self {
+        Self(
+            KVM_REG_RISCV_U64
+                | KVM_REG_SIZE_ULONG
+                | (KVM_REG_RISCV_SBI_EXT | KVM_REG_RISCV_SBI_MULTI_DIS) as u64
+                | word as u64,
+        )
+    }
+
+    /// Creates the id of an SBI steal-time accounting register from its byte offset in
+    /// `kvm_riscv_sbi_sta`.
+    pub const fn sbi_sta(offset: usize) -> Self {
+        Self::ulong(KVM_REG_RISCV_SBI_STATE | KVM_REG_RISCV_SBI_STA, offset)
+    }
+
+    /// Returns the class of the register, or `None` if it is not a riscv64 register or
+    /// the class is unknown.
+    pub const fn class(&self) -> Option<RiscvRegClass> {
+        if self.0 & KVM_REG_ARCH_MASK as u64 != KVM_REG_RISCV_U64 {
+            return None;
+        }
+        match self.0 as u32 & KVM_REG_RISCV_TYPE_MASK {
+            KVM_REG_RISCV_CONFIG => Some(RiscvRegClass::Config),
+            KVM_REG_RISCV_CORE => Some(RiscvRegClass::Core),
+            KVM_REG_RISCV_CSR => Some(RiscvRegClass::Csr),
+            KVM_REG_RISCV_TIMER => Some(RiscvRegClass::Timer),
+            KVM_REG_RISCV_FP_F => Some(RiscvRegClass::FpF),
+            KVM_REG_RISCV_FP_D => Some(RiscvRegClass::FpD),
+            KVM_REG_RISCV_ISA_EXT => Some(RiscvRegClass::IsaExt),
+            KVM_REG_RISCV_SBI_EXT => Some(RiscvRegClass::SbiExt),
+            KVM_REG_RISCV_VECTOR => Some(RiscvRegClass::Vector),
+            KVM_REG_RISCV_SBI_STATE => Some(RiscvRegClass::SbiState),
+            _ => None,
+        }
+    }
+
+    /// Returns the `KVM_RISCV_ISA_EXT_*` id if this is a single ISA extension register.
+    pub const fn as_isa_ext(&self) -> Option<u32> {
+        match self.class() {
+            Some(RiscvRegClass::IsaExt)
+                if self.0 as u32 & KVM_REG_RISCV_SUBTYPE_MASK == KVM_REG_RISCV_ISA_SINGLE =>
+            {
+                Some(self.0 as u32 & !(KVM_REG_RISCV_TYPE_MASK | KVM_REG_RISCV_SUBTYPE_MASK))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the `KVM_RISCV_SBI_EXT_*` id if this is a single SBI extension register.
+    pub const fn as_sbi_ext(&self) -> Option<u32> {
+        match self.class() {
+            Some(RiscvRegClass::SbiExt)
+                if self.0 as u32 & KVM_REG_RISCV_SUBTYPE_MASK == KVM_REG_RISCV_SBI_SINGLE =>
+            {
+                Some(self.0 as u32 & !(KVM_REG_RISCV_TYPE_MASK | KVM_REG_RISCV_SUBTYPE_MASK))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<u64> for RiscvReg {
+    fn from(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+impl From<RiscvReg> for u64 {
+    fn from(reg: RiscvReg) -> Self {
+        reg.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reg_ids() {
+        assert_eq!(RiscvReg::ISA.id(), 0x8030_0000_0100_0000);
+        assert_eq!(RiscvReg::SATP_MODE.id(), 0x8030_0000_0100_0006);
+        assert_eq!(RiscvReg::PC.id(), 0x8030_0000_0200_0000);
+        assert_eq!(RiscvReg::x(1).id(), 0x8030_0000_0200_0001);
+        assert_eq!(RiscvReg::x(31).id(), 0x8030_0000_0200_001f);
+        assert_eq!(RiscvReg::MODE.id(), 0x8030_0000_0200_0020);
+        assert_eq!(
+            RiscvReg::csr(offset_of!(kvm_riscv_csr, satp)).id(),
+            0x8030_0000_0300_0008
+        );
+        assert_eq!(
+            RiscvReg::aia_csr(offset_of!(kvm_riscv_aia_csr, siph)).id(),
+            0x8030_0000_0301_0004
+        );
+        assert_eq!(
+            RiscvReg::smstateen_csr(offset_of!(kvm_riscv_smstateen_csr, sstateen0)).id(),
+            0x8030_0000_0302_0000
+        );
+        assert_eq!(RiscvReg::TIMER_STATE.id(), 0x8030_0000_0400_0003);
+        assert_eq!(RiscvReg::fp_f(3).id(), 0x8020_0000_0500_0003);
+        assert_eq!(RiscvReg::FP_F_FCSR.id(), 0x8020_0000_0500_0020);
+        assert_eq!(RiscvReg::fp_d(3).id(), 0x8030_0000_0600_0003);
+        assert_eq!(RiscvReg::FP_D_FCSR.id(), 0x8020_0000_0600_0020);
+        assert_eq!(
+            RiscvReg::isa_ext(KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_V).id(),
+            0x8030_0000_0700_000f
+        );
+        assert_eq!(RiscvReg::isa_ext_multi_dis(1).id(), 0x8030_0000_0702_0001);
+        assert_eq!(
+            RiscvReg::sbi_ext(KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_HSM).id(),
+            0x8030_0000_0800_0005
+        );
+        assert_eq!(RiscvReg::sbi_ext_multi_en(0).id(), 0x8030_0000_0801_0000);
+        assert_eq!(RiscvReg::VLENB.id(), 0x8030_0000_0900_0004);
+        assert_eq!(RiscvReg::vector(2, 16).id(), 0x8040_0000_0900_0008);
+        assert_eq!(
+            RiscvReg::sbi_sta(offset_of!(kvm_riscv_sbi_sta, shmem_hi)).id(),
+            0x8030_0000_0a00_0001
+        );
+
+        assert_eq!(RiscvReg::fp_f(0).size(), 4);
+        assert_eq!(RiscvReg::vector(0, 32).size(), 32);
+        assert_eq!(u64::from(RiscvReg::PC), 0x8030_0000_0200_0000);
+    }
+
+    #[test]
+    fn test_reg_class() {
+        assert_eq!(RiscvReg::ISA.class(), Some(RiscvRegClass::Config));
+        assert_eq!(RiscvReg::x(5).class(), Some(RiscvRegClass::Core));
+        assert_eq!(RiscvReg::aia_csr(0).class(), Some(RiscvRegClass::Csr));
+        assert_eq!(RiscvReg::TIMER_TIME.class(), Some(RiscvRegClass::Timer));
+        assert_eq!(RiscvReg::FP_D_FCSR.class(), Some(RiscvRegClass::FpD));
+        assert_eq!(RiscvReg::VL.class(), Some(RiscvRegClass::Vector));
+        assert_eq!(RiscvReg::sbi_sta(0).class(), Some(RiscvRegClass::SbiState));
+        assert_eq!(RiscvReg::from(0x6030_0000_0010_0000).class(), None);
+
+        assert_eq!(RiscvReg::isa_ext(11).as_isa_ext(), Some(11));
+        assert_eq!(RiscvReg::isa_ext_multi_en(0).as_isa_ext(), None);
+        assert_eq!(RiscvReg::sbi_ext(9).as_sbi_ext(), Some(9));
+        assert_eq!(RiscvReg::isa_ext(9).as_sbi_ext(), None);
+    }
+
+    #[test]
+    fn test_ext_names() {
+        assert_eq!(
+            isa_ext_id("zicbom"),
+            Some(KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZICBOM)
+        );
+        assert_eq!(
+            isa_ext_id("V"),
+            Some(KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_V)
+        );
+        assert_eq!(isa_ext_id("foo"), None);
+        assert_eq!(
+            isa_ext_name(KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZALRSC),
+            Some("zalrsc")
+        );
+        assert_eq!(
+            isa_ext_name(KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_MAX),
+            None
+        );
+
+        assert_eq!(
+            sbi_ext_id("dbcn"),
+            Some(KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_DBCN)
+        );
+        assert_eq!(
+            sbi_ext_name(KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_SUSP),
+            Some("susp")
+        );
+        assert_eq!(
+            sbi_ext_name(KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_MAX),
+            None
+        );
+    }
+}
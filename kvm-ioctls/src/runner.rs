@@ -0,0 +1,535 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A vCPU run loop that dispatches port and memory mapped I/O to devices.
+//!
+//! [`VcpuRunner`] calls `VcpuFd::run` until an exit that it cannot handle. The
+//! `VcpuExit::IoIn` and `VcpuExit::IoOut` exits go to a [`PioBus`], and the
+//! `VcpuExit::MmioRead` and `VcpuExit::MmioWrite` exits to an [`MmioBus`]. The
+//! writes queued in the coalesced ring are dispatched first, so that devices see
+//! the accesses of the guest in order.
+//!
+//! [`Bus`] implements both traits, over [`BusDevice`]s registered for ranges of
+//! addresses or ports. It can be shared by the runners of all the vCPUs.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::sync::{Arc, Mutex};
+//!
+//! use kvm_ioctls::runner::{Bus, BusDevice, VcpuRunner};
+//! use kvm_ioctls::{Kvm, VcpuExit};
+//!
+//! struct Serial(Vec<u8>);
+//!
+//! impl BusDevice for Serial {
+//!     fn read(&mut self, _offset: u64, data: &mut [u8]) {
+//!         data.fill(0);
+//!     }
+//!
+//!     fn write(&mut self, offset: u64, data: &[u8]) {
+//!         if offset == 0 {
+//!             self.0.extend_from_slice(data);
+//!         }
+//!     }
+//! }
+//!
+//! let kvm = Kvm::new().unwrap();
+//! let vm = kvm.create_vm().unwrap();
+//! let vcpu = vm.create_vcpu(0).unwrap();
+//!
+//! let pio = Arc::new(Bus::new());
+//! let serial = Arc::new(Mutex::new(Serial(Vec::new())));
+//! pio.insert(0x3f8, 8, serial.clone()).unwrap();
+//!
+//! let mut runner = VcpuRunner::new(vcpu, pio, Bus::new());
+//! // There is no guest code here, so make the run return at once.
+//! runner.vcpu_mut().set_kvm_immediate_exit(1);
+//! match runner.run().unwrap() {
+//!     VcpuExit::Hlt => println!("output: {:?}", serial.lock().unwrap().0),
+//!     VcpuExit::Intr => {}
+//!     exit => panic!("unhandled exit: {exit:?}"),
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+
+use vmm_sys_util::errno;
+
+use crate::coalesced::CoalescedAccessKind;
+use crate::ioctls::Result;
+use crate::ioctls::vcpu::{VcpuExit, VcpuFd};
+
+/// A device emulated in userspace, registered on a [`Bus`].
+pub trait BusDevice: Send {
+    /// Handles a read of `data.len()` bytes at `offset` from the start of the
+    /// range of the device, by filling `data`.
+    fn read(&mut self, offset: u64, data: &mut [u8]);
+
+    /// Handles a write of `data` at `offset` from the start of the range of the
+    /// device.
+    fn write(&mut self, offset: u64, data: &[u8]);
+}
+
+/// The devices behind the I/O ports of the guest.
+pub trait PioBus {
+    /// Handles a read from `port` by filling `data`, and returns whether a device
+    /// handled it.
+    fn pio_read(&self, port: u16, data: &mut [u8]) -> bool;
+
+    /// Handles a write of `data` to `port`, and returns whether a device handled
+    /// it.
+    fn pio_write(&self, port: u16, data: &[u8]) -> bool;
+}
+
+/// The devices behind the memory mapped I/O addresses of the guest.
+pub trait MmioBus {
+    /// Handles a read from `addr` by filling `data`, and returns whether a device
+    /// handled it.
+    fn mmio_read(&self, addr: u64, data: &mut [u8]) -> bool;
+
+    /// Handles a write of `data` to `addr`, and returns whether a device handled
+    /// it.
+    fn mmio_write(&self, addr: u64, data: &[u8]) -> bool;
+}
+
+/// A bus with no device, which handles no access.
+impl PioBus for () {
+    fn pio_read(&self, _port: u16, _data: &mut [u8]) -> bool {
+        false
+    }
+
+    fn pio_write(&self, _port: u16, _data: &[u8]) -> bool {
+        false
+    }
+}
+
+/// A bus with no device, which handles no access.
+impl MmioBus for () {
+    fn mmio_read(&self, _addr: u64, _data: &mut [u8]) -> bool {
+        false
+    }
+
+    fn mmio_write(&self, _addr: u64, _data: &[u8]) -> bool {
+        false
+    }
+}
+
+impl<B: PioBus + ?Sized> PioBus for Arc<B> {
+    fn pio_read(&self, port: u16, data: &mut [u8]) -> bool {
+        (**self).pio_read(port, data)
+    }
+
+    fn pio_write(&self, port: u16, data: &[u8]) -> bool {
+        (**self).pio_write(port, data)
+    }
+}
+
+impl<B: MmioBus + ?Sized> MmioBus for Arc<B> {
+    fn mmio_read(&self, addr: u64, data: &mut [u8]) -> bool {
+        (**self).mmio_read(addr, data)
+    }
+
+    fn mmio_write(&self, addr: u64, data: &[u8]) -> bool {
+        (**self).mmio_write(addr, data)
+    }
+}
+
+// A device and the length of its range, indexed by the start of the range.
+type BusEntry = (u64, Arc<Mutex<dyn BusDevice>>);
+
+/// [`BusDevice`]s registered for non-overlapping ranges of addresses or ports.
+///
+/// Devices can be added and removed while the bus is used by vCPUs. An access is
+/// handled by the device whose range contains all its bytes, with the offset of
+/// the access in that range.
+#[derive(Default)]
+pub struct Bus {
+    devices: RwLock<BTreeMap<u64, BusEntry>>,
+}
+
+impl Bus {
+    /// Creates a bus with no device.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `device` for the range of `len` bytes starting at `base`.
+    ///
+    /// Fails with `EINVAL` if the range is empty or wraps around, and with `EEXIST`
+    /// if it overlaps the range of another device.
+    pub fn insert(&self, base: u64, len: u64, device: Arc<Mutex<dyn BusDevice>>) -> Result<()> {
+        if len == 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let end = base
+            .checked_add(len - 1)
+            .ok_or(errno::Error::new(libc::EINVAL))?;
+        let mut devices = self.devices.write().unwrap();
+        let overlaps_prev = devices
+            .range(..=base)
+            .next_back()
+            .is_some_and(|(&prev, &(prev_len, _))| prev + (prev_len - 1) >= base);
+        let overlaps_next = devices.range(base..=end).next().is_some();
+        if overlaps_prev || overlaps_next {
+            return Err(errno::Error::new(libc::EEXIST));
+        }
+        devices.insert(base, (len, device));
+        Ok(())
+    }
+
+    /// Removes the device registered at `base` and returns it.
+    pub fn remove(&self, base: u64) -> Option<Arc<Mutex<dyn BusDevice>>> {
+        self.devices
+            .write()
+            .unwrap()
+            .remove(&base)
+            .map(|(_, device)| device)
+    }
+
+    /// Returns the number of devices on the bus.
+    pub fn len(&self) -> usize {
+        self.devices.read().unwrap().len()
+    }
+
+    /// Returns whether the bus has no device.
+    pub fn is_empty(&self) -> bool {
+        self.devices.read().unwrap().is_empty()
+    }
+
+    // Returns the device handling an access of `len` bytes at `addr`, and the
+    // offset of the access in its range.
+    fn resolve(&self, addr: u64, len: usize) -> Option<(Arc<Mutex<dyn BusDevice>>, u64)> {
+        let devices = self.devices.read().unwrap();
+        let (&base, (dev_len, device)) = devices.range(..=addr).next_back()?;
+        let offset = addr - base;
+        let len = u64::try_from(len).ok()?;
+        if offset.checked_add(len)? > *dev_len {
+            return None;
+        }
+        Some((device.clone(), offset))
+    }
+
+    /// Handles a read of `data` at `addr`, and returns whether a device handled it.
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+        match self.resolve(addr, data.len()) {
+            Some((device, offset)) => {
+                device.lock().unwrap().read(offset, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Handles a write of `data` at `addr`, and returns whether a device handled it.
+    pub fn write(&self, addr: u64, data: &[u8]) -> bool {
+        match self.resolve(addr, data.len()) {
+            Some((device, offset)) => {
+                device.lock().unwrap().write(offset, data);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let devices = self.devices.read().unwrap();
+        f.debug_list()
+            .entries(
+                devices
+                    .iter()
+                    .map(|(&base, &(len, _))| base..=base + (len - 1)),
+            )
+            .finish()
+    }
+}
+
+impl PioBus for Bus {
+    fn pio_read(&self, port: u16, data: &mut [u8]) -> bool {
+        self.read(u64::from(port), data)
+    }
+
+    fn pio_write(&self, port: u16, data: &[u8]) -> bool {
+        self.write(u64::from(port), data)
+    }
+}
+
+impl MmioBus for Bus {
+    fn mmio_read(&self, addr: u64, data: &mut [u8]) -> bool {
+        self.read(addr, data)
+    }
+
+    fn mmio_write(&self, addr: u64, data: &[u8]) -> bool {
+        self.write(addr, data)
+    }
+}
+
+/// Runs a vCPU, dispatching its I/O exits to a [`PioBus`] and an [`MmioBus`].
+///
+/// If the coalesced ring is mapped with `VcpuFd::map_coalesced_mmio_ring`, its
+/// entries are dispatched after each exit, before the exit itself.
+#[derive(Debug)]
+pub struct VcpuRunner<P, M> {
+    vcpu: VcpuFd,
+    pio: P,
+    mmio: M,
+}
+
+impl<P: PioBus, M: MmioBus> VcpuRunner<P, M> {
+    /// Creates a runner for `vcpu`, dispatching to the `pio` and `mmio` buses.
+    pub fn new(vcpu: VcpuFd, pio: P, mmio: M) -> Self {
+        VcpuRunner { vcpu, pio, mmio }
+    }
+
+    /// Returns the vCPU.
+    pub fn vcpu(&self) -> &VcpuFd {
+        &self.vcpu
+    }
+
+    /// Returns the vCPU, e.g. to set its registers between runs.
+    pub fn vcpu_mut(&mut self) -> &mut VcpuFd {
+        &mut self.vcpu
+    }
+
+    /// Returns the PIO bus.
+    pub fn pio_bus(&self) -> &P {
+        &self.pio
+    }
+
+    /// Returns the MMIO bus.
+    pub fn mmio_bus(&self) -> &M {
+        &self.mmio
+    }
+
+    /// Returns the vCPU, dropping the runner.
+    pub fn into_vcpu(self) -> VcpuFd {
+        self.vcpu
+    }
+
+    /// Dispatches the writes in the coalesced ring to the buses, and returns their
+    /// number. Does nothing if the ring is not mapped.
+    ///
+    /// Writes that no device handles are dropped.
+    pub fn drain_coalesced(&mut self) -> usize {
+        let Ok(drain) = self.vcpu.drain_coalesced_io() else {
+            return 0;
+        };
+        let mut count = 0;
+        for access in drain {
+            match access.kind {
+                CoalescedAccessKind::Mmio => self.mmio.mmio_write(access.addr, access.data()),
+                CoalescedAccessKind::Pio => self.pio.pio_write(access.addr as u16, access.data()),
+            };
+            count += 1;
+        }
+        count
+    }
+
+    /// Runs the vCPU until an exit that is not handled by the buses, and returns
+    /// it.
+    ///
+    /// An I/O exit is returned as is when no device handles it, and the caller
+    /// can complete it before running the vCPU again. `VcpuExit::Intr` is
+    /// returned so that the caller sees the kicks of the vCPU, and `KVM_RUN` is
+    /// retried when it fails with `EAGAIN`.
+    pub fn run(&mut self) -> Result<VcpuExit<'_>> {
+        loop {
+            let (ret, errno) = self.vcpu.run_raw();
+            if ret != 0 && errno.errno() == libc::EAGAIN {
+                continue;
+            }
+            self.drain_coalesced();
+            // SAFETY: `kvm_run` is at the start of the mapping of the vCPU, which holds the
+            // I/O data. A handled exit is dropped before the vCPU is used again, and the
+            // returned one borrows `self`.
+            let exit = unsafe { VcpuFd::decode_run(self.vcpu.kvm_run_mut_ptr(), ret, errno)? };
+            if let Some(exit) = dispatch(exit, &self.pio, &self.mmio) {
+                return Ok(exit);
+            }
+        }
+    }
+}
+
+// Dispatches `exit` to the buses, and returns it if it is not handled.
+fn dispatch<'a>(
+    mut exit: VcpuExit<'a>,
+    pio: &impl PioBus,
+    mmio: &impl MmioBus,
+) -> Option<VcpuExit<'a>> {
+    let handled = match &mut exit {
+        VcpuExit::IoIn(port, data) => pio.pio_read(*port, data),
+        VcpuExit::IoOut(port, data) => pio.pio_write(*port, data),
+        VcpuExit::MmioRead(addr, data) => mmio.mmio_read(*addr, data),
+        VcpuExit::MmioWrite(addr, data) => mmio.mmio_write(*addr, data),
+        _ => false,
+    };
+    (!handled).then_some(exit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        reads: Vec<(u64, usize)>,
+        writes: Vec<(u64, Vec<u8>)>,
+    }
+
+    impl BusDevice for Recorder {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            self.reads.push((offset, data.len()));
+            data.fill(offset as u8);
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) {
+            self.writes.push((offset, data.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_bus() {
+        let bus = Bus::new();
+        let device = Arc::new(Mutex::new(Recorder::default()));
+        assert_eq!(
+            bus.insert(0x10, 0, device.clone()).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            bus.insert(u64::MAX, 2, device.clone()).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        bus.insert(0x10, 0x10, device.clone()).unwrap();
+        bus.insert(u64::MAX, 1, device.clone()).unwrap();
+        for (base, len) in [(0x10, 1), (0x8, 0x9), (0x1f, 2), (0x0, 0x100)] {
+            assert_eq!(
+                bus.insert(base, len, device.clone()).unwrap_err().errno(),
+                libc::EEXIST
+            );
+        }
+        bus.insert(0x20, 8, device.clone()).unwrap();
+        bus.insert(0x8, 8, device.clone()).unwrap();
+        assert_eq!(bus.len(), 4);
+        assert_eq!(
+            format!("{bus:?}"),
+            format!("[8..=15, 16..=31, 32..=39, {0}..={0}]", u64::MAX)
+        );
+
+        let mut data = [0u8; 4];
+        assert!(bus.read(0x14, &mut data));
+        assert_eq!(data, [4; 4]);
+        assert!(bus.pio_write(0x1c, &[1, 2, 3, 4]));
+        // Accesses must be within the range of a device.
+        assert!(!bus.mmio_write(0x1e, &[1, 2, 3, 4]));
+        assert!(!bus.read(0x40, &mut data));
+        assert!(!bus.read(u64::MAX, &mut data));
+        {
+            let device = device.lock().unwrap();
+            assert_eq!(device.reads, [(4, 4)]);
+            assert_eq!(device.writes, [(0xc, vec![1, 2, 3, 4])]);
+        }
+
+        assert!(bus.remove(0x10).is_some());
+        assert!(bus.remove(0x10).is_none());
+        assert!(!bus.read(0x14, &mut data));
+        assert!(!().pio_read(0x14, &mut data));
+        assert!(!().mmio_write(0x14, &data));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_vcpu_runner() {
+        use crate::Kvm;
+        use kvm_bindings::kvm_userspace_memory_region;
+        use std::ptr::null_mut;
+
+        #[rustfmt::skip]
+        let code: &[u8] = &[
+            0xe6, 0x2c,             // out 0x2c, al
+            0xe4, 0x2d,             // in al, 0x2d
+            0xe6, 0x2e,             // out 0x2e, al
+            0x88, 0x07,             // mov [bx], al
+            0x8a, 0x07,             // mov al, [bx]
+            0xf4,                   // hlt
+        ];
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let mem_size = 0x4000;
+        // SAFETY: This creates a new anonymous mapping, checked below.
+        let load_addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                mem_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(load_addr, libc::MAP_FAILED);
+        let guest_addr: u64 = 0x1000;
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        // SAFETY: The memory is mapped above and is only unmapped once the VM is
+        // dropped, and the code fits in it.
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+            std::ptr::copy_nonoverlapping(code.as_ptr(), load_addr.cast(), code.len());
+        }
+        // Coalesce the first write, and check that it is dispatched before the read.
+        vm.register_coalesced_pio(0x2c, 1).unwrap();
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        vcpu.map_coalesced_mmio_ring().unwrap();
+        let mut sregs = vcpu.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rip = guest_addr;
+        regs.rax = 0x39;
+        regs.rbx = 0x8000;
+        regs.rflags = 2;
+        vcpu.set_regs(&regs).unwrap();
+
+        let pio = Arc::new(Bus::new());
+        let ports = Arc::new(Mutex::new(Recorder::default()));
+        pio.insert(0x2c, 2, ports.clone()).unwrap();
+        let mut runner = VcpuRunner::new(vcpu, pio, ());
+
+        // The unhandled port write is returned, then the unhandled MMIO write.
+        assert!(matches!(runner.run().unwrap(), VcpuExit::IoOut(0x2e, [1])));
+        assert!(matches!(
+            runner.run().unwrap(),
+            VcpuExit::MmioWrite(0x8000, [1])
+        ));
+        {
+            let ports = ports.lock().unwrap();
+            assert_eq!(ports.writes, [(0, vec![0x39])]);
+            assert_eq!(ports.reads, [(1, 1)]);
+        }
+
+        // The MMIO read is handled once a device is on the MMIO bus.
+        let mmio = Arc::new(Bus::new());
+        let memory = Arc::new(Mutex::new(Recorder::default()));
+        mmio.insert(0x8000, 0x1000, memory.clone()).unwrap();
+        let mut runner = VcpuRunner::new(runner.into_vcpu(), (), mmio);
+        assert!(matches!(runner.run().unwrap(), VcpuExit::Hlt));
+        assert_eq!(memory.lock().unwrap().reads, [(0, 1)]);
+        assert_eq!(runner.vcpu().get_regs().unwrap().rax & 0xff, 0);
+
+        drop(runner);
+        drop(vm);
+        // SAFETY: The memory is mapped above, and no longer used by the guest.
+        assert_eq!(unsafe { libc::munmap(load_addr, mem_size) }, 0);
+    }
+}
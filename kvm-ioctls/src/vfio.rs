@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::BTreeMap;
+use std::mem::MaybeUninit;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+
+use kvm_bindings::{
+    KVM_DEV_VFIO_FILE, KVM_DEV_VFIO_FILE_ADD, KVM_DEV_VFIO_FILE_DEL, KVM_DEV_VFIO_GROUP,
+    KVM_DEV_VFIO_GROUP_SET_SPAPR_TCE, kvm_create_device, kvm_device_type_KVM_DEV_TYPE_VFIO,
+    kvm_vfio_spapr_tce,
+};
+use vmm_sys_util::errno;
+
+use crate::device_attr::{DeviceAttributes, Pod};
+use crate::ioctls::Result;
+use crate::ioctls::device::DeviceFd;
+use crate::ioctls::vm::VmFd;
+
+// SAFETY: `kvm_vfio_spapr_tce` is made of two `i32`, without padding.
+unsafe impl Pod for kvm_vfio_spapr_tce {}
+
+// Identifies an open file by the device and inode it refers to, which do not
+// depend on the file descriptor used to access it.
+fn file_id(fd: BorrowedFd) -> Result<(u64, u64)> {
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    // SAFETY: `fd` is a valid file descriptor and `stat` is large enough for the
+    // structure written by the kernel.
+    let ret = unsafe { libc::fstat(fd.as_raw_fd(), stat.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(errno::Error::last());
+    }
+    // SAFETY: `fstat` succeeded, so `stat` is initialized.
+    let stat = unsafe { stat.assume_init() };
+    Ok((stat.st_dev, stat.st_ino))
+}
+
+/// The KVM VFIO device, which tells KVM about the VFIO groups and device files
+/// (cdevs) used by the VM.
+///
+/// KVM uses these files to let the guest use non-coherent DMA and, on SPAPR
+/// hosts, to attach the TCE tables of the VM to the groups. The device keeps a
+/// duplicate of each file it added, so they stay open while they are registered
+/// even if the caller closes its own copy.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # use std::fs::File;
+/// # use kvm_ioctls::{Kvm, VfioKvmDevice};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let mut vfio = VfioKvmDevice::new(&vm).unwrap();
+///
+/// let group = File::open("/dev/vfio/26").unwrap();
+/// vfio.add_file(&group).unwrap();
+/// drop(group);
+/// assert_eq!(vfio.len(), 1);
+/// ```
+#[derive(Debug)]
+pub struct VfioKvmDevice {
+    fd: DeviceFd,
+    files: BTreeMap<(u64, u64), OwnedFd>,
+}
+
+impl VfioKvmDevice {
+    /// Creates the KVM VFIO device of `vm`.
+    pub fn new(vm: &VmFd) -> Result<Self> {
+        let mut device = kvm_create_device {
+            type_: kvm_device_type_KVM_DEV_TYPE_VFIO,
+            fd: 0,
+            flags: 0,
+        };
+        Ok(VfioKvmDevice {
+            fd: vm.create_device(&mut device)?,
+            files: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the file descriptor of the device.
+    pub fn device_fd(&self) -> &DeviceFd {
+        &self.fd
+    }
+
+    /// Returns the number of files added to the device.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Returns whether no file is added to the device.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Returns whether `file` is added to the device.
+    pub fn contains_file(&self, file: &impl AsFd) -> Result<bool> {
+        Ok(self.files.contains_key(&file_id(file.as_fd())?))
+    }
+
+    /// Adds a VFIO group or device file with `KVM_DEV_VFIO_FILE_ADD`.
+    ///
+    /// Fails with `EEXIST` if `file` is already added, even through another file
+    /// descriptor, and KVM fails with `EINVAL` if `file` is not a VFIO file.
+    pub fn add_file(&mut self, file: &impl AsFd) -> Result<()> {
+        let id = file_id(file.as_fd())?;
+        if self.files.contains_key(&id) {
+            return Err(errno::Error::new(libc::EEXIST));
+        }
+        let file = file.as_fd().try_clone_to_owned()?;
+        self.fd.set_attr(
+            KVM_DEV_VFIO_FILE,
+            u64::from(KVM_DEV_VFIO_FILE_ADD),
+            &file.as_raw_fd(),
+        )?;
+        self.files.insert(id, file);
+        Ok(())
+    }
+
+    /// Removes a file added with [`add_file`](Self::add_file), with
+    /// `KVM_DEV_VFIO_FILE_DEL`, and closes the duplicate kept by the device.
+    ///
+    /// Fails with `ENOENT` if `file` is not added to the device.
+    pub fn remove_file(&mut self, file: &impl AsFd) -> Result<()> {
+        let id = file_id(file.as_fd())?;
+        let added = self.files.get(&id).ok_or(errno::Error::new(libc::ENOENT))?;
+        self.fd.set_attr(
+            KVM_DEV_VFIO_FILE,
+            u64::from(KVM_DEV_VFIO_FILE_DEL),
+            &added.as_raw_fd(),
+        )?;
+        self.files.remove(&id);
+        Ok(())
+    }
+
+    /// Attaches the TCE table `table`, created with `KVM_CREATE_SPAPR_TCE_64`, to
+    /// the added VFIO group `group`. This is only supported on SPAPR hosts.
+    ///
+    /// Fails with `ENOENT` if `group` is not added to the device.
+    pub fn set_spapr_tce(&self, group: &impl AsFd, table: &impl AsFd) -> Result<()> {
+        let group = self
+            .files
+            .get(&file_id(group.as_fd())?)
+            .ok_or(errno::Error::new(libc::ENOENT))?;
+        let tce = kvm_vfio_spapr_tce {
+            groupfd: group.as_raw_fd(),
+            tablefd: table.as_fd().as_raw_fd(),
+        };
+        self.fd.set_attr(
+            KVM_DEV_VFIO_GROUP,
+            u64::from(KVM_DEV_VFIO_GROUP_SET_SPAPR_TCE),
+            &tce,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kvm;
+    use std::fs::File;
+
+    #[test]
+    fn test_vfio_kvm_device() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let mut vfio = VfioKvmDevice::new(&vm).unwrap();
+        let file = File::open("/dev/null").unwrap();
+
+        // KVM reads a valid file descriptor, and rejects it as it is not a VFIO file.
+        assert_eq!(vfio.add_file(&file).unwrap_err().errno(), libc::EINVAL);
+        assert!(vfio.is_empty());
+        assert!(!vfio.contains_file(&file).unwrap());
+        assert_eq!(vfio.remove_file(&file).unwrap_err().errno(), libc::ENOENT);
+        assert_eq!(
+            vfio.set_spapr_tce(&file, &file).unwrap_err().errno(),
+            libc::ENOENT
+        );
+
+        // Another file descriptor of an added file is rejected before reaching KVM,
+        // so the kept duplicate is not replaced.
+        let added = file.try_clone().unwrap();
+        vfio.files
+            .insert(file_id(added.as_fd()).unwrap(), added.into());
+        let other = File::open("/dev/null").unwrap();
+        assert_eq!(vfio.add_file(&other).unwrap_err().errno(), libc::EEXIST);
+        assert_eq!(vfio.len(), 1);
+        assert!(vfio.contains_file(&other).unwrap());
+    }
+}
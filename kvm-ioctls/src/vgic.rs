@@ -0,0 +1,575 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use kvm_bindings::{
+    KVM_DEV_ARM_ITS_RESTORE_TABLES, KVM_DEV_ARM_ITS_SAVE_TABLES, KVM_DEV_ARM_VGIC_CTRL_INIT,
+    KVM_DEV_ARM_VGIC_GRP_ADDR, KVM_DEV_ARM_VGIC_GRP_CPU_SYSREGS, KVM_DEV_ARM_VGIC_GRP_CTRL,
+    KVM_DEV_ARM_VGIC_GRP_DIST_REGS, KVM_DEV_ARM_VGIC_GRP_ITS_REGS, KVM_DEV_ARM_VGIC_GRP_LEVEL_INFO,
+    KVM_DEV_ARM_VGIC_GRP_NR_IRQS, KVM_DEV_ARM_VGIC_GRP_REDIST_REGS,
+    KVM_DEV_ARM_VGIC_LINE_LEVEL_INFO_SHIFT, KVM_DEV_ARM_VGIC_SAVE_PENDING_TABLES,
+    KVM_VGIC_ITS_ADDR_TYPE, KVM_VGIC_V3_ADDR_TYPE_DIST, KVM_VGIC_V3_ADDR_TYPE_REDIST,
+    KVM_VGIC_V3_ADDR_TYPE_REDIST_REGION, VGIC_LEVEL_INFO_LINE_LEVEL, kvm_create_device,
+    kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+};
+use vmm_sys_util::errno;
+
+use crate::device_attr::DeviceAttributes;
+use crate::ioctls::Result;
+use crate::ioctls::device::DeviceFd;
+use crate::ioctls::vm::VmFd;
+
+// Number of private interrupts (SGIs and PPIs), banked in the redistributors.
+const NR_PRIVATE_IRQS: u32 = 32;
+
+// Distributor registers holding a field for each SPI, with the number of bits per
+// SPI, in the order they are restored.
+const GICD_CTLR: u32 = 0x0000;
+const GICD_IIDR: u32 = 0x0008;
+const GICD_IRQ_REGS: [(u32, u32); 7] = [
+    (0x0080, 1),  // GICD_IGROUPR
+    (0x6000, 64), // GICD_IROUTER
+    (0x0c00, 2),  // GICD_ICFGR
+    (0x0400, 8),  // GICD_IPRIORITYR
+    (0x0100, 1),  // GICD_ISENABLER
+    (0x0200, 1),  // GICD_ISPENDR
+    (0x0300, 1),  // GICD_ISACTIVER
+];
+
+// Redistributor registers, as offsets from the RD_base frame, in the order they are
+// restored. The base addresses must be set before LPIs are enabled by GICR_CTLR.
+const GICR_CTLR: u32 = 0x0000;
+const GICR_SGI_BASE: u32 = 0x1_0000;
+const GICR_REGS: [(u32, u32); 8] = [
+    (0x0070, 8),                  // GICR_PROPBASER
+    (0x0078, 8),                  // GICR_PENDBASER
+    (GICR_SGI_BASE + 0x0080, 4),  // GICR_IGROUPR0
+    (GICR_SGI_BASE + 0x0c00, 8),  // GICR_ICFGR0 and GICR_ICFGR1
+    (GICR_SGI_BASE + 0x0400, 32), // GICR_IPRIORITYR<n>
+    (GICR_SGI_BASE + 0x0100, 4),  // GICR_ISENABLER0
+    (GICR_SGI_BASE + 0x0200, 4),  // GICR_ISPENDR0
+    (GICR_SGI_BASE + 0x0300, 4),  // GICR_ISACTIVER0
+];
+
+// Encodes a system register for KVM_DEV_ARM_VGIC_GRP_CPU_SYSREGS.
+const fn sys_reg(op0: u64, op1: u64, crn: u64, crm: u64, op2: u64) -> u64 {
+    (op0 << 14) | (op1 << 11) | (crn << 7) | (crm << 3) | op2
+}
+
+const ICC_SRE_EL1: u64 = sys_reg(3, 0, 12, 12, 5);
+const ICC_CTLR_EL1: u64 = sys_reg(3, 0, 12, 12, 4);
+const ICC_PMR_EL1: u64 = sys_reg(3, 0, 4, 6, 0);
+const ICC_BPR0_EL1: u64 = sys_reg(3, 0, 12, 8, 3);
+const ICC_BPR1_EL1: u64 = sys_reg(3, 0, 12, 12, 3);
+const ICC_IGRPEN0_EL1: u64 = sys_reg(3, 0, 12, 12, 6);
+const ICC_IGRPEN1_EL1: u64 = sys_reg(3, 0, 12, 12, 7);
+const fn icc_ap0r_el1(n: u64) -> u64 {
+    sys_reg(3, 0, 12, 8, 4 + n)
+}
+const fn icc_ap1r_el1(n: u64) -> u64 {
+    sys_reg(3, 0, 12, 9, n)
+}
+
+// Offsets of the ITS registers.
+const GITS_CTLR: u64 = 0x0000;
+const GITS_IIDR: u64 = 0x0004;
+const GITS_CBASER: u64 = 0x0080;
+const GITS_CWRITER: u64 = 0x0088;
+const GITS_CREADR: u64 = 0x0090;
+const GITS_BASER: u64 = 0x0100;
+
+// Converts an MPIDR_EL1 value to the affinity field of the redistributor, CPU
+// sysreg and line level attributes.
+fn mpidr_attr(mpidr: u64) -> u64 {
+    let aff = (mpidr & 0xff_ffff) | ((mpidr >> 8) & 0xff00_0000);
+    aff << 32
+}
+
+fn create_device(vm: &VmFd, type_: u32) -> Result<DeviceFd> {
+    let mut device = kvm_create_device {
+        type_,
+        fd: 0,
+        flags: 0,
+    };
+    vm.create_device(&mut device)
+}
+
+/// Builder of an in-kernel GICv3, optionally with an ITS.
+///
+/// The vGIC is initialized by [`build`](Self::build), which must be called after
+/// all the vCPUs are created.
+///
+/// # Example
+///
+/// ```rust
+/// # use kvm_ioctls::Kvm;
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let vcpu = vm.create_vcpu(0).unwrap();
+///
+/// # #[cfg(target_arch = "aarch64")]
+/// # {
+/// use kvm_ioctls::{VgicV3, VgicV3Builder};
+///
+/// let dist = 0x0800_0000;
+/// let redist = dist + VgicV3::DIST_SIZE;
+/// let vgic = VgicV3Builder::new(dist)
+///     .redist_region(redist, 1)
+///     .its(redist + VgicV3::REDIST_SIZE)
+///     .nr_irqs(128)
+///     .build(&vm)
+///     .unwrap();
+///
+/// let mpidr = 0x8000_0000;
+/// let state = vgic.save(&[mpidr]).unwrap();
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VgicV3Builder {
+    dist_addr: u64,
+    redist_addr: Option<u64>,
+    redist_regions: Vec<(u64, u32)>,
+    its_addr: Option<u64>,
+    nr_irqs: Option<u32>,
+}
+
+impl VgicV3Builder {
+    /// Creates a builder for a GICv3 with its distributor at `dist_addr`.
+    pub fn new(dist_addr: u64) -> Self {
+        VgicV3Builder {
+            dist_addr,
+            ..Default::default()
+        }
+    }
+
+    /// Places the redistributors of all the vCPUs contiguously from `addr`
+    /// (`KVM_VGIC_V3_ADDR_TYPE_REDIST`).
+    pub fn redist(mut self, addr: u64) -> Self {
+        self.redist_addr = Some(addr);
+        self
+    }
+
+    /// Adds a region of `count` redistributors at `addr`
+    /// (`KVM_VGIC_V3_ADDR_TYPE_REDIST_REGION`).
+    ///
+    /// Regions are assigned to the vCPUs in the order they are added.
+    pub fn redist_region(mut self, addr: u64, count: u32) -> Self {
+        self.redist_regions.push((addr, count));
+        self
+    }
+
+    /// Adds an ITS with its control frame at `addr`.
+    pub fn its(mut self, addr: u64) -> Self {
+        self.its_addr = Some(addr);
+        self
+    }
+
+    /// Sets the number of interrupts, including the 32 private ones.
+    ///
+    /// KVM defaults to 64 interrupts.
+    pub fn nr_irqs(mut self, nr_irqs: u32) -> Self {
+        self.nr_irqs = Some(nr_irqs);
+        self
+    }
+
+    /// Creates and initializes the GICv3 and its ITS.
+    ///
+    /// Fails with `EINVAL` if no redistributor was configured, or if both
+    /// [`redist`](Self::redist) and [`redist_region`](Self::redist_region) were.
+    pub fn build(self, vm: &VmFd) -> Result<VgicV3> {
+        if self.redist_addr.is_some() != self.redist_regions.is_empty() {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+
+        let fd = create_device(vm, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3)?;
+        fd.set_attr(
+            KVM_DEV_ARM_VGIC_GRP_ADDR,
+            u64::from(KVM_VGIC_V3_ADDR_TYPE_DIST),
+            &self.dist_addr,
+        )?;
+        if let Some(addr) = self.redist_addr {
+            fd.set_attr(
+                KVM_DEV_ARM_VGIC_GRP_ADDR,
+                u64::from(KVM_VGIC_V3_ADDR_TYPE_REDIST),
+                &addr,
+            )?;
+        }
+        for (index, &(addr, count)) in self.redist_regions.iter().enumerate() {
+            // Bits 63:52 hold the count, bits 51:16 the base and bits 11:0 the index.
+            let region = (u64::from(count) << 52) | addr | index as u64;
+            fd.set_attr(
+                KVM_DEV_ARM_VGIC_GRP_ADDR,
+                u64::from(KVM_VGIC_V3_ADDR_TYPE_REDIST_REGION),
+                &region,
+            )?;
+        }
+
+        let its = match self.its_addr {
+            Some(addr) => {
+                let its = create_device(vm, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS)?;
+                its.set_attr(
+                    KVM_DEV_ARM_VGIC_GRP_ADDR,
+                    u64::from(KVM_VGIC_ITS_ADDR_TYPE),
+                    &addr,
+                )?;
+                its.set_attr_empty(
+                    KVM_DEV_ARM_VGIC_GRP_CTRL,
+                    u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+                )?;
+                Some(Its { fd: its })
+            }
+            None => None,
+        };
+
+        if let Some(nr_irqs) = self.nr_irqs {
+            fd.set_attr(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0, &nr_irqs)?;
+        }
+        fd.set_attr_empty(
+            KVM_DEV_ARM_VGIC_GRP_CTRL,
+            u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+        )?;
+        Ok(VgicV3 { fd, its })
+    }
+}
+
+/// State of the CPU interface and redistributor of one vCPU.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VgicV3CpuState {
+    /// The MPIDR_EL1 of the vCPU.
+    pub mpidr: u64,
+    /// Redistributor registers, as `(offset, value)` pairs.
+    pub redist_regs: Vec<(u32, u32)>,
+    /// CPU interface system registers, as `(encoding, value)` pairs.
+    pub sys_regs: Vec<(u64, u64)>,
+    /// Line level of the private interrupts, one bit per interrupt.
+    pub line_level: u32,
+}
+
+/// State of a GICv3, as returned by [`VgicV3::save`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VgicV3State {
+    /// Distributor registers, as `(offset, value)` pairs.
+    pub dist_regs: Vec<(u32, u32)>,
+    /// Line level of the SPIs, one bit per interrupt from INTID 32.
+    pub line_levels: Vec<u32>,
+    /// State of each vCPU.
+    pub cpus: Vec<VgicV3CpuState>,
+    /// State of the ITS, if any.
+    pub its: Option<ItsState>,
+}
+
+/// An in-kernel GICv3 created by [`VgicV3Builder`].
+#[derive(Debug)]
+pub struct VgicV3 {
+    fd: DeviceFd,
+    its: Option<Its>,
+}
+
+impl VgicV3 {
+    /// Size of the distributor.
+    pub const DIST_SIZE: u64 = 0x1_0000;
+    /// Size of the redistributor of one vCPU.
+    pub const REDIST_SIZE: u64 = 0x2_0000;
+
+    /// Returns the file descriptor of the GICv3 device.
+    pub fn device_fd(&self) -> &DeviceFd {
+        &self.fd
+    }
+
+    /// Returns the ITS, if any.
+    pub fn its(&self) -> Option<&Its> {
+        self.its.as_ref()
+    }
+
+    /// Returns the number of interrupts, including the 32 private ones.
+    pub fn nr_irqs(&self) -> Result<u32> {
+        self.fd.get_attr(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0)
+    }
+
+    fn dist_reg_offsets(&self) -> Result<Vec<u32>> {
+        let nr_spis = self.nr_irqs()?.saturating_sub(NR_PRIVATE_IRQS);
+        let mut offsets = vec![GICD_IIDR, GICD_CTLR];
+        for (base, bits) in GICD_IRQ_REGS {
+            let start = base + NR_PRIVATE_IRQS * bits / 8;
+            let len = nr_spis * bits / 8;
+            offsets.extend((start..start + len).step_by(4));
+        }
+        Ok(offsets)
+    }
+
+    /// Saves the state of the GICv3, of the CPU interfaces of the vCPUs with the
+    /// given MPIDR_EL1 values, and of the ITS.
+    ///
+    /// The vCPUs must not be running. With an ITS, its tables and the LPI pending
+    /// tables are written to guest memory, which must be saved afterwards.
+    pub fn save(&self, mpidrs: &[u64]) -> Result<VgicV3State> {
+        let its = match &self.its {
+            Some(its) => {
+                let state = its.save()?;
+                self.fd.set_attr_empty(
+                    KVM_DEV_ARM_VGIC_GRP_CTRL,
+                    u64::from(KVM_DEV_ARM_VGIC_SAVE_PENDING_TABLES),
+                )?;
+                Some(state)
+            }
+            None => None,
+        };
+
+        let mut dist_regs = Vec::new();
+        for offset in self.dist_reg_offsets()? {
+            let value = self
+                .fd
+                .get_attr(KVM_DEV_ARM_VGIC_GRP_DIST_REGS, u64::from(offset))?;
+            dist_regs.push((offset, value));
+        }
+
+        // SPI line levels are read through the first vCPU.
+        let mut line_levels = Vec::new();
+        if let Some(&mpidr) = mpidrs.first() {
+            for intid in (NR_PRIVATE_IRQS..self.nr_irqs()?).step_by(32) {
+                line_levels.push(self.fd.get_attr(
+                    KVM_DEV_ARM_VGIC_GRP_LEVEL_INFO,
+                    Self::line_level_attr(mpidr, intid),
+                )?);
+            }
+        }
+
+        let cpus = mpidrs
+            .iter()
+            .map(|&mpidr| self.save_cpu(mpidr))
+            .collect::<Result<_>>()?;
+        Ok(VgicV3State {
+            dist_regs,
+            line_levels,
+            cpus,
+            its,
+        })
+    }
+
+    fn line_level_attr(mpidr: u64, intid: u32) -> u64 {
+        let info = VGIC_LEVEL_INFO_LINE_LEVEL << KVM_DEV_ARM_VGIC_LINE_LEVEL_INFO_SHIFT;
+        mpidr_attr(mpidr) | u64::from(info | intid)
+    }
+
+    fn save_cpu(&self, mpidr: u64) -> Result<VgicV3CpuState> {
+        let aff = mpidr_attr(mpidr);
+        let mut redist_regs = Vec::new();
+        for (base, len) in GICR_REGS.into_iter().chain([(GICR_CTLR, 4)]) {
+            for offset in (base..base + len).step_by(4) {
+                let value = self
+                    .fd
+                    .get_attr(KVM_DEV_ARM_VGIC_GRP_REDIST_REGS, aff | u64::from(offset))?;
+                redist_regs.push((offset, value));
+            }
+        }
+
+        let get_sys_reg = |reg: u64| -> Result<(u64, u64)> {
+            let value = self
+                .fd
+                .get_attr(KVM_DEV_ARM_VGIC_GRP_CPU_SYSREGS, aff | reg)?;
+            Ok((reg, value))
+        };
+        let mut sys_regs = vec![get_sys_reg(ICC_SRE_EL1)?, get_sys_reg(ICC_CTLR_EL1)?];
+        // ICC_CTLR_EL1.PRIbits gives the number of priority bits minus one, which
+        // determines the number of active priority registers.
+        let nr_apr = match (sys_regs[1].1 >> 8) & 0x7 {
+            4 => 1,
+            5 => 2,
+            _ => 4,
+        };
+        for reg in [ICC_PMR_EL1, ICC_BPR0_EL1, ICC_BPR1_EL1] {
+            sys_regs.push(get_sys_reg(reg)?);
+        }
+        for n in 0..nr_apr {
+            sys_regs.push(get_sys_reg(icc_ap0r_el1(n))?);
+        }
+        for n in 0..nr_apr {
+            sys_regs.push(get_sys_reg(icc_ap1r_el1(n))?);
+        }
+        sys_regs.push(get_sys_reg(ICC_IGRPEN0_EL1)?);
+        sys_regs.push(get_sys_reg(ICC_IGRPEN1_EL1)?);
+
+        let line_level = self.fd.get_attr(
+            KVM_DEV_ARM_VGIC_GRP_LEVEL_INFO,
+            Self::line_level_attr(mpidr, 0),
+        )?;
+        Ok(VgicV3CpuState {
+            mpidr,
+            redist_regs,
+            sys_regs,
+            line_level,
+        })
+    }
+
+    /// Restores a state returned by [`save`](Self::save) into this GICv3, which
+    /// must have been built with the same configuration and not used since.
+    ///
+    /// With an ITS, the guest memory must be restored first.
+    pub fn restore(&self, state: &VgicV3State) -> Result<()> {
+        for &(offset, value) in &state.dist_regs {
+            self.fd
+                .set_attr(KVM_DEV_ARM_VGIC_GRP_DIST_REGS, u64::from(offset), &value)?;
+        }
+        if let Some(cpu) = state.cpus.first() {
+            for (i, level) in state.line_levels.iter().enumerate() {
+                let intid = NR_PRIVATE_IRQS + 32 * i as u32;
+                self.fd.set_attr(
+                    KVM_DEV_ARM_VGIC_GRP_LEVEL_INFO,
+                    Self::line_level_attr(cpu.mpidr, intid),
+                    level,
+                )?;
+            }
+        }
+
+        for cpu in &state.cpus {
+            let aff = mpidr_attr(cpu.mpidr);
+            for &(offset, value) in &cpu.redist_regs {
+                self.fd.set_attr(
+                    KVM_DEV_ARM_VGIC_GRP_REDIST_REGS,
+                    aff | u64::from(offset),
+                    &value,
+                )?;
+            }
+            for &(reg, value) in &cpu.sys_regs {
+                self.fd
+                    .set_attr(KVM_DEV_ARM_VGIC_GRP_CPU_SYSREGS, aff | reg, &value)?;
+            }
+            self.fd.set_attr(
+                KVM_DEV_ARM_VGIC_GRP_LEVEL_INFO,
+                Self::line_level_attr(cpu.mpidr, 0),
+                &cpu.line_level,
+            )?;
+        }
+
+        match (&self.its, &state.its) {
+            (Some(its), Some(its_state)) => its.restore(its_state),
+            (None, None) => Ok(()),
+            _ => Err(errno::Error::new(libc::EINVAL)),
+        }
+    }
+}
+
+/// State of an ITS, as returned by [`Its::save`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ItsState {
+    /// GITS_CTLR.
+    pub ctlr: u64,
+    /// GITS_IIDR.
+    pub iidr: u64,
+    /// GITS_CBASER.
+    pub cbaser: u64,
+    /// GITS_CREADR.
+    pub creadr: u64,
+    /// GITS_CWRITER.
+    pub cwriter: u64,
+    /// GITS_BASER<n>.
+    pub baser: [u64; 8],
+}
+
+/// An in-kernel ITS attached to a [`VgicV3`].
+#[derive(Debug)]
+pub struct Its {
+    fd: DeviceFd,
+}
+
+impl Its {
+    /// Size of the ITS control and translation frames.
+    pub const SIZE: u64 = 0x2_0000;
+
+    /// Returns the file descriptor of the ITS device.
+    pub fn device_fd(&self) -> &DeviceFd {
+        &self.fd
+    }
+
+    fn reg(&self, offset: u64) -> Result<u64> {
+        self.fd.get_attr(KVM_DEV_ARM_VGIC_GRP_ITS_REGS, offset)
+    }
+
+    fn set_reg(&self, offset: u64, value: u64) -> Result<()> {
+        self.fd
+            .set_attr(KVM_DEV_ARM_VGIC_GRP_ITS_REGS, offset, &value)
+    }
+
+    /// Writes the ITS tables to guest memory (`KVM_DEV_ARM_ITS_SAVE_TABLES`) and
+    /// returns the ITS registers.
+    pub fn save(&self) -> Result<ItsState> {
+        self.fd.set_attr_empty(
+            KVM_DEV_ARM_VGIC_GRP_CTRL,
+            u64::from(KVM_DEV_ARM_ITS_SAVE_TABLES),
+        )?;
+        let mut state = ItsState {
+            ctlr: self.reg(GITS_CTLR)?,
+            iidr: self.reg(GITS_IIDR)?,
+            cbaser: self.reg(GITS_CBASER)?,
+            creadr: self.reg(GITS_CREADR)?,
+            cwriter: self.reg(GITS_CWRITER)?,
+            baser: [0; 8],
+        };
+        for (n, baser) in state.baser.iter_mut().enumerate() {
+            *baser = self.reg(GITS_BASER + 8 * n as u64)?;
+        }
+        Ok(state)
+    }
+
+    /// Restores the ITS registers, then its tables from guest memory
+    /// (`KVM_DEV_ARM_ITS_RESTORE_TABLES`), in the order required by KVM.
+    ///
+    /// The redistributors must be restored first.
+    pub fn restore(&self, state: &ItsState) -> Result<()> {
+        self.set_reg(GITS_IIDR, state.iidr)?;
+        // Writing GITS_CBASER resets GITS_CREADR and GITS_CWRITER.
+        self.set_reg(GITS_CBASER, state.cbaser)?;
+        self.set_reg(GITS_CREADR, state.creadr)?;
+        self.set_reg(GITS_CWRITER, state.cwriter)?;
+        for (n, &baser) in state.baser.iter().enumerate() {
+            self.set_reg(GITS_BASER + 8 * n as u64, baser)?;
+        }
+        self.fd.set_attr_empty(
+            KVM_DEV_ARM_VGIC_GRP_CTRL,
+            u64::from(KVM_DEV_ARM_ITS_RESTORE_TABLES),
+        )?;
+        self.set_reg(GITS_CTLR, state.ctlr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kvm;
+
+    #[test]
+    fn test_mpidr_attr() {
+        assert_eq!(mpidr_attr(0x8000_0000), 0);
+        assert_eq!(mpidr_attr(0x12_8034_5678), 0x1234_5678 << 32);
+        assert_eq!(icc_ap1r_el1(3), 0xc64b);
+        assert_eq!(ICC_SRE_EL1, 0xc665);
+    }
+
+    #[test]
+    fn test_vgic_v3_save_restore() {
+        let kvm = Kvm::new().unwrap();
+        let builder = VgicV3Builder::new(0x0800_0000)
+            .redist(0x0801_0000)
+            .nr_irqs(128);
+
+        let vm = kvm.create_vm().unwrap();
+        assert_eq!(
+            VgicV3Builder::new(0x0800_0000)
+                .build(&vm)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        let _vcpu = vm.create_vcpu(0).unwrap();
+        let vgic = builder.clone().build(&vm).unwrap();
+        assert_eq!(vgic.nr_irqs().unwrap(), 128);
+        let state = vgic.save(&[0]).unwrap();
+        assert_eq!(state.line_levels.len(), 3);
+        assert_eq!(state.cpus.len(), 1);
+        assert!(state.its.is_none());
+
+        let vm = kvm.create_vm().unwrap();
+        let _vcpu = vm.create_vcpu(0).unwrap();
+        let vgic = builder.build(&vm).unwrap();
+        vgic.restore(&state).unwrap();
+        assert_eq!(vgic.save(&[0]).unwrap(), state);
+    }
+}
@@ -0,0 +1,630 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::fmt;
+use std::mem::size_of_val;
+
+use kvm_bindings::{CpuId, Xsave, kvm_xsave};
+
+use crate::cpuid::entry_matches;
+
+/// An XSAVE state component.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum XstateComponent {
+    /// x87 FPU state.
+    X87 = 0,
+    /// `MXCSR` and the `XMM` registers.
+    Sse = 1,
+    /// Upper halves of the `YMM` registers.
+    Avx = 2,
+    /// MPX bound registers.
+    BndRegs = 3,
+    /// MPX bound configuration and status.
+    BndCsr = 4,
+    /// AVX-512 opmask registers.
+    Opmask = 5,
+    /// Upper halves of `ZMM0` to `ZMM15`.
+    ZmmHi256 = 6,
+    /// `ZMM16` to `ZMM31`.
+    Hi16Zmm = 7,
+    /// Protection key rights register for user pages.
+    Pkru = 9,
+    /// AMX tile configuration.
+    XtileCfg = 17,
+    /// AMX tile data.
+    XtileData = 18,
+}
+
+impl XstateComponent {
+    /// Returns the bit of this component in `XCR0`, `XSTATE_BV` and `XCOMP_BV`.
+    pub fn mask(self) -> u64 {
+        1 << self as u8
+    }
+}
+
+/// Size and position of an XSAVE state component, as reported by `CPUID` leaf 0xD.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct XstateComponentInfo {
+    /// Size of the component in bytes.
+    pub size: u32,
+    /// Offset of the component in the standard format. This is 0 for supervisor
+    /// components, which only exist in the compacted format.
+    pub offset: u32,
+    /// Whether the component is aligned to 64 bytes in the compacted format.
+    pub align64: bool,
+    /// Whether the component is managed through `IA32_XSS` rather than `XCR0`.
+    pub supervisor: bool,
+}
+
+/// The XSAVE layout of a CPU, built from `CPUID` leaf 0xD.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XsaveLayout {
+    components: [Option<XstateComponentInfo>; 64],
+    xcr0: u64,
+    xss: u64,
+}
+
+impl XsaveLayout {
+    /// Offset of the XSAVE header.
+    pub const HEADER_OFFSET: usize = 512;
+    /// Offset of the first extended component, right after the XSAVE header.
+    pub const EXTENDED_OFFSET: usize = 576;
+
+    /// Builds the layout from the leaf 0xD entries of `cpuid`, for example the
+    /// output of `Kvm::get_supported_cpuid` or the CPUID of a guest.
+    pub fn from_cpuid(cpuid: &CpuId) -> Self {
+        let entry = |subleaf| {
+            cpuid
+                .as_slice()
+                .iter()
+                .find(|entry| entry_matches(entry, 0xd, subleaf))
+        };
+        let mut layout = XsaveLayout {
+            components: [None; 64],
+            xcr0: 0,
+            xss: 0,
+        };
+        layout.components[0] = Some(XstateComponentInfo {
+            size: 160,
+            ..Default::default()
+        });
+        layout.components[1] = Some(XstateComponentInfo {
+            size: 256,
+            offset: 160,
+            ..Default::default()
+        });
+        if let Some(leaf) = entry(0) {
+            layout.xcr0 = u64::from(leaf.edx) << 32 | u64::from(leaf.eax);
+        }
+        if let Some(leaf) = entry(1) {
+            layout.xss = u64::from(leaf.edx) << 32 | u64::from(leaf.ecx);
+        }
+        for index in 2..64 {
+            layout.components[index as usize] =
+                entry(index)
+                    .filter(|leaf| leaf.eax != 0)
+                    .map(|leaf| XstateComponentInfo {
+                        size: leaf.eax,
+                        offset: leaf.ebx,
+                        align64: leaf.ecx & 0x2 != 0,
+                        supervisor: leaf.ecx & 0x1 != 0,
+                    });
+        }
+        layout
+    }
+
+    /// Returns the user components that can be enabled in `XCR0`.
+    pub fn xcr0(&self) -> u64 {
+        self.xcr0
+    }
+
+    /// Returns the supervisor components that can be enabled in `IA32_XSS`.
+    pub fn xss(&self) -> u64 {
+        self.xss
+    }
+
+    /// Returns the size and position of the component with the given index.
+    pub fn component(&self, index: u8) -> Option<XstateComponentInfo> {
+        self.components.get(usize::from(index)).copied().flatten()
+    }
+
+    /// Returns the size of a standard format area holding the components in `features`,
+    /// or `None` if one of them is unknown.
+    pub fn standard_size(&self, features: u64) -> Option<usize> {
+        let mut size = Self::EXTENDED_OFFSET;
+        for index in bits(features >> 2).map(|bit| bit + 2) {
+            let info = self.component(index)?;
+            size = size.max(info.offset as usize + info.size as usize);
+        }
+        Some(size)
+    }
+
+    /// Returns the offset of each component present in `xcomp_bv` in the compacted
+    /// format, or `None` if one of them is unknown.
+    fn compacted_offsets(&self, xcomp_bv: u64) -> Option<[usize; 64]> {
+        let mut offsets = [0; 64];
+        offsets[1] = 160;
+        let mut offset = Self::EXTENDED_OFFSET;
+        for index in bits((xcomp_bv >> 2) & !(1 << 61)).map(|bit| bit + 2) {
+            let info = self.component(index)?;
+            if info.align64 {
+                offset = offset.next_multiple_of(64);
+            }
+            offsets[usize::from(index)] = offset;
+            offset += info.size as usize;
+        }
+        Some(offsets)
+    }
+}
+
+// Iterates over the indices of the bits set in `value`.
+fn bits(mut value: u64) -> impl Iterator<Item = u8> {
+    std::iter::from_fn(move || {
+        if value == 0 {
+            return None;
+        }
+        let bit = value.trailing_zeros() as u8;
+        value &= value - 1;
+        Some(bit)
+    })
+}
+
+/// A reason why `KVM_SET_XSAVE` would reject an XSAVE area.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XsaveError {
+    /// The area is smaller than the components it holds.
+    TooSmall {
+        /// The size needed for the components in `XSTATE_BV`.
+        needed: usize,
+        /// The size of the area.
+        len: usize,
+    },
+    /// The area uses the compacted format, which KVM does not accept.
+    Compacted,
+    /// The reserved bytes of the XSAVE header are not zero.
+    ReservedHeader,
+    /// `XSTATE_BV` holds components that are not supported.
+    UnsupportedComponents(u64),
+    /// `MXCSR` sets bits that are reserved according to `MXCSR_MASK`.
+    InvalidMxcsr(u32),
+}
+
+impl fmt::Display for XsaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XsaveError::TooSmall { needed, len } => {
+                write!(f, "XSAVE area of {len} bytes, {needed} needed")
+            }
+            XsaveError::Compacted => write!(f, "XSAVE area in compacted format"),
+            XsaveError::ReservedHeader => write!(f, "XSAVE header reserved bytes are not zero"),
+            XsaveError::UnsupportedComponents(mask) => {
+                write!(f, "unsupported XSAVE components {mask:#x}")
+            }
+            XsaveError::InvalidMxcsr(mxcsr) => write!(f, "invalid MXCSR {mxcsr:#x}"),
+        }
+    }
+}
+
+impl std::error::Error for XsaveError {}
+
+/// A read-only view over an XSAVE area, such as the one returned by
+/// `VcpuFd::get_xsave` or `VcpuFd::get_xsave2`.
+///
+/// Both the standard format used by KVM and the compacted format produced by
+/// `XSAVEC`/`XSAVES` are supported. The offsets of the extended components come
+/// from an [`XsaveLayout`].
+///
+/// # Example
+///
+/// ```rust
+/// use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+/// use kvm_ioctls::{Kvm, XsaveArea, XsaveLayout};
+///
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let vcpu = vm.create_vcpu(0).unwrap();
+///
+/// let layout = XsaveLayout::from_cpuid(&kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap());
+/// let xsave = vcpu.get_xsave().unwrap();
+/// let area = XsaveArea::from_kvm_xsave(&xsave, &layout);
+/// area.check_restore(layout.xcr0()).unwrap();
+/// assert_eq!(area.fcw(), 0x37f);
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct XsaveArea<'a> {
+    bytes: &'a [u8],
+    layout: &'a XsaveLayout,
+}
+
+impl<'a> XsaveArea<'a> {
+    const MXCSR_OFFSET: usize = 24;
+    const MXCSR_MASK_OFFSET: usize = 28;
+    const ST_OFFSET: usize = 32;
+    const XMM_OFFSET: usize = 160;
+    const COMPACTED: u64 = 1 << 63;
+
+    /// Creates a view over `bytes`.
+    ///
+    /// Returns `None` if `bytes` is too small to hold the legacy area and the XSAVE
+    /// header.
+    pub fn new(bytes: &'a [u8], layout: &'a XsaveLayout) -> Option<Self> {
+        (bytes.len() >= XsaveLayout::EXTENDED_OFFSET).then_some(XsaveArea { bytes, layout })
+    }
+
+    /// Creates a view over the area returned by `VcpuFd::get_xsave`.
+    pub fn from_kvm_xsave(xsave: &'a kvm_xsave, layout: &'a XsaveLayout) -> Self {
+        // SAFETY: `region` is a plain array of `u32`, so it can be read as bytes,
+        // and the slice covers exactly its size.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(xsave.region.as_ptr().cast(), size_of_val(&xsave.region))
+        };
+        XsaveArea { bytes, layout }
+    }
+
+    /// Creates a view over the area returned by `VcpuFd::get_xsave2`, including the
+    /// components stored past the first 4096 bytes.
+    pub fn from_xsave(xsave: &'a Xsave, layout: &'a XsaveLayout) -> Self {
+        let region = &xsave.as_fam_struct_ref().xsave.region;
+        let len = size_of_val(region) + size_of_val(xsave.as_slice());
+        // SAFETY: The flexible array member directly follows `region` in the
+        // allocation owned by `xsave`, and both are plain arrays of `u32`. The
+        // pointer is derived from the pointer to the whole structure, so it is
+        // valid for the entries as well.
+        let bytes = unsafe {
+            let base = xsave.as_fam_struct_ptr();
+            std::slice::from_raw_parts(std::ptr::addr_of!((*base).xsave.region).cast(), len)
+        };
+        XsaveArea { bytes, layout }
+    }
+
+    /// Returns the raw bytes of the area.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    fn read<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.bytes.get(offset..offset + N)?.try_into().ok()
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.read(offset).unwrap())
+    }
+
+    /// Returns the `XSTATE_BV` field of the header: the components that are not in
+    /// their initial state.
+    pub fn xstate_bv(&self) -> u64 {
+        self.read_u64(XsaveLayout::HEADER_OFFSET)
+    }
+
+    /// Returns the `XCOMP_BV` field of the header.
+    pub fn xcomp_bv(&self) -> u64 {
+        self.read_u64(XsaveLayout::HEADER_OFFSET + 8)
+    }
+
+    /// Returns whether the area uses the compacted format.
+    pub fn is_compacted(&self) -> bool {
+        self.xcomp_bv() & Self::COMPACTED != 0
+    }
+
+    /// Returns the offset of the component with the given index, or `None` if the
+    /// component is not part of the area or its position is unknown.
+    pub fn component_offset(&self, index: u8) -> Option<usize> {
+        match index {
+            0 => Some(0),
+            1 => Some(Self::XMM_OFFSET),
+            2..=63 if self.is_compacted() => {
+                let xcomp_bv = self.xcomp_bv();
+                if xcomp_bv & (1 << index) == 0 {
+                    return None;
+                }
+                Some(self.layout.compacted_offsets(xcomp_bv)?[usize::from(index)])
+            }
+            2..=63 => self
+                .layout
+                .component(index)
+                .filter(|info| !info.supervisor)
+                .map(|info| info.offset as usize),
+            _ => None,
+        }
+    }
+
+    /// Returns the bytes of the component with the given index, or `None` if the
+    /// component is in its initial state, is not part of the area or its position is
+    /// unknown.
+    ///
+    /// For the x87 and SSE components, the first 160 bytes of the legacy area and
+    /// the `XMM` registers are returned. `MXCSR` belongs to the SSE component but
+    /// is stored among the x87 bytes: use [`mxcsr`](Self::mxcsr) to read it.
+    pub fn component_bytes(&self, index: u8) -> Option<&'a [u8]> {
+        if index >= 64 || self.xstate_bv() & (1 << index) == 0 {
+            return None;
+        }
+        let offset = self.component_offset(index)?;
+        let size = self.layout.component(index)?.size as usize;
+        self.bytes.get(offset..offset + size)
+    }
+
+    /// Returns the bytes of `component`; see [`component_bytes`](Self::component_bytes).
+    pub fn component(&self, component: XstateComponent) -> Option<&'a [u8]> {
+        self.component_bytes(component as u8)
+    }
+
+    /// Returns the x87 FPU control word.
+    pub fn fcw(&self) -> u16 {
+        u16::from_le_bytes(self.read(0).unwrap())
+    }
+
+    /// Returns the x87 FPU status word.
+    pub fn fsw(&self) -> u16 {
+        u16::from_le_bytes(self.read(2).unwrap())
+    }
+
+    /// Returns the abridged x87 FPU tag word.
+    pub fn ftw(&self) -> u8 {
+        self.bytes[4]
+    }
+
+    /// Returns the 80-bit x87 register `ST(i)`, or `MM(i)`, for `i` in `0..8`.
+    pub fn st(&self, i: usize) -> Option<[u8; 10]> {
+        (i < 8).then(|| self.read(Self::ST_OFFSET + 16 * i).unwrap())
+    }
+
+    /// Returns the `MXCSR` register.
+    pub fn mxcsr(&self) -> u32 {
+        u32::from_le_bytes(self.read(Self::MXCSR_OFFSET).unwrap())
+    }
+
+    /// Returns the `MXCSR_MASK` field: the `MXCSR` bits supported by the CPU.
+    pub fn mxcsr_mask(&self) -> u32 {
+        u32::from_le_bytes(self.read(Self::MXCSR_MASK_OFFSET).unwrap())
+    }
+
+    /// Returns the register `XMMi`, for `i` in `0..16`.
+    pub fn xmm(&self, i: usize) -> Option<u128> {
+        (i < 16).then(|| u128::from_le_bytes(self.read(Self::XMM_OFFSET + 16 * i).unwrap()))
+    }
+
+    /// Returns the upper 128 bits of `YMMi`, for `i` in `0..16`.
+    pub fn ymm_hi128(&self, i: usize) -> Option<u128> {
+        let avx = self.component(XstateComponent::Avx)?;
+        Some(u128::from_le_bytes(
+            avx.get(16 * i..16 * (i + 1))?.try_into().ok()?,
+        ))
+    }
+
+    /// Returns the AVX-512 opmask register `Ki`, for `i` in `0..8`.
+    pub fn opmask(&self, i: usize) -> Option<u64> {
+        let opmask = self.component(XstateComponent::Opmask)?;
+        Some(u64::from_le_bytes(
+            opmask.get(8 * i..8 * (i + 1))?.try_into().ok()?,
+        ))
+    }
+
+    /// Returns the upper 256 bits of `ZMMi`, for `i` in `0..16`.
+    pub fn zmm_hi256(&self, i: usize) -> Option<[u8; 32]> {
+        let zmm = self.component(XstateComponent::ZmmHi256)?;
+        zmm.get(32 * i..32 * (i + 1))?.try_into().ok()
+    }
+
+    /// Returns `ZMMi`, for `i` in `16..32`.
+    pub fn hi16_zmm(&self, i: usize) -> Option<[u8; 64]> {
+        let zmm = self.component(XstateComponent::Hi16Zmm)?;
+        let i = i.checked_sub(16)?;
+        zmm.get(64 * i..64 * (i + 1))?.try_into().ok()
+    }
+
+    /// Returns the `PKRU` register.
+    pub fn pkru(&self) -> Option<u32> {
+        let pkru = self.component(XstateComponent::Pkru)?;
+        Some(u32::from_le_bytes(pkru.get(..4)?.try_into().ok()?))
+    }
+
+    /// Returns the AMX tile configuration.
+    pub fn xtilecfg(&self) -> Option<&'a [u8]> {
+        self.component(XstateComponent::XtileCfg)
+    }
+
+    /// Returns the AMX tile data.
+    pub fn xtiledata(&self) -> Option<&'a [u8]> {
+        self.component(XstateComponent::XtileData)
+    }
+
+    /// Checks that `KVM_SET_XSAVE` would accept this area for a vCPU supporting the
+    /// user components in `xcr0`.
+    ///
+    /// `xcr0` is usually [`XsaveLayout::xcr0`] for the layout of the destination host,
+    /// or the XSAVE features enabled in the CPUID of the guest.
+    pub fn check_restore(&self, xcr0: u64) -> std::result::Result<(), XsaveError> {
+        if self.xcomp_bv() != 0 {
+            return Err(XsaveError::Compacted);
+        }
+        let reserved = &self.bytes[XsaveLayout::HEADER_OFFSET + 16..XsaveLayout::EXTENDED_OFFSET];
+        if reserved.iter().any(|&byte| byte != 0) {
+            return Err(XsaveError::ReservedHeader);
+        }
+        let xstate_bv = self.xstate_bv();
+        let unsupported = xstate_bv & !(xcr0 | 0x3);
+        if unsupported != 0 {
+            return Err(XsaveError::UnsupportedComponents(unsupported));
+        }
+        let needed = self
+            .layout
+            .standard_size(xstate_bv)
+            .ok_or(XsaveError::UnsupportedComponents(xstate_bv))?;
+        if self.bytes.len() < needed {
+            return Err(XsaveError::TooSmall {
+                needed,
+                len: self.bytes.len(),
+            });
+        }
+        let mxcsr_mask = match self.mxcsr_mask() {
+            0 => 0xffbf,
+            mask => mask,
+        };
+        if self.mxcsr() & !mxcsr_mask != 0 {
+            return Err(XsaveError::InvalidMxcsr(self.mxcsr()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kvm;
+    use kvm_bindings::{KVM_CPUID_FLAG_SIGNIFCANT_INDEX, KVM_MAX_CPUID_ENTRIES, kvm_cpuid_entry2};
+
+    fn layout() -> XsaveLayout {
+        let leaf = |index, eax, ebx, ecx, edx| kvm_cpuid_entry2 {
+            function: 0xd,
+            index,
+            flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+            eax,
+            ebx,
+            ecx,
+            edx,
+            ..Default::default()
+        };
+        // x87, SSE, AVX, opmask, ZMM_Hi256, Hi16_ZMM and PKRU, as on Skylake-SP.
+        XsaveLayout::from_cpuid(
+            &CpuId::from_entries(&[
+                leaf(0, 0x2e7, 0xa88, 0xa88, 0),
+                leaf(1, 0xf, 0, 0, 0),
+                leaf(2, 256, 576, 0, 0),
+                leaf(5, 64, 1088, 0, 0),
+                leaf(6, 512, 1152, 0, 0),
+                leaf(7, 1024, 1664, 0, 0),
+                leaf(9, 8, 2688, 0, 0),
+            ])
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = layout();
+        assert_eq!(layout.xcr0(), 0x2e7);
+        assert_eq!(layout.component(9).unwrap().size, 8);
+        assert!(layout.component(3).is_none());
+        assert_eq!(layout.standard_size(0x3), Some(576));
+        assert_eq!(layout.standard_size(0x2e7), Some(2696));
+        assert_eq!(layout.standard_size(1 << 17), None);
+
+        let offsets = layout.compacted_offsets(0x2e7).unwrap();
+        assert_eq!(offsets[2], 576);
+        assert_eq!(offsets[5], 832);
+        assert_eq!(offsets[6], 896);
+        assert_eq!(offsets[7], 1408);
+        assert_eq!(offsets[9], 2432);
+    }
+
+    #[test]
+    fn test_standard_area() {
+        let layout = layout();
+        let mut bytes = vec![0u8; 4096];
+        bytes[0..2].copy_from_slice(&0x37fu16.to_le_bytes());
+        bytes[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        bytes[160 + 16..160 + 32].copy_from_slice(&7u128.to_le_bytes());
+        bytes[512..520].copy_from_slice(&0x207u64.to_le_bytes());
+        bytes[576 + 16..576 + 32].copy_from_slice(&9u128.to_le_bytes());
+        bytes[2688..2692].copy_from_slice(&0x5555_5554u32.to_le_bytes());
+        // Opmask is in its initial state, even if the bytes are not zero.
+        bytes[1088] = 0xff;
+
+        let area = XsaveArea::new(&bytes, &layout).unwrap();
+        assert!(XsaveArea::new(&bytes[..575], &layout).is_none());
+        assert!(!area.is_compacted());
+        assert_eq!(area.fcw(), 0x37f);
+        assert_eq!(area.mxcsr(), 0x1f80);
+        assert_eq!(area.xmm(1), Some(7));
+        assert_eq!(area.xmm(16), None);
+        assert_eq!(area.ymm_hi128(1), Some(9));
+        assert_eq!(area.ymm_hi128(16), None);
+        assert_eq!(area.opmask(0), None);
+        assert_eq!(area.pkru(), Some(0x5555_5554));
+        assert_eq!(area.component(XstateComponent::Avx).unwrap().len(), 256);
+        assert_eq!(area.xtilecfg(), None);
+        assert_eq!(area.check_restore(layout.xcr0()), Ok(()));
+
+        // The destination does not support PKRU.
+        assert_eq!(
+            area.check_restore(0x7),
+            Err(XsaveError::UnsupportedComponents(
+                XstateComponent::Pkru.mask()
+            ))
+        );
+        assert_eq!(
+            XsaveArea::new(&bytes[..2048], &layout)
+                .unwrap()
+                .check_restore(layout.xcr0()),
+            Err(XsaveError::TooSmall {
+                needed: 2696,
+                len: 2048
+            })
+        );
+        bytes[530] = 1;
+        assert_eq!(
+            XsaveArea::new(&bytes, &layout)
+                .unwrap()
+                .check_restore(layout.xcr0()),
+            Err(XsaveError::ReservedHeader)
+        );
+        bytes[530] = 0;
+        bytes[24..28].copy_from_slice(&0x1_0000u32.to_le_bytes());
+        assert_eq!(
+            XsaveArea::new(&bytes, &layout)
+                .unwrap()
+                .check_restore(layout.xcr0()),
+            Err(XsaveError::InvalidMxcsr(0x1_0000))
+        );
+    }
+
+    #[test]
+    fn test_compacted_area() {
+        let layout = layout();
+        let mut bytes = vec![0u8; 4096];
+        // XCOMP_BV without AVX, so opmask directly follows the header.
+        bytes[512..520].copy_from_slice(&0x220u64.to_le_bytes());
+        bytes[520..528].copy_from_slice(&(0x220u64 | 1 << 63).to_le_bytes());
+        bytes[576 + 8..576 + 16].copy_from_slice(&0xf0u64.to_le_bytes());
+        bytes[640..644].copy_from_slice(&0x4u32.to_le_bytes());
+
+        let area = XsaveArea::new(&bytes, &layout).unwrap();
+        assert!(area.is_compacted());
+        assert_eq!(area.component_offset(5), Some(576));
+        assert_eq!(area.component_offset(9), Some(640));
+        assert_eq!(area.component_offset(2), None);
+        assert_eq!(area.opmask(1), Some(0xf0));
+        assert_eq!(area.pkru(), Some(0x4));
+        assert_eq!(area.ymm_hi128(0), None);
+        assert_eq!(
+            area.check_restore(layout.xcr0()),
+            Err(XsaveError::Compacted)
+        );
+    }
+
+    #[test]
+    fn test_vcpu_xsave() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        vcpu.set_cpuid2(&cpuid).unwrap();
+        let layout = XsaveLayout::from_cpuid(&cpuid);
+
+        let mut xsave = vcpu.get_xsave().unwrap();
+        let area = XsaveArea::from_kvm_xsave(&xsave, &layout);
+        area.check_restore(layout.xcr0()).unwrap();
+        assert_eq!(area.fcw(), 0x37f);
+        assert_eq!(area.mxcsr(), 0x1f80);
+
+        // XMM3 lives at byte 208 of the legacy area.
+        xsave.region[52..56].copy_from_slice(&[1, 2, 3, 4]);
+        xsave.region[128] |= 0x2;
+        // SAFETY: `xsave` was returned by `get_xsave`, so it has the expected size.
+        unsafe { vcpu.set_xsave(&xsave).unwrap() };
+        let fpu = vcpu.get_fpu().unwrap();
+        let xmm3 = XsaveArea::from_kvm_xsave(&xsave, &layout).xmm(3).unwrap();
+        assert_eq!(xmm3.to_le_bytes(), fpu.xmm[3]);
+    }
+}